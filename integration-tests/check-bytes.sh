@@ -0,0 +1,12 @@
+# RUN: sh @file
+
+# Offsets and the ASCII gutter are ignored, and sequences may span lines.
+# CHECK: begin dump
+# CHECK-BYTES: DE AD
+# CHECK-BYTES: BEEF 0102 0304 0506
+# CHECK: end dump
+echo begin dump
+echo "00000000  00 de ad 00 00 00 00 00  |........|"
+echo "00000008  00 00 be ef 01 02 03 04  |........|"
+echo "00000010  05 06 07 08 09 0a 0b 0c  |........|"
+echo end dump
@@ -0,0 +1,12 @@
+# RUN: sh @file
+
+# Each record must appear exactly once, in any order.
+echo "record: carbon"
+echo "record: hydrogen"
+echo "record: oxygen"
+echo "end of records"
+
+# CHECK-DAG-ONCE: record: hydrogen
+# CHECK-DAG-ONCE: record: oxygen
+# CHECK-DAG-ONCE: record: carbon
+# CHECK: end of records
@@ -0,0 +1,14 @@
+# RUN: sh @file
+
+# The order of the lines within each group does not matter.
+echo "second"
+echo "first"
+echo "separator"
+echo "fourth"
+echo "third"
+
+# CHECK-DAG: first
+# CHECK-DAG: second
+# CHECK: separator
+# CHECK-DAG: third
+# CHECK-DAG: fourth
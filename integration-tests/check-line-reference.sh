@@ -0,0 +1,5 @@
+# RUN: sh @file
+
+# The line number of the echo below is 4.
+echo "reported line 4"
+# CHECK: reported line [[@LINE-1]]
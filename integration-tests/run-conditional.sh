@@ -0,0 +1,4 @@
+# RUN: %if lit-integration-test-nonexistent-feature %{ echo wrong branch %} %else %{ sh @file %}
+
+# CHECK: right branch
+echo right branch
@@ -3,13 +3,21 @@
 //! Use the code in this module to tune testing behaviour.
 
 #[cfg(feature = "clap")] pub mod clap;
+pub mod matchers;
 
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::time::Duration;
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use crate::file_system::{FileSystem, OsFileSystem};
+use crate::model::{TestFile, TestResultKind};
+use regex::Regex;
 use tempfile::NamedTempFile;
 
 const DEFAULT_MAX_OUTPUT_CONTEXT_LINE_COUNT: usize = 10;
+const DEFAULT_CONTEXT_LINES: usize = 3;
+const DEFAULT_SPAWN_RETRIES: u32 = 3;
 
 /// The configuration of the test runner.
 #[derive(Clone, Debug)]
@@ -21,6 +29,8 @@ pub struct Config
     pub test_paths: Vec<PathBuf>,
     /// Constants that tests can refer to via `@<name>` syntax.
     pub constants: HashMap<String, String>,
+    /// Parameters of the test suite, like a target triple, which tests can substitute with `%{param:<name>}`.
+    pub params: HashMap<String, String>,
     /// A function which used to dynamically lookup variables.
     ///
     /// The default variable lookup can be found at `Config::DEFAULT_VARIABLE_LOOKUP`.
@@ -28,12 +38,29 @@ pub struct Config
     /// In your own custom variable lookups, most of the time you will want to
     /// include a fallback call to `Config::DEFAULT_VARIABLE_LOOKUP`.
     pub variable_lookup: VariableLookup,
+    /// When temporary files generated by the tests should be kept, rather
+    /// than cleaned up.
+    ///
+    /// This includes temporary files created by using `@tempfile`
+    /// variables. By default, they are only kept for failing tests.
+    pub keep_tempfiles: KeepTempfiles,
     /// Whether temporary files generated by the tests should be
     /// cleaned up, where possible.
     ///
-    /// This includes temporary files created by using `@tempfile`
-    /// variables.
+    /// If this is `false`, temporary files are always kept, whatever `keep_tempfiles` says.
+    #[deprecated(note = "use `keep_tempfiles` instead")]
     pub cleanup_temporary_files: bool,
+    /// If set, parsed test files are cached inside this directory, so that
+    /// unchanged tests do not need to be parsed again on subsequent runs.
+    pub parse_cache_directory: Option<PathBuf>,
+    /// Whether directives are only read from the header of each test file.
+    ///
+    /// The header ends at the first blank line, or at the first line containing
+    /// `END-DIRECTIVES`, whichever comes first. Lines after it are never scanned.
+    pub header_only_directives: bool,
+    /// Whether every test should be parsed before any are run, so that all invalid
+    /// test files are reported at once and none of the tests run if any are invalid.
+    pub validate_first: bool,
     /// Export all generated test artifacts to the specified directory.
     pub save_artifacts_to_directory: Option<PathBuf>,
     /// Whether verbose information about resolved variables should be printed to stderr.
@@ -41,20 +68,239 @@ pub struct Config
     /// If set, debug output should be truncated to this many number of
     /// context lines.
     pub truncate_output_context_to_number_of_lines: Option<usize>,
+    /// The number of lines of output shown before and after the position that a failed check
+    /// searched from.
+    pub context_lines: usize,
     /// A list of extra directory paths that should be included in the `$PATH` when
     /// executing processes specified inside the tests.
     pub extra_executable_search_paths: Vec<PathBuf>,
     /// Whether messages on the standard error streams emitted during test runs
     /// should always be shown.
     pub always_show_stderr: bool,
+    /// Whether checks against standard output should match the standard output of each
+    /// `RUN` command followed by its standard error.
+    ///
+    /// The streams are captured separately and then concatenated, so unlike redirecting
+    /// with `2>&1`, the combined output does not depend on how the program buffers its output.
+    pub combine_stderr_into_stdout: bool,
+    /// The value of `@os` that `CHECK-FILE` directives fall back to, if the expected
+    /// file for the current platform does not exist.
+    ///
+    /// For example, with `default`, `CHECK-FILE: expected.@os.txt` on Linux reads
+    /// `expected.linux.txt` if it exists, otherwise `expected.default.txt`.
+    pub check_file_fallback_os: Option<String>,
+    /// Whether any output on the standard error stream of a `RUN` command
+    /// should fail the test, unless the test checks the standard error stream itself.
+    pub fail_on_unexpected_stderr: bool,
+    /// Only run one shard of the test suite, given as the zero-based index of
+    /// the shard along with the total number of shards.
+    ///
+    /// Tests are assigned to shards by their position in the discovery order,
+    /// so shards are balanced and stable as long as the set of tests is unchanged.
+    pub shard: Option<(usize, usize)>,
+    /// If set, tests are executed in a shuffled order which is determined by this seed,
+    /// instead of the order that they were discovered in.
+    ///
+    /// The `Results` still list the tests in discovery order. The seed is printed if the test
+    /// suite fails, so that the order can be reproduced.
+    pub shuffle_seed: Option<u64>,
+    /// Decides whether each test is run, after it has been parsed.
+    ///
+    /// Tests which are not selected are reported as filtered, like tests in other shards.
+    /// This allows picking tests by any property of the test, such as its directives,
+    /// rather than only by its path.
+    pub selector: Option<TestSelector>,
+    /// Tests which are known to fail, given by their absolute paths or their paths relative to the test directory.
+    ///
+    /// Failures and errors of these tests are reported as known failures, which do not fail
+    /// the test suite. If one of these tests passes, it is reported as an unexpected pass,
+    /// so that it can be removed from the list.
+    pub allow_failures: HashSet<PathBuf>,
+    /// If set, only the `RUN` directive with this one-based index is executed in each test.
+    ///
+    /// Tests with fewer `RUN` directives are skipped.
+    pub run_line: Option<usize>,
+    /// Whether tests declared with `XFAIL` that pass should not fail the test suite.
+    pub tolerate_unexpected_pass: bool,
+    /// Whether only failing tests and a one-line summary should be printed.
+    pub quiet: bool,
+    /// Whether the reason for every skipped test should be printed, even when `quiet` is set.
+    pub verbose_skips: bool,
+    /// Whether failures with identical messages should be reported once, along with
+    /// the paths of every test that failed with that message.
+    pub fold_identical_failures: bool,
+    /// Whether the number of finished tests and an estimate of the time remaining should be
+    /// printed after each test.
+    pub show_progress: bool,
+    /// Whether unquoted glob patterns in `RUN` commands, like `*.c`, are expanded by lit
+    /// relative to the directory of the test, rather than by the shell.
+    pub expand_globs: bool,
+    /// Whether an expanded glob which matches no files is passed to the command as it is,
+    /// instead of being an error.
+    pub keep_unmatched_globs: bool,
     /// Which shell to use (defaults to 'bash').
     pub shell: String,
+    /// A program and arguments that every `RUN` command is executed by, like
+    /// `["timeout", "60"]`. The shell running the command is passed as extra arguments.
+    pub run_wrapper: Option<Vec<String>>,
+    /// Environment variables which are set for every program executed by the tests.
+    pub env: HashMap<String, String>,
+    /// If set, the name of an environment file which is looked up in the directory of
+    /// each test, for example `.env`.
+    ///
+    /// The file contains `NAME=VALUE` lines. Blank lines and lines starting with `#`
+    /// are ignored. Variables from the file take precedence over `Config::env`,
+    /// and variables set inline by a `RUN` command take precedence over both.
+    pub env_file_name: Option<String>,
+    /// If set, the name of a configuration file which is looked up in the directory of
+    /// each test and in every directory above it, for example `lit.toml`.
+    ///
+    /// The files can set constants, available features and the comment prefix, using
+    /// a small subset of TOML. Files in nearer directories override farther ones.
+    pub directory_config_file_name: Option<String>,
+    /// If set, directives are only read from lines starting with this prefix, ignoring
+    /// any leading whitespace.
+    pub comment_prefix: Option<String>,
+    /// The maximum number of tests to execute at the same time.
+    ///
+    /// Defaults to the number of CPUs available to lit.
+    pub jobs: usize,
+    /// If set, the test is never executed at the same time as other tests with the same key.
+    ///
+    /// This is set to the directory of a configuration file containing `parallel = false`.
+    pub serialization_key: Option<PathBuf>,
+    /// The features available in the current testing environment.
+    ///
+    /// These are consulted by `%if <feature> %{ ... %} %else %{ ... %}`
+    /// conditionals inside `RUN` directives.
+    pub available_features: HashSet<String>,
+    /// Transformations applied, in order, to the stdout and stderr of every `RUN`
+    /// command before it is checked.
+    ///
+    /// The original output is still used when reporting results.
+    pub output_scrubbers: Vec<OutputScrubber>,
+    /// Functions that `CHECK-CUSTOM[<name>]` directives match output lines with, by name.
+    ///
+    /// Each function is given the argument of the directive and a line of output, and
+    /// returns whether the line matches. See the `matchers` module for examples.
+    pub custom_matchers: HashMap<String, CustomMatcher>,
+    /// Whether trailing whitespace on each line of output, and at the end of each
+    /// check pattern, should be ignored.
+    ///
+    /// Lines which only contain whitespace are treated as empty.
+    pub ignore_trailing_whitespace: bool,
+    /// Whether the percent substitutions of LLVM's lit, like `%s` and `%t`, are expanded in
+    /// `RUN` commands.
+    ///
+    /// This is off by default, because commands like `printf '%s\n'` use the same syntax.
+    pub percent_substitutions: bool,
+    /// The factor that the durations of `CHECK-TIME` directives are multiplied by,
+    /// to allow for environments that are slower than usual.
+    pub time_scale: f64,
+    /// If set, limits how long each `RUN` command may run for, unless the test has its own
+    /// `TIMEOUT` directive. Commands that run for longer are killed.
+    pub timeout: Option<Duration>,
+    /// How many times starting the process of a `RUN` command is retried, with a delay growing up
+    /// to a second, when the operating system is temporarily unable to start it.
+    ///
+    /// Other errors, and commands which fail, are never retried.
+    pub spawn_retries: u32,
+    /// If set, the test suite stops when the token is cancelled.
+    ///
+    /// Tests which have not started are not run, and the commands of tests which are
+    /// running are killed, so that the run stops promptly. Both are reported as skipped
+    /// with `SkipReason::Cancelled`.
+    pub cancellation_token: Option<CancellationToken>,
+    /// Whether lit installs handlers for `SIGINT`, `SIGTERM` and `SIGHUP` which kill the
+    /// commands that are running before lit exits.
+    ///
+    /// This is off by default, so that applications which embed lit keep their own signal
+    /// handling. The `lit` binary turns it on.
+    pub kill_children_on_termination: bool,
+    /// The file system that tests, and the files that they read, are loaded from.
+    ///
+    /// `RUN` commands are always executed by the operating system.
+    pub file_system: Arc<dyn FileSystem>,
 }
 
 /// A function which can dynamically define newly used variables in a test.
 #[derive(Clone)]
 pub struct VariableLookup(fn(&str) -> Option<String>);
 
+/// A function which decides whether a test should be run.
+#[derive(Clone)]
+pub struct TestSelector(Arc<dyn Fn(&TestFile) -> bool + Send + Sync>);
+
+/// When the temporary files of a `RUN` command are kept for inspection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KeepTempfiles {
+    /// Temporary files are never cleaned up.
+    Always,
+    /// Temporary files are always cleaned up.
+    Never,
+    /// Temporary files are only kept if the `RUN` command does not succeed.
+    OnFailure,
+}
+
+impl KeepTempfiles {
+    /// Checks if the temporary files of a `RUN` command with the given result should be kept.
+    pub fn should_keep(self, result: &TestResultKind) -> bool {
+        match self {
+            KeepTempfiles::Always => true,
+            KeepTempfiles::Never => false,
+            KeepTempfiles::OnFailure => result.is_erroneous(),
+        }
+    }
+}
+
+impl std::str::FromStr for KeepTempfiles {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "always" => Ok(KeepTempfiles::Always),
+            "never" => Ok(KeepTempfiles::Never),
+            "on-failure" => Ok(KeepTempfiles::OnFailure),
+            _ => Err(format!("expected 'always', 'never' or 'on-failure' but got '{}'", s)),
+        }
+    }
+}
+
+/// A function which checks if a line of output matches the argument of a `CHECK-CUSTOM` directive.
+pub type CustomMatcher = fn(argument: &str, line: &str) -> bool;
+
+/// A flag which cancels a test suite run, for example when it is set from another thread.
+///
+/// Clones of a token share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Creates a token which is not yet cancelled.
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Cancels the test suite run that uses this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Checks if the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// A transformation of program output, used to normalize text that differs between runs.
+#[derive(Clone, Debug)]
+pub enum OutputScrubber {
+    /// Replaces every match of a regex. The replacement may refer to capture groups, like `$1`.
+    Regex { regex: Regex, replacement: String },
+    /// Transforms the output with a function.
+    Function(fn(&str) -> String),
+}
+
 impl Config
 {
     /// The default variable lookup function.
@@ -92,6 +338,37 @@ impl Config
         self.test_paths.push(PathBuf::from(path.into()).canonicalize().unwrap());
     }
 
+    /// Marks a feature as available to the tests.
+    pub fn add_feature<S>(&mut self, feature: S) where S: AsRef<str> {
+        self.available_features.insert(feature.as_ref().to_owned());
+    }
+
+    /// Checks if a feature is available to the tests.
+    ///
+    /// Unknown features are simply unavailable. A feature like `<name>=<value>` is
+    /// available if the parameter with the name has the value.
+    pub fn is_feature_available(&self, feature: &str) -> bool {
+        match feature.split_once('=') {
+            Some((name, value)) => self.params.get(name).is_some_and(|v| v == value),
+            None => self.available_features.contains(feature),
+        }
+    }
+
+    /// Registers a matcher which can be used by `CHECK-CUSTOM[<name>]` directives.
+    pub fn add_custom_matcher<S>(&mut self, name: S, matcher: CustomMatcher) where S: Into<String> {
+        self.custom_matchers.insert(name.into(), matcher);
+    }
+
+    /// Checks if a test is chosen to be run by the selector, if there is one.
+    pub fn is_selected(&self, test_file: &TestFile) -> bool {
+        self.selector.as_ref().is_none_or(|selector| selector.selects(test_file))
+    }
+
+    /// Checks if the test suite run has been cancelled by the cancellation token.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation_token.as_ref().is_some_and(CancellationToken::is_cancelled)
+    }
+
     /// Adds an extra executable directory to the OS `$PATH` when executing tests.
     pub fn add_executable_search_path<P>(&mut self, path: P) where P: AsRef<Path> {
         self.extra_executable_search_paths.push(path.as_ref().to_owned())
@@ -111,26 +388,29 @@ impl Config
             find(|ext| &ext[..] == extension).is_some()
     }
 
+    /// Checks if a variable has a value, either in the given variables or from the variable lookup.
+    pub(crate) fn has_variable(&self, name: &str, variables: &HashMap<String, String>) -> bool {
+        variables.contains_key(name) || self.variable_lookup.0(name).is_some()
+    }
+
     /// Looks up a variable.
     pub fn lookup_variable<'a>(&self,
                            name: &str,
                            variables: &'a mut HashMap<String, String>)
         -> &'a str {
         if !variables.contains_key(name) {
-            match self.variable_lookup.0(name) {
-                Some(initial_value) => {
-                    variables.insert(name.to_owned(), initial_value.clone());
-                },
-                None => (),
+            if let Some(initial_value) = self.variable_lookup.0(name) {
+                variables.insert(name.to_owned(), initial_value);
             }
         }
 
-        variables.get(name).expect(&format!("no variable with the name '{}' exists", name))
+        variables.get(name).unwrap_or_else(|| panic!("no variable with the name '{}' exists", name))
     }
 }
 
 impl Default for Config
 {
+    #[allow(deprecated)]
     fn default() -> Self {
         let mut extra_executable_search_paths = Vec::new();
 
@@ -146,18 +426,105 @@ impl Default for Config
             supported_file_extensions: Vec::new(),
             test_paths: Vec::new(),
             constants: HashMap::new(),
+            params: HashMap::new(),
             variable_lookup: Config::DEFAULT_VARIABLE_LOOKUP,
+            keep_tempfiles: KeepTempfiles::OnFailure,
             cleanup_temporary_files: true,
+            parse_cache_directory: None,
+            header_only_directives: false,
+            validate_first: false,
             save_artifacts_to_directory: None,
             dump_variable_resolution: false,
             always_show_stderr: false,
+            combine_stderr_into_stdout: false,
+            check_file_fallback_os: None,
+            fail_on_unexpected_stderr: false,
+            shard: None,
+            shuffle_seed: None,
+            selector: None,
+            tolerate_unexpected_pass: false,
+            quiet: false,
+            fold_identical_failures: false,
+            show_progress: false,
+            expand_globs: false,
+            keep_unmatched_globs: false,
+            verbose_skips: false,
             truncate_output_context_to_number_of_lines: Some(DEFAULT_MAX_OUTPUT_CONTEXT_LINE_COUNT),
+            context_lines: DEFAULT_CONTEXT_LINES,
             extra_executable_search_paths,
             shell: "bash".to_string(),
+            run_wrapper: None,
+            env: HashMap::new(),
+            env_file_name: None,
+            directory_config_file_name: None,
+            comment_prefix: None,
+            jobs: std::thread::available_parallelism().map_or(1, |jobs| jobs.get()),
+            serialization_key: None,
+            available_features: HashSet::new(),
+            allow_failures: HashSet::new(),
+            run_line: None,
+            output_scrubbers: Vec::new(),
+            custom_matchers: HashMap::new(),
+            ignore_trailing_whitespace: false,
+            percent_substitutions: false,
+            time_scale: 1.0,
+            timeout: None,
+            spawn_retries: DEFAULT_SPAWN_RETRIES,
+            cancellation_token: None,
+            kill_children_on_termination: false,
+            file_system: Arc::new(OsFileSystem),
+        }
+    }
+}
+
+impl OutputScrubber {
+    /// Creates a scrubber which replaces every match of a regex.
+    pub fn regex(regex: &str, replacement: &str) -> Result<Self, regex::Error> {
+        Ok(OutputScrubber::Regex { regex: Regex::new(regex)?, replacement: replacement.to_owned() })
+    }
+
+    /// Creates a scrubber which replaces timestamps like `2020-01-31 12:30:59` or
+    /// `2020-01-31T12:30:59.123Z` with `<TIMESTAMP>`.
+    pub fn timestamps() -> Self {
+        OutputScrubber::regex(r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(\.\d+)?(Z|[+-]\d{2}:?\d{2})?", "<TIMESTAMP>").unwrap()
+    }
+
+    /// Creates a scrubber which replaces paths inside the temporary directory with `<TEMP PATH>`.
+    pub fn temporary_paths() -> Self {
+        let temp_dir = std::env::temp_dir();
+        let temp_dir = temp_dir.to_string_lossy();
+        let temp_dir = temp_dir.trim_end_matches(std::path::MAIN_SEPARATOR);
+
+        OutputScrubber::regex(&format!(r#"{}[^\s'"]*"#, regex::escape(temp_dir)), "<TEMP PATH>").unwrap()
+    }
+
+    /// Applies the scrubber to some output.
+    pub fn scrub(&self, output: &str) -> String {
+        match *self {
+            OutputScrubber::Regex { ref regex, ref replacement } => regex.replace_all(output, &replacement[..]).into_owned(),
+            OutputScrubber::Function(f) => f(output),
         }
     }
 }
 
+impl TestSelector {
+    /// Creates a selector from a function which returns `true` for the tests that should be run.
+    pub fn new<F>(f: F) -> Self where F: Fn(&TestFile) -> bool + Send + Sync + 'static {
+        TestSelector(Arc::new(f))
+    }
+
+    /// Checks if a test should be run.
+    pub fn selects(&self, test_file: &TestFile) -> bool {
+        (self.0)(test_file)
+    }
+}
+
+impl fmt::Debug for TestSelector {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        "<function>".fmt(fmt)
+    }
+}
+
 impl fmt::Debug for VariableLookup {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         "<function>".fmt(fmt)
@@ -196,5 +563,19 @@ mod test {
                    config.lookup_variable("second_tempfile", &mut variables),
                    "second temp has changed its value");
     }
-}
 
+    #[test]
+    fn builtin_scrubbers_replace_run_specific_text() {
+        assert_eq!(OutputScrubber::timestamps().scrub("built at 2020-01-31T12:30:59.123Z, done"), "built at <TIMESTAMP>, done");
+
+        let temp_path = std::env::temp_dir().join("foo.o");
+        let output = format!("wrote '{}'", temp_path.display());
+        assert_eq!(OutputScrubber::temporary_paths().scrub(&output), "wrote '<TEMP PATH>'");
+    }
+
+    #[test]
+    fn function_scrubbers_are_applied() {
+        assert_eq!(OutputScrubber::Function(|s| s.to_uppercase()).scrub("id 42"), "ID 42");
+        assert_eq!(OutputScrubber::regex("id [0-9]+", "id N").unwrap().scrub("id 42"), "id N");
+    }
+}
@@ -2,18 +2,23 @@
 //!
 //! These routines can be used to update `Config` objects with automatic CLI arguments.
 
-use crate::Config;
+use crate::{config::KeepTempfiles, Config};
 use clap::{App, Arg, ArgMatches, SubCommand};
-use std::{io::Write, path::Path};
+use std::{io::Write, path::{Path, PathBuf}};
+
+/// A function which enables a debug parameter on a config.
+type DebugOptionFn = fn(&mut Config);
+/// A function which prints a value for the `show` subcommand.
+type ShowOptionFn = fn(&Config, &mut dyn Write) -> std::io::Result<()>;
 
 /// The set of available debug parameters.
-const DEBUG_OPTION_VALUES: &'static [(&'static str, fn(&mut Config))] = &[
+const DEBUG_OPTION_VALUES: &[(&str, DebugOptionFn)] = &[
     ("variable-resolution", |config: &mut Config| {
         config.dump_variable_resolution = true;
     }),
 ];
 
-const SHOW_OPTION_VALUES: &'static [(&'static str, fn(&Config, &mut dyn Write) -> std::io::Result<()>)] = &[
+const SHOW_OPTION_VALUES: &[(&str, ShowOptionFn)] = &[
     ("test-file-paths", |config, writer| {
         let test_file_paths = crate::run::find_files::with_config(config).unwrap();
         for test_file_path in test_file_paths {
@@ -23,6 +28,24 @@ const SHOW_OPTION_VALUES: &'static [(&'static str, fn(&Config, &mut dyn Write) -
         Ok(())
 
     }),
+    ("tests", |config, writer| {
+        let tests = match crate::run::list(config) {
+            Ok(tests) => tests,
+            Err(e) => return writeln!(writer, "error: {}", e),
+        };
+
+        for test in tests {
+            writeln!(writer, "{}: {} RUN, {} checks", test.path.relative.display(), test.run_commands, test.checks)?;
+        }
+
+        Ok(())
+    }),
+    ("plan", |config, writer| {
+        match crate::run::export_plan(config) {
+            Ok(plan) => writeln!(writer, "{}", plan),
+            Err(e) => writeln!(writer, "error: {}", e),
+        }
+    }),
     ("lit-config", |config, writer| {
         writeln!(writer, "{:#?}", config)
     }),
@@ -60,6 +83,12 @@ pub fn mount_inside_app<'a, 'b>(
             .value_name("EXT")
             .multiple(true)
             .help("Adds a file extension to the test search list. Extensions can be specified either with or without a leading period"))
+        .arg(Arg::with_name("feature")
+            .long("add-feature")
+            .takes_value(true)
+            .value_name("FEATURE")
+            .multiple(true)
+            .help("Marks a feature as available, for use in '%if <FEATURE>' conditionals inside RUN directives"))
         .arg(Arg::with_name("constant")
             .long("define-constant")
             .short("c")
@@ -67,6 +96,12 @@ pub fn mount_inside_app<'a, 'b>(
             .value_name("NAME>=<VALUE") // this shows as '<NAME>=<VALUE>'
             .multiple(true)
             .help("Sets a constant, accessible in the test via '@<NAME>"))
+        .arg(Arg::with_name("param")
+            .long("param")
+            .takes_value(true)
+            .value_name("NAME>=<VALUE")
+            .multiple(true)
+            .help("Sets a parameter, accessible in the test via '%{param:<NAME>}'"))
         .arg(Arg::with_name("show-context-lines")
             .long("show-context-lines")
             .short("C")
@@ -76,15 +111,110 @@ pub fn mount_inside_app<'a, 'b>(
         .arg(Arg::with_name("always-show-stderr")
             .long("always-show-stderr")
             .help("Always echo the stderr streams emitted by programs under test. By default this is only done if the program exits with an error code. Stderr is also always printed when verbose mode is on."))
+        .arg(Arg::with_name("fail-on-unexpected-stderr")
+            .long("fail-on-unexpected-stderr")
+            .help("Fail any test whose programs emit text on standard error"))
+        .arg(Arg::with_name("run-wrapper")
+            .long("run-wrapper")
+            .takes_value(true)
+            .value_name("COMMAND")
+            .help("Executes every RUN command through a wrapper program, like 'timeout 60'. The shell running the RUN command is passed to the wrapper as extra arguments"))
+        .arg(Arg::with_name("jobs")
+            .short("j")
+            .long("jobs")
+            .takes_value(true)
+            .value_name("COUNT")
+            .help("Sets the maximum number of tests to execute at the same time, which defaults to the number of CPUs"))
+        .arg(Arg::with_name("shard")
+            .long("shard")
+            .takes_value(true)
+            .value_name("INDEX>/<TOTAL") // this shows as '<INDEX>/<TOTAL>'
+            .help("Only runs one shard of the test suite, where '0/4' is the first of four shards"))
+        .arg(Arg::with_name("shuffle-seed")
+            .long("shuffle-seed")
+            .takes_value(true)
+            .value_name("SEED")
+            .help("Executes the tests in a shuffled order, which is the same for every run with the same seed"))
+        .arg(Arg::with_name("run-line")
+            .long("run-line")
+            .takes_value(true)
+            .value_name("INDEX")
+            .help("Only executes the RUN directive with this index in each test, where '1' is the first"))
+        .arg(Arg::with_name("time-scale")
+            .long("time-scale")
+            .takes_value(true)
+            .value_name("FACTOR")
+            .env("LIT_TIME_SCALE")
+            .help("Multiplies the durations of CHECK-TIME directives, for environments that are slower than usual"))
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .takes_value(true)
+            .value_name("DURATION")
+            .help("Kills RUN commands which run for longer than this, like '30' or '500ms', unless their test has a TIMEOUT directive"))
+        .arg(Arg::with_name("spawn-retries")
+            .long("spawn-retries")
+            .takes_value(true)
+            .value_name("COUNT")
+            .help("Sets how many times starting a RUN command is retried when the system is temporarily out of resources"))
+        .arg(Arg::with_name("combine-stderr")
+            .long("combine-stderr")
+            .help("Matches checks against standard output followed by standard error, deterministically concatenated"))
+        .arg(Arg::with_name("header-only-directives")
+            .long("header-only-directives")
+            .help("Only reads directives from the header of each test file, which ends at the first blank line or END-DIRECTIVES marker"))
+        .arg(Arg::with_name("validate-first")
+            .long("validate-first")
+            .help("Parses every test before running any, reporting all invalid test files at once"))
+        .arg(Arg::with_name("ignore-trailing-whitespace")
+            .long("ignore-trailing-whitespace")
+            .help("Ignores trailing whitespace on each line of output when checking it"))
+        .arg(Arg::with_name("percent-substitutions")
+            .long("percent-substitutions")
+            .help("Expands the percent substitutions of LLVM's lit, like '%s' and '%t', in RUN commands"))
+        .arg(Arg::with_name("allow-failure")
+            .long("allow-failure")
+            .takes_value(true)
+            .value_name("TEST PATH")
+            .multiple(true)
+            .help("Marks a test as known to fail, so that its failures do not fail the test suite"))
+        .arg(Arg::with_name("tolerate-unexpected-pass")
+            .long("tolerate-unexpected-pass")
+            .help("Do not fail the test suite when tests declared with XFAIL pass"))
         .arg(Arg::with_name("keep-tempfiles")
             .long("keep-tempfiles")
-            .help("Disables automatic deletion of tempfiles generated during the test run"))
+            .takes_value(true)
+            .min_values(0)
+            // Otherwise a test path following the flag would be taken as its value.
+            .require_equals(true)
+            .value_name("WHEN")
+            .possible_values(&["always", "never", "on-failure"])
+            .help("Keeps the tempfiles generated during the test run 'always', 'never' or 'on-failure' (the default). Without '=WHEN', tempfiles are always kept"))
         .arg(Arg::with_name("save-artifacts-to")
             .long("save-artifacts-to")
             .short("O")
             .takes_value(true)
             .value_name("DIRECTORY")
             .help("Exports all program outputs, temporary files, and logs, to a directory at the specified path. Will create the directory if it does not yet exist."))
+        .arg(Arg::with_name("quiet")
+            .long("quiet")
+            .short("q")
+            .help("Only print the tests that did not succeed, followed by a one-line summary"))
+        .arg(Arg::with_name("verbose-skips")
+            .long("verbose-skips")
+            .help("Prints the reason for every skipped test, even with --quiet"))
+        .arg(Arg::with_name("expand-globs")
+            .long("expand-globs")
+            .help("Expands unquoted globs in RUN commands relative to the directory of the test, failing if one matches no files"))
+        .arg(Arg::with_name("keep-unmatched-globs")
+            .long("keep-unmatched-globs")
+            .requires("expand-globs")
+            .help("Passes globs which match no files to the command as they are, instead of failing"))
+        .arg(Arg::with_name("fold-failures")
+            .long("fold-failures")
+            .help("Reports failures with identical messages once, with a list of the tests that failed with each message"))
+        .arg(Arg::with_name("progress")
+            .long("progress")
+            .help("Prints how many tests have finished and an estimate of the time remaining after each test"))
         .arg(Arg::with_name("verbose")
             .long("verbose")
             .short("v")
@@ -139,6 +269,12 @@ pub fn parse_arguments(matches: &ArgMatches,
         }
     }
 
+    if let Some(features) = matches.values_of("feature") {
+        for feature in features {
+            destination_config.add_feature(feature);
+        }
+    }
+
     if let Some(test_paths) = matches.values_of("add-tests") {
         for test_path in test_paths {
             destination_config.add_search_path(test_path);
@@ -156,8 +292,20 @@ pub fn parse_arguments(matches: &ArgMatches,
         }
     }
 
+    if let Some(param_define_strs) = matches.values_of("param") {
+        for param_define_str in param_define_strs {
+            match param_define_str.parse::<ConstantDefinition>() {
+                Ok(param) => { destination_config.params.insert(param.name, param.value); },
+                Err(e) => fatal_error(format!("could not parse parameter definition: {}", e)),
+            }
+        }
+    }
+
     if matches.is_present("keep-tempfiles") {
-        destination_config.cleanup_temporary_files = false;
+        destination_config.keep_tempfiles = match matches.value_of("keep-tempfiles") {
+            Some(when) => when.parse().unwrap_or_else(|e: String| fatal_error(e)),
+            None => KeepTempfiles::Always,
+        };
     }
 
     if let Some(artifacts_path) = matches.value_of("save-artifacts-to") {
@@ -191,6 +339,119 @@ pub fn parse_arguments(matches: &ArgMatches,
         destination_config.always_show_stderr = true;
     }
 
+    if let Some(run_wrapper) = matches.value_of("run-wrapper") {
+        destination_config.run_wrapper = Some(run_wrapper.split_whitespace().map(ToOwned::to_owned).collect());
+    }
+
+    if let Some(jobs) = matches.value_of("jobs") {
+        match jobs.parse::<usize>() {
+            Ok(count) if count > 0 => destination_config.jobs = count,
+            _ => fatal_error(format!("invalid number of jobs: '{}' - must be a positive number", jobs)),
+        }
+    }
+
+    if let Some(shard) = matches.value_of("shard") {
+        let parsed_shard = shard.split_once('/').and_then(|(index, total)| {
+            Some((index.trim().parse::<usize>().ok()?, total.trim().parse::<usize>().ok()?))
+        });
+
+        match parsed_shard {
+            Some((index, total)) if index < total => destination_config.shard = Some((index, total)),
+            _ => fatal_error(format!("invalid shard: '{}' - must be '<INDEX>/<TOTAL>', where the index is less than the total", shard)),
+        }
+    }
+
+    if let Some(seed) = matches.value_of("shuffle-seed") {
+        match seed.parse::<u64>() {
+            Ok(seed) => destination_config.shuffle_seed = Some(seed),
+            Err(..) => fatal_error(format!("invalid shuffle seed: '{}' - must be a non-negative number", seed)),
+        }
+    }
+
+    if let Some(run_line) = matches.value_of("run-line") {
+        match run_line.parse::<usize>() {
+            Ok(index) if index > 0 => destination_config.run_line = Some(index),
+            _ => fatal_error(format!("invalid RUN line index: '{}' - must be a positive number", run_line)),
+        }
+    }
+
+    if let Some(time_scale) = matches.value_of("time-scale") {
+        match time_scale.parse::<f64>() {
+            Ok(factor) if factor.is_finite() && factor > 0.0 => destination_config.time_scale = factor,
+            _ => fatal_error(format!("invalid time scale: '{}' - must be a positive number", time_scale)),
+        }
+    }
+
+    if let Some(timeout) = matches.value_of("timeout") {
+        match crate::parse::duration(timeout) {
+            Ok(duration) => destination_config.timeout = Some(duration),
+            Err(e) => fatal_error(format!("invalid timeout: {}", e)),
+        }
+    }
+
+    if let Some(spawn_retries) = matches.value_of("spawn-retries") {
+        match spawn_retries.parse::<u32>() {
+            Ok(count) => destination_config.spawn_retries = count,
+            Err(_) => fatal_error(format!("invalid number of spawn retries: '{}' - must be a non-negative integer", spawn_retries)),
+        }
+    }
+
+    if matches.is_present("combine-stderr") {
+        destination_config.combine_stderr_into_stdout = true;
+    }
+
+    if matches.is_present("header-only-directives") {
+        destination_config.header_only_directives = true;
+    }
+
+    if matches.is_present("validate-first") {
+        destination_config.validate_first = true;
+    }
+
+    if matches.is_present("ignore-trailing-whitespace") {
+        destination_config.ignore_trailing_whitespace = true;
+    }
+
+    if matches.is_present("percent-substitutions") {
+        destination_config.percent_substitutions = true;
+    }
+
+    if let Some(test_paths) = matches.values_of("allow-failure") {
+        destination_config.allow_failures.extend(test_paths.map(PathBuf::from));
+    }
+
+    if matches.is_present("tolerate-unexpected-pass") {
+        destination_config.tolerate_unexpected_pass = true;
+    }
+
+    if matches.is_present("quiet") {
+        destination_config.quiet = true;
+    }
+
+    if matches.is_present("verbose-skips") {
+        destination_config.verbose_skips = true;
+    }
+
+    if matches.is_present("expand-globs") {
+        destination_config.expand_globs = true;
+    }
+
+    if matches.is_present("keep-unmatched-globs") {
+        destination_config.keep_unmatched_globs = true;
+    }
+
+    if matches.is_present("fold-failures") {
+        destination_config.fold_identical_failures = true;
+    }
+
+    if matches.is_present("progress") {
+        destination_config.show_progress = true;
+    }
+
+    if matches.is_present("fail-on-unexpected-stderr") {
+        destination_config.fail_on_unexpected_stderr = true;
+    }
+
     if let Some(debug_flags) = matches.values_of("debug") {
         for debug_flag in debug_flags {
             let apply_fn = DEBUG_OPTION_VALUES.iter().find(|(k, _)| k == &debug_flag.trim()).map(|d| d.1);
@@ -212,10 +473,12 @@ pub fn parse_arguments(matches: &ArgMatches,
         match cli_show_context_lines.parse::<isize>() {
             Ok(-1) => {
                 destination_config.truncate_output_context_to_number_of_lines = None;
+                destination_config.context_lines = usize::MAX;
             },
             Ok(lines) if lines < 0 => fatal_error(format!("invalid number of context lines: '{}' - must be a positive integer, or '-1' to disable truncation", cli_show_context_lines)),
             Ok(lines) => {
                 destination_config.truncate_output_context_to_number_of_lines = Some(lines as usize);
+                destination_config.context_lines = lines as usize;
             },
             Err(_) => fatal_error(format!("invalid number of context lines: '{}' - must be a positive integer, or '-1' to disable truncation", cli_show_context_lines)),
         }
@@ -245,13 +508,13 @@ pub fn parse_arguments(matches: &ArgMatches,
                 writeln!(writer, "=================================================================").unwrap();
                 writeln!(writer, "{}:", label).unwrap();
                 writeln!(writer, "=================================================================").unwrap();
-                writeln!(writer, "").unwrap();
+                writeln!(writer).unwrap();
             }
 
-            what_fn(&destination_config, writer).unwrap();
+            what_fn(destination_config, writer).unwrap();
 
             if show_labels {
-                writeln!(writer, "").unwrap();
+                writeln!(writer).unwrap();
             }
         }
 
@@ -274,7 +537,7 @@ impl std::str::FromStr for ConstantDefinition {
             return Err(format!("constant definition must have exactly one equals sign but got '{}", s))
         }
         if s.len() < 3 {
-            return Err(format!("constant definitions must include both a <NAME> and a <VALUE>, separated by equals"));
+            return Err("constant definitions must include both a <NAME> and a <VALUE>, separated by equals".to_owned());
         }
 
         let (name, value) = s.split_at(s.find('=').unwrap());
@@ -0,0 +1,94 @@
+//! Custom matchers for `CHECK-CUSTOM[<name>]` directives.
+//!
+//! A custom matcher is a function which is given the argument of the directive
+//! and a line of output, and returns whether the line matches.
+
+use regex::Regex;
+
+/// The tolerance used by `float_eq` if the directive does not give one.
+const DEFAULT_FLOAT_TOLERANCE: f64 = 1e-6;
+
+lazy_static! {
+    /// A decimal number, with an optional sign, fraction and exponent.
+    static ref NUMBER_REGEX: Regex = Regex::new(r"[-+]?(?:[0-9]+(?:\.[0-9]*)?|\.[0-9]+)(?:[eE][-+]?[0-9]+)?").unwrap();
+}
+
+/// Matches lines containing a number that is approximately equal to the expected number.
+///
+/// The argument is the expected number, optionally followed by `+-` (or `+/-`) and an
+/// absolute tolerance, like `3.14 +- 0.01`.
+///
+/// Register it with `Config::add_custom_matcher("floateq", matchers::float_eq)`.
+pub fn float_eq(argument: &str, line: &str) -> bool {
+    match self::expected_number(argument) {
+        Some((expected, tolerance)) => self::numbers(line).any(|(number, _)| (number - expected).abs() <= tolerance),
+        None => false,
+    }
+}
+
+/// Parses an expected number and its tolerance, like `3.14 +- 0.01` or `3.14 +/- 0.01`.
+///
+/// The tolerance defaults to a small value, if it is not given.
+pub(crate) fn expected_number(argument: &str) -> Option<(f64, f64)> {
+    let (expected, tolerance) = match argument.split_once("+/-").or_else(|| argument.split_once("+-")) {
+        Some((expected, tolerance)) => (expected.trim().parse::<f64>().ok()?, tolerance.trim().parse::<f64>().ok()?),
+        None => (argument.trim().parse::<f64>().ok()?, DEFAULT_FLOAT_TOLERANCE),
+    };
+
+    if expected.is_finite() && tolerance.is_finite() && tolerance >= 0.0 { Some((expected, tolerance)) } else { None }
+}
+
+/// Gets the first number within a line of text, and the index of the byte after it.
+pub(crate) fn first_number(line: &str) -> Option<(f64, usize)> {
+    self::numbers(line).next()
+}
+
+/// Gets the finite numbers within a line of text, each with the index of the byte after it.
+///
+/// A sign directly after a digit or letter is a separator rather than a sign, so that the
+/// range `1-5` contains the numbers 1 and 5.
+fn numbers(line: &str) -> impl Iterator<Item=(f64, usize)> + '_ {
+    NUMBER_REGEX.find_iter(line).filter_map(move |m| {
+        let is_separator = line[..m.start()].ends_with(|c: char| c.is_alphanumeric());
+        let text = if is_separator { &m.as_str()[1..] } else { m.as_str() };
+
+        text.parse::<f64>().ok().filter(|number| number.is_finite()).map(|number| (number, m.end()))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn float_eq_allows_numbers_within_the_tolerance() {
+        assert!(float_eq("3.14", "pi is approximately 3.1400000001"));
+        assert!(float_eq("3.14 +- 0.01", "result: 3.145, error: 0.2"));
+        assert!(float_eq("-2.5e3", "x=-2500.0"));
+
+        assert!(!float_eq("3.14", "pi is approximately 3.15"));
+        assert!(!float_eq("3.14 +- 0.001", "result: 3.145"));
+        assert!(!float_eq("not a number", "3.14"));
+    }
+
+    #[test]
+    fn expected_numbers_can_have_a_tolerance() {
+        assert_eq!(expected_number("2.5 +/- 0.01"), Some((2.5, 0.01)));
+        assert_eq!(expected_number("-2 +- 1"), Some((-2.0, 1.0)));
+        assert_eq!(expected_number("7"), Some((7.0, DEFAULT_FLOAT_TOLERANCE)));
+        assert_eq!(expected_number("7 +- -1"), None);
+        assert_eq!(expected_number("seven"), None);
+        assert_eq!(expected_number("NaN"), None);
+        assert_eq!(expected_number("inf +- 1"), None);
+    }
+
+    #[test]
+    fn numbers_are_found_between_other_text() {
+        let numbers = |line| numbers(line).map(|(number, _)| number).collect::<Vec<_>>();
+
+        assert_eq!(numbers("width is 2.5. height is 1.5e1"), vec![2.5, 15.0]);
+        assert_eq!(numbers("range 1-5, offset -2, gain +.5"), vec![1.0, 5.0, -2.0, 0.5]);
+        assert_eq!(numbers("x=NaN y=inf z=1e400"), vec![]);
+        assert_eq!(first_number("took 12ms"), Some((12.0, 7)));
+    }
+}
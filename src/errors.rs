@@ -12,6 +12,30 @@ error_chain! {
             description("invalid toolchain name")
             display("invalid toolchain name: '{}'", t)
         }
+
+        /// A test file contains an invalid directive.
+        ParseError(path: ::std::path::PathBuf, message: String) {
+            description("invalid test file")
+            display("could not parse test file '{}': {}", path.display(), message)
+        }
+
+        /// The process of a `RUN` command could not be started.
+        SpawnError(command_line: String, message: String) {
+            description("RUN command could not be started")
+            display("could not start command '{}': {}", command_line, message)
+        }
+
+        /// A `RUN` command was killed because it ran for longer than the timeout.
+        Timeout(duration: ::std::time::Duration) {
+            description("RUN command timed out")
+            display("RUN command timed out after {:?}", duration)
+        }
+
+        /// The output of a test did not satisfy its checks or success criteria.
+        MatchFailure(reason: String) {
+            description("test output did not match")
+            display("{}", reason)
+        }
     }
 }
 
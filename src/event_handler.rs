@@ -4,7 +4,8 @@
 
 pub use self::default::EventHandler as Default;
 
-use crate::{Config, model::{TestResult}};
+use crate::{Config, model::{TestFile, TestResult}};
+use std::{fmt, time::Duration};
 
 mod default;
 
@@ -16,17 +17,99 @@ pub trait EventHandler {
     /// Called to notify when the entire test suite has finished execution.
     fn on_test_suite_finished(&mut self, passed: bool, config: &Config);
 
+    /// Called to notify when a test has started executing.
+    ///
+    /// Tests can run at the same time, so several tests may start before the first one finishes.
+    fn on_test_started(&mut self, _test_file: &TestFile, _config: &Config) {}
+
     /// Called to notify when a test has been executed.
     fn on_test_finished(&mut self, result: TestResult, config: &Config);
 
+    /// Called to notify how far the test suite has progressed, after each test has finished.
+    fn on_progress(&mut self, _progress: &Progress, _config: &Config) {}
+
     /// Called to notify about a nonfatal warning.
     fn note_warning(&mut self, message: &str);
 }
 
+/// Forwards every event to the event handler that is referred to, so that it can be inspected after the test suite has run.
+impl<T: EventHandler + ?Sized> EventHandler for &mut T {
+    fn on_test_suite_started(&mut self, suite_details: &TestSuiteDetails, config: &Config) { (**self).on_test_suite_started(suite_details, config) }
+    fn on_test_suite_finished(&mut self, passed: bool, config: &Config) { (**self).on_test_suite_finished(passed, config) }
+    fn on_test_started(&mut self, test_file: &TestFile, config: &Config) { (**self).on_test_started(test_file, config) }
+    fn on_test_finished(&mut self, result: TestResult, config: &Config) { (**self).on_test_finished(result, config) }
+    fn on_progress(&mut self, progress: &Progress, config: &Config) { (**self).on_progress(progress, config) }
+    fn note_warning(&mut self, message: &str) { (**self).note_warning(message) }
+}
+
 /// Stores details about the test suite.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct TestSuiteDetails {
     /// The number of test files in the suite.
     pub number_of_test_files: usize,
+    /// The number of test files that were found but will not be run, like those in other shards.
+    pub number_of_filtered_test_files: usize,
 }
 
+/// How far a test suite run has progressed.
+///
+/// The `Display` implementation renders it like `342/2000 (17%) 3 failed ~45s remaining`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Progress {
+    /// The number of tests that have finished.
+    pub completed: usize,
+    /// The number of the finished tests that failed.
+    pub failed: usize,
+    /// The number of tests that will be run.
+    pub total: usize,
+    /// How long the test suite has been running for.
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Gets the percentage of the tests that have finished, from 0 to 100.
+    pub fn percentage(&self) -> f64 {
+        if self.total == 0 { 100.0 } else { self.completed as f64 * 100.0 / self.total as f64 }
+    }
+
+    /// Estimates how much longer the rest of the tests will take.
+    ///
+    /// This assumes that the remaining tests finish at the same rate as the tests so far
+    /// did, so it accounts for tests running in parallel. There is no estimate before the
+    /// first test has finished.
+    pub fn estimated_time_remaining(&self) -> Option<Duration> {
+        match self.completed {
+            0 => None,
+            completed => Some(self.elapsed.mul_f64(self.total.saturating_sub(completed) as f64 / completed as f64)),
+        }
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}/{} ({:.0}%)", self.completed, self.total, self.percentage().floor())?;
+        if self.failed > 0 {
+            write!(fmt, " {} failed", self.failed)?;
+        }
+
+        match self.estimated_time_remaining() {
+            Some(remaining) => write!(fmt, " ~{}s remaining", remaining.as_secs_f64().round()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn progress_estimates_the_time_remaining_from_the_rate_so_far() {
+        let progress = Progress { completed: 342, failed: 0, total: 2000, elapsed: Duration::from_secs(9) };
+
+        assert_eq!(progress.estimated_time_remaining().map(|d| d.as_secs()), Some(43));
+        assert_eq!(progress.to_string(), "342/2000 (17%) ~44s remaining");
+        assert_eq!(Progress { failed: 3, ..progress }.to_string(), "342/2000 (17%) 3 failed ~44s remaining");
+        assert_eq!(Progress { completed: 0, failed: 0, total: 10, elapsed: Duration::from_secs(1) }.to_string(), "0/10 (0%)");
+    }
+}
@@ -1,19 +1,34 @@
 use crate::{util, Config, model::*};
 
 use itertools::Itertools;
-use std::io;
 use std::io::prelude::*;
+use std::time::Instant;
 use term;
 
 /// The default event handler, logging to stdout/stderr.
 pub struct EventHandler {
     test_results: Vec<TestResult>,
+    /// When the test suite started running.
+    started_at: Option<Instant>,
 }
 
 impl EventHandler {
     /// Creates a new default event handler.
     pub fn new() -> Self {
-        EventHandler { test_results: Vec::new() }
+        EventHandler { test_results: Vec::new(), started_at: None }
+    }
+
+    /// Describes how many tests ran, and for how long.
+    ///
+    /// When tests can run in parallel, this includes how much faster that made the test suite.
+    fn ran_tests_message(&self, config: &Config) -> String {
+        let duration = self.started_at.map(|started_at| started_at.elapsed()).unwrap_or_default();
+        let message = format!("ran {} tests in {:.1}s", self.test_results.len(), duration.as_secs_f64());
+
+        match crate::model::speedup(&self.test_results, duration) {
+            Some(speedup) if config.jobs > 1 => format!("{} ({:.1}x speedup from running tests in parallel)", message, speedup),
+            _ => message,
+        }
     }
 }
 
@@ -24,12 +39,18 @@ impl std::default::Default for EventHandler {
 }
 
 impl super::EventHandler for EventHandler {
-    fn on_test_suite_started(&mut self, suite_details: &super::TestSuiteDetails, _: &Config) {
+    fn on_test_suite_started(&mut self, suite_details: &super::TestSuiteDetails, config: &Config) {
         print::reset_colors(); // our white might not match initial console white. we should be consistent.
+        self.started_at = Some(Instant::now());
+
+        if config.quiet { return; }
 
         print::line();
         print::horizontal_rule();
-        print::textln(format!("Running tests ({} files)", suite_details.number_of_test_files));
+        match suite_details.number_of_filtered_test_files {
+            0 => print::textln(format!("Running tests ({} files)", suite_details.number_of_test_files)),
+            filtered => print::textln(format!("Running tests ({} files, {} filtered out)", suite_details.number_of_test_files, filtered)),
+        }
         print::horizontal_rule();
         print::line();
     }
@@ -39,39 +60,66 @@ impl super::EventHandler for EventHandler {
         // This is required for itertools group_by used before to work properly.
         self.test_results.sort_by_key(|r| r.overall_result.human_label_pluralized());
 
+        if config.quiet {
+            // The failures have already been printed as the tests finished, unless they are folded.
+            if config.fold_identical_failures && !passed {
+                self::folded_failures(&self.test_results, config);
+            }
+            if !passed { self::shuffle_seed_note(config); }
+            print::test_suite_summary_line(passed, &self.test_results, &self.ran_tests_message(config));
+            print::reset_colors();
+            return;
+        }
+
         print::line();
         print::textln("finished running tests");
-        print::test_suite_status_message(passed, false, &self.test_results);
+        print::test_suite_status_message(passed, false, &self.test_results, &self.ran_tests_message(config));
         print::line();
         print::horizontal_rule();
         print::horizontal_rule();
         print::line();
 
         if !passed {
-            let failed_results = self.test_results.iter().filter(|r| r.overall_result.is_erroneous()).collect::<Vec<_>>();
+            let failed_results = self.test_results.iter().filter(|r| r.overall_result.is_erroneous_with_config(config)).collect::<Vec<_>>();
 
             print::line();
             print::textln_colored(format!("Failing tests ({}/{}):", failed_results.len(), self.test_results.len()), print::YELLOW);
             print::line();
 
-            for failed_test_result in failed_results.iter() {
-                print::with("  ", print::StdStream::Err, print::RED); // indent the errors.
-                self::result(failed_test_result, false, config);
+            if config.fold_identical_failures {
+                self::folded_failures(&self.test_results, config);
+            } else {
+                for failed_test_result in failed_results.iter() {
+                    print::with("  ", print::StdStream::Err, print::RED); // indent the errors.
+                    self::result(failed_test_result, false, config);
+                }
             }
+
+            self::shuffle_seed_note(config);
         }
 
-        print::test_suite_status_message(passed, true, &self.test_results);
+        print::test_suite_status_message(passed, true, &self.test_results, &self.ran_tests_message(config));
 
         // 'cargo test' will use the color we last emitted if we don't do this.
         print::reset_colors();
     }
 
     fn on_test_finished(&mut self, result: TestResult, config: &Config) {
-        self::result(&result, true, config);
+        if self::is_printed_when_finished(&result, config) {
+            // Folded failures are described in full once the test suite has finished.
+            let verbose = !(config.fold_identical_failures && result.overall_result.is_erroneous_with_config(config));
+            self::result(&result, verbose, config);
+        }
 
         self.test_results.push(result);
     }
 
+    fn on_progress(&mut self, progress: &super::Progress, config: &Config) {
+        if config.show_progress && !config.quiet {
+            print::textln(progress.to_string());
+        }
+    }
+
     fn note_warning(&mut self, message: &str) {
         print::warning(message);
     }
@@ -85,19 +133,21 @@ pub fn result(result: &TestResult, verbose: bool, config: &Config) {
         TestResultKind::UnexpectedPass => {
             print::failure(format!("UNEXPECTED PASS :: {}", result.path.relative.display()));
         },
-        TestResultKind::Skip => {
-            print::line();
-            print::warning(format!(
-                "SKIP :: {} (test does not contain any test commands, perhaps you meant to add a 'CHECK'?)",
-                     result.path.relative.display()));
-            print::line();
+        TestResultKind::Skip { ref reason } => {
+            match config.verbose_skips {
+                true => print::warning(format!("SKIP :: {} ({})", result.path.relative.display(), reason)),
+                false => print::warning(format!("SKIP :: {}", result.path.relative.display())),
+            }
         },
-        TestResultKind::Error { ref message } => {
+        TestResultKind::Error { ref message } | TestResultKind::SpawnError { ref message, .. } => {
             if verbose { print::line(); }
 
-            print::error(format!("ERROR :: {}", result.path.relative.display()));
+            print::error(format!("ERROR :: {}{}", result.path.relative.display(), failing_run_suffix(result)));
 
             if verbose {
+                if let Some(failing_run) = result.failing_run() {
+                    print::textln(describe_run(failing_run));
+                }
                 print::textln(message);
 
                 print::line();
@@ -106,16 +156,25 @@ pub fn result(result: &TestResult, verbose: bool, config: &Config) {
         TestResultKind::Fail { ref reason, ref hint } => {
             if verbose { print::line(); }
 
-            print::failure(format!("FAIL :: {}", result.path.relative.display()));
+            print::failure(format!("FAIL :: {}{}", result.path.relative.display(), failing_run_suffix(result)));
 
             // FIXME: improve formatting
 
             if verbose {
                 print::line();
+                if let Some(failing_run) = result.failing_run() {
+                    print::textln(describe_run(failing_run));
+                }
+                if let Some(failing_check) = self::describe_failing_check(result, config) {
+                    print::textln(failing_check);
+                }
                 print::text("test failed: ");
                 print::textln_colored(reason.human_summary(), print::RED);
+                if let Some(failing_run) = result.failing_run().filter(|r| !r.checks.is_empty()) {
+                    print::textln(format!("{}/{} checks evaluated", failing_run.number_of_checks_evaluated(), failing_run.checks.len()));
+                }
                 print::line();
-                print::textln(reason.human_detail_message(config));
+                print::diagnostic(&reason.human_detail_sections(config));
 
                 if let Some(hint_text) = hint {
                     print::textln(format!("hint: {}", hint_text));
@@ -127,14 +186,45 @@ pub fn result(result: &TestResult, verbose: bool, config: &Config) {
         TestResultKind::ExpectedFailure { .. } => {
             print::warning(format!("XFAIL :: {}", result.path.relative.display()));
         },
-        TestResultKind::EmptyTest { .. } => {
+        TestResultKind::KnownFailure { .. } => {
+            print::warning(format!("KNOWN FAILURE :: {}", result.path.relative.display()));
+        },
+        TestResultKind::EmptyTest => {
             print::error(format!("EMPTY TEST :: {}", result.path.relative.display()));
         },
+        TestResultKind::Timeout { duration } => {
+            print::failure(format!("TIMEOUT :: {}{}", result.path.relative.display(), failing_run_suffix(result)));
+
+            if verbose {
+                if let Some(failing_run) = result.failing_run() {
+                    print::textln(describe_run(failing_run));
+                }
+                print::textln(format!("the command was killed after exceeding its timeout of {:?}", duration));
+                print::line();
+            }
+        },
+        TestResultKind::Custom { ref label, passed, ref message } => {
+            let line = format!("{} :: {}{}", label, result.path.relative.display(), failing_run_suffix(result));
+            if passed { print::success(line) } else { print::failure(line) }
+
+            if verbose && !message.is_empty() {
+                print::textln(message);
+                print::line();
+            }
+        },
+    }
+
+    if result.overall_result.is_erroneous() {
+        for individual_run_result in result.individual_run_results.iter().filter(|r| !r.kept_tempfiles.is_empty()) {
+            let paths: Vec<_> = individual_run_result.kept_tempfiles.iter().map(|p| p.display().to_string()).collect();
+            print::textln(format!("NOTE: the tempfiles of the RUN directive on line {} were kept: {}",
+                                  individual_run_result.line_number, paths.join(", ")));
+        }
     }
 
     if verbose && (result.overall_result.is_erroneous() || config.always_show_stderr) {
         for individual_run_result in result.individual_run_results.iter() {
-            let (_, _, command_line, output) = individual_run_result;
+            let IndividualRunResult { command_line, output, .. } = individual_run_result;
 
             let formatted_stderr = crate::model::format_test_output("stderr", &output.stderr, 1, util::TruncateDirection::Bottom, config);
             if !output.stderr.is_empty() {
@@ -147,6 +237,68 @@ pub fn result(result: &TestResult, verbose: bool, config: &Config) {
     }
 }
 
+/// Checks if the result of a test is printed as soon as the test finishes.
+///
+/// In quiet mode, only results which fail the test suite are printed, and skips if they are verbose.
+fn is_printed_when_finished(result: &TestResult, config: &Config) -> bool {
+    let is_skip = matches!(result.overall_result, TestResultKind::Skip { .. });
+
+    !config.quiet || result.overall_result.is_erroneous_with_config(config) || (is_skip && config.verbose_skips)
+}
+
+/// Prints the seed that the tests were shuffled with, so that a failing order can be reproduced.
+fn shuffle_seed_note(config: &Config) {
+    if let Some(seed) = config.shuffle_seed {
+        print::line();
+        print::warning(format!("NOTE: the tests ran in a shuffled order, pass '--shuffle-seed {}' to run them in the same order again", seed));
+    }
+}
+
+/// Prints each distinct failure message once, followed by the tests that failed with it.
+fn folded_failures(test_results: &[TestResult], config: &Config) {
+    for (message, failed_results) in crate::model::identical_failures(test_results, config) {
+        match failed_results.len() {
+            1 => print::failure(format!("  {}{} failed with:", failed_results[0].path.relative.display(), failing_run_suffix(failed_results[0]))),
+            count => print::failure(format!("  {} tests failed with:", count)),
+        }
+        print::line();
+        print::textln(message);
+        print::line();
+
+        if failed_results.len() > 1 {
+            for failed_result in failed_results {
+                print::textln(format!("    {}{}", failed_result.path.relative.display(), failing_run_suffix(failed_result)));
+            }
+            print::line();
+        }
+    }
+}
+
+/// Gets a short note on which `RUN` directive failed, only needed when there
+/// are several of them in the test.
+fn failing_run_suffix(result: &TestResult) -> String {
+    match result.failing_run() {
+        Some(failing_run) if result.individual_run_results.len() > 1 => {
+            format!(" (RUN on line {})", failing_run.line_number)
+        },
+        _ => String::new(),
+    }
+}
+
+/// Describes a `RUN` directive by its line number and command text.
+fn describe_run(run_result: &IndividualRunResult) -> String {
+    format!("failing RUN directive on line {}: {}", run_result.line_number, run_result.invocation.original_command)
+}
+
+/// Describes the check directive which failed by its line number and text, read from the test file.
+fn describe_failing_check(result: &TestResult, config: &Config) -> Option<String> {
+    let line_number = result.failing_run()?.checks.iter().find(|check| check.status == CheckStatus::Failed)?.line_number;
+    let source = config.file_system.read_to_string(&result.path.absolute).ok()?;
+    let line = source.lines().nth((line_number as usize).saturating_sub(1))?;
+
+    Some(format!("failing check directive on line {}: {}", line_number, line.trim()))
+}
+
 mod print {
     pub use term::color::*;
     use super::*;
@@ -173,7 +325,7 @@ mod print {
 
     pub fn text<S>(msg: S)
         where S: Into<String> {
-        with(format!("{}", msg.into()),
+        with(msg.into(),
              StdStream::Out,
              term::color::WHITE);
     }
@@ -187,6 +339,23 @@ mod print {
     }
 
 
+    /// Prints the details of a failure, highlighting the lines of its diffs.
+    pub fn diagnostic(sections: &[DetailSection]) {
+        for section in sections {
+            match *section {
+                DetailSection::Text(ref text) => text.lines().for_each(textln),
+                DetailSection::Diff(ref diff) => for line in diff.lines() {
+                    match line {
+                        l if l.starts_with("- ") => textln_colored(l, RED),
+                        l if l.starts_with("+ ") => textln_colored(l, GREEN),
+                        l if l.trim_start() == "^" => textln_colored(l, YELLOW),
+                        l => textln(l),
+                    }
+                },
+            }
+        }
+    }
+
     pub fn success<S>(msg: S)
         where S: Into<String> {
         with(format!("{}\n", msg.into()),
@@ -215,7 +384,7 @@ mod print {
              term::color::MAGENTA);
     }
 
-    pub fn test_suite_status_message(passed: bool, verbose: bool, test_results: &[TestResult]) {
+    pub fn test_suite_status_message(passed: bool, verbose: bool, test_results: &[TestResult], ran_tests_message: &str) {
         if verbose {
             self::line();
             self::horizontal_rule();
@@ -229,14 +398,36 @@ mod print {
                 self::textln(format!("  {}: {}", result_label, corresponding_results.count()));
             }
 
+            let skip_breakdown = crate::model::skip_breakdown(test_results);
+            if !skip_breakdown.is_empty() {
+                self::line();
+                self::textln("  Skipped Because:");
+
+                for (reason, count) in skip_breakdown.iter().sorted() {
+                    self::textln(format!("    {}: {}", reason, count));
+                }
+            }
+
             self::line();
             self::horizontal_rule();
             self::line();
         }
 
         match passed {
-            true => self::success("all tests succeeded"),
-            false => self::error("error: tests failed"),
+            true => self::success(format!("all tests succeeded, {}", ran_tests_message)),
+            false => self::error(format!("error: tests failed, {}", ran_tests_message)),
+        }
+    }
+
+    /// Prints the status of the test suite along with the number of tests of each result, on one line.
+    pub fn test_suite_summary_line(passed: bool, test_results: &[TestResult], ran_tests_message: &str) {
+        let counts = test_results.iter().group_by(|r| r.overall_result.human_label_pluralized()).into_iter()
+            .map(|(result_label, corresponding_results)| format!("{}: {}", result_label, corresponding_results.count()))
+            .join(", ");
+
+        match passed {
+            true => self::success(format!("all tests succeeded ({}), {}", counts, ran_tests_message)),
+            false => self::error(format!("error: tests failed ({}), {}", counts, ran_tests_message)),
         }
     }
 
@@ -264,7 +455,7 @@ mod print {
                     }
                 } else {
                     if let Some(msg) = msg {
-                        write!(io::stdout(), "{}", msg.into()).unwrap();
+                        print!("{}", msg.into());
                     }
                 }
             },
@@ -277,7 +468,7 @@ mod print {
                     }
                 } else {
                     if let Some(msg) = msg {
-                        write!(io::stderr(), "{}", msg.into()).unwrap();
+                        eprint!("{}", msg.into());
                     }
                 }
             },
@@ -291,3 +482,31 @@ mod print {
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+    fn result(overall_result: TestResultKind) -> TestResult {
+        TestResult {
+            path: TestFilePath { absolute: PathBuf::from("/tests/foo.txt"), relative: PathBuf::from("foo.txt") },
+            overall_result,
+            individual_run_results: Vec::new(),
+            metadata: HashMap::new(),
+            duration: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn quiet_mode_only_prints_results_which_fail_the_test_suite() {
+        let config = Config { quiet: true, tolerate_unexpected_pass: true, ..Config::default() };
+        let allowed_failure = TestResultKind::KnownFailure { actual_result: Box::new(TestResultKind::Error { message: "bad input".to_owned() }) };
+
+        assert!(!is_printed_when_finished(&result(TestResultKind::Pass), &config));
+        assert!(!is_printed_when_finished(&result(allowed_failure), &config));
+        assert!(!is_printed_when_finished(&result(TestResultKind::UnexpectedPass), &config));
+        assert!(is_printed_when_finished(&result(TestResultKind::Error { message: "bad input".to_owned() }), &config));
+        assert!(is_printed_when_finished(&result(TestResultKind::UnexpectedPass), &Config { quiet: true, ..Config::default() }));
+    }
+}
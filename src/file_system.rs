@@ -0,0 +1,132 @@
+//! Access to the files that tests are discovered and read from.
+//!
+//! By default, files are read from the disk, but tests can also be supplied
+//! from memory. `RUN` commands are still executed as OS processes, and any
+//! tempfiles they refer to are created on the disk.
+
+use std::{collections::BTreeMap, fmt, fs, io};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// A source of test files and the files that tests read.
+pub trait FileSystem: fmt::Debug + Send + Sync {
+    /// Reads the complete contents of a file.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Checks if a path exists and is a directory.
+    fn is_dir(&self, path: &Path) -> io::Result<bool>;
+
+    /// Recursively lists the files within a directory, sorted by path.
+    fn files_in_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Gets the absolute form of a path that exists.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Reads the complete contents of a file as UTF-8 text.
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// The file system of the operating system.
+#[derive(Clone, Debug, Default)]
+pub struct OsFileSystem;
+
+/// A file system whose files are stored in memory.
+///
+/// Directories exist implicitly, if they contain any files.
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryFileSystem {
+    files: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl FileSystem for OsFileSystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> io::Result<bool> {
+        Ok(fs::metadata(path)?.is_dir())
+    }
+
+    fn files_in_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+
+        // Sort the entries so that tests are always discovered in the same order.
+        for entry in WalkDir::new(path).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+            let entry = entry?;
+
+            if entry.file_type().is_file() {
+                files.push(entry.into_path());
+            }
+        }
+
+        Ok(files)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+}
+
+impl InMemoryFileSystem {
+    /// Creates an empty file system.
+    pub fn new() -> Self {
+        InMemoryFileSystem::default()
+    }
+
+    /// Adds a file, replacing any existing file at the same path.
+    pub fn add_file<P, C>(&mut self, path: P, contents: C) -> &mut Self
+        where P: Into<PathBuf>, C: Into<Vec<u8>> {
+        self.files.insert(path.into(), contents.into());
+        self
+    }
+
+    fn not_found(path: &Path) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, format!("'{}' does not exist in the in-memory file system", path.display()))
+    }
+}
+
+impl FileSystem for InMemoryFileSystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.files.get(path).cloned().ok_or_else(|| InMemoryFileSystem::not_found(path))
+    }
+
+    fn is_dir(&self, path: &Path) -> io::Result<bool> {
+        if self.files.contains_key(path) {
+            Ok(false)
+        } else if self.files.keys().any(|file_path| file_path.starts_with(path)) {
+            Ok(true)
+        } else {
+            Err(InMemoryFileSystem::not_found(path))
+        }
+    }
+
+    fn files_in_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self.files.keys().filter(|file_path| file_path.starts_with(path) && file_path.as_path() != path).cloned().collect())
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.is_dir(path).map(|_| path.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn in_memory_directories_contain_the_files_below_them() {
+        let mut file_system = InMemoryFileSystem::new();
+        file_system.add_file("/tests/b.txt", "b").add_file("/tests/nested/a.txt", "a").add_file("/other/c.txt", "c");
+
+        assert!(file_system.is_dir(Path::new("/tests")).unwrap());
+        assert!(!file_system.is_dir(Path::new("/tests/b.txt")).unwrap());
+        assert!(file_system.is_dir(Path::new("/missing")).is_err());
+
+        assert_eq!(file_system.files_in_dir(Path::new("/tests")).unwrap(),
+                   vec![PathBuf::from("/tests/b.txt"), PathBuf::from("/tests/nested/a.txt")]);
+        assert_eq!(file_system.read_to_string(Path::new("/tests/nested/a.txt")).unwrap(), "a");
+        assert_eq!(file_system.read(Path::new("/tests/a.txt")).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,383 @@
+//! A minimal JSON parser and writer.
+//!
+//! lit only needs to read the output of tools and JSON schemas, and to write its own
+//! reports, so this supports the complete JSON syntax but none of the extensions.
+
+pub mod schema;
+
+use std::{collections::BTreeMap, fmt};
+
+/// How deeply arrays and objects may be nested, so that parsing cannot overflow the stack.
+const MAX_NESTING_DEPTH: usize = 128;
+
+/// A JSON value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(BTreeMap<String, Value>),
+}
+
+/// Parses a complete JSON document.
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser { text, position: 0, depth: 0 };
+
+    let value = parser.value()?;
+    parser.skip_whitespace();
+
+    if parser.position == text.len() {
+        Ok(value)
+    } else {
+        Err(parser.error("unexpected trailing characters"))
+    }
+}
+
+/// Writes a string as a quoted JSON string.
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+impl Value {
+    /// Gets the name of the type of the value, as used by JSON schemas.
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::Null => "null",
+            Value::Bool(..) => "boolean",
+            Value::Number(n) if n.fract() == 0.0 => "integer",
+            Value::Number(..) => "number",
+            Value::String(..) => "string",
+            Value::Array(..) => "array",
+            Value::Object(..) => "object",
+        }
+    }
+}
+
+/// Writes the value as compact JSON, or indented over several lines with the alternate flag (`{:#}`).
+impl fmt::Display for Value {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let indentation = if fmt.alternate() { Some(0) } else { None };
+        self.write(fmt, indentation)
+    }
+}
+
+impl Value {
+    fn write(&self, fmt: &mut fmt::Formatter, indentation: Option<usize>) -> fmt::Result {
+        // Writes the separator before an element of an array or object.
+        let separator = |fmt: &mut fmt::Formatter, i: usize| match indentation {
+            Some(level) => write!(fmt, "{}\n{:width$}", if i > 0 { "," } else { "" }, "", width = (level + 1) * 2),
+            None if i > 0 => write!(fmt, ","),
+            None => Ok(()),
+        };
+        let end = |fmt: &mut fmt::Formatter, is_empty: bool, c: char| match indentation {
+            Some(level) if !is_empty => write!(fmt, "\n{:width$}{}", "", c, width = level * 2),
+            _ => write!(fmt, "{}", c),
+        };
+        let nested_indentation = indentation.map(|level| level + 1);
+
+        match *self {
+            Value::Null => write!(fmt, "null"),
+            Value::Bool(b) => write!(fmt, "{}", b),
+            Value::Number(n) => write!(fmt, "{}", n),
+            Value::String(ref s) => write!(fmt, "{}", self::quote(s)),
+            Value::Array(ref values) => {
+                write!(fmt, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    separator(fmt, i)?;
+                    value.write(fmt, nested_indentation)?;
+                }
+                end(fmt, values.is_empty(), ']')
+            },
+            Value::Object(ref members) => {
+                write!(fmt, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    separator(fmt, i)?;
+                    write!(fmt, "{}:{}", self::quote(key), if indentation.is_some() { " " } else { "" })?;
+                    value.write(fmt, nested_indentation)?;
+                }
+                end(fmt, members.is_empty(), '}')
+            },
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+    /// The number of arrays and objects that the parser is inside of.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+
+        match self.peek() {
+            Some('{') | Some('[') if self.depth == MAX_NESTING_DEPTH => Err(self.error("too deeply nested")),
+            Some('{') => {
+                self.depth += 1;
+                let object = self.object();
+                self.depth -= 1;
+                object
+            },
+            Some('[') => {
+                self.depth += 1;
+                let array = self.array();
+                self.depth -= 1;
+                array
+            },
+            Some('"') => self.string().map(Value::String),
+            Some('t') => self.keyword("true", Value::Bool(true)),
+            Some('f') => self.keyword("false", Value::Bool(false)),
+            Some('n') => self.keyword("null", Value::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(..) => Err(self.error("expected a value")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        let mut members = BTreeMap::new();
+        self.expect('{')?;
+        self.skip_whitespace();
+
+        if self.eat('}') {
+            return Ok(Value::Object(members));
+        }
+
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            let value = self.value()?;
+            members.insert(key, value);
+
+            self.skip_whitespace();
+            if self.eat('}') {
+                return Ok(Value::Object(members));
+            }
+            self.expect(',')?;
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        let mut values = Vec::new();
+        self.expect('[')?;
+        self.skip_whitespace();
+
+        if self.eat(']') {
+            return Ok(Value::Array(values));
+        }
+
+        loop {
+            values.push(self.value()?);
+
+            self.skip_whitespace();
+            if self.eat(']') {
+                return Ok(Value::Array(values));
+            }
+            self.expect(',')?;
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let mut s = String::new();
+        self.expect('"')?;
+
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.next() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('/') => s.push('/'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('u') => s.push(self.unicode_escape()?),
+                    _ => return Err(self.error("invalid escape sequence")),
+                },
+                Some(c) if (c as u32) < 0x20 => return Err(self.error("unescaped control character in string")),
+                Some(c) => s.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let first = self.hex_code_unit()?;
+
+        let code_point = if (0xD800..0xDC00).contains(&first) {
+            // A high surrogate, which must be followed by an escaped low surrogate.
+            if !(self.eat('\\') && self.eat('u')) {
+                return Err(self.error("expected a low surrogate"));
+            }
+            let second = self.hex_code_unit()?;
+            0x10000 + ((first - 0xD800) << 10) + (second.wrapping_sub(0xDC00) & 0x3FF)
+        } else {
+            first
+        };
+
+        std::char::from_u32(code_point).ok_or_else(|| self.error("invalid unicode escape"))
+    }
+
+    fn hex_code_unit(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.position..self.position + 4).ok_or_else(|| self.error("invalid unicode escape"))?;
+        let code_unit = u32::from_str_radix(digits, 16).map_err(|_| self.error("invalid unicode escape"))?;
+
+        self.position += 4;
+        Ok(code_unit)
+    }
+
+    /// Parses a number, which is an optional minus sign, an integer part without leading zeros,
+    /// and optionally a fraction and an exponent which each have at least one digit.
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.position;
+        let invalid = |parser: &Self| format!("invalid number '{}' at byte {}", &parser.text[start..parser.position], start);
+
+        self.eat('-');
+        if !self.eat('0') && self.digits() == 0 {
+            return Err(invalid(self));
+        }
+        if self.eat('.') && self.digits() == 0 {
+            return Err(invalid(self));
+        }
+        if self.eat('e') || self.eat('E') {
+            let _ = self.eat('+') || self.eat('-');
+            if self.digits() == 0 {
+                return Err(invalid(self));
+            }
+        }
+        // Leading zeros are not allowed, so a zero integer part cannot be followed by more digits.
+        if self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.digits();
+            return Err(invalid(self));
+        }
+
+        match self.text[start..self.position].parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(Value::Number(n)),
+            _ => Err(format!("number '{}' at byte {} is out of range", &self.text[start..self.position], start)),
+        }
+    }
+
+    /// Skips over a sequence of decimal digits, returning how many there were.
+    fn digits(&mut self) -> usize {
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.position += 1;
+        }
+
+        self.position - start
+    }
+
+    fn keyword(&mut self, keyword: &str, value: Value) -> Result<Value, String> {
+        if self.text[self.position..].starts_with(keyword) {
+            self.position += keyword.len();
+            Ok(value)
+        } else {
+            Err(self.error("expected a value"))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(' ') | Some('\t') | Some('\n') | Some('\r') = self.peek() {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) { Ok(()) } else { Err(self.error(&format!("expected '{}'", c))) }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.position += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.position..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("{} at byte {}", message, self.position)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_and_writes_documents() {
+        let value = parse(r#" {"name": "lit", "tags": [1, 2.5, -3e2, true, null], "nested": {"s": "a\"bé😀"}} "#).unwrap();
+
+        assert_eq!(value.to_string(), "{\"name\":\"lit\",\"nested\":{\"s\":\"a\\\"b\u{e9}\u{1F600}\"},\"tags\":[1,2.5,-300,true,null]}");
+        assert_eq!(parse(&value.to_string()).unwrap(), value);
+        assert_eq!(parse(&format!("{:#}", value)).unwrap(), value);
+    }
+
+    #[test]
+    fn writes_indented_documents() {
+        let value = parse(r#"{"a": [1, {}], "b": []}"#).unwrap();
+
+        assert_eq!(format!("{:#}", value), "{\n  \"a\": [\n    1,\n    {}\n  ],\n  \"b\": []\n}");
+    }
+
+    #[test]
+    fn rejects_invalid_documents() {
+        assert!(parse("").is_err());
+        assert!(parse("[1, 2").is_err());
+        assert!(parse("{\"a\" 1}").is_err());
+        assert!(parse("[1] 2").is_err());
+        assert!(parse("tru").is_err());
+    }
+
+    #[test]
+    fn follows_the_number_grammar() {
+        assert_eq!(parse("[0, -0.5, 10, 1e3, 2.5E-1, 1e+2]").unwrap(),
+                   Value::Array(vec![Value::Number(0.0), Value::Number(-0.5), Value::Number(10.0), Value::Number(1000.0), Value::Number(0.25), Value::Number(100.0)]));
+
+        for invalid in ["01", "-01", "1.", ".5", "-", "1e", "1e+", "+1", "1.5.2", "1e400"] {
+            assert!(parse(invalid).is_err(), "'{}' should not be a valid number", invalid);
+        }
+    }
+
+    #[test]
+    fn limits_the_nesting_depth() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+
+        assert!(parse(&nested(MAX_NESTING_DEPTH)).is_ok());
+        assert_eq!(parse(&nested(100_000)), Err(format!("too deeply nested at byte {}", MAX_NESTING_DEPTH)));
+    }
+}
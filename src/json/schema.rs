@@ -0,0 +1,250 @@
+//! Validation of JSON values against JSON schemas.
+//!
+//! The commonly used validation keywords of JSON Schema are supported: `type`, `enum`,
+//! `const`, `properties`, `required`, `additionalProperties`, `items`, `minItems`,
+//! `maxItems`, `minimum`, `maximum`, `minLength`, `maxLength`, `pattern`, `allOf`,
+//! `anyOf` and `oneOf`. Annotations like `title` and `description` are allowed, but
+//! schemas using any other keyword, including references, are rejected by `check_supported`.
+
+use super::Value;
+use regex::Regex;
+
+/// The keywords which constrain values.
+const VALIDATION_KEYWORDS: &[&str] = &[
+    "type", "enum", "const", "properties", "required", "additionalProperties", "items", "minItems",
+    "maxItems", "minimum", "maximum", "minLength", "maxLength", "pattern", "allOf", "anyOf", "oneOf",
+];
+
+/// The keywords which only describe a schema, and which do not affect validation.
+const ANNOTATION_KEYWORDS: &[&str] = &["$schema", "$id", "$comment", "title", "description", "default", "examples"];
+
+/// Makes sure that a schema and all of its subschemas only use supported keywords.
+///
+/// Validating against a schema with unsupported keywords would silently accept values which
+/// the keywords forbid.
+pub fn check_supported(schema: &Value) -> Result<(), String> {
+    self::check_supported_at(schema, "")
+}
+
+fn check_supported_at(schema: &Value, pointer: &str) -> Result<(), String> {
+    let schema = match *schema {
+        Value::Object(ref schema) => schema,
+        _ => return Ok(()),
+    };
+
+    for (keyword, value) in schema {
+        if !VALIDATION_KEYWORDS.contains(&&keyword[..]) && !ANNOTATION_KEYWORDS.contains(&&keyword[..]) {
+            return Err(format!("unsupported keyword '{}' at {}", keyword, self::location(pointer)));
+        }
+
+        let keyword_pointer = format!("{}/{}", pointer, keyword);
+        match (&keyword[..], value) {
+            ("properties", Value::Object(properties)) => for (name, subschema) in properties {
+                self::check_supported_at(subschema, &format!("{}/{}", keyword_pointer, name.replace('~', "~0").replace('/', "~1")))?;
+            },
+            ("allOf", Value::Array(subschemas)) | ("anyOf", Value::Array(subschemas)) | ("oneOf", Value::Array(subschemas)) => {
+                for (i, subschema) in subschemas.iter().enumerate() {
+                    self::check_supported_at(subschema, &format!("{}/{}", keyword_pointer, i))?;
+                }
+            },
+            ("items", subschema) | ("additionalProperties", subschema) => self::check_supported_at(subschema, &keyword_pointer)?,
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates a value against a schema.
+///
+/// Returns a description of every way in which the value does not conform to the schema,
+/// each prefixed with the JSON pointer of the invalid part of the value.
+pub fn validate(schema: &Value, value: &Value) -> Vec<String> {
+    let mut errors = Vec::new();
+    self::validate_at(schema, value, "", &mut errors);
+    errors
+}
+
+fn validate_at(schema: &Value, value: &Value, pointer: &str, errors: &mut Vec<String>) {
+    let schema = match *schema {
+        Value::Object(ref schema) => schema,
+        Value::Bool(false) => return errors.push(format!("{}: no value is allowed", self::location(pointer))),
+        _ => return,
+    };
+    let mut error = |message: String| errors.push(format!("{}: {}", self::location(pointer), message));
+
+    if let Some(expected_type) = schema.get("type") {
+        let allowed_types: Vec<&str> = match *expected_type {
+            Value::String(ref t) => vec![t],
+            Value::Array(ref types) => types.iter().filter_map(|t| if let Value::String(ref t) = *t { Some(&t[..]) } else { None }).collect(),
+            _ => Vec::new(),
+        };
+
+        let actual_type = value.type_name();
+        if !allowed_types.iter().any(|&t| t == actual_type || (t == "number" && actual_type == "integer")) {
+            error(format!("expected {} but got {}", allowed_types.join(" or "), actual_type));
+        }
+    }
+
+    if let Some(Value::Array(ref allowed_values)) = schema.get("enum") {
+        if !allowed_values.contains(value) {
+            error(format!("{} is not one of the allowed values", value));
+        }
+    }
+
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            error(format!("expected {} but got {}", expected, value));
+        }
+    }
+
+    match *value {
+        Value::Number(n) => {
+            if let Some(Value::Number(minimum)) = schema.get("minimum") {
+                if n < *minimum { error(format!("{} is less than the minimum of {}", n, minimum)); }
+            }
+            if let Some(Value::Number(maximum)) = schema.get("maximum") {
+                if n > *maximum { error(format!("{} is greater than the maximum of {}", n, maximum)); }
+            }
+        },
+        Value::String(ref s) => {
+            let length = s.chars().count() as f64;
+
+            if let Some(Value::Number(min_length)) = schema.get("minLength") {
+                if length < *min_length { error(format!("string is shorter than {} characters", min_length)); }
+            }
+            if let Some(Value::Number(max_length)) = schema.get("maxLength") {
+                if length > *max_length { error(format!("string is longer than {} characters", max_length)); }
+            }
+            if let Some(Value::String(ref pattern)) = schema.get("pattern") {
+                match Regex::new(pattern) {
+                    Ok(regex) => if !regex.is_match(s) { error(format!("{} does not match the pattern '{}'", value, pattern)); },
+                    Err(e) => error(format!("invalid pattern '{}' in schema: {}", pattern, e)),
+                }
+            }
+        },
+        Value::Array(ref items) => {
+            let count = items.len() as f64;
+
+            if let Some(Value::Number(min_items)) = schema.get("minItems") {
+                if count < *min_items { error(format!("array has fewer than {} items", min_items)); }
+            }
+            if let Some(Value::Number(max_items)) = schema.get("maxItems") {
+                if count > *max_items { error(format!("array has more than {} items", max_items)); }
+            }
+        },
+        Value::Object(ref members) => {
+            if let Some(Value::Array(ref required)) = schema.get("required") {
+                for name in required {
+                    if let Value::String(ref name) = *name {
+                        if !members.contains_key(name) {
+                            error(format!("missing required property '{}'", name));
+                        }
+                    }
+                }
+            }
+
+            let has_property = |name: &str| match schema.get("properties") {
+                Some(Value::Object(ref properties)) => properties.contains_key(name),
+                _ => false,
+            };
+            if let Some(Value::Bool(false)) = schema.get("additionalProperties") {
+                for name in members.keys().filter(|name| !has_property(name)) {
+                    error(format!("unexpected property '{}'", name));
+                }
+            }
+        },
+        Value::Null | Value::Bool(..) => (),
+    }
+
+    // Validate the parts of the value against their own schemas.
+    match *value {
+        Value::Array(ref items) => if let Some(item_schema) = schema.get("items") {
+            for (i, item) in items.iter().enumerate() {
+                self::validate_at(item_schema, item, &format!("{}/{}", pointer, i), errors);
+            }
+        },
+        Value::Object(ref members) => for (name, member) in members {
+            let member_pointer = format!("{}/{}", pointer, name.replace('~', "~0").replace('/', "~1"));
+
+            match (schema.get("properties"), schema.get("additionalProperties")) {
+                (Some(Value::Object(ref properties)), _) if properties.contains_key(name) => {
+                    self::validate_at(&properties[name], member, &member_pointer, errors);
+                },
+                (_, Some(additional_schema @ Value::Object(..))) => {
+                    self::validate_at(additional_schema, member, &member_pointer, errors);
+                },
+                _ => (),
+            }
+        },
+        _ => (),
+    }
+
+    if let Some(Value::Array(ref subschemas)) = schema.get("allOf") {
+        for subschema in subschemas {
+            self::validate_at(subschema, value, pointer, errors);
+        }
+    }
+
+    for keyword in &["anyOf", "oneOf"] {
+        if let Some(Value::Array(ref subschemas)) = schema.get(*keyword) {
+            let valid_count = subschemas.iter().filter(|subschema| self::validate(subschema, value).is_empty()).count();
+            let is_valid = if *keyword == "anyOf" { valid_count > 0 } else { valid_count == 1 };
+
+            if !is_valid {
+                errors.push(format!("{}: value matches {} of the schemas in '{}'", self::location(pointer), valid_count, keyword));
+            }
+        }
+    }
+}
+
+fn location(pointer: &str) -> &str {
+    if pointer.is_empty() { "/" } else { pointer }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn reports_every_violation_with_its_location() {
+        let schema = json::parse(r#"{
+            "type": "object",
+            "required": ["name", "version"],
+            "additionalProperties": false,
+            "properties": {
+                "name": { "type": "string", "minLength": 1 },
+                "version": { "type": "integer", "minimum": 1 },
+                "tags": { "type": "array", "items": { "enum": ["fast", "slow"] } }
+            }
+        }"#).unwrap();
+
+        assert!(validate(&schema, &json::parse(r#"{"name": "lit", "version": 2, "tags": ["fast"]}"#).unwrap()).is_empty());
+        assert_eq!(validate(&schema, &json::parse(r#"{"name": "", "tags": ["fast", "medium"], "extra": 1}"#).unwrap()), vec![
+            "/: missing required property 'version'".to_owned(),
+            "/: unexpected property 'extra'".to_owned(),
+            "/name: string is shorter than 1 characters".to_owned(),
+            "/tags/1: \"medium\" is not one of the allowed values".to_owned(),
+        ]);
+        assert_eq!(validate(&schema, &json::parse("[]").unwrap()), vec!["/: expected object but got array".to_owned()]);
+    }
+
+    #[test]
+    fn rejects_unsupported_keywords() {
+        let schema = |text: &str| json::parse(text).unwrap();
+
+        assert_eq!(check_supported(&schema(r#"{ "title": "a", "type": "object", "properties": { "a": { "items": { "maxItems": 1 } } } }"#)), Ok(()));
+        assert_eq!(check_supported(&schema(r#"{ "$ref": "other.json" }"#)), Err("unsupported keyword '$ref' at /".to_owned()));
+        assert_eq!(check_supported(&schema(r#"{ "anyOf": [{ "type": "string" }, { "multipleOf": 2 }] }"#)),
+                   Err("unsupported keyword 'multipleOf' at /anyOf/1".to_owned()));
+    }
+
+    #[test]
+    fn combines_subschemas() {
+        let schema = json::parse(r#"{ "oneOf": [{ "type": "number" }, { "type": "integer" }] }"#).unwrap();
+
+        assert!(validate(&schema, &Value::Number(1.5)).is_empty());
+        assert_eq!(validate(&schema, &Value::Number(1.0)), vec!["/: value matches 2 of the schemas in 'oneOf'".to_owned()]);
+    }
+}
@@ -5,20 +5,30 @@
 //! This crate contains both a reusable library for creating test tools and
 //! an executable with generalized command line interface for manual usage.
 
-pub use self::config::Config;
+pub use self::config::{CancellationToken, Config, TestSelector};
+pub use self::model::{
+    CheckCoverage, CheckFailureInfo, CheckStatus, Command, Comparison, ExpectedExitCode, CommandKind, FeatureExpression, IndividualRunResult, Invocation, MatchMode,
+    NumericExpression, NumericOperand, NumericTerm, OutputStream, PatternComponent, Pipeline, ProgramOutput, Redirect, RedirectKind, Results, Stage,
+    SkipReason, Summary, TestFailReason, TestFile, TestFilePath, TestInfo, TestResult, TestResultKind, TextPattern, TextPatternKind,
+};
 
 pub use self::errors::*;
+pub use self::file_system::{FileSystem, InMemoryFileSystem, OsFileSystem};
+pub use self::parse::DIRECTIVE_KEYWORDS;
+pub use self::run::{debug_test, DebugReport};
 pub use self::vars::{Variables, VariablesExt};
 
 // The file extensions used by the integration tests for this repository.
 #[doc(hidden)]
-pub const INTEGRATION_TEST_FILE_EXTENSIONS: &'static [&'static str] = &[
+pub const INTEGRATION_TEST_FILE_EXTENSIONS: &[&str] = &[
     "txt", "sh",
 ];
 
 pub mod config;
 mod errors;
 pub mod event_handler;
+pub mod file_system;
+mod json;
 mod model;
 mod parse;
 pub mod run;
@@ -26,6 +26,7 @@ fn main() {
 
         config.constants.insert("arch".to_owned(), consts::ARCH.to_owned());
         config.constants.insert("os".to_owned(), consts::OS.to_owned());
+        config.kill_children_on_termination = true;
 
         lit::config::clap::parse_arguments(&arg_matches, config);
     }).unwrap()
@@ -1,13 +1,57 @@
-use crate::{run, util, Config, Variables};
-use std::{fmt, path::PathBuf};
+use crate::{json, run, util, Config, ErrorKind, Variables};
+use std::{collections::HashMap, fmt, path::{Path, PathBuf}, time::Duration};
 use std::fmt::Write;
+use regex::Regex;
 
 /// A tool invocation.
-#[derive(Clone,Debug,PartialEq,Eq)]
+#[derive(Clone,Debug)]
 pub struct Invocation
 {
     /// The original command string.
     pub original_command: String,
+    /// The structure of the command, if it only consists of programs connected
+    /// by pipes, along with their environment assignments and redirects.
+    pub pipeline: Option<Pipeline>,
+}
+
+/// A sequence of programs, each with its standard output piped into the next.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pipeline {
+    pub stages: Vec<Stage>,
+}
+
+/// A single program inside a pipeline.
+///
+/// Words are stored as they are written in the command, including any quotes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stage {
+    /// Environment variables assigned before the program name, like `FOO=bar`.
+    pub env: Vec<(String, String)>,
+    /// The program name followed by its arguments.
+    pub words: Vec<String>,
+    pub redirects: Vec<Redirect>,
+}
+
+/// A redirection of a file descriptor, like `2> errors.txt` or `2>&1`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Redirect {
+    /// The redirected file descriptor.
+    pub fd: u32,
+    pub kind: RedirectKind,
+    /// The file path, or the file descriptor number for duplications.
+    pub target: String,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RedirectKind {
+    /// `<`
+    Read,
+    /// `>`
+    Write,
+    /// `>>`
+    Append,
+    /// `>&`
+    Duplicate,
 }
 
 // TODO: rename to TestFile
@@ -30,6 +74,8 @@ pub struct Command
 {
     pub line_number: u32,
     pub kind: CommandKind,
+    /// The feature that must be available for the directive to be evaluated, as in `CHECK{linux}:`.
+    pub feature_guard: Option<String>,
 }
 
 #[derive(Clone,Debug)]
@@ -41,8 +87,147 @@ pub enum CommandKind
     Check(TextPattern),
     /// Verify that the very next output line matches an expression.
     CheckNext(TextPattern),
-    /// Mark the test as supposed to fail.
-    XFail,
+    /// Verify that one of the next few output lines matches an expression.
+    CheckWithin(usize, TextPattern),
+    /// Verify that the output text matches an expression, in any order
+    /// relative to the adjacent `CHECK-DAG` directives.
+    CheckDag(TextPattern),
+    /// Verify that an expression matches exactly once, in any order relative to the
+    /// other directives in its group of `CHECK-DAG` directives.
+    CheckDagOnce(TextPattern),
+    /// Verify that the remaining output text matches an expression, without consuming any of it.
+    CheckAny(TextPattern),
+    /// Verify that an expression does not match between the surrounding checks.
+    CheckNot(TextPattern),
+    /// Verify that the output text matches an expression, and only match the checks after it
+    /// against the output up to the match of the next `CHECK-LABEL`.
+    CheckLabel(TextPattern),
+    /// Verify that an expression matches nowhere in the stream being checked, regardless of
+    /// how much of it has been checked.
+    CheckNone(TextPattern),
+    /// Verify that the output text matches one of several expressions, tried in order.
+    CheckAlt(Vec<TextPattern>),
+    /// Verify that an expression matches a number of times in the rest of the line matched by the previous check.
+    CheckSameCount(usize, TextPattern),
+    /// Verify that the rest of the line after the match of the previous check matches an expression.
+    CheckSame(TextPattern),
+    /// Verify that the line after the match of the previous check is empty.
+    CheckEmpty,
+    /// Verify that an expression matches a number of times in a row, like that many `CHECK` directives.
+    CheckCount(usize, TextPattern),
+    /// Verify that a hex dump in the output contains a sequence of bytes.
+    CheckBytes(Vec<u8>),
+    /// Select the output stream that subsequent checks are matched against.
+    CheckStream(OutputStream),
+    /// Set how the patterns of every check in the test are interpreted, unless a check overrides it.
+    MatchMode(MatchMode),
+    /// Limit how long each `RUN` command of the test may run for.
+    Timeout(Duration),
+    /// Evaluate the checks whilst each `RUN` command is running, giving each check this long to pass.
+    Streaming(Duration),
+    /// Mark the test as supposed to fail, or only with the features that satisfy an expression.
+    XFail(Option<FeatureExpression>),
+    /// Require features to be available for the test to run, skipping it otherwise.
+    Requires(Vec<String>),
+    /// Skip the test if any of the features are available.
+    Unsupported(Vec<String>),
+    /// Attach a `key=value` pair of metadata to the test, which lit does not interpret.
+    Meta(String, String),
+    /// Declare a file that the test depends on, relative to the directory of the test.
+    Depends(String),
+    /// Require the `RUN` commands to exit with a specific exit code.
+    Exit(ExpectedExitCode),
+    /// Require the `RUN` commands to not emit anything on standard error.
+    CheckStderrEmpty,
+    /// Require the `RUN` commands to not emit anything on either standard output or standard error.
+    CheckNoOutput,
+    /// Match subsequent checks against the contents of a file, instead of standard output.
+    CheckOutputFile(String),
+    /// Verify that the size in bytes of the stream being checked satisfies a comparison.
+    CheckSize(Comparison, usize),
+    /// Verify whether the complete stream being checked ends with a newline.
+    CheckFinalNewline(bool),
+    /// Verify that the rest of the stream being checked is identical to the given lines.
+    ///
+    /// Consecutive `CHECK-REST` directives are parsed into a single command.
+    CheckRest(Vec<String>),
+    /// Write the given lines to the standard input of the `RUN` commands.
+    ///
+    /// Consecutive `STDIN` directives are parsed into a single command.
+    Stdin(Vec<String>),
+    /// Write the contents of a file to the standard input of the `RUN` commands.
+    StdinFile(String),
+    /// Verify that files exist after the `RUN` command has finished.
+    ///
+    /// With `only`, no other files may have appeared in the directories of the files.
+    CheckCreated { paths: Vec<String>, only: bool },
+    /// Verify that a subsequent line matches an argument, according to the custom matcher with a name.
+    CheckCustom(String, String),
+    /// Replace the spans matching a regex with a placeholder, in both the output and the checks.
+    Mask(Regex),
+    /// Verify that two `RUN` commands, by their zero-based indices, print identical standard output.
+    CheckRunsEqual(usize, usize),
+    /// Verify that the complete stream being checked is identical to the contents of a file.
+    CheckFile(String),
+    /// Verify that the complete stream being checked is JSON which is valid according to a JSON schema file.
+    CheckSchema(String),
+    /// Verify that the first number on a subsequent line is within a tolerance of an expected number.
+    CheckNum { expected: f64, tolerance: f64 },
+    /// Verify that the duration of the `RUN` command satisfies a comparison.
+    CheckTime(Comparison, Duration),
+}
+
+/// An output stream of a program.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+    /// The lines of both streams in the order that they were printed, each prefixed by `[stdout] ` or `[stderr] `.
+    Interleaved,
+}
+
+/// How the pattern of a check is interpreted.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MatchMode {
+    /// The pattern is literal text, which may contain `[[regex]]`s and `$$variables`.
+    #[default]
+    Literal,
+    /// The whole pattern is a regex.
+    Regex,
+    /// The pattern is interpreted like a literal pattern, but it must match a complete line.
+    WholeLine,
+}
+
+/// The exit code that a test requires its `RUN` commands to exit with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ExpectedExitCode {
+    /// Exactly this exit code, where `EXIT: zero` is the same as `EXIT: 0`.
+    Code(i32),
+    /// Any exit code other than zero.
+    Nonzero,
+}
+
+/// A boolean expression over the features available in a config, like `linux && !asserts`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FeatureExpression {
+    /// The feature is available.
+    Feature(String),
+    /// The expression is not satisfied.
+    Not(Box<FeatureExpression>),
+    /// Both expressions are satisfied.
+    And(Box<FeatureExpression>, Box<FeatureExpression>),
+    /// Either expression is satisfied.
+    Or(Box<FeatureExpression>, Box<FeatureExpression>),
+}
+
+/// A comparison between two numbers, like `<` or `>=`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -50,6 +235,15 @@ pub struct TextPattern {
     pub components: Vec<PatternComponent>,
 }
 
+/// The kind of matching performed by a text pattern.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TextPatternKind {
+    /// The pattern only matches literal text, possibly containing variables.
+    Literal,
+    /// The pattern contains at least one regex.
+    Regex,
+}
+
 /// A component in a text pattern.
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum PatternComponent {
@@ -57,6 +251,43 @@ pub enum PatternComponent {
     Variable(String),
     Regex(String),
     NamedRegex { name: String, regex: String },
+    /// A decimal number which is captured into a numeric variable, written `[[#NAME:]]`.
+    NumericCapture(String),
+    /// A number computed from numeric variables, like `[[#NAME+8]]`.
+    NumericExpression(NumericExpression),
+}
+
+/// The sum of the terms of a numeric substitution, like `NAME+8` in `[[#NAME+8]]`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NumericExpression {
+    pub terms: Vec<NumericTerm>,
+}
+
+/// A term of a numeric expression.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NumericTerm {
+    /// Whether the term is subtracted rather than added.
+    pub is_subtracted: bool,
+    pub operand: NumericOperand,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NumericOperand {
+    Number(i64),
+    Variable(String),
+}
+
+/// Why a test was skipped.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SkipReason {
+    /// The test requires a feature that is not available.
+    MissingFeature(String),
+    /// The test is unsupported when a feature is available.
+    UnsupportedFeature(String),
+    /// The test does not have the `RUN` directive selected by `Config::run_line`.
+    RunLineNotFound(usize),
+    /// The test suite run was cancelled before or whilst the test was running.
+    Cancelled,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -69,6 +300,11 @@ pub enum TestResultKind
     UnexpectedPass,
     /// An error occurred whilst running the test.
     Error { message: String },
+    /// The process of a `RUN` command could not be started.
+    SpawnError {
+        program_command_line: String,
+        message: String,
+    },
     /// The test failed.
     Fail {
         reason: TestFailReason,
@@ -80,7 +316,39 @@ pub enum TestResultKind
     },
     EmptyTest,
     /// The test was skipped.
-    Skip,
+    Skip { reason: SkipReason },
+    /// A `RUN` command was killed because it ran for longer than the timeout.
+    Timeout { duration: Duration },
+    /// The test failed, but it is listed in `Config::allow_failures`.
+    KnownFailure {
+        actual_result: Box<TestResultKind>,
+    },
+    /// A domain-specific outcome, reported by an extension rather than by lit itself.
+    Custom {
+        /// A short name for the outcome, shown in place of `PASS` or `FAIL`.
+        label: String,
+        /// Whether the outcome counts as a success for the test suite.
+        passed: bool,
+        message: String,
+    },
+}
+
+/// A part of the detailed description of a test failure.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DetailSection {
+    /// Text which is shown as it is, like program output.
+    Text(String),
+    /// A diff, whose lines start with `- ` for expected text and `+ ` for actual text.
+    Diff(String),
+}
+
+impl DetailSection {
+    /// Gets the text of the section.
+    pub fn into_text(self) -> String {
+        match self {
+            DetailSection::Text(text) | DetailSection::Diff(text) => text,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -90,6 +358,83 @@ pub enum TestFailReason {
         exit_status: i32,
     },
     CheckFailed(CheckFailureInfo),
+    /// The program was killed by a signal, like `SIGSEGV`, rather than exiting by itself.
+    TerminatedBySignal {
+        program_command_line: String,
+        signal: i32,
+    },
+    UnexpectedStderr {
+        program_command_line: String,
+        stderr: String,
+    },
+    /// The program printed something, but the test declares `CHECK-NO-OUTPUT`.
+    UnexpectedOutput {
+        program_command_line: String,
+        stdout: String,
+        stderr: String,
+    },
+    UnexpectedExitCode {
+        program_command_line: String,
+        expected_exit_status: ExpectedExitCode,
+        exit_status: i32,
+    },
+    UnreadableOutputFile {
+        path: String,
+        error: String,
+    },
+    /// The output ended with a newline when it should not have, or the other way around.
+    UnexpectedFinalNewline {
+        expected: bool,
+    },
+    /// A pattern declared by `CHECK-NOT` or `CHECK-NONE` appeared in the output.
+    ForbiddenMatch {
+        pattern: TextPattern,
+        line_number: usize,
+        line: String,
+        /// Whether the pattern was forbidden in the whole output, rather than between two checks.
+        anywhere: bool,
+    },
+    /// The line after the match of the previous check was not empty, or there was no such line.
+    NonEmptyLine {
+        found: Option<String>,
+    },
+    /// The rest of the output was not identical to the lines declared by `CHECK-REST`.
+    RestMismatch {
+        diff: String,
+    },
+    /// The files declared by `CHECK-CREATED` were not created, or other files were.
+    UnexpectedCreatedFiles {
+        missing: Vec<String>,
+        unexpected: Vec<String>,
+    },
+    UnexpectedOutputSize {
+        comparison: Comparison,
+        expected_size: usize,
+        actual_size: usize,
+    },
+    UnexpectedDuration {
+        comparison: Comparison,
+        expected_duration: Duration,
+        actual_duration: Duration,
+    },
+    /// More than one of the success criteria declared by the test did not hold.
+    CriteriaNotMet(Vec<TestFailReason>),
+    /// The output was not identical to the contents of an expected file.
+    ExpectedFileMismatch {
+        path: String,
+        diff: String,
+    },
+    /// The output was not JSON which is valid according to a schema.
+    SchemaViolation {
+        schema_path: String,
+        errors: Vec<String>,
+    },
+    /// Two `RUN` commands that should print identical output did not.
+    RunOutputsDiffer {
+        first_run_index: usize,
+        second_run_index: usize,
+        diff: String,
+    },
 }
 
 impl TestFailReason {
@@ -101,47 +446,228 @@ impl TestFailReason {
             TestFailReason::CheckFailed(..) => {
                 "test checked for text that did not exist in the output"
             },
+            TestFailReason::TerminatedBySignal { .. } => {
+                "program was terminated by a signal"
+            },
+            TestFailReason::UnexpectedStderr { .. } => {
+                "program emitted text on standard error"
+            },
+            TestFailReason::UnexpectedOutput { .. } => {
+                "program emitted output, but it should be silent"
+            },
+            TestFailReason::UnexpectedExitCode { .. } => {
+                "program exited with an unexpected exit code"
+            },
+            TestFailReason::UnreadableOutputFile { .. } => {
+                "output file of the program could not be read"
+            },
+            TestFailReason::UnexpectedFinalNewline { .. } => {
+                "output of the program did not end as expected"
+            },
+            TestFailReason::ForbiddenMatch { .. } => {
+                "output of the program contained text that it should not have"
+            },
+            TestFailReason::NonEmptyLine { .. } => {
+                "program did not print an empty line where expected"
+            },
+            TestFailReason::RestMismatch { .. } => {
+                "rest of the output was not identical to the expected lines"
+            },
+            TestFailReason::UnexpectedCreatedFiles { .. } => {
+                "program did not create the expected files"
+            },
+            TestFailReason::UnexpectedOutputSize { .. } => {
+                "output of the program did not have the expected size"
+            },
+            TestFailReason::UnexpectedDuration { .. } => {
+                "program did not run for the expected duration"
+            },
+            TestFailReason::CriteriaNotMet(..) => {
+                "several success criteria of the test did not hold"
+            },
+            TestFailReason::ExpectedFileMismatch { .. } => {
+                "output of the program was not identical to the expected file"
+            },
+            TestFailReason::SchemaViolation { .. } => {
+                "output of the program was not valid according to the schema"
+            },
+            TestFailReason::RunOutputsDiffer { .. } => {
+                "RUN commands that should print identical output did not"
+            },
         }
     }
 
-    pub fn human_detail_message(&self, config: &Config) -> String {
+    /// Gets the detailed description of the failure, with the diffs in it as separate sections.
+    pub fn human_detail_sections(&self, config: &Config) -> Vec<DetailSection> {
         match *self {
-            TestFailReason::UnsuccessfulExecution { ref program_command_line, exit_status } => {
-                format!("command '{}' exited with code '{}'", program_command_line, exit_status)
-            },
             TestFailReason::CheckFailed(ref check_failure_info) => {
                 let mut buf = String::new();
                 writeln!(&mut buf, "expected text '{}' but that was not found", check_failure_info.expected_pattern).unwrap();
+                writeln!(&mut buf, "matching stopped at line {} of the output", check_failure_info.successfully_checked_upto_line_number()).unwrap();
                 writeln!(&mut buf).unwrap();
 
-                // Write the successfully checked output.
-                writeln!(&mut buf, "{}", format_test_output("successfully checked output",
+                // Write the end of the successfully checked output.
+                writeln!(&mut buf, "{}", format_truncated_test_output("successfully checked output",
                         check_failure_info.successfully_checked_text(), 1, util::TruncateDirection::Top,
-                        config)).unwrap();
+                        Some(config.context_lines))).unwrap();
 
                 writeln!(&mut buf).unwrap();
 
-                // Write the remaining unchecked output.
-                writeln!(&mut buf, "{}", format_test_output("remaining unchecked output",
+                // Write the start of the remaining unchecked output.
+                writeln!(&mut buf, "{}", format_truncated_test_output("remaining unchecked output",
                         check_failure_info.remaining_text(),
                         check_failure_info.successfully_checked_upto_line_number(), util::TruncateDirection::Bottom,
-                        config)).unwrap();
+                        Some(config.context_lines))).unwrap();
+
+                // Show how the closest text differs from the expected text, with a caret under the first difference.
+                match check_failure_info.closest_line() {
+                    Some((line_number, line, range)) => {
+                        let (expected, found) = (check_failure_info.expected_pattern.to_string(), &line[range]);
+                        let column = expected.chars().zip(found.chars()).take_while(|(a, b)| a == b).count();
+
+                        writeln!(&mut buf).unwrap();
+                        writeln!(&mut buf, "the closest text in the remaining output is on line {}: {}", line_number, line.trim()).unwrap();
+                        writeln!(&mut buf).unwrap();
+                        vec![DetailSection::Text(buf), DetailSection::Diff(format!("- {}\n+ {}\n  {:column$}^\n", expected, found, "", column = column))]
+                    },
+                    None => vec![DetailSection::Text(buf)],
+                }
+            },
+            TestFailReason::RestMismatch { ref diff } => vec![
+                DetailSection::Text("the rest of the output differs from the lines of CHECK-REST\n\n".to_owned()),
+                DetailSection::Diff(diff.clone()),
+            ],
+            TestFailReason::ExpectedFileMismatch { ref path, ref diff } => vec![
+                DetailSection::Text(format!("the output differs from the expected file '{}'\n\n", path)),
+                DetailSection::Diff(diff.clone()),
+            ],
+            TestFailReason::RunOutputsDiffer { first_run_index, second_run_index, ref diff } => vec![
+                DetailSection::Text(format!("the standard output of RUN {} differs from that of RUN {}\n\n", second_run_index, first_run_index)),
+                DetailSection::Diff(diff.clone()),
+            ],
+            TestFailReason::CriteriaNotMet(ref reasons) => {
+                let mut sections = Vec::new();
+                for (i, reason) in reasons.iter().enumerate() {
+                    sections.push(DetailSection::Text(format!("{}. {}\n", i + 1, reason.human_summary())));
+                    sections.extend(reason.human_detail_sections(config));
+                    sections.push(DetailSection::Text("\n".to_owned()));
+                }
+                sections
+            },
+            _ => vec![DetailSection::Text(self.human_detail_message(config))],
+        }
+    }
 
+    pub fn human_detail_message(&self, config: &Config) -> String {
+        match *self {
+            TestFailReason::CheckFailed(..) | TestFailReason::RestMismatch { .. } | TestFailReason::ExpectedFileMismatch { .. } |
+                TestFailReason::RunOutputsDiffer { .. } | TestFailReason::CriteriaNotMet(..) => {
+                self.human_detail_sections(config).into_iter().map(DetailSection::into_text).collect()
+            },
+            TestFailReason::UnsuccessfulExecution { ref program_command_line, exit_status } => {
+                // Windows has no signals, but crashes exit with an NTSTATUS error code, like 0xC0000005.
+                let note = if cfg!(windows) && exit_status as u32 >= 0xC000_0000 { " (an NTSTATUS error code, so the program probably crashed)" } else { "" };
+                format!("command '{}' exited with code '{}'{}", program_command_line, exit_status, note)
+            },
+            TestFailReason::TerminatedBySignal { ref program_command_line, signal } => {
+                match self::signal_name(signal) {
+                    Some(name) => format!("command '{}' was terminated by signal {} ({})", program_command_line, signal, name),
+                    None => format!("command '{}' was terminated by signal {}", program_command_line, signal),
+                }
+            },
+            TestFailReason::UnexpectedStderr { ref program_command_line, ref stderr } => {
+                format!("command '{}' unexpectedly emitted text on standard error\n\n{}", program_command_line,
+                        format_test_output("stderr", stderr, 1, util::TruncateDirection::Bottom, config))
+            },
+            TestFailReason::UnexpectedOutput { ref program_command_line, ref stdout, ref stderr } => {
+                let mut buf = format!("command '{}' unexpectedly emitted output", program_command_line);
+
+                for (label, output) in [("stdout", stdout), ("stderr", stderr)] {
+                    if !output.is_empty() {
+                        write!(&mut buf, "\n\n{}", format_test_output(label, output, 1, util::TruncateDirection::Bottom, config)).unwrap();
+                    }
+                }
                 buf
             },
+            TestFailReason::UnexpectedExitCode { ref program_command_line, expected_exit_status, exit_status } => {
+                format!("command '{}' exited with code '{}', but the test expects {}", program_command_line, exit_status, expected_exit_status)
+            },
+            TestFailReason::UnreadableOutputFile { ref path, ref error } => {
+                format!("could not read output file '{}': {}", path, error)
+            },
+            TestFailReason::UnexpectedFinalNewline { expected: true } => {
+                "expected the output to end with a newline, but no trailing newline was present".to_owned()
+            },
+            TestFailReason::UnexpectedFinalNewline { expected: false } => {
+                "expected the output to not end with a newline, but a trailing newline was present".to_owned()
+            },
+            TestFailReason::ForbiddenMatch { ref pattern, line_number, ref line, anywhere } => {
+                let region = if anywhere { "anywhere in the output" } else { "between the surrounding checks" };
+                format!("expected text '{}' to not appear {}, but it was found on line {}:\n\n  {}", pattern, region, line_number, line)
+            },
+            TestFailReason::NonEmptyLine { found: Some(ref line) } => {
+                format!("expected the next line to be empty, but found '{}'", line)
+            },
+            TestFailReason::NonEmptyLine { found: None } => {
+                "expected the next line to be empty, but the output ended".to_owned()
+            },
+            TestFailReason::UnexpectedCreatedFiles { ref missing, ref unexpected } => {
+                let missing = missing.iter().map(|path| format!("file '{}' was not created", path));
+                let unexpected = unexpected.iter().map(|path| format!("file '{}' was created, but the test does not expect it", path));
+
+                missing.chain(unexpected).collect::<Vec<_>>().join("\n")
+            },
+            TestFailReason::UnexpectedOutputSize { comparison, expected_size, actual_size } => {
+                format!("expected the output size to be {} {} bytes, but it is {} bytes", comparison, expected_size, actual_size)
+            },
+            TestFailReason::UnexpectedDuration { comparison, expected_duration, actual_duration } => {
+                format!("expected the command to run for {} {:?}, but it ran for {:?}", comparison, expected_duration, actual_duration)
+            },
+            TestFailReason::SchemaViolation { ref schema_path, ref errors } => {
+                format!("the output is not valid according to the schema '{}'\n\n{}", schema_path, util::indent(&errors.join("\n"), 1))
+            },
         }
     }
 }
 
+/// Gets the name of a signal, for the signals whose numbers are the same on every Unix.
+fn signal_name(signal: i32) -> Option<&'static str> {
+    match signal {
+        1 => Some("SIGHUP"),
+        2 => Some("SIGINT"),
+        3 => Some("SIGQUIT"),
+        4 => Some("SIGILL"),
+        5 => Some("SIGTRAP"),
+        6 => Some("SIGABRT"),
+        8 => Some("SIGFPE"),
+        9 => Some("SIGKILL"),
+        11 => Some("SIGSEGV"),
+        13 => Some("SIGPIPE"),
+        14 => Some("SIGALRM"),
+        15 => Some("SIGTERM"),
+        _ => None,
+    }
+}
+
 pub(crate) fn format_test_output(
     output_label: &str,
     unformatted_output: &str,
     output_base_line_number: usize,
     truncate_direction: util::TruncateDirection,
     config: &Config) -> String {
+    self::format_truncated_test_output(output_label, unformatted_output, output_base_line_number, truncate_direction,
+                                       config.truncate_output_context_to_number_of_lines)
+}
+
+fn format_truncated_test_output(
+    output_label: &str,
+    unformatted_output: &str,
+    output_base_line_number: usize,
+    truncate_direction: util::TruncateDirection,
+    max_line_count: Option<usize>) -> String {
     let mut formatted_output = util::decorate_with_line_numbers(unformatted_output, output_base_line_number);
 
-    if let Some(max_line_count) = config.truncate_output_context_to_number_of_lines {
+    if let Some(max_line_count) = max_line_count {
         formatted_output = util::truncate_to_max_lines(&formatted_output, max_line_count, truncate_direction);
     }
     let formatted_output = util::indent(&formatted_output, 1);
@@ -158,14 +684,86 @@ pub struct CheckFailureInfo {
 }
 
 /// Results from executing a test.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct TestResult
 {
     /// A path to the test.
     pub path: TestFilePath,
     /// The kind of result.
     pub overall_result: TestResultKind,
-    pub individual_run_results: Vec<(TestResultKind, Invocation, run::CommandLine, ProgramOutput)>,
+    pub individual_run_results: Vec<IndividualRunResult>,
+    /// The metadata declared by the test with `META` directives.
+    pub metadata: HashMap<String, String>,
+    /// How long the test took to run.
+    pub duration: Duration,
+}
+
+/// A summary of the directives of a test, for listing tests without running them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestInfo {
+    /// A path to the test.
+    pub path: TestFilePath,
+    /// The number of `RUN` directives.
+    pub run_commands: usize,
+    /// The number of check directives.
+    pub checks: usize,
+    /// The metadata declared by the test with `META` directives.
+    pub metadata: HashMap<String, String>,
+    /// The features that parts of the test depend on, sorted and without duplicates.
+    pub features: Vec<String>,
+    /// The files that the test depends on, starting with the test file itself.
+    pub dependencies: Vec<PathBuf>,
+}
+
+impl TestInfo {
+    /// Checks if a change to a file affects the test, so that the test should be rerun.
+    pub fn depends_on(&self, path: &Path) -> bool {
+        self.dependencies.iter().any(|dependency| dependency == path)
+    }
+}
+
+/// Results from executing a single `RUN` directive of a test.
+#[derive(Clone, Debug)]
+pub struct IndividualRunResult
+{
+    /// The kind of result.
+    pub kind: TestResultKind,
+    /// The invocation declared by the `RUN` directive.
+    pub invocation: Invocation,
+    /// The line number of the `RUN` directive inside the test file.
+    pub line_number: u32,
+    /// The command line that was executed, after variables were resolved.
+    pub command_line: run::CommandLine,
+    /// The output of the executed program.
+    pub output: ProgramOutput,
+    /// The status of every check directive in the test, in the order they were declared.
+    pub checks: Vec<CheckCoverage>,
+    /// The temporary files that were kept for inspection, rather than cleaned up.
+    pub kept_tempfiles: Vec<PathBuf>,
+}
+
+/// How far a single check directive got during a run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckCoverage {
+    /// The line number of the check directive inside the test file.
+    pub line_number: u32,
+    pub status: CheckStatus,
+    /// The line of the checked stream that all prior output had been checked up to,
+    /// once the check was evaluated.
+    pub checked_up_to_line: Option<usize>,
+}
+
+/// Whether a check directive was evaluated during a run.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CheckStatus {
+    /// The check was evaluated and it matched.
+    Passed,
+    /// The check was evaluated and it did not match.
+    Failed,
+    /// The check was never evaluated, for example because an earlier check failed.
+    NotReached,
+    /// The check was not evaluated because its feature guard is not available.
+    Disabled,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -175,10 +773,31 @@ pub struct ProgramOutput {
 }
 
 
-#[derive(Debug)]
+/// Results from executing an entire test suite.
+#[derive(Clone, Debug)]
 pub struct Results
 {
+    /// The results of each test, in the order the tests were discovered in.
     pub test_results: Vec<TestResult>,
+    /// Whether the run was cancelled, in which case tests which did not finish are skipped.
+    pub cancelled: bool,
+    /// How long the complete test suite took to run, from start to finish.
+    pub(crate) total_duration: Duration,
+}
+
+/// The number of tests in a test suite with each kind of result.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub passes: usize,
+    pub unexpected_passes: usize,
+    pub errors: usize,
+    pub failures: usize,
+    pub expected_failures: usize,
+    pub empty_tests: usize,
+    pub skipped: usize,
+    pub timeouts: usize,
+    pub known_failures: usize,
+    pub custom: usize,
 }
 
 impl PartialEq for CommandKind {
@@ -187,13 +806,117 @@ impl PartialEq for CommandKind {
             CommandKind::Run(ref a) => if let CommandKind::Run(ref b) = *other { a == b } else { false },
             CommandKind::Check(ref a) => if let CommandKind::Check(ref b) = *other { a.to_string() == b.to_string() } else { false },
             CommandKind::CheckNext(ref a) => if let CommandKind::CheckNext(ref b) = *other { a.to_string() == b.to_string() } else { false },
-            CommandKind::XFail => *other == CommandKind::XFail,
+            CommandKind::CheckWithin(n, ref a) => if let CommandKind::CheckWithin(m, ref b) = *other { n == m && a.to_string() == b.to_string() } else { false },
+            CommandKind::CheckDag(ref a) => if let CommandKind::CheckDag(ref b) = *other { a.to_string() == b.to_string() } else { false },
+            CommandKind::CheckDagOnce(ref a) => if let CommandKind::CheckDagOnce(ref b) = *other { a.to_string() == b.to_string() } else { false },
+            CommandKind::CheckAny(ref a) => if let CommandKind::CheckAny(ref b) = *other { a.to_string() == b.to_string() } else { false },
+            CommandKind::CheckNone(ref a) => if let CommandKind::CheckNone(ref b) = *other { a.to_string() == b.to_string() } else { false },
+            CommandKind::CheckNot(ref a) => if let CommandKind::CheckNot(ref b) = *other { a.to_string() == b.to_string() } else { false },
+            CommandKind::CheckLabel(ref a) => if let CommandKind::CheckLabel(ref b) = *other { a.to_string() == b.to_string() } else { false },
+            CommandKind::CheckAlt(ref a) => if let CommandKind::CheckAlt(ref b) = *other {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.to_string() == b.to_string())
+            } else { false },
+            CommandKind::CheckSameCount(n, ref a) => if let CommandKind::CheckSameCount(m, ref b) = *other { n == m && a.to_string() == b.to_string() } else { false },
+            CommandKind::CheckSame(ref a) => if let CommandKind::CheckSame(ref b) = *other { a.to_string() == b.to_string() } else { false },
+            CommandKind::CheckEmpty => *other == CommandKind::CheckEmpty,
+            CommandKind::CheckCount(n, ref a) => if let CommandKind::CheckCount(m, ref b) = *other { n == m && a.to_string() == b.to_string() } else { false },
+            CommandKind::CheckBytes(ref a) => if let CommandKind::CheckBytes(ref b) = *other { a == b } else { false },
+            CommandKind::CheckStream(a) => if let CommandKind::CheckStream(b) = *other { a == b } else { false },
+            CommandKind::MatchMode(a) => if let CommandKind::MatchMode(b) = *other { a == b } else { false },
+            CommandKind::Timeout(a) => if let CommandKind::Timeout(b) = *other { a == b } else { false },
+            CommandKind::Streaming(a) => if let CommandKind::Streaming(b) = *other { a == b } else { false },
+            CommandKind::XFail(ref a) => if let CommandKind::XFail(ref b) = *other { a == b } else { false },
+            CommandKind::Requires(ref a) => if let CommandKind::Requires(ref b) = *other { a == b } else { false },
+            CommandKind::Unsupported(ref a) => if let CommandKind::Unsupported(ref b) = *other { a == b } else { false },
+            CommandKind::Meta(ref k, ref v) => if let CommandKind::Meta(ref k2, ref v2) = *other { k == k2 && v == v2 } else { false },
+            CommandKind::Depends(ref a) => if let CommandKind::Depends(ref b) = *other { a == b } else { false },
+            CommandKind::Exit(a) => if let CommandKind::Exit(b) = *other { a == b } else { false },
+            CommandKind::CheckStderrEmpty => *other == CommandKind::CheckStderrEmpty,
+            CommandKind::CheckNoOutput => *other == CommandKind::CheckNoOutput,
+            CommandKind::CheckOutputFile(ref a) => if let CommandKind::CheckOutputFile(ref b) = *other { a == b } else { false },
+            CommandKind::CheckSize(c, n) => if let CommandKind::CheckSize(c2, n2) = *other { c == c2 && n == n2 } else { false },
+            CommandKind::CheckFinalNewline(a) => if let CommandKind::CheckFinalNewline(b) = *other { a == b } else { false },
+            CommandKind::CheckRest(ref a) => if let CommandKind::CheckRest(ref b) = *other { a == b } else { false },
+            CommandKind::Stdin(ref a) => if let CommandKind::Stdin(ref b) = *other { a == b } else { false },
+            CommandKind::StdinFile(ref a) => if let CommandKind::StdinFile(ref b) = *other { a == b } else { false },
+            CommandKind::CheckCreated { ref paths, only } => if let CommandKind::CheckCreated { paths: ref p2, only: o2 } = *other { paths == p2 && only == o2 } else { false },
+            CommandKind::CheckCustom(ref m, ref a) => if let CommandKind::CheckCustom(ref m2, ref a2) = *other { m == m2 && a == a2 } else { false },
+            CommandKind::Mask(ref a) => if let CommandKind::Mask(ref b) = *other { a.as_str() == b.as_str() } else { false },
+            CommandKind::CheckRunsEqual(a, b) => if let CommandKind::CheckRunsEqual(a2, b2) = *other { a == a2 && b == b2 } else { false },
+            CommandKind::CheckFile(ref a) => if let CommandKind::CheckFile(ref b) = *other { a == b } else { false },
+            CommandKind::CheckSchema(ref a) => if let CommandKind::CheckSchema(ref b) = *other { a == b } else { false },
+            CommandKind::CheckTime(c, d) => if let CommandKind::CheckTime(c2, d2) = *other { c == c2 && d == d2 } else { false },
+            CommandKind::CheckNum { expected, tolerance } => if let CommandKind::CheckNum { expected: e, tolerance: t } = *other { expected == e && tolerance == t } else { false },
         }
     }
 }
 
 impl Eq for CommandKind { }
 
+impl PartialEq for Invocation {
+    /// Invocations are compared by their structure when both of them could be parsed as pipelines.
+    fn eq(&self, other: &Invocation) -> bool {
+        match (&self.pipeline, &other.pipeline) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.original_command == other.original_command,
+        }
+    }
+}
+
+impl Eq for Invocation { }
+
+impl Invocation {
+    /// Creates an invocation of a command line, parsing it as a pipeline if possible.
+    pub fn new(command: impl Into<String>) -> Self {
+        let original_command = command.into();
+        let pipeline = crate::parse::pipeline(&original_command);
+
+        Invocation { original_command, pipeline }
+    }
+
+    /// Creates an invocation from a pipeline constructed by hand.
+    pub fn from_pipeline(pipeline: Pipeline) -> Self {
+        Invocation { original_command: pipeline.to_string(), pipeline: Some(pipeline) }
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for (i, stage) in self.stages.iter().enumerate() {
+            if i > 0 { write!(fmt, " | ")?; }
+            write!(fmt, "{}", stage)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let env = self.env.iter().map(|(name, value)| format!("{}={}", name, value));
+        let words = self.words.iter().cloned();
+        let redirects = self.redirects.iter().map(|r| r.to_string());
+
+        write!(fmt, "{}", env.chain(words).chain(redirects).collect::<Vec<_>>().join(" "))
+    }
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let default_fd = if self.kind == RedirectKind::Read { 0 } else { 1 };
+        if self.fd != default_fd {
+            write!(fmt, "{}", self.fd)?;
+        }
+
+        match self.kind {
+            RedirectKind::Read => write!(fmt, "< {}", self.target),
+            RedirectKind::Write => write!(fmt, "> {}", self.target),
+            RedirectKind::Append => write!(fmt, ">> {}", self.target),
+            RedirectKind::Duplicate => write!(fmt, ">&{}", self.target),
+        }
+    }
+}
+
 impl fmt::Display for TextPattern {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         for component in self.components.iter() {
@@ -202,6 +925,8 @@ impl fmt::Display for TextPattern {
                 PatternComponent::Variable(ref name) => write!(fmt, "$${}", name)?,
                 PatternComponent::Regex(ref regex) => write!(fmt, "[[{}]]", regex)?,
                 PatternComponent::NamedRegex { ref name, ref regex } => write!(fmt, "[[{}:{}]]", name, regex)?,
+                PatternComponent::NumericCapture(ref name) => write!(fmt, "[[#{}:]]", name)?,
+                PatternComponent::NumericExpression(ref expression) => write!(fmt, "[[#{}]]", expression)?,
             }
         }
 
@@ -209,107 +934,930 @@ impl fmt::Display for TextPattern {
     }
 }
 
-impl Command
-{
-    pub fn new(kind: CommandKind, line_number: u32) -> Self {
-        Command { kind, line_number }
-    }
-}
-
-impl TestResultKind {
-    /// Checks if the result is considered an error.
-    pub fn is_erroneous(&self) -> bool {
-        use self::TestResultKind::*;
+impl fmt::Display for NumericExpression {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for (i, term) in self.terms.iter().enumerate() {
+            match (i, term.is_subtracted) {
+                (_, true) => write!(fmt, "-")?,
+                (0, false) => (),
+                (_, false) => write!(fmt, "+")?,
+            }
 
-        match *self {
-            UnexpectedPass | Error { .. } | Fail { .. } => true,
-            Pass | Skip | ExpectedFailure { .. } | EmptyTest => false,
+            match term.operand {
+                NumericOperand::Number(number) => write!(fmt, "{}", number)?,
+                NumericOperand::Variable(ref name) => write!(fmt, "{}", name)?,
+            }
         }
+
+        Ok(())
     }
+}
 
-    pub fn unwrap(&self) {
-        if self.is_erroneous() {
-            panic!("error whilst running test: {:?}", self);
-        }
+impl NumericExpression {
+    /// Gets the names of the variables that the expression refers to.
+    pub fn variable_names(&self) -> impl Iterator<Item=&str> {
+        self.terms.iter().filter_map(|term| match term.operand {
+            NumericOperand::Variable(ref name) => Some(name.as_str()),
+            NumericOperand::Number(..) => None,
+        })
     }
 
-    pub fn human_label_pluralized(&self) -> &'static str {
-        use self::TestResultKind::*;
+    /// Computes the value of the expression from the values of its variables.
+    pub fn evaluate(&self, variables: &Variables) -> Result<i64, String> {
+        self.terms.iter().try_fold(0i64, |sum, term| {
+            let value = match term.operand {
+                NumericOperand::Number(number) => number,
+                NumericOperand::Variable(ref name) => match variables.get(name) {
+                    Some(value) => value.trim().parse().map_err(|_| format!("variable '{}' is not a number: '{}'", name, value))?,
+                    None => return Err(format!("numeric variable '{}' is not defined", name)),
+                },
+            };
 
-        match *self {
-            Pass => "Passes",
-            UnexpectedPass => "Unexpected passes",
-            Error { .. } => "Errors",
-            Fail { .. } => "Test failures",
-            ExpectedFailure { .. } => "Expected failures",
-            EmptyTest { .. } => "Empty tests",
-            Skip => "Skipped tests",
-        }
+            let sum = if term.is_subtracted { sum.checked_sub(value) } else { sum.checked_add(value) };
+            sum.ok_or_else(|| format!("numeric expression '{}' overflows", self))
+        })
     }
 }
 
-impl CheckFailureInfo {
-    /// Gets the slice containing the portion of successfully checked text.
-    pub fn successfully_checked_text(&self) -> &str {
-        let byte_subslice = &self.complete_output_text.as_bytes()[0..self.successfully_checked_until_byte_index];
-        convert_bytes_to_str(byte_subslice)
-    }
+impl TextPattern {
+    /// Gets the kind of matching performed by the pattern.
+    pub fn kind(&self) -> TextPatternKind {
+        let has_regex = self.components.iter().any(|c| match *c {
+            PatternComponent::Regex(..) | PatternComponent::NamedRegex { .. } | PatternComponent::NumericCapture(..) => true,
+            PatternComponent::Text(..) | PatternComponent::Variable(..) | PatternComponent::NumericExpression(..) => false,
+        });
 
-    /// Gets the slice containing the portion of unchecked, remaining text.
-    pub fn remaining_text(&self) -> &str {
-        let byte_subslice = &self.complete_output_text.as_bytes()[self.successfully_checked_until_byte_index..];
-        convert_bytes_to_str(byte_subslice)
+        if has_regex { TextPatternKind::Regex } else { TextPatternKind::Literal }
     }
 
-    pub fn successfully_checked_upto_line_number(&self) -> usize {
-        self.successfully_checked_text().lines().count() + 1
+    /// Gets the source of the pattern, as it would be written in a test file.
+    pub fn source(&self) -> String {
+        self.to_string()
     }
 }
 
-impl TestFile
+impl Command
 {
-    /// Extra test-specific variables.
-    pub fn variables(&self) -> Variables {
-        let mut v = Variables::new();
-        v.insert("file".to_owned(), self.path.absolute.to_str().unwrap().to_owned());
-        v
+    pub fn new(kind: CommandKind, line_number: u32) -> Self {
+        Command { kind, line_number, feature_guard: None }
     }
 
-    /// Gets an iterator over all `RUN` commands in the test file.
-    pub fn run_command_invocations(&self) -> impl Iterator<Item=&Invocation> {
-        self.commands.iter().filter_map(|c| match c.kind {
-            CommandKind::Run(ref invocation) => Some(invocation),
+    /// Gets the text patterns of a check directive, if it has any.
+    pub fn text_patterns(&self) -> &[TextPattern] {
+        match self.kind {
+            CommandKind::Check(ref p) | CommandKind::CheckNext(ref p) | CommandKind::CheckWithin(_, ref p) |
+                CommandKind::CheckDag(ref p) | CommandKind::CheckDagOnce(ref p) | CommandKind::CheckAny(ref p) |
+                CommandKind::CheckNot(ref p) | CommandKind::CheckLabel(ref p) | CommandKind::CheckNone(ref p) | CommandKind::CheckSameCount(_, ref p) |
+                CommandKind::CheckSame(ref p) | CommandKind::CheckCount(_, ref p) => std::slice::from_ref(p),
+            CommandKind::CheckAlt(ref patterns) => patterns,
+            _ => &[],
+        }
+    }
+
+    /// Gets mutable references to the text patterns of a check directive.
+    pub(crate) fn text_patterns_mut(&mut self) -> &mut [TextPattern] {
+        match self.kind {
+            CommandKind::Check(ref mut p) | CommandKind::CheckNext(ref mut p) | CommandKind::CheckWithin(_, ref mut p) |
+                CommandKind::CheckDag(ref mut p) | CommandKind::CheckDagOnce(ref mut p) | CommandKind::CheckAny(ref mut p) |
+                CommandKind::CheckNot(ref mut p) | CommandKind::CheckLabel(ref mut p) | CommandKind::CheckNone(ref mut p) | CommandKind::CheckSameCount(_, ref mut p) |
+                CommandKind::CheckSame(ref mut p) | CommandKind::CheckCount(_, ref mut p) => std::slice::from_mut(p),
+            CommandKind::CheckAlt(ref mut patterns) => patterns,
+            _ => &mut [],
+        }
+    }
+
+    /// Gets the text pattern of a check directive, if it has exactly one.
+    #[deprecated(note = "use `text_patterns`, which also gives the alternatives of `CHECK-ALT`")]
+    pub fn text_pattern(&self) -> Option<&TextPattern> {
+        match *self.text_patterns() {
+            [ref text_pattern] => Some(text_pattern),
             _ => None,
-        })
+        }
     }
 
-    /// Is this test expected to fail.
-    pub fn is_expected_failure(&self) -> bool {
-        self.commands.iter().any(|c| if let CommandKind::XFail = c.kind { true } else { false })
+    /// Checks if the directive should be evaluated with the features available in a config.
+    pub fn is_enabled(&self, config: &Config) -> bool {
+        self.feature_guard.as_ref().is_none_or(|feature| config.is_feature_available(feature))
+    }
+
+    /// Checks if the command verifies the output of a `RUN` directive.
+    pub fn is_check(&self) -> bool {
+        match self.kind {
+            CommandKind::Run(..) | CommandKind::CheckStream(..) | CommandKind::MatchMode(..) | CommandKind::Timeout(..) | CommandKind::Streaming(..) | CommandKind::XFail(..) | CommandKind::Requires(..) | CommandKind::Unsupported(..) |
+                CommandKind::Meta(..) | CommandKind::Depends(..) | CommandKind::Exit(..) | CommandKind::CheckStderrEmpty | CommandKind::CheckNoOutput | CommandKind::Mask(..) |
+                CommandKind::CheckRunsEqual(..) | CommandKind::Stdin(..) | CommandKind::StdinFile(..) => false,
+            CommandKind::Check(..) | CommandKind::CheckNext(..) | CommandKind::CheckWithin(..) | CommandKind::CheckDag(..) | CommandKind::CheckDagOnce(..) |
+                CommandKind::CheckAny(..) | CommandKind::CheckNot(..) | CommandKind::CheckLabel(..) | CommandKind::CheckNone(..) | CommandKind::CheckAlt(..) | CommandKind::CheckSameCount(..) | CommandKind::CheckSame(..) | CommandKind::CheckEmpty | CommandKind::CheckCount(..) | CommandKind::CheckBytes(..) | CommandKind::CheckOutputFile(..) |
+                CommandKind::CheckSize(..) | CommandKind::CheckFinalNewline(..) | CommandKind::CheckCreated { .. } | CommandKind::CheckCustom(..) | CommandKind::CheckFile(..) |
+                CommandKind::CheckRest(..) | CommandKind::CheckSchema(..) | CommandKind::CheckNum { .. } | CommandKind::CheckTime(..) => true,
+        }
     }
 }
 
-/// Build a text pattern from a single component.
-impl From<PatternComponent> for TextPattern {
-    fn from(component: PatternComponent) -> Self {
-        TextPattern { components: vec![component] }
+impl std::str::FromStr for MatchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "literal" => Ok(MatchMode::Literal),
+            "regex" => Ok(MatchMode::Regex),
+            "whole-line" => Ok(MatchMode::WholeLine),
+            _ => Err(format!("expected 'literal', 'regex' or 'whole-line' but got '{}'", s)),
+        }
     }
 }
 
-impl std::fmt::Debug for CheckFailureInfo {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
-        #[derive(Debug)]
-        struct CheckFailureInfo<'a> {
-            expected_pattern: &'a TextPattern,
-            successfully_checked_text: PrintStrTruncate<'a>,
-            remaining_text: PrintStrTruncate<'a>,
+impl ExpectedExitCode {
+    /// Checks if an exit code is the expected one.
+    pub fn matches(self, exit_status: i32) -> bool {
+        match self {
+            ExpectedExitCode::Code(code) => exit_status == code,
+            ExpectedExitCode::Nonzero => exit_status != 0,
         }
+    }
+}
 
-        const TRUNCATE_MIN: usize = 70;
-        const TRUNCATE_MARKER: &'static str = "...";
-        struct PrintStrTruncate<'a>(&'a str);
-        impl<'a> std::fmt::Debug for PrintStrTruncate<'a> {
-            fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl FeatureExpression {
+    /// Evaluates the expression with the features available in a config.
+    pub fn is_satisfied(&self, config: &Config) -> bool {
+        match *self {
+            FeatureExpression::Feature(ref feature) => config.is_feature_available(feature),
+            FeatureExpression::Not(ref expression) => !expression.is_satisfied(config),
+            FeatureExpression::And(ref lhs, ref rhs) => lhs.is_satisfied(config) && rhs.is_satisfied(config),
+            FeatureExpression::Or(ref lhs, ref rhs) => lhs.is_satisfied(config) || rhs.is_satisfied(config),
+        }
+    }
+
+    /// Gets the features that the expression refers to, in the order they appear.
+    pub fn features(&self) -> Vec<&str> {
+        match *self {
+            FeatureExpression::Feature(ref feature) => vec![feature.as_str()],
+            FeatureExpression::Not(ref expression) => expression.features(),
+            FeatureExpression::And(ref lhs, ref rhs) | FeatureExpression::Or(ref lhs, ref rhs) => {
+                lhs.features().into_iter().chain(rhs.features()).collect()
+            },
+        }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SkipReason::MissingFeature(ref feature) => write!(fmt, "requires {}", feature),
+            SkipReason::UnsupportedFeature(ref feature) => write!(fmt, "unsupported with {}", feature),
+            SkipReason::RunLineNotFound(run_line) => write!(fmt, "does not have RUN directive {}", run_line),
+            SkipReason::Cancelled => write!(fmt, "cancelled"),
+        }
+    }
+}
+
+impl fmt::Display for ExpectedExitCode {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExpectedExitCode::Code(code) => write!(fmt, "code '{}'", code),
+            ExpectedExitCode::Nonzero => write!(fmt, "a nonzero code"),
+        }
+    }
+}
+
+impl Comparison {
+    /// Checks if the comparison holds between two values.
+    pub fn holds<T: PartialOrd>(self, lhs: T, rhs: T) -> bool {
+        match self {
+            Comparison::Less => lhs < rhs,
+            Comparison::LessOrEqual => lhs <= rhs,
+            Comparison::Greater => lhs > rhs,
+            Comparison::GreaterOrEqual => lhs >= rhs,
+            Comparison::Equal => lhs == rhs,
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match *self {
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+            Comparison::Equal => "==",
+        };
+
+        symbol.fmt(fmt)
+    }
+}
+
+impl TestResultKind {
+    /// Checks if the result is considered an error.
+    pub fn is_erroneous(&self) -> bool {
+        use self::TestResultKind::*;
+
+        match *self {
+            UnexpectedPass | Error { .. } | SpawnError { .. } | Fail { .. } | Timeout { .. } => true,
+            Pass | Skip { .. } | ExpectedFailure { .. } | EmptyTest | KnownFailure { .. } => false,
+            Custom { passed, .. } => !passed,
+        }
+    }
+
+    /// Checks if the result is considered an error, taking into account
+    /// whether the config tolerates unexpected passes.
+    pub fn is_erroneous_with_config(&self, config: &Config) -> bool {
+        match *self {
+            TestResultKind::UnexpectedPass => !config.tolerate_unexpected_pass,
+            _ => self.is_erroneous(),
+        }
+    }
+
+    /// Gets the typed error corresponding to an erroneous result.
+    ///
+    /// Other errors whilst running a test are reported as `ErrorKind::Msg` with the same message.
+    pub fn error(&self, config: &Config) -> Option<crate::Error> {
+        match *self {
+            TestResultKind::Error { ref message } => Some(ErrorKind::Msg(message.clone()).into()),
+            TestResultKind::SpawnError { ref program_command_line, ref message } => {
+                Some(ErrorKind::SpawnError(program_command_line.clone(), message.clone()).into())
+            },
+            TestResultKind::Timeout { duration } => Some(ErrorKind::Timeout(duration).into()),
+            TestResultKind::Fail { ref reason, .. } => Some(ErrorKind::MatchFailure(reason.human_detail_message(config)).into()),
+            TestResultKind::UnexpectedPass if !config.tolerate_unexpected_pass => {
+                Some(ErrorKind::MatchFailure("test passed, but it was expected to fail".to_owned()).into())
+            },
+            TestResultKind::Custom { passed: false, ref message, .. } => Some(ErrorKind::Msg(message.clone()).into()),
+            _ => None,
+        }
+    }
+
+    pub fn unwrap(&self) {
+        if self.is_erroneous() {
+            panic!("error whilst running test: {:?}", self);
+        }
+    }
+
+    pub fn human_label_pluralized(&self) -> &'static str {
+        use self::TestResultKind::*;
+
+        match *self {
+            Pass => "Passes",
+            UnexpectedPass => "Unexpected passes",
+            Error { .. } | SpawnError { .. } => "Errors",
+            Fail { .. } => "Test failures",
+            ExpectedFailure { .. } => "Expected failures",
+            EmptyTest => "Empty tests",
+            Skip { .. } => "Skipped tests",
+            Timeout { .. } => "Timeouts",
+            KnownFailure { .. } => "Known failures",
+            Custom { .. } => "Custom results",
+        }
+    }
+}
+
+impl CheckFailureInfo {
+    /// Gets the slice containing the portion of successfully checked text.
+    pub fn successfully_checked_text(&self) -> &str {
+        let byte_subslice = &self.complete_output_text.as_bytes()[0..self.successfully_checked_until_byte_index];
+        convert_bytes_to_str(byte_subslice)
+    }
+
+    /// Gets the slice containing the portion of unchecked, remaining text.
+    pub fn remaining_text(&self) -> &str {
+        let byte_subslice = &self.complete_output_text.as_bytes()[self.successfully_checked_until_byte_index..];
+        convert_bytes_to_str(byte_subslice)
+    }
+
+    pub fn successfully_checked_upto_line_number(&self) -> usize {
+        self.successfully_checked_text().lines().count() + 1
+    }
+
+    /// Finds the line of the remaining output with the text closest to the expected text.
+    ///
+    /// This is only done for literal patterns, and only lines which are a few edits away from
+    /// the expected text are considered. Returns the line number, the line, and the byte
+    /// range of the closest text within the line.
+    pub fn closest_line(&self) -> Option<(usize, &str, std::ops::Range<usize>)> {
+        const MAX_LINES_SEARCHED: usize = 10_000;
+
+        let expected: String = self.expected_pattern.components.iter().map(|component| match *component {
+            PatternComponent::Text(ref text) => Some(text.as_str()),
+            _ => None,
+        }).collect::<Option<_>>()?;
+        let max_edits = (expected.chars().count() / 3).max(1);
+
+        self.remaining_text().lines().take(MAX_LINES_SEARCHED).enumerate().map(|(i, line)| {
+            let (edits, range) = util::closest_substring(&expected, line);
+            (edits, self.successfully_checked_upto_line_number() + i, line, range)
+        }).filter(|&(edits, ..)| edits > 0 && edits <= max_edits).min_by_key(|&(edits, ..)| edits)
+            .map(|(_, line_number, line, range)| (line_number, line, range))
+    }
+}
+
+impl Results {
+    /// Combines the results of two test suites, with the results of `other` after those of `self`.
+    ///
+    /// The combined results are cancelled if either of the test suites was cancelled, and
+    /// took as long as both test suites did, one after the other.
+    pub fn merge(mut self, other: Results) -> Results {
+        self.test_results.extend(other.test_results);
+        self.cancelled |= other.cancelled;
+        self.total_duration += other.total_duration;
+        self
+    }
+
+    /// Gets how long the complete test suite took to run, from start to finish.
+    pub fn total_duration(&self) -> Duration {
+        self.total_duration
+    }
+
+    /// Gets how many times faster the test suite ran than it would have with one test at a time.
+    ///
+    /// This is the time taken by the tests, added up, divided by the total duration of the suite.
+    pub fn speedup(&self) -> Option<f64> {
+        self::speedup(&self.test_results, self.total_duration)
+    }
+
+    /// Checks if none of the tests had an erroneous result.
+    pub fn is_successful(&self) -> bool {
+        !self.test_results.iter().any(|r| r.overall_result.is_erroneous())
+    }
+
+    /// Checks if none of the tests had an erroneous result, taking into account
+    /// whether the config tolerates unexpected passes.
+    pub fn is_successful_with_config(&self, config: &Config) -> bool {
+        !self.test_results.iter().any(|r| r.overall_result.is_erroneous_with_config(config))
+    }
+
+    /// Gets the exit code of a process that ran the test suite.
+    ///
+    /// This is zero if the test suite was successful and was not cancelled, and one otherwise.
+    pub fn exit_code(&self, config: &Config) -> i32 {
+        if !self.test_results.is_empty() && !self.cancelled && self.is_successful_with_config(config) { 0 } else { 1 }
+    }
+
+    /// Counts the number of tests with each kind of result.
+    pub fn summary(&self) -> Summary {
+        let mut summary = Summary::default();
+
+        for test_result in self.test_results.iter() {
+            let count = match test_result.overall_result {
+                TestResultKind::Pass => &mut summary.passes,
+                TestResultKind::UnexpectedPass => &mut summary.unexpected_passes,
+                TestResultKind::Error { .. } | TestResultKind::SpawnError { .. } => &mut summary.errors,
+                TestResultKind::Fail { .. } => &mut summary.failures,
+                TestResultKind::ExpectedFailure { .. } => &mut summary.expected_failures,
+                TestResultKind::EmptyTest => &mut summary.empty_tests,
+                TestResultKind::Skip { .. } => &mut summary.skipped,
+                TestResultKind::Timeout { .. } => &mut summary.timeouts,
+                TestResultKind::KnownFailure { .. } => &mut summary.known_failures,
+                TestResultKind::Custom { .. } => &mut summary.custom,
+            };
+
+            *count += 1;
+        }
+
+        summary
+    }
+
+    /// Counts the number of skipped tests for each reason, keyed by the description of the reason.
+    pub fn skip_breakdown(&self) -> HashMap<String, usize> {
+        self::skip_breakdown(&self.test_results)
+    }
+
+    /// Groups the erroneous results by their failure message.
+    pub fn identical_failures(&self, config: &Config) -> Vec<(String, Vec<&TestResult>)> {
+        self::identical_failures(&self.test_results, config)
+    }
+
+    /// Writes the erroneous results as a compact JSON array, for annotating the failing lines of tests.
+    ///
+    /// Every entry has the relative `path` of the test, the `line` of the directive which
+    /// failed, or `null` when that is not known, and the first line of the failure `message`.
+    pub fn failures_json(&self, config: &Config) -> String {
+        let failures = self.test_results.iter().filter(|r| r.overall_result.is_erroneous_with_config(config)).map(|test_result| {
+            let message = test_result.overall_result.error(config).map(|e| e.to_string()).unwrap_or_default();
+            let members = vec![
+                ("path".to_owned(), json::Value::String(test_result.path.relative.display().to_string())),
+                ("line".to_owned(), test_result.failing_line().map_or(json::Value::Null, |line| json::Value::Number(line.into()))),
+                ("message".to_owned(), json::Value::String(message.lines().next().unwrap_or("").to_owned())),
+            ];
+            json::Value::Object(members.into_iter().collect())
+        });
+
+        json::Value::Array(failures.collect()).to_string()
+    }
+
+    /// Writes the results as a JUnit XML report, which most CI systems can display.
+    ///
+    /// Every test is a `testcase` named by its relative path. Failures and unexpected passes
+    /// which the config does not tolerate are reported as a `failure`, errors and timeouts as
+    /// an `error`, and skipped tests as `skipped` with the reason. The `META` metadata of a
+    /// test is written as its `properties`, sorted by key.
+    pub fn write_junit_xml<W: std::io::Write>(&self, mut w: W, config: &Config) -> std::io::Result<()> {
+        let elements: Vec<_> = self.test_results.iter().map(|r| self::junit_element(&r.overall_result, config)).collect();
+        let count = |name| elements.iter().filter(|&&element| element == Some(name)).count();
+        let counts = format!("tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\"",
+                             self.test_results.len(), count("failure"), count("error"), count("skipped"), self.total_duration.as_secs_f64());
+
+        writeln!(w, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+        writeln!(w, "<testsuites {}>", counts)?;
+        writeln!(w, "  <testsuite name=\"lit\" {}>", counts)?;
+
+        for (test_result, element) in self.test_results.iter().zip(elements) {
+            let name = util::xml_escape(&test_result.path.relative.display().to_string());
+            write!(w, "    <testcase name=\"{}\" classname=\"lit\" time=\"{:.3}\"", name, test_result.duration.as_secs_f64())?;
+
+            let mut children = String::new();
+            if !test_result.metadata.is_empty() {
+                let mut metadata: Vec<_> = test_result.metadata.iter().collect();
+                metadata.sort();
+
+                children += "      <properties>\n";
+                for (key, value) in metadata {
+                    children += &format!("        <property name=\"{}\" value=\"{}\"/>\n", util::xml_escape(key), util::xml_escape(value));
+                }
+                children += "      </properties>\n";
+            }
+
+            match (element, &test_result.overall_result) {
+                (None, _) => (),
+                (Some(_), TestResultKind::Skip { reason }) => {
+                    children += &format!("      <skipped message=\"{}\"/>\n", util::xml_escape(&reason.to_string()));
+                },
+                (Some(element), kind) => {
+                    let message = kind.error(config).map(|e| e.to_string()).unwrap_or_default();
+                    children += &format!("      <{} message=\"{}\">{}</{}>\n",
+                                         element, util::xml_escape(message.lines().next().unwrap_or("")), util::xml_escape(&message), element);
+                },
+            }
+
+            match children.is_empty() {
+                true => writeln!(w, "/>")?,
+                false => writeln!(w, ">\n{}    </testcase>", children)?,
+            }
+        }
+
+        writeln!(w, "  </testsuite>")?;
+        writeln!(w, "</testsuites>")
+    }
+
+    /// Writes the results as a JSON document, for tools which post-process test runs.
+    ///
+    /// The document has whether the run was `cancelled`, its `duration` in seconds, the
+    /// `summary` counts, and every test in the format of `TestResult::to_json`.
+    pub fn to_json(&self, config: &Config) -> String {
+        let summary = self.summary();
+        let counts = [
+            ("passes", summary.passes), ("unexpected_passes", summary.unexpected_passes), ("errors", summary.errors),
+            ("failures", summary.failures), ("expected_failures", summary.expected_failures), ("empty_tests", summary.empty_tests),
+            ("skipped", summary.skipped), ("timeouts", summary.timeouts), ("known_failures", summary.known_failures), ("custom", summary.custom),
+        ];
+
+        self::json_object(vec![
+            ("cancelled", json::Value::Bool(self.cancelled)),
+            ("duration", self::json_seconds(self.total_duration)),
+            ("summary", self::json_object(counts.iter().map(|&(name, count)| (name, json::Value::Number(count as f64))).collect())),
+            ("tests", json::Value::Array(self.test_results.iter().map(|test_result| self::test_result_json(test_result, config)).collect())),
+        ]).to_string()
+    }
+
+    /// Writes the results in version 13 of the Test Anything Protocol, for TAP harnesses like `prove`.
+    ///
+    /// Failing tests have a YAML diagnostics block with the failure message and the line of the
+    /// directive which failed. Failed checks also have the expected pattern, and an excerpt of
+    /// the output which it did not match. Skipped tests and expected failures are marked with
+    /// the `SKIP` and `TODO` directives. Unexpected passes which the config tolerates are `ok`.
+    pub fn write_tap<W: std::io::Write>(&self, mut w: W, config: &Config) -> std::io::Result<()> {
+        writeln!(w, "TAP version 13")?;
+        writeln!(w, "1..{}", self.test_results.len())?;
+
+        for (i, test_result) in self.test_results.iter().enumerate() {
+            let description = format!("{} - {}", i + 1, test_result.path.relative.display().to_string().replace('#', "\\#"));
+
+            match test_result.overall_result {
+                TestResultKind::Skip { ref reason } => writeln!(w, "ok {} # SKIP {}", description, reason)?,
+                TestResultKind::EmptyTest => writeln!(w, "ok {} # SKIP no directives were found", description)?,
+                TestResultKind::ExpectedFailure { .. } | TestResultKind::KnownFailure { .. } => {
+                    writeln!(w, "not ok {} # TODO expected to fail", description)?
+                },
+                ref kind if kind.is_erroneous_with_config(config) => {
+                    writeln!(w, "not ok {}", description)?;
+                    self::write_tap_diagnostics(&mut w, test_result, config)?;
+                },
+                _ => writeln!(w, "ok {}", description)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes the YAML diagnostics block of a failing test in a TAP report.
+fn write_tap_diagnostics<W: std::io::Write>(w: &mut W, test_result: &TestResult, config: &Config) -> std::io::Result<()> {
+    let kind = &test_result.overall_result;
+    let message = kind.error(config).map(|e| e.to_string()).unwrap_or_default();
+    let severity = if self::junit_element(kind, config) == Some("error") { "error" } else { "fail" };
+
+    writeln!(w, "  ---")?;
+    writeln!(w, "  message: {}", json::quote(message.lines().next().unwrap_or("")))?;
+    writeln!(w, "  severity: {}", severity)?;
+    writeln!(w, "  file: {}", json::quote(&test_result.path.relative.display().to_string()))?;
+    if let Some(line) = test_result.failing_line() {
+        writeln!(w, "  line: {}", line)?;
+    }
+
+    if let TestResultKind::Fail { reason: TestFailReason::CheckFailed(ref info), .. } = *kind {
+        let max_lines = config.truncate_output_context_to_number_of_lines.unwrap_or(usize::MAX);
+        let excerpt = util::truncate_to_max_lines(info.remaining_text(), max_lines, util::TruncateDirection::Bottom);
+
+        writeln!(w, "  expected: {}", json::quote(&info.expected_pattern.to_string()))?;
+        if excerpt.trim().is_empty() {
+            writeln!(w, "  actual: \"\"")?;
+        } else {
+            writeln!(w, "  actual: |")?;
+            for line in excerpt.lines() {
+                writeln!(w, "    {}", line)?;
+            }
+        }
+    }
+
+    writeln!(w, "  ...")
+}
+
+fn test_result_json(test_result: &TestResult, config: &Config) -> json::Value {
+    let runs = test_result.individual_run_results.iter().map(|run| {
+        let checks = run.checks.iter().map(|check| {
+            let status = match check.status {
+                CheckStatus::Passed => "passed",
+                CheckStatus::Failed => "failed",
+                CheckStatus::NotReached => "not_reached",
+                CheckStatus::Disabled => "disabled",
+            };
+
+            self::json_object(vec![
+                ("line", json::Value::Number(check.line_number.into())),
+                ("status", json::Value::String(status.to_owned())),
+                ("checked_up_to_line", check.checked_up_to_line.map_or(json::Value::Null, |line| json::Value::Number(line as f64))),
+            ])
+        }).collect();
+
+        self::json_object(vec![
+            ("line", json::Value::Number(run.line_number.into())),
+            ("command_line", json::Value::String(run.command_line.0.clone())),
+            ("result", self::result_json(&run.kind, config)),
+            ("stdout", json::Value::String(run.output.stdout.clone())),
+            ("stderr", json::Value::String(run.output.stderr.clone())),
+            ("checks", json::Value::Array(checks)),
+        ])
+    }).collect();
+
+    self::json_object(vec![
+        ("path", json::Value::String(test_result.path.relative.display().to_string())),
+        ("duration", self::json_seconds(test_result.duration)),
+        ("metadata", json::Value::Object(test_result.metadata.iter().map(|(k, v)| (k.clone(), json::Value::String(v.clone()))).collect())),
+        ("result", self::result_json(&test_result.overall_result, config)),
+        ("runs", json::Value::Array(runs)),
+    ])
+}
+
+/// Gets how many times faster tests ran, in a total duration, than they would have one at a time.
+pub(crate) fn speedup(test_results: &[TestResult], total_duration: Duration) -> Option<f64> {
+    let test_durations: Duration = test_results.iter().map(|r| r.duration).sum();
+
+    if total_duration.is_zero() { None } else { Some(test_durations.as_secs_f64() / total_duration.as_secs_f64()) }
+}
+
+fn result_json(kind: &TestResultKind, config: &Config) -> json::Value {
+    use self::TestResultKind::*;
+
+    let name = match *kind {
+        Pass => "pass",
+        UnexpectedPass => "unexpected_pass",
+        Error { .. } => "error",
+        SpawnError { .. } => "spawn_error",
+        Fail { .. } => "fail",
+        ExpectedFailure { .. } => "expected_failure",
+        EmptyTest => "empty_test",
+        Skip { .. } => "skip",
+        Timeout { .. } => "timeout",
+        KnownFailure { .. } => "known_failure",
+        Custom { .. } => "custom",
+    };
+    let mut members = vec![("kind", json::Value::String(name.to_owned()))];
+
+    match *kind {
+        Fail { hint: Some(ref hint), .. } => members.push(("hint", json::Value::String(hint.clone()))),
+        ExpectedFailure { ref actual_reason } => members.push(("actual_reason", json::Value::String(actual_reason.human_summary().to_owned()))),
+        Skip { ref reason } => members.push(("reason", json::Value::String(reason.to_string()))),
+        Timeout { duration } => members.push(("timeout", self::json_seconds(duration))),
+        SpawnError { ref program_command_line, .. } => members.push(("command_line", json::Value::String(program_command_line.clone()))),
+        KnownFailure { ref actual_result } => members.push(("actual_result", self::result_json(actual_result, config))),
+        Custom { ref label, passed, ref message } => members.extend(vec![
+            ("label", json::Value::String(label.clone())),
+            ("passed", json::Value::Bool(passed)),
+            ("message", json::Value::String(message.clone())),
+        ]),
+        _ => (),
+    }
+
+    // The messages of custom results are always included above.
+    if let (false, Some(error)) = (matches!(*kind, Custom { .. }), kind.error(config)) {
+        members.push(("message", json::Value::String(error.to_string())));
+    }
+
+    self::json_object(members)
+}
+
+fn json_object(members: Vec<(&str, json::Value)>) -> json::Value {
+    json::Value::Object(members.into_iter().map(|(key, value)| (key.to_owned(), value)).collect())
+}
+
+fn json_seconds(duration: Duration) -> json::Value {
+    json::Value::Number(duration.as_secs_f64())
+}
+
+/// Gets the JUnit element that a result is reported as, if it is not a success.
+fn junit_element(kind: &TestResultKind, config: &Config) -> Option<&'static str> {
+    match *kind {
+        TestResultKind::Error { .. } | TestResultKind::SpawnError { .. } | TestResultKind::Timeout { .. } => Some("error"),
+        TestResultKind::Skip { .. } => Some("skipped"),
+        ref kind if kind.is_erroneous_with_config(config) => Some("failure"),
+        _ => None,
+    }
+}
+
+/// Collects the results of tests, as if they ran one after the other.
+impl std::iter::FromIterator<TestResult> for Results {
+    fn from_iter<I: IntoIterator<Item=TestResult>>(test_results: I) -> Self {
+        let test_results: Vec<_> = test_results.into_iter().collect();
+        let total_duration = test_results.iter().map(|r| r.duration).sum();
+
+        Results { test_results, cancelled: false, total_duration }
+    }
+}
+
+impl std::iter::FromIterator<Results> for Results {
+    fn from_iter<I: IntoIterator<Item=Results>>(results: I) -> Self {
+        results.into_iter().fold(Results { test_results: Vec::new(), cancelled: false, total_duration: Duration::ZERO }, Results::merge)
+    }
+}
+
+/// Counts the number of skipped tests for each reason, keyed by the description of the reason.
+pub fn skip_breakdown(test_results: &[TestResult]) -> HashMap<String, usize> {
+    let mut breakdown = HashMap::new();
+
+    for test_result in test_results {
+        if let TestResultKind::Skip { ref reason } = test_result.overall_result {
+            *breakdown.entry(reason.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    breakdown
+}
+
+/// Groups the erroneous results by their failure message.
+///
+/// Groups are in the order of the first test that failed with each message.
+pub fn identical_failures<'a>(test_results: &'a [TestResult], config: &Config) -> Vec<(String, Vec<&'a TestResult>)> {
+    let mut groups: Vec<(String, Vec<&TestResult>)> = Vec::new();
+
+    for test_result in test_results.iter().filter(|r| r.overall_result.is_erroneous_with_config(config)) {
+        let message = test_result.overall_result.error(config).map(|e| e.to_string()).unwrap_or_default();
+
+        match groups.iter_mut().find(|(m, _)| *m == message) {
+            Some((_, results)) => results.push(test_result),
+            None => groups.push((message, vec![test_result])),
+        }
+    }
+
+    groups
+}
+
+impl IndividualRunResult {
+    /// Gets the number of check directives that were evaluated, whether they matched or not.
+    pub fn number_of_checks_evaluated(&self) -> usize {
+        self.checks.iter().filter(|c| matches!(c.status, CheckStatus::Passed | CheckStatus::Failed)).count()
+    }
+}
+
+impl TestResult {
+    /// Gets the `RUN` directive which determined the overall result of the test.
+    ///
+    /// Returns `None` if every `RUN` directive passed.
+    pub fn failing_run(&self) -> Option<&IndividualRunResult> {
+        self.individual_run_results.iter().find(|r| r.kind != TestResultKind::Pass)
+    }
+
+    /// Gets the line of the directive which determined the overall result of the test.
+    ///
+    /// This is the check directive which failed if there was one, and otherwise the `RUN`
+    /// directive which failed.
+    pub fn failing_line(&self) -> Option<u32> {
+        let run = self.failing_run()?;
+        let failed_check = run.checks.iter().find(|check| check.status == CheckStatus::Failed);
+
+        Some(failed_check.map_or(run.line_number, |check| check.line_number))
+    }
+
+    /// Writes the result as a JSON object.
+    ///
+    /// The object has the relative `path` of the test, its `duration` in seconds, its
+    /// `metadata`, the overall `result`, and the `runs` of its `RUN` commands with their
+    /// output and the status of every check. Each result has a `kind`, like `"pass"` or
+    /// `"fail"`, and a `message` when it is erroneous with the config.
+    pub fn to_json(&self, config: &Config) -> String {
+        self::test_result_json(self, config).to_string()
+    }
+}
+
+impl TestFile
+{
+    /// Extra test-specific variables.
+    pub fn variables(&self) -> Variables {
+        let mut v = Variables::new();
+        v.insert("file".to_owned(), self.path.absolute.to_str().unwrap().to_owned());
+        v
+    }
+
+    /// Gets an iterator over all `RUN` commands in the test file, along with
+    /// the line number that each command was declared on.
+    pub fn run_command_invocations(&self) -> impl Iterator<Item=(u32, &Invocation)> {
+        self.commands.iter().filter_map(|c| match c.kind {
+            CommandKind::Run(ref invocation) => Some((c.line_number, invocation)),
+            _ => None,
+        })
+    }
+
+    /// Is this test expected to fail, on any platform.
+    ///
+    /// This is true when the test has any `XFAIL` directive, whatever its condition.
+    pub fn is_expected_failure(&self) -> bool {
+        self.commands.iter().any(|c| matches!(c.kind, CommandKind::XFail(..)))
+    }
+
+    /// Is this test expected to fail with the features available in a config.
+    pub fn is_expected_failure_with_config(&self, config: &Config) -> bool {
+        self.commands.iter().any(|c| match c.kind {
+            CommandKind::XFail(ref condition) => condition.as_ref().is_none_or(|condition| condition.is_satisfied(config)),
+            _ => false,
+        })
+    }
+
+    /// Gets the features required by the `REQUIRES` directives of the test.
+    pub fn required_features(&self) -> impl Iterator<Item=&str> {
+        self.commands.iter().filter_map(|c| match c.kind {
+            CommandKind::Requires(ref features) => Some(features.iter().map(String::as_str)),
+            _ => None,
+        }).flatten()
+    }
+
+    /// Gets the features that the `UNSUPPORTED` directives of the test exclude it from.
+    pub fn unsupported_features(&self) -> impl Iterator<Item=&str> {
+        self.commands.iter().filter_map(|c| match c.kind {
+            CommandKind::Unsupported(ref features) => Some(features.iter().map(String::as_str)),
+            _ => None,
+        }).flatten()
+    }
+
+    /// Gets why the test should be skipped with the features available in a config, if it should be.
+    pub fn skip_reason(&self, config: &Config) -> Option<SkipReason> {
+        if let Some(feature) = self.required_features().find(|feature| !config.is_feature_available(feature)) {
+            return Some(SkipReason::MissingFeature(feature.to_owned()));
+        }
+
+        self.unsupported_features().find(|feature| config.is_feature_available(feature))
+            .map(|feature| SkipReason::UnsupportedFeature(feature.to_owned()))
+    }
+
+    /// Parses the directives of a test file from its text.
+    pub fn parse(path: TestFilePath, text: &str) -> Result<TestFile, crate::Error> {
+        let absolute_path = path.absolute.clone();
+
+        crate::parse::test_file(path, text.chars()).map_err(|message| ErrorKind::ParseError(absolute_path, message).into())
+    }
+
+    /// Evaluates the checks of the test against the given output, without running any commands.
+    ///
+    /// This is useful for testing checks in isolation, or for using lit's matching on its own.
+    pub fn run_against(&self, output: &str, config: &Config) -> TestResultKind {
+        run::run_against(self, output, config)
+    }
+
+    /// Summarizes the directives of the test.
+    pub fn info(&self) -> TestInfo {
+        let mut features: Vec<_> = self.commands.iter().filter_map(|c| c.feature_guard.clone())
+            .chain(self.required_features().chain(self.unsupported_features()).map(str::to_owned))
+            .chain(self.commands.iter().filter_map(|c| match c.kind {
+                CommandKind::XFail(Some(ref condition)) => Some(condition.features().into_iter().map(str::to_owned)),
+                _ => None,
+            }).flatten()).collect();
+        features.sort();
+        features.dedup();
+
+        TestInfo {
+            path: self.path.clone(),
+            run_commands: self.run_command_invocations().count(),
+            checks: self.commands.iter().filter(|c| c.is_check()).count(),
+            metadata: self.metadata(),
+            features,
+            dependencies: self.dependencies(),
+        }
+    }
+
+    /// Gets the absolute paths of the files that the test depends on.
+    ///
+    /// This is the test file itself, which `@file` refers to, followed by every file declared
+    /// with a `DEPENDS` directive.
+    pub fn dependencies(&self) -> Vec<PathBuf> {
+        let test_directory = self.path.absolute.parent().unwrap_or_else(|| Path::new(""));
+
+        std::iter::once(self.path.absolute.clone()).chain(self.commands.iter().filter_map(|c| match c.kind {
+            CommandKind::Depends(ref path) => Some(test_directory.join(path)),
+            _ => None,
+        })).collect()
+    }
+
+    /// Gets the metadata declared by the test with `META` directives.
+    ///
+    /// If a key is declared more than once, the last value is used.
+    pub fn metadata(&self) -> HashMap<String, String> {
+        self.commands.iter().filter_map(|c| match c.kind {
+            CommandKind::Meta(ref key, ref value) => Some((key.clone(), value.clone())),
+            _ => None,
+        }).collect()
+    }
+
+    /// Gets how the patterns of the checks in the test are interpreted by default.
+    pub fn match_mode(&self) -> MatchMode {
+        self.commands.iter().find_map(|c| match c.kind {
+            CommandKind::MatchMode(mode) => Some(mode),
+            _ => None,
+        }).unwrap_or_default()
+    }
+
+    /// Gets the timeout declared by the test, if any.
+    ///
+    /// If there are several `TIMEOUT` directives, the last one is used.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.commands.iter().rev().find_map(|c| match c.kind {
+            CommandKind::Timeout(duration) => Some(duration),
+            _ => None,
+        })
+    }
+
+    /// Gets how long each check may take to pass, if the test evaluates its checks
+    /// whilst the `RUN` commands are running.
+    pub fn streaming_check_timeout(&self) -> Option<Duration> {
+        self.commands.iter().rev().find_map(|c| match c.kind {
+            CommandKind::Streaming(duration) => Some(duration),
+            _ => None,
+        })
+    }
+
+    /// Does this test match any checks against the standard error stream.
+    pub fn checks_stderr(&self) -> bool {
+        self.commands.iter().any(|c| matches!(c.kind, CommandKind::CheckStream(OutputStream::Stderr) | CommandKind::CheckStream(OutputStream::Interleaved) |
+                                            CommandKind::CheckStderrEmpty))
+    }
+
+    /// Gets the exit code that the `RUN` commands must exit with, if the test declares one.
+    pub fn expected_exit_code(&self) -> Option<ExpectedExitCode> {
+        self.commands.iter().rev().find_map(|c| match c.kind {
+            CommandKind::Exit(code) => Some(code),
+            _ => None,
+        })
+    }
+
+    /// Checks if the test requires the `RUN` commands to not emit anything on standard error.
+    pub fn requires_empty_stderr(&self) -> bool {
+        self.commands.iter().any(|c| matches!(c.kind, CommandKind::CheckStderrEmpty))
+    }
+
+    /// Does this test require its `RUN` commands to print nothing at all.
+    pub fn requires_no_output(&self) -> bool {
+        self.commands.iter().any(|c| matches!(c.kind, CommandKind::CheckNoOutput))
+    }
+
+    /// Gets the regexes declared by the test with `MASK` directives, in order.
+    pub fn masks(&self) -> Vec<Regex> {
+        self.commands.iter().filter_map(|c| match c.kind {
+            CommandKind::Mask(ref regex) => Some(regex.clone()),
+            _ => None,
+        }).collect()
+    }
+}
+
+/// Build a text pattern from a single component.
+impl From<PatternComponent> for TextPattern {
+    fn from(component: PatternComponent) -> Self {
+        TextPattern { components: vec![component] }
+    }
+}
+
+impl std::fmt::Debug for CheckFailureInfo {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        const TRUNCATE_MIN: usize = 70;
+        const TRUNCATE_MARKER: &str = "...";
+        struct PrintStrTruncate<'a>(&'a str);
+        impl<'a> std::fmt::Debug for PrintStrTruncate<'a> {
+            fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
                 if self.0.len() <= TRUNCATE_MIN {
                     std::fmt::Debug::fmt(self.0, fmt)
                 } else {
@@ -320,11 +1868,11 @@ impl std::fmt::Debug for CheckFailureInfo {
             }
         }
 
-        CheckFailureInfo {
-            expected_pattern: &self.expected_pattern,
-            remaining_text: PrintStrTruncate(self.remaining_text()),
-            successfully_checked_text: PrintStrTruncate(self.successfully_checked_text()),
-        }.fmt(fmt)
+        fmt.debug_struct("CheckFailureInfo")
+            .field("expected_pattern", &self.expected_pattern)
+            .field("successfully_checked_text", &PrintStrTruncate(self.successfully_checked_text()))
+            .field("remaining_text", &PrintStrTruncate(self.remaining_text()))
+            .finish()
     }
 }
 
@@ -338,3 +1886,330 @@ impl ProgramOutput {
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn results(kinds: &[TestResultKind]) -> Results {
+        Results {
+            test_results: kinds.iter().map(|kind| TestResult {
+                path: TestFilePath { absolute: PathBuf::from("/tests/foo.txt"), relative: PathBuf::from("foo.txt") },
+                overall_result: kind.clone(),
+                individual_run_results: Vec::new(),
+                metadata: HashMap::new(),
+                duration: Duration::from_millis(100),
+            }).collect(),
+            cancelled: false,
+            total_duration: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn summary_counts_unexpected_passes_separately() {
+        let summary = results(&[TestResultKind::Pass, TestResultKind::UnexpectedPass, TestResultKind::Pass]).summary();
+
+        assert_eq!(summary, Summary { passes: 2, unexpected_passes: 1, ..Summary::default() });
+    }
+
+    #[test]
+    fn unexpected_passes_fail_unless_tolerated() {
+        let results = results(&[TestResultKind::Pass, TestResultKind::UnexpectedPass]);
+        let tolerant_config = Config { tolerate_unexpected_pass: true, ..Config::default() };
+
+        assert_eq!(results.exit_code(&Config::default()), 1);
+        assert_eq!(results.exit_code(&tolerant_config), 0);
+    }
+
+    #[test]
+    fn custom_results_count_towards_the_exit_code() {
+        let custom = |passed| TestResultKind::Custom { label: "BENCH".to_owned(), passed, message: String::new() };
+
+        assert_eq!(results(&[custom(true)]).summary(), Summary { custom: 1, ..Summary::default() });
+        assert_eq!(results(&[TestResultKind::Pass, custom(true)]).exit_code(&Config::default()), 0);
+        assert_eq!(results(&[TestResultKind::Pass, custom(false)]).exit_code(&Config::default()), 1);
+    }
+
+    #[test]
+    fn known_failures_do_not_fail_the_test_suite() {
+        let known_failure = TestResultKind::KnownFailure { actual_result: Box::new(TestResultKind::Error { message: "oops".to_owned() }) };
+        let results = results(&[TestResultKind::Pass, known_failure]);
+
+        assert_eq!(results.summary(), Summary { passes: 1, known_failures: 1, ..Summary::default() });
+        assert_eq!(results.exit_code(&Config::default()), 0);
+    }
+
+    #[test]
+    fn failures_are_grouped_by_their_message() {
+        let error = |message: &str| TestResultKind::Error { message: message.to_owned() };
+        let results = results(&[error("missing header"), TestResultKind::Pass, error("bad input"), error("missing header")]);
+
+        let groups: Vec<_> = results.identical_failures(&Config::default()).into_iter().map(|(message, results)| (message, results.len())).collect();
+        assert_eq!(groups, vec![("missing header".to_owned(), 2), ("bad input".to_owned(), 1)]);
+    }
+
+    #[test]
+    fn failures_are_written_as_json_with_their_failing_lines() {
+        let mut results = results(&[TestResultKind::Pass, TestResultKind::Error { message: "bad input\nat line 1".to_owned() }, TestResultKind::UnexpectedPass]);
+        results.test_results[1].individual_run_results.push(IndividualRunResult {
+            kind: TestResultKind::Error { message: "bad input".to_owned() },
+            invocation: Invocation { original_command: "cat".to_owned(), pipeline: None },
+            line_number: 1,
+            command_line: run::CommandLine("cat".to_owned()),
+            output: ProgramOutput::empty(),
+            checks: vec![CheckCoverage { line_number: 3, status: CheckStatus::Failed, checked_up_to_line: Some(1) }],
+            kept_tempfiles: Vec::new(),
+        });
+
+        assert_eq!(results.failures_json(&Config::default()),
+                   "[{\"line\":3,\"message\":\"bad input\",\"path\":\"foo.txt\"},\
+                    {\"line\":null,\"message\":\"test passed, but it was expected to fail\",\"path\":\"foo.txt\"}]");
+
+        let tolerant_config = Config { tolerate_unexpected_pass: true, ..Config::default() };
+        assert_eq!(results.failures_json(&tolerant_config), "[{\"line\":3,\"message\":\"bad input\",\"path\":\"foo.txt\"}]");
+    }
+
+    #[test]
+    fn results_are_written_as_junit_xml() {
+        let results = results(&[
+            TestResultKind::Pass,
+            TestResultKind::Error { message: "bad <input>".to_owned() },
+            TestResultKind::UnexpectedPass,
+            TestResultKind::Skip { reason: SkipReason::MissingFeature("x86".to_owned()) },
+        ]);
+        let mut xml = Vec::new();
+        results.write_junit_xml(&mut xml, &Config::default()).unwrap();
+
+        assert_eq!(String::from_utf8(xml).unwrap(), "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<testsuites tests=\"4\" failures=\"1\" errors=\"1\" skipped=\"1\" time=\"0.100\">
+  <testsuite name=\"lit\" tests=\"4\" failures=\"1\" errors=\"1\" skipped=\"1\" time=\"0.100\">
+    <testcase name=\"foo.txt\" classname=\"lit\" time=\"0.100\"/>
+    <testcase name=\"foo.txt\" classname=\"lit\" time=\"0.100\">
+      <error message=\"bad &lt;input&gt;\">bad &lt;input&gt;</error>
+    </testcase>
+    <testcase name=\"foo.txt\" classname=\"lit\" time=\"0.100\">
+      <failure message=\"test passed, but it was expected to fail\">test passed, but it was expected to fail</failure>
+    </testcase>
+    <testcase name=\"foo.txt\" classname=\"lit\" time=\"0.100\">
+      <skipped message=\"requires x86\"/>
+    </testcase>
+  </testsuite>
+</testsuites>
+");
+
+        let mut tolerant_xml = Vec::new();
+        results.write_junit_xml(&mut tolerant_xml, &Config { tolerate_unexpected_pass: true, ..Config::default() }).unwrap();
+        let tolerant_xml = String::from_utf8(tolerant_xml).unwrap();
+        assert!(tolerant_xml.contains("<testsuites tests=\"4\" failures=\"0\" errors=\"1\" skipped=\"1\""), "{}", tolerant_xml);
+        assert!(!tolerant_xml.contains("<failure"), "{}", tolerant_xml);
+    }
+
+    #[test]
+    fn metadata_is_written_as_junit_properties() {
+        let mut results = results(&[TestResultKind::Error { message: "bad input".to_owned() }]);
+        results.test_results[0].metadata.insert("owner".to_owned(), "a \"team\"".to_owned());
+        results.test_results[0].metadata.insert("issue".to_owned(), "<42>".to_owned());
+        let mut xml = Vec::new();
+        results.write_junit_xml(&mut xml, &Config::default()).unwrap();
+
+        assert!(String::from_utf8(xml).unwrap().contains("    <testcase name=\"foo.txt\" classname=\"lit\" time=\"0.100\">
+      <properties>
+        <property name=\"issue\" value=\"&lt;42&gt;\"/>
+        <property name=\"owner\" value=\"a &quot;team&quot;\"/>
+      </properties>
+      <error message=\"bad input\">bad input</error>
+    </testcase>
+"));
+    }
+
+    #[test]
+    fn results_are_written_as_tap() {
+        let results = results(&[
+            TestResultKind::Pass,
+            TestResultKind::Fail {
+                reason: TestFailReason::CheckFailed(CheckFailureInfo {
+                    complete_output_text: "start\nfoo\nbar\n".to_owned(),
+                    successfully_checked_until_byte_index: 6,
+                    expected_pattern: PatternComponent::Text("baz".to_owned()).into(),
+                }),
+                hint: None,
+            },
+            TestResultKind::Skip { reason: SkipReason::MissingFeature("x86".to_owned()) },
+            TestResultKind::ExpectedFailure { actual_reason: TestFailReason::CheckFailed(CheckFailureInfo {
+                complete_output_text: String::new(),
+                successfully_checked_until_byte_index: 0,
+                expected_pattern: PatternComponent::Text("baz".to_owned()).into(),
+            }) },
+        ]);
+        let mut tap = Vec::new();
+        results.write_tap(&mut tap, &Config::default()).unwrap();
+
+        assert_eq!(String::from_utf8(tap).unwrap(), "TAP version 13
+1..4
+ok 1 - foo.txt
+not ok 2 - foo.txt
+  ---
+  message: \"expected text 'baz' but that was not found\"
+  severity: fail
+  file: \"foo.txt\"
+  expected: \"baz\"
+  actual: |
+    foo
+    bar
+  ...
+ok 3 - foo.txt # SKIP requires x86
+not ok 4 - foo.txt # TODO expected to fail
+");
+
+        let mut tap = Vec::new();
+        let tolerant_config = Config { tolerate_unexpected_pass: true, ..Config::default() };
+        self::results(&[TestResultKind::UnexpectedPass]).write_tap(&mut tap, &tolerant_config).unwrap();
+        assert_eq!(String::from_utf8(tap).unwrap(), "TAP version 13\n1..1\nok 1 - foo.txt\n");
+    }
+
+    #[test]
+    fn results_are_written_as_json() {
+        let mut results = results(&[TestResultKind::Pass, TestResultKind::Skip { reason: SkipReason::MissingFeature("x86".to_owned()) }]);
+        results.test_results[0].individual_run_results.push(IndividualRunResult {
+            kind: TestResultKind::Pass,
+            invocation: Invocation { original_command: "echo hi".to_owned(), pipeline: None },
+            line_number: 1,
+            command_line: run::CommandLine("echo hi".to_owned()),
+            output: ProgramOutput { stdout: "hi\n".to_owned(), stderr: String::new() },
+            checks: vec![CheckCoverage { line_number: 2, status: CheckStatus::Passed, checked_up_to_line: Some(1) }],
+            kept_tempfiles: Vec::new(),
+        });
+
+        let document = json::parse(&results.to_json(&Config::default())).unwrap();
+        let json::Value::Object(ref members) = document else { panic!("expected an object but got {}", document) };
+        let summary = members["summary"].to_string();
+        assert!(summary.contains("\"passes\":1,") && summary.contains("\"skipped\":1,"), "{}", summary);
+        assert_eq!(members["duration"], json::Value::Number(0.1));
+
+        let json::Value::Array(ref tests) = members["tests"] else { panic!("expected an array of tests") };
+        assert_eq!(tests[0].to_string(), "{\"duration\":0.1,\"metadata\":{},\"path\":\"foo.txt\",\"result\":{\"kind\":\"pass\"},\"runs\":[{\
+            \"checks\":[{\"checked_up_to_line\":1,\"line\":2,\"status\":\"passed\"}],\"command_line\":\"echo hi\",\"line\":1,\
+            \"result\":{\"kind\":\"pass\"},\"stderr\":\"\",\"stdout\":\"hi\\n\"}]}");
+        assert_eq!(tests[1].to_string(), results.test_results[1].to_json(&Config::default()));
+        assert!(tests[1].to_string().contains("\"result\":{\"kind\":\"skip\",\"reason\":\"requires x86\"}"));
+
+        let unexpected_pass = &self::results(&[TestResultKind::UnexpectedPass]).test_results[0];
+        let tolerant_config = Config { tolerate_unexpected_pass: true, ..Config::default() };
+        assert!(unexpected_pass.to_json(&Config::default()).contains("\"message\":"));
+        assert!(!unexpected_pass.to_json(&tolerant_config).contains("\"message\":"));
+    }
+
+    #[test]
+    fn erroneous_results_have_typed_errors() {
+        let config = Config::default();
+        let timeout = TestResultKind::Timeout { duration: Duration::from_secs(3) };
+
+        assert!(matches!(*timeout.error(&config).unwrap().kind(), ErrorKind::Timeout(d) if d == Duration::from_secs(3)));
+        assert!(matches!(*TestResultKind::Error { message: "oops".to_owned() }.error(&config).unwrap().kind(), ErrorKind::Msg(ref m) if m == "oops"));
+        let spawn_error = TestResultKind::SpawnError { program_command_line: "tool".to_owned(), message: "shell 'sh' does not exist".to_owned() };
+        assert!(matches!(*spawn_error.error(&config).unwrap().kind(), ErrorKind::SpawnError(ref c, _) if c == "tool"));
+        assert!(TestResultKind::Pass.error(&config).is_none());
+        assert!(TestResultKind::Skip { reason: SkipReason::Cancelled }.error(&config).is_none());
+    }
+
+    #[test]
+    fn invalid_test_files_are_parse_errors() {
+        let path = TestFilePath { absolute: PathBuf::from("/tests/foo.txt"), relative: PathBuf::from("foo.txt") };
+        let error = TestFile::parse(path, "; TIMEOUT: never\n").unwrap_err();
+
+        assert!(matches!(*error.kind(), ErrorKind::ParseError(ref path, _) if path == &PathBuf::from("/tests/foo.txt")));
+        assert!(error.to_string().starts_with("could not parse test file '/tests/foo.txt': "), "{}", error);
+    }
+
+    #[test]
+    fn skip_breakdown_counts_each_reason() {
+        let results = results(&[
+            TestResultKind::Skip { reason: SkipReason::MissingFeature("gpu".to_owned()) },
+            TestResultKind::Pass,
+            TestResultKind::Skip { reason: SkipReason::UnsupportedFeature("windows".to_owned()) },
+            TestResultKind::Skip { reason: SkipReason::MissingFeature("gpu".to_owned()) },
+        ]);
+
+        let breakdown = results.skip_breakdown();
+        assert_eq!(breakdown.len(), 2);
+        assert_eq!(breakdown["requires gpu"], 2);
+        assert_eq!(breakdown["unsupported with windows"], 1);
+    }
+
+    #[test]
+    fn merged_results_are_concatenated_in_order() {
+        let first = results(&[TestResultKind::Pass, TestResultKind::EmptyTest]);
+        let mut second = results(&[TestResultKind::Error { message: "oops".to_owned() }]);
+        second.cancelled = true;
+
+        let merged = first.clone().merge(second.clone());
+        assert_eq!(merged.test_results.iter().map(|r| r.overall_result.clone()).collect::<Vec<_>>(),
+                   vec![TestResultKind::Pass, TestResultKind::EmptyTest, TestResultKind::Error { message: "oops".to_owned() }]);
+        assert!(merged.cancelled);
+
+        let collected: Results = vec![first.clone(), second].into_iter().collect();
+        assert_eq!(collected.summary(), merged.summary());
+
+        let collected: Results = first.test_results.into_iter().collect();
+        assert_eq!(collected.summary().passes, 1);
+        assert!(!collected.cancelled);
+    }
+
+    #[test]
+    fn check_failures_show_the_configured_number_of_context_lines() {
+        let reason = TestFailReason::CheckFailed(CheckFailureInfo {
+            complete_output_text: "1\n2\n3\n4\n5\n6\n".to_owned(),
+            successfully_checked_until_byte_index: 6,
+            expected_pattern: PatternComponent::Text("7".to_owned()).into(),
+        });
+        let config = Config { context_lines: 1, ..Config::default() };
+
+        let message = reason.human_detail_message(&config);
+        assert!(message.contains("3|      3") && message.contains("4|      4"), "{}", message);
+        assert!(!message.contains("2|      2") && !message.contains("5|      5"), "{}", message);
+    }
+
+    #[test]
+    fn check_failures_show_how_the_closest_text_differs() {
+        let reason = TestFailReason::CheckFailed(CheckFailureInfo {
+            complete_output_text: "start\nsay hello wrold\nhelp\n".to_owned(),
+            successfully_checked_until_byte_index: 6,
+            expected_pattern: PatternComponent::Text("hello world".to_owned()).into(),
+        });
+
+        let message = reason.human_detail_message(&Config::default());
+        assert!(message.contains("matching stopped at line 2 of the output\n"), "{}", message);
+        assert!(message.ends_with("the closest text in the remaining output is on line 2: say hello wrold\n\n- hello world\n+ hello wrold\n         ^\n"), "{}", message);
+
+        let reason = TestFailReason::CheckFailed(CheckFailureInfo {
+            complete_output_text: "nothing alike\n".to_owned(),
+            successfully_checked_until_byte_index: 0,
+            expected_pattern: PatternComponent::Text("hello world".to_owned()).into(),
+        });
+        assert!(!reason.human_detail_message(&Config::default()).contains("closest text"));
+    }
+
+    #[test]
+    fn only_diffs_are_diff_sections() {
+        let config = Config::default();
+        let rest_mismatch = TestFailReason::RestMismatch { diff: "  a\n- b\n+ c\n".to_owned() };
+        assert_eq!(rest_mismatch.human_detail_sections(&config)[1], DetailSection::Diff("  a\n- b\n+ c\n".to_owned()));
+
+        let stderr = TestFailReason::UnexpectedStderr { program_command_line: "tool".to_owned(), stderr: "- not a diff\n".to_owned() };
+        assert!(stderr.human_detail_sections(&config).iter().all(|section| matches!(section, DetailSection::Text(..))));
+
+        let criteria = TestFailReason::CriteriaNotMet(vec![rest_mismatch.clone(), stderr]);
+        assert_eq!(criteria.human_detail_sections(&config).iter().filter(|section| matches!(section, DetailSection::Diff(..))).count(), 1);
+        assert_eq!(rest_mismatch.human_detail_message(&config), "the rest of the output differs from the lines of CHECK-REST\n\n  a\n- b\n+ c\n");
+    }
+
+    #[test]
+    fn speedup_compares_the_test_durations_with_the_total_duration() {
+        let results = results(&[TestResultKind::Pass, TestResultKind::Pass, TestResultKind::Pass, TestResultKind::Pass]);
+        assert_eq!(results.speedup(), Some(4.0));
+
+        let sequential: Results = results.test_results.into_iter().collect();
+        assert_eq!(sequential.total_duration(), Duration::from_millis(400));
+        assert_eq!(sequential.speedup(), Some(1.0));
+    }
+}
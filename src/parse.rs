@@ -1,24 +1,92 @@
 use crate::model::*;
+use crate::util;
 
 use regex::Regex;
-use std::mem;
+use std::{collections::HashSet, mem, time::Duration};
+
+/// The keywords of every directive that can be used in a test file.
+pub const DIRECTIVE_KEYWORDS: &[&str] = &[
+    "RUN", "CHECK", "CHECK-NEXT", "CHECK-WITHIN-<N>", "CHECK-DAG", "CHECK-DAG-ONCE", "CHECK-ANY", "CHECK-BYTES", "CHECK-STREAM", "TIMEOUT", "XFAIL", "REQUIRES", "UNSUPPORTED", "META", "EXIT",
+    "CHECK-STDERR-EMPTY", "CHECK-OUTPUT-FILE", "CHECK-SIZE", "CHECK-CUSTOM[<NAME>]", "MASK", "CHECK-RUNS-EQUAL", "STREAMING", "CHECK-FILE",
+    "CHECK-SCHEMA", "CHECK-NUM", "CHECK-TIME", "CHECK-SAME-COUNT-<N>", "DEPENDS", "CHECK-NO-OUTPUT", "CHECK-ALT", "MATCH-MODE",
+    "CHECK-CREATED", "CHECK-CREATED-ONLY", "CHECK-FINAL-NEWLINE", "CHECK-NO-FINAL-NEWLINE", "CHECK-REST", "STDIN", "STDIN-FILE", "CHECK-NONE", "CHECK-NOT", "CHECK-LABEL", "CHECK-SAME", "CHECK-EMPTY", "CHECK-COUNT-<N>",
+];
 
 lazy_static! {
-    static ref DIRECTIVE_REGEX: Regex = Regex::new("([A-Z-]+):(.*)").unwrap();
+    // Only a known keyword can have a count, a bracketed name or mode and a feature guard,
+    // so that text like 'X86:' or 'FOO[x]:' is not mistaken for a directive.
+    static ref DIRECTIVE_REGEX: Regex = {
+        let keywords: Vec<_> = DIRECTIVE_KEYWORDS.iter().map(|keyword| regex::escape(&keyword.replace("<N>", "").replace("[<NAME>]", ""))).collect();
+        Regex::new(&format!(r"((?:{})[0-9]*(?:\[[^\]]*\])?(?:\{{[^}}]*\}})?|[A-Z-]+):(.*)", keywords.join("|"))).unwrap()
+    };
     static ref IDENTIFIER_REGEX: Regex = Regex::new("^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap();
+    static ref NUMERIC_SUBSTITUTION_REGEX: Regex = Regex::new(r"\[\[#([^\]]*)\]\]").unwrap();
+    static ref LINE_REFERENCE_REGEX: Regex = Regex::new(r"\[\[@LINE(?:\s*([+-])\s*(\d+))?\]\]").unwrap();
+}
+
+/// The marker which ends the header of a test file, when only the header is scanned for directives.
+pub const END_OF_DIRECTIVES_MARKER: &str = "END-DIRECTIVES";
+
+/// Gets the header of a test file, which ends before the first blank line or
+/// the first line containing `END-DIRECTIVES`.
+pub fn directive_header(text: &str) -> &str {
+    let mut header_length = 0;
+
+    for line in text.split_inclusive('\n') {
+        if line.trim().is_empty() || line.contains(END_OF_DIRECTIVES_MARKER) {
+            break;
+        }
+        header_length += line.len();
+    }
+
+    &text[..header_length]
+}
+
+/// Blanks out the lines of a test file that do not start with a comment prefix,
+/// so that directives are only read from comments. Line numbers are preserved.
+pub fn lines_with_comment_prefix(text: &str, comment_prefix: &str) -> String {
+    text.split_inclusive('\n').map(|line| {
+        if line.trim_start().starts_with(comment_prefix) { line } else if line.ends_with('\n') { "\n" } else { "" }
+    }).collect()
 }
 
 /// Parses a test file
 pub fn test_file<I>(path: TestFilePath, chars: I) -> Result<TestFile, String>
     where I: Iterator<Item=char> {
-    let mut commands = Vec::new();
     let test_body: String = chars.collect();
+    let commands = self::commands(test_body.lines().enumerate().map(|(line_idx, line)| ((line_idx + 1) as u32, line)))?;
 
-    for (line_idx, line) in test_body.lines().enumerate() {
-        let line_number = line_idx + 1;
+    Ok(TestFile {
+        path,
+        commands,
+    })
+}
+
+/// Parses the directives on some lines of a test file, each given with its line number.
+///
+/// The `MATCH-MODE` of the test applies to every check, wherever it is declared.
+pub fn commands<'a, I>(lines: I) -> Result<Vec<Command>, String>
+    where I: Iterator<Item=(u32, &'a str)> + Clone {
+    let mut match_mode = None;
+    for (line_number, line) in lines.clone().filter(|(_, line)| line.contains("MATCH-MODE")) {
+        if let Some(Ok(Command { kind: CommandKind::MatchMode(mode), .. })) = self::possible_command(line, line_number) {
+            if match_mode.is_some() {
+                return Err(format!("could not parse command: line {}: a test can only have one MATCH-MODE directive", line_number));
+            }
+            match_mode = Some(mode);
+        }
+    }
+
+    let mut commands = Vec::new();
 
-        match self::possible_command(line, line_number as _) {
-            Some(Ok(command)) => commands.push(command),
+    for (line_number, line) in lines {
+        match self::command(line, line_number, match_mode.unwrap_or_default()) {
+            // Consecutive CHECK-REST and STDIN directives declare the lines of a single block.
+            Some(Ok(command)) => match (commands.last_mut().map(|c: &mut Command| &mut c.kind), command.kind) {
+                (Some(CommandKind::CheckRest(block)), CommandKind::CheckRest(lines)) |
+                    (Some(CommandKind::Stdin(block)), CommandKind::Stdin(lines)) => block.extend(lines),
+                (_, kind) => commands.push(Command { kind, ..command }),
+            },
             Some(Err(e)) => {
                 return Err(format!(
                     "could not parse command: {}", e)
@@ -28,10 +96,41 @@ pub fn test_file<I>(path: TestFilePath, chars: I) -> Result<TestFile, String>
         }
     }
 
-    Ok(TestFile {
-        path,
-        commands: commands,
-    })
+    let mut inputs = commands.iter().filter(|c| matches!(c.kind, CommandKind::Stdin(..) | CommandKind::StdinFile(..)));
+    if let Some(second_input) = inputs.nth(1) {
+        return Err(format!("could not parse command: line {}: a test can only have one block of STDIN or STDIN-FILE directives", second_input.line_number));
+    }
+
+    // Whole patterns are regexes in the `regex` mode, so they are never variable uses.
+    if match_mode != Some(MatchMode::Regex) {
+        self::captured_variable_uses(&mut commands);
+    }
+
+    Ok(commands)
+}
+
+/// Turns every `[[NAME]]` into a use of the variable NAME, if NAME is captured by `[[NAME:regex]]`
+/// or `[[#NAME:]]` anywhere in the test.
+///
+/// Otherwise `[[NAME]]` is kept as an unnamed regex, which matches the text NAME.
+fn captured_variable_uses(commands: &mut [Command]) {
+    let captured_names: HashSet<String> = commands.iter()
+        .flat_map(|command| command.text_patterns().iter().flat_map(|pattern| pattern.components.iter()))
+        .filter_map(|component| match *component {
+            PatternComponent::NamedRegex { ref name, .. } | PatternComponent::NumericCapture(ref name) => Some(name.clone()),
+            _ => None,
+        }).collect();
+
+    for command in commands.iter_mut() {
+        for component in command.text_patterns_mut().iter_mut().flat_map(|pattern| pattern.components.iter_mut()) {
+            let name = match *component {
+                PatternComponent::Regex(ref regex) if captured_names.contains(regex) => regex.clone(),
+                _ => continue,
+            };
+
+            *component = PatternComponent::Variable(name);
+        }
+    }
 }
 
 
@@ -45,9 +144,118 @@ pub fn test_file<I>(path: TestFilePath, chars: I) -> Result<TestFile, String>
 pub fn invocation<'a,I>(words: I) -> Result<Invocation, String>
     where I: Iterator<Item=&'a str> {
     let parts: Vec<_> = words.collect();
-    let original_command = parts.join(" ");
 
-    Ok(Invocation { original_command })
+    Ok(Invocation::new(parts.join(" ")))
+}
+
+/// A token of a shell command line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ShellToken {
+    Word(String),
+    Pipe,
+    Redirect(u32, RedirectKind),
+}
+
+/// Parses a shell command line into a pipeline.
+///
+/// Returns `None` if the command uses any shell syntax other than pipes,
+/// redirects, and environment assignments, like `&&` or subshells.
+pub fn pipeline(command: &str) -> Option<Pipeline> {
+    let mut stages = Vec::new();
+
+    for stage_tokens in self::shell_tokens(command)?.split(|t| *t == ShellToken::Pipe) {
+        let mut stage = Stage { env: Vec::new(), words: Vec::new(), redirects: Vec::new() };
+        let mut tokens = stage_tokens.iter();
+
+        while let Some(token) = tokens.next() {
+            match *token {
+                ShellToken::Word(ref word) => match word.find('=') {
+                    Some(equals_idx) if stage.words.is_empty() && IDENTIFIER_REGEX.is_match(&word[..equals_idx]) => {
+                        stage.env.push((word[..equals_idx].to_owned(), word[equals_idx + 1..].to_owned()));
+                    },
+                    _ => stage.words.push(word.clone()),
+                },
+                ShellToken::Redirect(fd, kind) => match tokens.next() {
+                    Some(ShellToken::Word(target)) => stage.redirects.push(Redirect { fd, kind, target: target.clone() }),
+                    _ => return None,
+                },
+                ShellToken::Pipe => unreachable!(),
+            }
+        }
+
+        if stage.words.is_empty() {
+            return None;
+        }
+        stages.push(stage);
+    }
+
+    Some(Pipeline { stages })
+}
+
+/// Splits a shell command line into tokens, keeping quotes inside words.
+fn shell_tokens(command: &str) -> Option<Vec<ShellToken>> {
+    let mut tokens = Vec::new();
+    let mut current_word = String::new();
+    let mut chars = command.chars().peekable();
+
+    let complete_word = |current_word: &mut String, tokens: &mut Vec<ShellToken>| {
+        if !current_word.is_empty() {
+            tokens.push(ShellToken::Word(mem::take(current_word)));
+        }
+    };
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => complete_word(&mut current_word, &mut tokens),
+            '\\' => {
+                current_word.push(c);
+                current_word.push(chars.next()?);
+            },
+            '\'' | '"' => {
+                current_word.push(c);
+                loop {
+                    let quoted = chars.next()?;
+                    current_word.push(quoted);
+
+                    match quoted {
+                        '\\' if c == '"' => current_word.push(chars.next()?),
+                        q if q == c => break,
+                        _ => (),
+                    }
+                }
+            },
+            '|' => {
+                if chars.peek() == Some(&'|') { return None; }
+
+                complete_word(&mut current_word, &mut tokens);
+                tokens.push(ShellToken::Pipe);
+            },
+            '<' | '>' => {
+                let fd = if !current_word.is_empty() && current_word.chars().all(|c| c.is_ascii_digit()) {
+                    mem::take(&mut current_word).parse().ok()?
+                } else {
+                    complete_word(&mut current_word, &mut tokens);
+                    if c == '<' { 0 } else { 1 }
+                };
+
+                let kind = match (c, chars.peek().cloned()) {
+                    ('>', Some('>')) => { chars.next(); RedirectKind::Append },
+                    ('>', Some('&')) => { chars.next(); RedirectKind::Duplicate },
+                    ('>', _) => RedirectKind::Write,
+                    ('<', Some('<')) | ('<', Some('&')) => return None,
+                    _ => RedirectKind::Read,
+                };
+
+                tokens.push(ShellToken::Redirect(fd, kind));
+            },
+            '$' if chars.peek() == Some(&'(') => return None,
+            ';' | '&' | '(' | ')' | '`' => return None,
+            c => current_word.push(c),
+        }
+    }
+    complete_word(&mut current_word, &mut tokens);
+
+    Some(tokens)
 }
 
 pub fn text_pattern(s: &str) -> TextPattern {
@@ -58,7 +266,7 @@ pub fn text_pattern(s: &str) -> TextPattern {
 
     loop {
         let complete_text = |current_text: &mut Vec<char>, components: &mut Vec<PatternComponent>| {
-            let text = mem::replace(current_text, Vec::new())
+            let text = mem::take(current_text)
                 .into_iter().collect();
             components.push(PatternComponent::Text(text));
         };
@@ -66,16 +274,22 @@ pub fn text_pattern(s: &str) -> TextPattern {
         match (chars.next(), chars.peek().cloned()) {
             // Variable.
             (Some('$'), Some('$')) => {
-                complete_text(&mut current_text, &mut components);
-                chars.next(); // Eat second '$'.
-
                 let name: String = chars.clone()
+                                        .skip(1) // Skip second '$'.
                                         .take_while(|c| c.is_alphanumeric())
                                         .collect();
-                chars.nth(name.len() - 1); // Skip the variable name.
+
+                // Without a variable name, the dollar signs are plain text.
+                if name.is_empty() {
+                    current_text.push('$');
+                    continue;
+                }
+
+                complete_text(&mut current_text, &mut components);
+                chars.nth(name.chars().count()); // Skip the second '$' and the variable name.
                 components.push(PatternComponent::Variable(name));
             },
-            // Named or unnamed regex.
+            // Named or unnamed regex, or a use of a captured variable.
             (Some('['), Some('[')) => {
                 complete_text(&mut current_text, &mut components);
                 chars.next(); // Eat second `[`
@@ -105,12 +319,18 @@ pub fn text_pattern(s: &str) -> TextPattern {
 
                 let regex: String = current_regex.into_iter().collect();
 
-                let first_colon_idx = regex.chars().position(|c| c == ':');
+                // Numeric substitutions which do not parse are reported by `check_pattern`.
+                if let Some(Ok(component)) = regex.strip_prefix('#').map(self::numeric_substitution) {
+                    components.push(component);
+                    continue;
+                }
+
+                let first_colon_idx = regex.find(':');
                 let (name, regex): (Option<&str>, &str) = match first_colon_idx {
                     Some(first_colon_idx) => {
                         let substr = &regex[0..first_colon_idx];
 
-                        if IDENTIFIER_REGEX.is_match(&substr) {
+                        if IDENTIFIER_REGEX.is_match(substr) {
                             (Some(substr), &regex[first_colon_idx+1..])
                         } else {
                             (None, &regex)
@@ -135,7 +355,111 @@ pub fn text_pattern(s: &str) -> TextPattern {
         }
     }
 
-    TextPattern { components: components }
+    TextPattern { components }
+}
+
+/// Parses the text of a numeric substitution after its `#`, like `NAME:` or `NAME+8`.
+fn numeric_substitution(s: &str) -> Result<PatternComponent, String> {
+    let error = || format!("invalid numeric substitution '[[#{}]]', expected '[[#<NAME>:]]' or an expression like '[[#<NAME>+8]]'", s);
+
+    if let Some(name) = s.trim().strip_suffix(':') {
+        return match IDENTIFIER_REGEX.is_match(name.trim()) {
+            true => Ok(PatternComponent::NumericCapture(name.trim().to_owned())),
+            false => Err(error()),
+        };
+    }
+
+    let (mut terms, mut rest, mut is_subtracted) = (Vec::new(), s, false);
+    loop {
+        let operand_end = rest.find(['+', '-']).unwrap_or(rest.len());
+        let operand = rest[..operand_end].trim();
+        let operand = match IDENTIFIER_REGEX.is_match(operand) {
+            true => NumericOperand::Variable(operand.to_owned()),
+            false => NumericOperand::Number(operand.parse().map_err(|_| error())?),
+        };
+        terms.push(NumericTerm { is_subtracted, operand });
+
+        match rest[operand_end..].chars().next() {
+            Some(operator) => {
+                is_subtracted = operator == '-';
+                rest = &rest[operand_end + 1..];
+            },
+            None => return Ok(PatternComponent::NumericExpression(NumericExpression { terms })),
+        }
+    }
+}
+
+/// Substitutes `[[@LINE]]`, `[[@LINE+N]]` and `[[@LINE-N]]` with line numbers
+/// relative to the line of the directive the text belongs to.
+pub fn line_references(s: &str, line: u32) -> Result<String, String> {
+    let mut substituted = String::new();
+    let mut index = 0;
+
+    for captures in LINE_REFERENCE_REGEX.captures_iter(s) {
+        let reference = captures.get(0).unwrap();
+
+        let referenced_line = match (captures.get(1).map(|m| m.as_str()), captures.get(2)) {
+            (Some(sign), Some(offset)) => {
+                let offset: u32 = match offset.as_str().parse() {
+                    Ok(offset) => offset,
+                    Err(_) => return Err(format!("line offset in '{}' is too large", reference.as_str())),
+                };
+
+                if sign == "+" { line.checked_add(offset) } else { line.checked_sub(offset) }
+            },
+            _ => Some(line),
+        };
+
+        match referenced_line {
+            Some(referenced_line) if referenced_line > 0 => {
+                substituted += &s[index..reference.start()];
+                substituted += &referenced_line.to_string();
+                index = reference.end();
+            },
+            _ => return Err(format!("'{}' on line {} refers to a line outside of the file", reference.as_str(), line)),
+        }
+    }
+
+    substituted += &s[index..];
+    Ok(substituted)
+}
+
+/// Parses a duration, like `30`, `1.5s`, `500ms` or `2m`.
+///
+/// Durations without a unit are in seconds.
+pub fn duration(s: &str) -> Result<Duration, String> {
+    let unit_idx = s.find(|c: char| c.is_alphabetic()).unwrap_or(s.len());
+    let (number, unit) = (s[..unit_idx].trim(), s[unit_idx..].trim());
+
+    let seconds_per_unit = match unit {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        _ => return Err(format!("unknown duration unit '{}' in '{}', expected 'ms', 's' or 'm'", unit, s)),
+    };
+
+    match number.parse::<f64>().ok().and_then(|number| Duration::try_from_secs_f64(number * seconds_per_unit).ok()) {
+        Some(duration) => Ok(duration),
+        None => Err(format!("expected a duration but got '{}'", s)),
+    }
+}
+
+/// Parses a whitespace-separated sequence of hex bytes, like `DE AD BE EF`.
+pub fn byte_sequence(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+
+    for word in s.split_whitespace() {
+        match util::decode_hex(word.trim_start_matches("0x")) {
+            Some(word_bytes) => bytes.extend(word_bytes),
+            None => return Err(format!("expected a hex byte sequence but got '{}'", word)),
+        }
+    }
+
+    if bytes.is_empty() {
+        return Err("expected at least one hex byte".to_owned());
+    }
+
+    Ok(bytes)
 }
 
 /// Parses a possible command, if a string defines one.
@@ -143,11 +467,41 @@ pub fn text_pattern(s: &str) -> TextPattern {
 /// Returns `None` if no command is specified.
 pub fn possible_command(string: &str, line: u32)
     -> Option<Result<Command, String>> {
-    if !DIRECTIVE_REGEX.is_match(string) { return None; }
+    self::command(string, line, MatchMode::default())
+}
+
+/// Parses a possible command, interpreting the patterns of checks according to a match mode.
+fn command(string: &str, line: u32, match_mode: MatchMode)
+    -> Option<Result<Command, String>> {
+    let captures = DIRECTIVE_REGEX.captures(string)?;
+    let command_str = captures.get(1)?.as_str().trim();
+    let raw_after_command_str = captures.get(2)?.as_str();
+    let after_command_str = raw_after_command_str.trim();
+
+    // A directive can be guarded by a feature, like 'CHECK{linux}'.
+    if let Some((command_str, guard)) = command_str.strip_suffix('}').and_then(|s| s.split_once('{')) {
+        let feature = guard.trim();
 
-    let captures = DIRECTIVE_REGEX.captures(string).unwrap();
-    let command_str = captures.get(1).unwrap().as_str().trim();
-    let after_command_str = captures.get(2).unwrap().as_str().trim();
+        return match self::command(&format!("{}:{}", command_str, raw_after_command_str), line, match_mode)? {
+            Ok(..) if feature.is_empty() => Some(Err(format!("expected a feature between the braces of '{}'", command_str))),
+            Ok(command) if !command.is_check() => Some(Err(format!("'{}' cannot be guarded by a feature, only checks can", command_str))),
+            Ok(command) => Some(Ok(Command { feature_guard: Some(feature.to_owned()), ..command })),
+            Err(e) => Some(Err(e)),
+        };
+    }
+
+    // The match mode of a check can be overridden, like 'CHECK[regex]'.
+    if let Some((command_str, mode)) = command_str.strip_suffix(']').and_then(|s| s.split_once('[')).filter(|&(c, _)| c != "CHECK-CUSTOM") {
+        let match_mode = match mode.trim().parse() {
+            Ok(match_mode) => match_mode,
+            Err(e) => return Some(Err(e)),
+        };
+
+        return match self::command(&format!("{}:{}", command_str, raw_after_command_str), line, match_mode)? {
+            Ok(command) if command.text_patterns().is_empty() => Some(Err(format!("'{}' has no pattern, so it cannot have a match mode", command_str))),
+            result => Some(result),
+        };
+    }
 
     match command_str {
         // FIXME: better message if we have 'RUN :'
@@ -160,16 +514,209 @@ pub fn possible_command(string: &str, line: u32)
 
             Some(Ok(Command::new(CommandKind::Run(invocation), line)))
         },
-        "CHECK" => {
-            let text_pattern = self::text_pattern(after_command_str);
-            Some(Ok(Command::new(CommandKind::Check(text_pattern), line)))
+        "CHECK" => self::check_command(after_command_str, line, match_mode, CommandKind::Check),
+        "CHECK-NEXT" => self::check_command(after_command_str, line, match_mode, CommandKind::CheckNext),
+        "CHECK-DAG" => self::check_command(after_command_str, line, match_mode, CommandKind::CheckDag),
+        "CHECK-DAG-ONCE" => self::check_command(after_command_str, line, match_mode, CommandKind::CheckDagOnce),
+        "CHECK-ANY" => self::check_command(after_command_str, line, match_mode, CommandKind::CheckAny),
+        "CHECK-NONE" => self::check_command(after_command_str, line, match_mode, CommandKind::CheckNone),
+        "CHECK-NOT" => self::check_command(after_command_str, line, match_mode, CommandKind::CheckNot),
+        "CHECK-LABEL" => self::check_command(after_command_str, line, match_mode, CommandKind::CheckLabel),
+        "CHECK-SAME" => self::check_command(after_command_str, line, match_mode, CommandKind::CheckSame),
+        "CHECK-ALT" => {
+            let alternatives = self::alternatives(after_command_str);
+
+            if alternatives.len() < 2 || alternatives.iter().any(|alternative| alternative.is_empty()) {
+                return Some(Err(format!("expected two or more '|'-separated alternatives but got '{}'", after_command_str)));
+            }
+
+            match alternatives.into_iter().map(|alternative| self::check_pattern(alternative, line, match_mode)).collect() {
+                Ok(patterns) => Some(Ok(Command::new(CommandKind::CheckAlt(patterns), line))),
+                Err(e) => Some(Err(e)),
+            }
         },
-        "CHECK-NEXT" => {
-            let text_pattern = self::text_pattern(after_command_str);
-            Some(Ok(Command::new(CommandKind::CheckNext(text_pattern), line)))
+        "CHECK-BYTES" => {
+            match self::byte_sequence(after_command_str) {
+                Ok(bytes) => Some(Ok(Command::new(CommandKind::CheckBytes(bytes), line))),
+                Err(e) => Some(Err(e)),
+            }
+        },
+        "CHECK-STREAM" => {
+            match after_command_str {
+                "stdout" => Some(Ok(Command::new(CommandKind::CheckStream(OutputStream::Stdout), line))),
+                "stderr" => Some(Ok(Command::new(CommandKind::CheckStream(OutputStream::Stderr), line))),
+                "interleaved" => Some(Ok(Command::new(CommandKind::CheckStream(OutputStream::Interleaved), line))),
+                _ => Some(Err(format!("expected 'stdout', 'stderr' or 'interleaved' but got '{}'", after_command_str))),
+            }
+        },
+        "TIMEOUT" => {
+            match self::duration(after_command_str) {
+                Ok(duration) => Some(Ok(Command::new(CommandKind::Timeout(duration), line))),
+                Err(e) => Some(Err(e)),
+            }
+        },
+        "STREAMING" => {
+            match self::duration(after_command_str) {
+                Ok(duration) => Some(Ok(Command::new(CommandKind::Streaming(duration), line))),
+                Err(e) => Some(Err(e)),
+            }
         },
         "XFAIL" => {
-            Some(Ok(Command::new(CommandKind::XFail, line)))
+            match after_command_str.trim() {
+                "" => Some(Ok(Command::new(CommandKind::XFail(None), line))),
+                condition => Some(self::feature_expression(condition).map(|condition| Command::new(CommandKind::XFail(Some(condition)), line))),
+            }
+        },
+        "REQUIRES" => Some(self::features(after_command_str).map(|features| Command::new(CommandKind::Requires(features), line))),
+        "UNSUPPORTED" => Some(self::features(after_command_str).map(|features| Command::new(CommandKind::Unsupported(features), line))),
+        "EXIT" => {
+            let expected_exit_code = match after_command_str {
+                "zero" => Ok(ExpectedExitCode::Code(0)),
+                "nonzero" => Ok(ExpectedExitCode::Nonzero),
+                code => code.parse().map(ExpectedExitCode::Code),
+            };
+
+            match expected_exit_code {
+                Ok(expected_exit_code) => Some(Ok(Command::new(CommandKind::Exit(expected_exit_code), line))),
+                Err(..) => Some(Err(format!("expected an exit code, 'zero' or 'nonzero' but got '{}'", after_command_str))),
+            }
+        },
+        "CHECK-OUTPUT-FILE" if after_command_str.is_empty() => Some(Err("expected the path of an output file".to_owned())),
+        "CHECK-OUTPUT-FILE" => {
+            Some(Ok(Command::new(CommandKind::CheckOutputFile(after_command_str.to_owned()), line)))
+        },
+        "CHECK-CREATED" | "CHECK-CREATED-ONLY" => {
+            let paths: Vec<_> = after_command_str.split_whitespace().map(str::to_owned).collect();
+
+            match paths.is_empty() {
+                true => Some(Err("expected the paths of the files that the command creates".to_owned())),
+                false => Some(Ok(Command::new(CommandKind::CheckCreated { paths, only: command_str == "CHECK-CREATED-ONLY" }, line))),
+            }
+        },
+        "CHECK-SIZE" => {
+            match self::size_comparison(after_command_str) {
+                Ok((comparison, size)) => Some(Ok(Command::new(CommandKind::CheckSize(comparison, size), line))),
+                Err(e) => Some(Err(e)),
+            }
+        },
+        "CHECK-FILE" => {
+            match after_command_str.trim() {
+                "" => Some(Err("expected the path of a file with the expected output".to_owned())),
+                path => Some(Ok(Command::new(CommandKind::CheckFile(path.to_owned()), line))),
+            }
+        },
+        "CHECK-TIME" => {
+            match self::duration_comparison(after_command_str) {
+                Ok((comparison, duration)) => Some(Ok(Command::new(CommandKind::CheckTime(comparison, duration), line))),
+                Err(e) => Some(Err(e)),
+            }
+        },
+        "CHECK-NUM" => {
+            match crate::config::matchers::expected_number(after_command_str) {
+                Some((expected, tolerance)) => Some(Ok(Command::new(CommandKind::CheckNum { expected, tolerance }, line))),
+                None => Some(Err(format!("expected a number and an optional tolerance, like '3.14 +/- 0.01', but got '{}'", after_command_str))),
+            }
+        },
+        "CHECK-SCHEMA" => {
+            match after_command_str.trim() {
+                "" => Some(Err("expected the path of a JSON schema file".to_owned())),
+                path => Some(Ok(Command::new(CommandKind::CheckSchema(path.to_owned()), line))),
+            }
+        },
+        "CHECK-RUNS-EQUAL" => {
+            let run_indices: Vec<_> = after_command_str.split_whitespace().map(str::parse::<usize>).collect();
+
+            match run_indices[..] {
+                [Ok(first), Ok(second)] if first != second => Some(Ok(Command::new(CommandKind::CheckRunsEqual(first, second), line))),
+                _ => Some(Err(format!("expected the indices of two different RUN commands but got '{}'", after_command_str))),
+            }
+        },
+        "MATCH-MODE" => {
+            match after_command_str.parse() {
+                Ok(mode) => Some(Ok(Command::new(CommandKind::MatchMode(mode), line))),
+                Err(e) => Some(Err(e)),
+            }
+        },
+        "CHECK-STDERR-EMPTY" => {
+            Some(Ok(Command::new(CommandKind::CheckStderrEmpty, line)))
+        },
+        "CHECK-NO-OUTPUT" => {
+            Some(Ok(Command::new(CommandKind::CheckNoOutput, line)))
+        },
+        "CHECK-EMPTY" => {
+            Some(Ok(Command::new(CommandKind::CheckEmpty, line)))
+        },
+        "CHECK-FINAL-NEWLINE" => {
+            Some(Ok(Command::new(CommandKind::CheckFinalNewline(true), line)))
+        },
+        "CHECK-NO-FINAL-NEWLINE" => {
+            Some(Ok(Command::new(CommandKind::CheckFinalNewline(false), line)))
+        },
+        "CHECK-REST" | "STDIN" => {
+            // Only the space after the colon is removed, so that indentation is kept.
+            // Trailing whitespace is kept too, since output lines and input lines keep theirs.
+            let text = raw_after_command_str.strip_prefix(' ').unwrap_or(raw_after_command_str);
+            let text_line = vec![text.strip_suffix('\r').unwrap_or(text).to_owned()];
+
+            match command_str {
+                "STDIN" => Some(Ok(Command::new(CommandKind::Stdin(text_line), line))),
+                _ => Some(Ok(Command::new(CommandKind::CheckRest(text_line), line))),
+            }
+        },
+        "STDIN-FILE" => match after_command_str {
+            "" => Some(Err("expected the path of a file to write to standard input".to_owned())),
+            path => Some(Ok(Command::new(CommandKind::StdinFile(path.to_owned()), line))),
+        },
+        "MASK" => {
+            let regex = after_command_str.trim();
+
+            match Regex::new(regex) {
+                Ok(..) if regex.is_empty() => Some(Err("expected a regex to mask".to_owned())),
+                Ok(regex) => Some(Ok(Command::new(CommandKind::Mask(regex), line))),
+                Err(e) => Some(Err(format!("invalid mask regex '{}': {}", regex, e))),
+            }
+        },
+        "DEPENDS" => {
+            match after_command_str.trim() {
+                "" => Some(Err("expected the path of a file that the test depends on".to_owned())),
+                path => Some(Ok(Command::new(CommandKind::Depends(path.to_owned()), line))),
+            }
+        },
+        "META" => {
+            match after_command_str.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => {
+                    Some(Ok(Command::new(CommandKind::Meta(key.trim().to_owned(), value.trim().to_owned()), line)))
+                },
+                _ => Some(Err(format!("expected 'key=value' but got '{}'", after_command_str))),
+            }
+        },
+        _ if command_str.starts_with("CHECK-CUSTOM[") => {
+            match command_str["CHECK-CUSTOM[".len()..].strip_suffix(']').map(str::trim) {
+                Some(name) if !name.is_empty() => {
+                    Some(Ok(Command::new(CommandKind::CheckCustom(name.to_owned(), after_command_str.to_owned()), line)))
+                },
+                _ => Some(Err(format!("expected the name of a custom matcher in '{}'", command_str))),
+            }
+        },
+        _ if command_str.starts_with("CHECK-WITHIN-") => {
+            match command_str["CHECK-WITHIN-".len()..].parse::<usize>() {
+                Ok(max_line_distance) if max_line_distance > 0 => {
+                    self::check_command(after_command_str, line, match_mode, |pattern| CommandKind::CheckWithin(max_line_distance, pattern))
+                },
+                _ => Some(Err(format!("expected a positive number of lines in '{}'", command_str))),
+            }
+        },
+        _ if command_str.starts_with("CHECK-COUNT-") => {
+            match command_str["CHECK-COUNT-".len()..].parse::<usize>() {
+                Ok(count) if count > 0 => self::check_command(after_command_str, line, match_mode, |pattern| CommandKind::CheckCount(count, pattern)),
+                _ => Some(Err(format!("expected a positive number of matches in '{}'", command_str))),
+            }
+        },
+        _ if command_str.starts_with("CHECK-SAME-COUNT-") => {
+            match command_str["CHECK-SAME-COUNT-".len()..].parse::<usize>() {
+                Ok(count) => self::check_command(after_command_str, line, match_mode, |pattern| CommandKind::CheckSameCount(count, pattern)),
+                Err(..) => Some(Err(format!("expected a number of matches in '{}'", command_str))),
+            }
         },
         _ => {
             Some(Err(format!("command '{}' not known", command_str)))
@@ -177,39 +724,597 @@ pub fn possible_command(string: &str, line: u32)
     }
 }
 
-#[cfg(tes)]
+/// Parses a comma-separated list of features, like `x86, asserts`.
+fn features(s: &str) -> Result<Vec<String>, String> {
+    let features: Vec<_> = s.split(',').map(str::trim).map(str::to_owned).collect();
+
+    match features.iter().any(String::is_empty) {
+        true => Err(format!("expected a comma-separated list of features but got '{}'", s.trim())),
+        false => Ok(features),
+    }
+}
+
+/// Parses a boolean expression over features, like `windows || (linux && !asserts)`.
+///
+/// `!` binds tighter than `&&`, which binds tighter than `||`.
+fn feature_expression(s: &str) -> Result<FeatureExpression, String> {
+    let mut tokens = self::feature_expression_tokens(s)?.into_iter().peekable();
+    let expression = self::feature_disjunction(&mut tokens, s)?;
+
+    match tokens.next() {
+        None => Ok(expression),
+        Some(token) => Err(format!("unexpected '{}' in feature expression '{}'", token, s)),
+    }
+}
+
+type FeatureExpressionTokens<'a> = std::iter::Peekable<std::vec::IntoIter<&'a str>>;
+
+/// Splits a feature expression into operators, parentheses and feature names.
+fn feature_expression_tokens(s: &str) -> Result<Vec<&str>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+
+    while !rest.is_empty() {
+        let length = match rest.as_bytes()[0] {
+            b'!' | b'(' | b')' => 1,
+            b'&' if rest.starts_with("&&") => 2,
+            b'|' if rest.starts_with("||") => 2,
+            b'&' | b'|' => return Err(format!("expected '&&' or '||' in feature expression '{}'", s)),
+            _ => rest.find(|c: char| c.is_whitespace() || "!()&|".contains(c)).unwrap_or(rest.len()),
+        };
+
+        tokens.push(&rest[..length]);
+        rest = rest[length..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+fn feature_disjunction(tokens: &mut FeatureExpressionTokens, s: &str) -> Result<FeatureExpression, String> {
+    let mut expression = self::feature_conjunction(tokens, s)?;
+    while tokens.next_if_eq(&"||").is_some() {
+        expression = FeatureExpression::Or(Box::new(expression), Box::new(self::feature_conjunction(tokens, s)?));
+    }
+
+    Ok(expression)
+}
+
+fn feature_conjunction(tokens: &mut FeatureExpressionTokens, s: &str) -> Result<FeatureExpression, String> {
+    let mut expression = self::feature_negation(tokens, s)?;
+    while tokens.next_if_eq(&"&&").is_some() {
+        expression = FeatureExpression::And(Box::new(expression), Box::new(self::feature_negation(tokens, s)?));
+    }
+
+    Ok(expression)
+}
+
+fn feature_negation(tokens: &mut FeatureExpressionTokens, s: &str) -> Result<FeatureExpression, String> {
+    match tokens.next() {
+        Some("!") => Ok(FeatureExpression::Not(Box::new(self::feature_negation(tokens, s)?))),
+        Some("(") => {
+            let expression = self::feature_disjunction(tokens, s)?;
+            match tokens.next() {
+                Some(")") => Ok(expression),
+                _ => Err(format!("'(' is never closed in feature expression '{}'", s)),
+            }
+        },
+        Some(feature) if !matches!(feature, ")" | "&&" | "||") => Ok(FeatureExpression::Feature(feature.to_owned())),
+        _ => Err(format!("expected a feature in feature expression '{}'", s)),
+    }
+}
+
+/// Parses a comparison against a size in bytes, like `< 1024`.
+fn size_comparison(s: &str) -> Result<(Comparison, usize), String> {
+    let (comparison, size) = self::comparison(s).ok_or_else(|| format!("expected a comparison like '< 1024' but got '{}'", s))?;
+
+    match size.parse() {
+        Ok(size) => Ok((comparison, size)),
+        Err(..) => Err(format!("expected a size in bytes but got '{}'", size)),
+    }
+}
+
+/// Parses a comparison with a duration, like `< 500ms`.
+fn duration_comparison(s: &str) -> Result<(Comparison, Duration), String> {
+    let (comparison, duration) = self::comparison(s).ok_or_else(|| format!("expected a comparison like '< 500ms' but got '{}'", s))?;
+
+    Ok((comparison, self::duration(duration)?))
+}
+
+/// Splits the comparison operator off the start of a string, returning the rest of it.
+fn comparison(s: &str) -> Option<(Comparison, &str)> {
+    const OPERATORS: &[(&str, Comparison)] = &[
+        ("<=", Comparison::LessOrEqual), (">=", Comparison::GreaterOrEqual), ("==", Comparison::Equal),
+        ("<", Comparison::Less), (">", Comparison::Greater),
+    ];
+
+    OPERATORS.iter().find_map(|&(operator, comparison)| s.strip_prefix(operator).map(|rest| (comparison, rest.trim())))
+}
+
+/// Parses the text pattern of a check directive into a command.
+fn check_command(pattern: &str, line: u32, match_mode: MatchMode, kind: impl FnOnce(TextPattern) -> CommandKind)
+    -> Option<Result<Command, String>> {
+    Some(self::check_pattern(pattern, line, match_mode).map(|text_pattern| Command::new(kind(text_pattern), line)))
+}
+
+/// Parses and validates the text pattern of a check directive.
+fn check_pattern(pattern: &str, line: u32, match_mode: MatchMode) -> Result<TextPattern, String> {
+    let pattern = self::line_references(pattern, line)?;
+    if match_mode != MatchMode::Regex {
+        for captures in NUMERIC_SUBSTITUTION_REGEX.captures_iter(&pattern) {
+            self::numeric_substitution(&captures[1])?;
+        }
+    }
+
+    let text_pattern = match match_mode {
+        MatchMode::Literal => self::text_pattern(&pattern),
+        MatchMode::Regex => TextPattern { components: vec![PatternComponent::Regex(pattern)] },
+        MatchMode::WholeLine => {
+            let components = self::text_pattern(&pattern).components;
+            let (start, end) = (PatternComponent::Regex("(?m:^)".to_owned()), PatternComponent::Regex("(?m:$)".to_owned()));
+
+            TextPattern { components: std::iter::once(start).chain(components).chain(std::iter::once(end)).collect() }
+        },
+    };
+
+    self::validate_text_pattern(&text_pattern)?;
+    Ok(text_pattern)
+}
+
+/// Splits the alternatives of a `CHECK-ALT` directive at every `|` which is not inside a regex.
+fn alternatives(s: &str) -> Vec<&str> {
+    let mut alternatives = Vec::new();
+    let (mut alternative_start, mut regex_bracket_level) = (0, None);
+    let mut chars = s.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, c)| c);
+
+        match (c, next, regex_bracket_level) {
+            ('[', Some('['), None) => { chars.next(); regex_bracket_level = Some(0); },
+            (']', Some(']'), Some(0)) => { chars.next(); regex_bracket_level = None; },
+            ('[', _, Some(level)) => regex_bracket_level = Some(level + 1),
+            (']', _, Some(level)) => regex_bracket_level = Some(level - 1),
+            ('|', _, None) => {
+                alternatives.push(s[alternative_start..i].trim());
+                alternative_start = i + 1;
+            },
+            _ => (),
+        }
+    }
+
+    alternatives.push(s[alternative_start..].trim());
+    alternatives
+}
+
+/// Verifies that the regexes inside a text pattern are valid.
+///
+/// The values of variables are only known when the test runs, so they are not validated.
+fn validate_text_pattern(text_pattern: &TextPattern) -> Result<(), String> {
+    let regex_parts: Vec<_> = text_pattern.components.iter().map(|comp| match *comp {
+        PatternComponent::Text(ref text) => regex::escape(text),
+        PatternComponent::Variable(..) => String::new(),
+        PatternComponent::Regex(ref regex) => format!("(?:{})", regex),
+        PatternComponent::NamedRegex { ref name, ref regex } => format!("(?P<{}>{})", name, regex),
+        PatternComponent::NumericCapture(ref name) => format!("(?P<{}>[0-9]+)", name),
+        PatternComponent::NumericExpression(..) => String::new(),
+    }).collect();
+
+    match Regex::new(&regex_parts.join("")) {
+        Ok(..) => Ok(()),
+        Err(e) => Err(format!("invalid regex in pattern '{}': {}", text_pattern, e)),
+    }
+}
+
+#[cfg(test)]
 mod test {
     use super::*;
+    use crate::{vars, Config};
     use std::collections::HashMap;
 
+    /// Parses a text pattern and resolves it into the regex it will be matched with.
+    fn regex(s: &str) -> String {
+        vars::resolve::text_pattern(&text_pattern(s), &Config::default(), &mut HashMap::new()).unwrap().as_str().to_owned()
+    }
+
     #[test]
     fn parses_single_text() {
         assert_eq!(text_pattern("hello world"),
-                   "hello world");
+                   PatternComponent::Text("hello world".to_owned()).into());
     }
 
     #[test]
     fn correctly_escapes_text() {
-        assert_eq!(text_pattern("hello()").as_str(),
+        assert_eq!(regex("hello()"),
                    "hello\\(\\)");
     }
 
     #[test]
     fn correctly_picks_up_single_regex() {
-        assert_eq!(text_pattern("[[\\d]]").as_str(),
+        assert_eq!(regex("[[\\d]]"),
                    "\\d");
     }
 
     #[test]
     fn correctly_picks_up_regex_between_text() {
-        assert_eq!(text_pattern("1[[\\d]]3").as_str(),
+        assert_eq!(regex("1[[\\d]]3"),
                    "1\\d3");
     }
 
     #[test]
     fn correctly_picks_up_named_regex() {
-        assert_eq!(text_pattern("[[num:\\d]]").as_str(),
+        assert_eq!(regex("[[num:\\d]]"),
                    "(?P<num>\\d)");
     }
-}
 
+    #[test]
+    fn bracketed_names_are_variable_uses_only_when_captured() {
+        let patterns = |text: &str| -> Vec<TextPattern> {
+            commands(text.lines().enumerate().map(|(i, line)| (i as u32 + 1, line))).unwrap()
+                .iter().flat_map(|command| command.text_patterns().to_vec()).collect()
+        };
+
+        assert_eq!(patterns("CHECK: [[reg:r\\d]]\nCHECK: ret [[reg]]")[1], text_pattern("ret $$reg"));
+        assert_eq!(patterns("CHECK: [[REG_1]]\nCHECK: [[#REG_1:]]")[0].components[1], PatternComponent::Variable("REG_1".to_owned()));
+        assert_eq!(patterns("CHECK: [[hello]]")[0], text_pattern("[[hello]]"));
+        assert_eq!(patterns("MATCH-MODE: regex\nCHECK: [[reg:.]]\nCHECK: reg")[1].components, vec![PatternComponent::Regex("reg".to_owned())]);
+        assert_eq!(regex("[[hello]]"), "hello");
+        assert_eq!(regex("[[a-z]]"), "a-z");
+    }
+
+    #[test]
+    fn numeric_substitutions_are_parsed() {
+        let term = |is_subtracted, operand| NumericTerm { is_subtracted, operand };
+
+        assert_eq!(text_pattern("[[#BASE:]]").components[1], PatternComponent::NumericCapture("BASE".to_owned()));
+        assert_eq!(text_pattern("[[#BASE + 8 - OFFSET]]").components[1], PatternComponent::NumericExpression(NumericExpression {
+            terms: vec![term(false, NumericOperand::Variable("BASE".to_owned())), term(false, NumericOperand::Number(8)),
+                        term(true, NumericOperand::Variable("OFFSET".to_owned()))],
+        }));
+        assert_eq!(regex("at [[#BASE:]]"), "at (?P<BASE>[0-9]+)");
+        assert_eq!(text_pattern("[[#BASE:]] [[#BASE+8]]").source(), "[[#BASE:]] [[#BASE+8]]");
+
+        assert!(possible_command("CHECK: [[#BASE+]]", 1).unwrap().unwrap_err().contains("invalid numeric substitution '[[#BASE+]]'"));
+        assert!(possible_command("CHECK: [[#2BASE:]]", 1).unwrap().is_err());
+    }
+
+    #[test]
+    fn literal_pattern_has_literal_kind() {
+        assert_eq!(text_pattern("hello $$name").kind(), TextPatternKind::Literal);
+    }
+
+    #[test]
+    fn pattern_containing_regex_has_regex_kind() {
+        assert_eq!(text_pattern("hello [[.*]]").kind(), TextPatternKind::Regex);
+        assert_eq!(text_pattern("[[num:\\d+]]").kind(), TextPatternKind::Regex);
+    }
+
+    #[test]
+    fn source_round_trips() {
+        for source in &["hello world", "hello $$name", "a [[.*]] b", "[[num:\\d+]]"] {
+            assert_eq!(&text_pattern(source).source(), source);
+        }
+    }
+
+    #[test]
+    fn line_references_are_relative_to_the_directive() {
+        assert_eq!(line_references("error on line [[@LINE]]", 5), Ok("error on line 5".to_owned()));
+        assert_eq!(line_references("[[@LINE+2]] and [[@LINE-4]]", 5), Ok("7 and 1".to_owned()));
+    }
+
+    #[test]
+    fn line_references_before_the_file_are_an_error() {
+        assert!(line_references("[[@LINE-5]]", 5).is_err());
+        assert!(line_references("[[@LINE-99999999999]]", 5).is_err());
+    }
+
+    #[test]
+    fn check_substitutes_line_references() {
+        let command = possible_command("; CHECK: foo.c:[[@LINE-1]]: error", 3).unwrap().unwrap();
+        assert_eq!(command.kind, CommandKind::Check(text_pattern("foo.c:2: error")));
+    }
+
+    #[test]
+    fn parses_pipeline_stages() {
+        let pipeline = pipeline("FOO=1 tool -v @file 2>&1 | grep 'a b' > @tempfile").unwrap();
+
+        assert_eq!(pipeline.stages.len(), 2);
+        assert_eq!(pipeline.stages[0].env, vec![("FOO".to_owned(), "1".to_owned())]);
+        assert_eq!(pipeline.stages[0].words, vec!["tool", "-v", "@file"]);
+        assert_eq!(pipeline.stages[0].redirects, vec![Redirect { fd: 2, kind: RedirectKind::Duplicate, target: "1".to_owned() }]);
+        assert_eq!(pipeline.stages[1].words, vec!["grep", "'a b'"]);
+        assert_eq!(pipeline.stages[1].redirects, vec![Redirect { fd: 1, kind: RedirectKind::Write, target: "@tempfile".to_owned() }]);
+    }
+
+    #[test]
+    fn other_shell_syntax_is_not_a_pipeline() {
+        for command in &["a && b", "a || b", "a; b", "(a)", "echo $(a)", "cat << EOF", "echo 'unterminated", "| a"] {
+            assert_eq!(pipeline(command), None, "'{}' should not be a pipeline", command);
+        }
+    }
+
+    #[test]
+    fn invocations_compare_structurally() {
+        assert_eq!(Invocation::new("cat   @file |  grep foo"), Invocation::new("cat @file | grep foo"));
+        assert_ne!(Invocation::new("cat @file | grep foo"), Invocation::new("cat @file | grep bar"));
+    }
+
+    #[test]
+    fn pipelines_round_trip_through_invocations() {
+        let pipeline = pipeline("FOO=1 tool @file 2> @tempfile | grep foo >> out.txt").unwrap();
+        let invocation = Invocation::from_pipeline(pipeline.clone());
+
+        assert_eq!(invocation.original_command, "FOO=1 tool @file 2> @tempfile | grep foo >> out.txt");
+        assert_eq!(invocation.pipeline, Some(pipeline));
+    }
+
+    #[test]
+    fn parses_durations_with_units() {
+        assert_eq!(duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(duration("2m"), Ok(Duration::from_secs(120)));
+        assert!(duration("5h").is_err());
+        assert!(duration("-1").is_err());
+        assert!(duration("").is_err());
+    }
+
+    #[test]
+    fn every_directive_keyword_is_known() {
+        for keyword in DIRECTIVE_KEYWORDS {
+            if let Some(Err(e)) = possible_command(&format!("{}: 1", keyword.replace("<N>", "2").replace("<NAME>", "name")), 1) {
+                assert!(!e.contains("not known"), "'{}' is listed as a directive keyword but it is not known", keyword);
+            }
+        }
+
+        assert_eq!(possible_command("CHEKC: foo", 1), Some(Err("command 'CHEKC' not known".to_owned())));
+    }
+
+    #[test]
+    fn parses_check_runs_equal() {
+        assert_eq!(possible_command("; CHECK-RUNS-EQUAL: 0 1", 1),
+                   Some(Ok(Command::new(CommandKind::CheckRunsEqual(0, 1), 1))));
+        assert!(possible_command("; CHECK-RUNS-EQUAL: 1 1", 1).unwrap().is_err());
+        assert!(possible_command("; CHECK-RUNS-EQUAL: 0", 1).unwrap().is_err());
+    }
+
+    #[test]
+    fn parses_masks() {
+        assert_eq!(possible_command("; MASK: \\d{4}-\\d{2}", 1),
+                   Some(Ok(Command::new(CommandKind::Mask(Regex::new("\\d{4}-\\d{2}").unwrap()), 1))));
+        assert!(possible_command("; MASK: [0-9", 1).unwrap().unwrap_err().contains("invalid mask regex"));
+    }
+
+    #[test]
+    fn parses_check_custom() {
+        assert_eq!(possible_command("; CHECK-CUSTOM[floateq]: 3.14 +- 0.01", 1),
+                   Some(Ok(Command::new(CommandKind::CheckCustom("floateq".to_owned(), "3.14 +- 0.01".to_owned()), 1))));
+        assert!(possible_command("; CHECK-CUSTOM[]: 3.14", 1).unwrap().is_err());
+    }
+
+    #[test]
+    fn parses_feature_guards() {
+        let command = possible_command("; CHECK-NEXT{linux}: ok", 1).unwrap().unwrap();
+        assert_eq!(command.kind, CommandKind::CheckNext(text_pattern("ok")));
+        assert_eq!(command.feature_guard, Some("linux".to_owned()));
+
+        assert!(possible_command("; CHECK{}: ok", 1).unwrap().is_err());
+        assert!(possible_command("; RUN{linux}: true", 1).unwrap().unwrap_err().contains("only checks"));
+        assert_eq!(possible_command("; CHEKC{linux}: ok", 1), None);
+    }
+
+    #[test]
+    fn guarded_check_rest_keeps_its_whitespace() {
+        let command = possible_command("; CHECK-REST{linux}:   b  ", 1).unwrap().unwrap();
+        assert_eq!(command.kind, CommandKind::CheckRest(vec!["  b  ".to_owned()]));
+        assert_eq!(command.feature_guard, Some("linux".to_owned()));
+    }
+
+    #[test]
+    fn text_with_digits_or_brackets_is_not_a_directive() {
+        assert_eq!(possible_command("; UTF8: is an encoding", 1), None);
+        assert_eq!(possible_command("; on X86: foo", 1), None);
+        assert_eq!(possible_command("; FOO[x]: bar", 1), None);
+        assert_eq!(possible_command("; X86 CHECK: foo", 1), Some(Ok(Command::new(CommandKind::Check(text_pattern("foo")), 1))));
+    }
+
+    #[test]
+    fn parses_check_time() {
+        assert_eq!(possible_command("; CHECK-TIME: < 500ms", 1),
+                   Some(Ok(Command::new(CommandKind::CheckTime(Comparison::Less, Duration::from_millis(500)), 1))));
+        assert!(possible_command("; CHECK-TIME: 500ms", 1).unwrap().is_err());
+        assert!(possible_command("; CHECK-TIME: < soon", 1).unwrap().is_err());
+    }
+
+    #[test]
+    fn parses_check_num() {
+        assert_eq!(possible_command("; CHECK-NUM: 2.5 +/- 0.01", 1),
+                   Some(Ok(Command::new(CommandKind::CheckNum { expected: 2.5, tolerance: 0.01 }, 1))));
+        assert!(possible_command("; CHECK-NUM: pi", 1).unwrap().is_err());
+    }
+
+    #[test]
+    fn parses_size_comparisons() {
+        assert_eq!(size_comparison("< 1024"), Ok((Comparison::Less, 1024)));
+        assert_eq!(size_comparison("<=10"), Ok((Comparison::LessOrEqual, 10)));
+        assert_eq!(size_comparison(">= 0"), Ok((Comparison::GreaterOrEqual, 0)));
+        assert_eq!(size_comparison("== 3"), Ok((Comparison::Equal, 3)));
+        assert!(size_comparison("1024").is_err());
+        assert!(size_comparison("< lots").is_err());
+    }
+
+    #[test]
+    fn parses_expected_exit_codes() {
+        assert_eq!(possible_command("EXIT: 3", 1), Some(Ok(Command::new(CommandKind::Exit(ExpectedExitCode::Code(3)), 1))));
+        assert_eq!(possible_command("EXIT: zero", 1), Some(Ok(Command::new(CommandKind::Exit(ExpectedExitCode::Code(0)), 1))));
+        assert_eq!(possible_command("EXIT: nonzero", 1), Some(Ok(Command::new(CommandKind::Exit(ExpectedExitCode::Nonzero), 1))));
+        assert!(possible_command("EXIT: failure", 1).unwrap().is_err());
+    }
+
+    #[test]
+    fn parses_required_features() {
+        let requires = |features: &[&str]| Some(Ok(Command::new(CommandKind::Requires(features.iter().map(|f| f.to_string()).collect()), 1)));
+
+        assert_eq!(possible_command("; REQUIRES: x86, asserts", 1), requires(&["x86", "asserts"]));
+        assert_eq!(possible_command("; REQUIRES: os=linux", 1), requires(&["os=linux"]));
+        assert!(possible_command("; REQUIRES:", 1).unwrap().is_err());
+        assert!(possible_command("; REQUIRES: x86,", 1).unwrap().is_err());
+        assert_eq!(possible_command("; UNSUPPORTED: windows", 1), Some(Ok(Command::new(CommandKind::Unsupported(vec!["windows".to_owned()]), 1))));
+        assert!(possible_command("; UNSUPPORTED: ", 1).unwrap().is_err());
+    }
+
+    #[test]
+    fn parses_conditional_expected_failures() {
+        let feature = |name: &str| Box::new(FeatureExpression::Feature(name.to_owned()));
+        let xfail = |condition| Some(Ok(Command::new(CommandKind::XFail(condition), 1)));
+
+        assert_eq!(possible_command("; XFAIL:", 1), xfail(None));
+        assert_eq!(possible_command("; XFAIL: windows", 1), xfail(Some(*feature("windows"))));
+        assert_eq!(possible_command("; XFAIL: a || b && !c", 1),
+                   xfail(Some(FeatureExpression::Or(feature("a"), Box::new(FeatureExpression::And(feature("b"), Box::new(FeatureExpression::Not(feature("c")))))))));
+        assert_eq!(possible_command("; XFAIL: (a || os=linux) && b", 1),
+                   xfail(Some(FeatureExpression::And(Box::new(FeatureExpression::Or(feature("a"), feature("os=linux"))), feature("b")))));
+
+        for invalid in ["a &", "a && ", "(a || b", "a b", "!", "a || )"] {
+            assert!(possible_command(&format!("; XFAIL: {}", invalid), 1).unwrap().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_metadata() {
+        assert_eq!(possible_command("; META: owner = someone@example.com", 1),
+                   Some(Ok(Command::new(CommandKind::Meta("owner".to_owned(), "someone@example.com".to_owned()), 1))));
+        assert_eq!(possible_command("; META: ticket=", 1), Some(Ok(Command::new(CommandKind::Meta("ticket".to_owned(), String::new()), 1))));
+        assert_eq!(possible_command("; DEPENDS: inputs/a.c", 1), Some(Ok(Command::new(CommandKind::Depends("inputs/a.c".to_owned()), 1))));
+        assert!(possible_command("; DEPENDS: ", 1).unwrap().is_err());
+        assert!(possible_command("; META: ticket", 1).unwrap().is_err());
+        assert!(possible_command("; META: =123", 1).unwrap().is_err());
+    }
+
+    #[test]
+    fn parses_created_files() {
+        let created = |paths: &[&str], only| CommandKind::CheckCreated { paths: paths.iter().map(|p| p.to_string()).collect(), only };
+
+        assert_eq!(possible_command("CHECK-CREATED: @tempfile.o  @tempfile.d", 1), Some(Ok(Command::new(created(&["@tempfile.o", "@tempfile.d"], false), 1))));
+        assert_eq!(possible_command("CHECK-CREATED-ONLY: out/a.o", 1), Some(Ok(Command::new(created(&["out/a.o"], true), 1))));
+        assert!(possible_command("CHECK-CREATED:", 1).unwrap().is_err());
+    }
+
+    #[test]
+    fn consecutive_check_rest_directives_form_one_block() {
+        let path = TestFilePath { absolute: "/tests/foo.txt".into(), relative: "foo.txt".into() };
+        let test_file = test_file(path, "; RUN: cat\n; CHECK-REST: a\n; CHECK-REST:   b  \n; CHECK-REST:\n".chars()).unwrap();
+        let block = vec!["a".to_owned(), "  b  ".to_owned(), String::new()];
+
+        assert_eq!(test_file.commands[1], Command::new(CommandKind::CheckRest(block), 2));
+        assert_eq!(test_file.commands.len(), 2);
+    }
+
+    #[test]
+    fn a_test_has_at_most_one_stdin_block() {
+        let commands = |text: &str| test_file(TestFilePath { absolute: "/tests/foo.txt".into(), relative: "foo.txt".into() }, text.chars()).map(|t| t.commands);
+
+        assert_eq!(commands("; STDIN: a\n; STDIN: b\n; RUN: cat\n").unwrap()[0], Command::new(CommandKind::Stdin(vec!["a".to_owned(), "b".to_owned()]), 1));
+        assert!(commands("; STDIN: a\n; RUN: cat\n; STDIN: b\n").is_err());
+        assert!(commands("; STDIN: a\n; STDIN-FILE: input.txt\n").is_err());
+        assert!(commands("; STDIN-FILE:\n").is_err());
+    }
+
+    #[test]
+    fn parses_check_within() {
+        assert_eq!(possible_command("CHECK-WITHIN-3: foo", 1), Some(Ok(Command::new(CommandKind::CheckWithin(3, text_pattern("foo")), 1))));
+        assert!(possible_command("CHECK-WITHIN-0: foo", 1).unwrap().is_err());
+        assert!(possible_command("CHECK-WITHIN-: foo", 1).unwrap().is_err());
+        assert_eq!(possible_command("CHECK-ALT: linux | [[mac|darwin]]", 1),
+                   Some(Ok(Command::new(CommandKind::CheckAlt(vec![text_pattern("linux"), text_pattern("[[mac|darwin]]")]), 1))));
+        assert!(possible_command("CHECK-ALT: linux", 1).unwrap().is_err());
+        assert!(possible_command("CHECK-ALT: linux | ", 1).unwrap().is_err());
+        assert_eq!(possible_command("CHECK-SAME-COUNT-2: ,", 1), Some(Ok(Command::new(CommandKind::CheckSameCount(2, text_pattern(",")), 1))));
+        assert!(possible_command("CHECK-SAME-COUNT-: ,", 1).unwrap().is_err());
+        assert_eq!(possible_command("CHECK-COUNT-5: record", 1), Some(Ok(Command::new(CommandKind::CheckCount(5, text_pattern("record")), 1))));
+        assert!(possible_command("CHECK-COUNT-0: record", 1).unwrap().is_err());
+    }
+
+    #[test]
+    fn dollar_signs_without_a_variable_name_are_text() {
+        assert_eq!(text_pattern("costs $$ 5"), PatternComponent::Text("costs $$ 5".to_owned()).into());
+        assert_eq!(text_pattern("$$"), PatternComponent::Text("$$".to_owned()).into());
+    }
+
+    #[test]
+    fn invalid_regexes_are_parse_errors() {
+        assert!(possible_command("CHECK: [[(]]", 1).unwrap().is_err());
+        assert!(possible_command("CHECK: [[a:x]] [[a:y]]", 1).unwrap().is_err(), "duplicate capture names are invalid");
+        assert!(possible_command("CHECK: [[a|b]]c", 1).unwrap().is_ok());
+    }
+
+    /// Feeds pseudo-random combinations of awkward fragments to the parser, which should never panic.
+    #[test]
+    fn parsing_arbitrary_input_never_panics() {
+        const ARGUMENT_FRAGMENTS: &[&str] = &[
+            "zero", "nonzero", "{linux}", "{", "}", "[regex]", "whole-line", "+/-", "<=", "==",
+            "[[", "]]", "#", "[", "]", "$$", "$", ":", "@LINE", "+", "-", "99999999999999999999", "1e400", "ms",
+            "%if", "%{", "%}", "|", ">", "2>&1", "<", "'", "\"", "\\", "(", ")", "=", "0x", "zz", "DE",
+            " ", "\t", "\n", "\r\n", "\u{1F600}", "\u{e9}", "\u{0}",
+        ];
+
+        // Every directive is a fragment, up to the placeholder of directives like 'CHECK-COUNT-<N>'.
+        let directive_fragments = DIRECTIVE_KEYWORDS.iter().map(|keyword| match keyword.find('<') {
+            Some(placeholder_start) => keyword[..placeholder_start].to_owned(),
+            None => format!("{}:", keyword),
+        });
+        let fragments: Vec<String> = directive_fragments.chain(ARGUMENT_FRAGMENTS.iter().map(|&fragment| fragment.to_owned())).collect();
+
+        // A xorshift generator, so that the inputs are the same on every run.
+        let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+        let mut next = || { state ^= state << 13; state ^= state >> 7; state ^= state << 17; state as usize };
+
+        for _ in 0..5000 {
+            let fragment_count = next() % 32;
+            let text: String = (0..fragment_count).map(|_| fragments[next() % fragments.len()].as_str()).collect();
+            let path = TestFilePath { absolute: "/tests/fuzz.txt".into(), relative: "fuzz.txt".into() };
+
+            let _ = test_file(path, text.chars());
+            let _ = pipeline(&text);
+        }
+
+        let huge_line = format!("CHECK: {}", "[[".repeat(100_000));
+        let _ = possible_command(&huge_line, 1);
+    }
+
+    #[test]
+    fn directive_header_ends_at_blank_line_or_marker() {
+        assert_eq!(directive_header("; RUN: cat @file\n; CHECK: foo\n\n; CHECK: bar\n"), "; RUN: cat @file\n; CHECK: foo\n");
+        assert_eq!(directive_header("; RUN: cat @file\n; END-DIRECTIVES\n; CHECK: bar\n"), "; RUN: cat @file\n");
+        assert_eq!(directive_header("; RUN: cat @file"), "; RUN: cat @file");
+        assert_eq!(directive_header("\n; RUN: cat @file\n"), "");
+    }
+
+    #[test]
+    fn only_lines_with_the_comment_prefix_are_kept() {
+        assert_eq!(lines_with_comment_prefix("; RUN: cat @file\nCHECK: foo\n  ; CHECK: bar", ";"), "; RUN: cat @file\n\n  ; CHECK: bar");
+    }
+
+    #[test]
+    fn match_mode_sets_how_every_check_is_interpreted() {
+        let commands = |text: &str| test_file(TestFilePath { absolute: "/tests/foo.txt".into(), relative: "foo.txt".into() }, text.chars()).map(|t| t.commands);
+        let regex = |r: &str| TextPattern { components: vec![PatternComponent::Regex(r.to_owned())] };
+
+        let regex_commands = commands("; CHECK: a+\n; MATCH-MODE: regex\n; CHECK[literal]: a+\n; CHECK-ALT: a+ | b?\n").unwrap();
+        assert_eq!(regex_commands.iter().map(|c| c.kind.clone()).collect::<Vec<_>>(), vec![
+            CommandKind::Check(regex("a+")),
+            CommandKind::MatchMode(MatchMode::Regex),
+            CommandKind::Check(text_pattern("a+")),
+            CommandKind::CheckAlt(vec![regex("a+"), regex("b?")]),
+        ]);
+
+        let whole_line_commands = commands("; MATCH-MODE: whole-line\n; CHECK{x86}: a\n").unwrap();
+        assert_eq!(whole_line_commands[1].kind, CommandKind::Check(TextPattern {
+            components: vec![PatternComponent::Regex("(?m:^)".to_owned()), PatternComponent::Text("a".to_owned()), PatternComponent::Regex("(?m:$)".to_owned())],
+        }));
+
+        assert!(commands("; MATCH-MODE: fuzzy\n").is_err());
+        assert!(commands("; MATCH-MODE: regex\n; MATCH-MODE: literal\n").is_err());
+        assert!(commands("; CHECK[regex]: [unclosed\n").is_err());
+        assert!(commands("; XFAIL[regex]:\n").is_err());
+    }
+}
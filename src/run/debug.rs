@@ -0,0 +1,138 @@
+//! Rerunning a single test with as much diagnostic information as possible.
+
+use crate::{Config, config::KeepTempfiles, model::*, util};
+use super::{directory_config, find_files};
+use std::{fmt, path::Path};
+
+/// Everything that is known about a single run of a test, for diagnosing why it failed.
+///
+/// The `Display` implementation renders an annotated transcript of the run.
+#[derive(Clone, Debug)]
+pub struct DebugReport {
+    /// The parsed test.
+    pub test_file: TestFile,
+    /// The text of the test file.
+    pub source: String,
+    /// The result of the test, including the complete output of every `RUN` command.
+    pub result: TestResult,
+    /// The config that the test was run with, after directory configuration files were applied.
+    pub config: Config,
+}
+
+/// Runs a single test with verbose matching, returning a report of how each directive was evaluated.
+///
+/// Output is never truncated, variable resolution is traced, and the tempfiles of every
+/// `RUN` command are kept for inspection.
+pub fn test<P>(path: P, config: &Config) -> Result<DebugReport, crate::Error>
+    where P: AsRef<Path> {
+    let path = path.as_ref();
+    let absolute = config.file_system.canonicalize(path)
+        .map_err(|e| crate::ErrorKind::Msg(format!("could not find test {}: {}", path.display(), e)))?;
+    let relative = find_files::relative_path::compute(&absolute, config).unwrap_or_else(|| path.to_owned());
+    let test_file_path = TestFilePath { absolute, relative };
+
+    let mut config = directory_config::Loader::default().for_test(&test_file_path, config).map_err(crate::ErrorKind::Msg)?;
+    config.truncate_output_context_to_number_of_lines = None;
+    config.context_lines = usize::MAX;
+    config.dump_variable_resolution = true;
+    config.always_show_stderr = true;
+    config.keep_tempfiles = KeepTempfiles::Always;
+
+    let source = config.file_system.read_to_string(&test_file_path.absolute)
+        .map_err(|e| crate::ErrorKind::Msg(format!("could not open {}: {}", test_file_path.absolute.display(), e)))?;
+    let test_file = super::util::parse_test(test_file_path, &config)?;
+    let result = super::single_file(&test_file, &config);
+
+    Ok(DebugReport { test_file, source, result, config })
+}
+
+impl DebugReport {
+    /// Checks if the test had a successful result.
+    pub fn is_successful(&self) -> bool {
+        !self.result.overall_result.is_erroneous_with_config(&self.config)
+    }
+
+    /// Gets the text of the directive on a line of the test file.
+    fn source_line(&self, line_number: u32) -> &str {
+        self.source.lines().nth((line_number as usize).saturating_sub(1)).unwrap_or("").trim()
+    }
+
+    fn describe_result(&self, kind: &TestResultKind) -> String {
+        match *kind {
+            TestResultKind::Pass => "passed".to_owned(),
+            TestResultKind::UnexpectedPass => "passed, but it was expected to fail".to_owned(),
+            TestResultKind::Error { ref message } | TestResultKind::SpawnError { ref message, .. } => format!("error: {}", message),
+            TestResultKind::Fail { ref reason, ref hint } => {
+                let hint = hint.as_ref().map(|hint| format!("\nhint: {}", hint)).unwrap_or_default();
+                format!("failed: {}\n\n{}{}", reason.human_summary(), reason.human_detail_message(&self.config), hint)
+            },
+            TestResultKind::ExpectedFailure { ref actual_reason } => format!("failed as expected: {}", actual_reason.human_summary()),
+            TestResultKind::EmptyTest => "no directives were found".to_owned(),
+            TestResultKind::Skip { ref reason } => format!("skipped ({})", reason),
+            TestResultKind::Timeout { duration } => format!("timed out after {:?}", duration),
+            TestResultKind::KnownFailure { ref actual_result } => format!("known failure: {}", self.describe_result(actual_result)),
+            TestResultKind::Custom { ref label, ref message, .. } => format!("{}: {}", label, message),
+        }
+    }
+}
+
+impl fmt::Display for DebugReport {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(fmt, "test: {}", self.result.path.relative.display())?;
+        writeln!(fmt, "result: {}", self.describe_result(&self.result.overall_result))?;
+
+        for (i, run) in self.result.individual_run_results.iter().enumerate() {
+            writeln!(fmt)?;
+            writeln!(fmt, "RUN #{} (line {}): {}", i + 1, run.line_number, self.source_line(run.line_number))?;
+            writeln!(fmt, "command line: {}", run.command_line)?;
+            writeln!(fmt, "result: {}", self.describe_result(&run.kind))?;
+            writeln!(fmt)?;
+
+            for check in run.checks.iter() {
+                let status = match (check.status, check.checked_up_to_line) {
+                    (CheckStatus::Passed, Some(line)) => format!("passed, output checked up to line {}", line),
+                    (CheckStatus::Failed, Some(line)) => format!("FAILED, output checked up to line {}", line),
+                    (CheckStatus::Disabled, _) => "disabled, its feature is not available".to_owned(),
+                    (CheckStatus::NotReached, _) | (_, None) => "not reached".to_owned(),
+                };
+                writeln!(fmt, "  line {}: {}", check.line_number, self.source_line(check.line_number))?;
+                writeln!(fmt, "    {}", status)?;
+            }
+
+            writeln!(fmt)?;
+            writeln!(fmt, "{}", format_test_output("stdout", &run.output.stdout, 1, util::TruncateDirection::Bottom, &self.config))?;
+            writeln!(fmt, "{}", format_test_output("stderr", &run.output.stderr, 1, util::TruncateDirection::Bottom, &self.config))?;
+
+            for tempfile in run.kept_tempfiles.iter() {
+                writeln!(fmt, "kept tempfile: {}", tempfile.display())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::InMemoryFileSystem;
+    use std::sync::Arc;
+
+    #[test]
+    fn reports_how_far_each_check_got() {
+        let mut file_system = InMemoryFileSystem::new();
+        file_system.add_file("/tests/foo.txt", "; RUN: printf 'a\\nb\\nc\\n'\n; CHECK: b\n; CHECK-NEXT: a\n; CHECK: c\n");
+
+        let config = Config { file_system: Arc::new(file_system), ..Config::default() };
+        let report = test("/tests/foo.txt", &config).unwrap();
+
+        assert!(!report.is_successful());
+        let checks = &report.result.individual_run_results[0].checks;
+        assert_eq!(checks.iter().map(|c| (c.status, c.checked_up_to_line)).collect::<Vec<_>>(),
+                   vec![(CheckStatus::Passed, Some(3)), (CheckStatus::Failed, Some(3)), (CheckStatus::NotReached, None)]);
+
+        let transcript = report.to_string();
+        assert!(transcript.contains("line 3: ; CHECK-NEXT: a\n    FAILED, output checked up to line 3\n"), "{}", transcript);
+        assert!(transcript.contains("command line: printf 'a\\nb\\nc\\n'"), "{}", transcript);
+    }
+}
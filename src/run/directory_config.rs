@@ -0,0 +1,270 @@
+//! Logic for loading per-directory configuration files.
+//!
+//! A configuration file applies to every test in its directory and below. The files
+//! use a small subset of TOML:
+//!
+//! ```toml
+//! # Comments and blank lines are ignored.
+//! features = ["asserts", "x86"]
+//! comment_prefix = ";"
+//! parallel = false
+//!
+//! [constants]
+//! tool = "/opt/tool/bin/tool"
+//! ```
+
+use crate::{Config, model::TestFilePath};
+use std::{collections::HashMap, io, path::{Path, PathBuf}};
+
+/// The settings of a single configuration file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DirectoryConfig {
+    /// Constants that tests can refer to via `@<name>` syntax.
+    pub constants: HashMap<String, String>,
+    /// Features that are available to the tests.
+    pub features: Vec<String>,
+    /// The prefix of the lines that contain directives.
+    pub comment_prefix: Option<String>,
+    /// Whether the tests may execute at the same time as each other.
+    pub parallel: Option<bool>,
+}
+
+/// Loads and caches the configuration files of test directories.
+#[derive(Default)]
+pub struct Loader {
+    configs_by_directory: HashMap<PathBuf, Option<DirectoryConfig>>,
+}
+
+impl Loader {
+    /// Gets the config of a test, which is the global config with the configuration files
+    /// of every directory containing the test layered over it.
+    ///
+    /// Only directories within the root that the test was found in are searched, which is the
+    /// part of the absolute path of the test that precedes its relative path. Configuration
+    /// files in nearer directories override those in farther ones.
+    pub fn for_test(&mut self, test_file_path: &TestFilePath, config: &Config) -> Result<Config, String> {
+        let config_file_name = match config.directory_config_file_name {
+            Some(ref name) => name,
+            None => return Ok(config.clone()),
+        };
+
+        let mut test_config = config.clone();
+        let test_path = &test_file_path.absolute;
+        // Tests whose relative path is not a suffix of their absolute path only use their own directory.
+        let depth = if test_path.ends_with(&test_file_path.relative) { test_file_path.relative.components().count() } else { 1 };
+        let mut directories: Vec<_> = test_path.ancestors().skip(1).take(depth).collect();
+        directories.reverse();
+
+        for directory in directories {
+            if let Some(directory_config) = self.load(directory, config_file_name, config)? {
+                test_config.constants.extend(directory_config.constants.clone());
+                for feature in directory_config.features.iter() {
+                    test_config.add_feature(feature);
+                }
+                if let Some(ref comment_prefix) = directory_config.comment_prefix {
+                    test_config.comment_prefix = Some(comment_prefix.clone());
+                }
+                match directory_config.parallel {
+                    Some(false) => test_config.serialization_key = Some(directory.to_owned()),
+                    Some(true) => test_config.serialization_key = None,
+                    None => (),
+                }
+            }
+        }
+
+        Ok(test_config)
+    }
+
+    fn load(&mut self, directory: &Path, config_file_name: &str, config: &Config) -> Result<Option<&DirectoryConfig>, String> {
+        if !self.configs_by_directory.contains_key(directory) {
+            let config_file_path = directory.join(config_file_name);
+
+            let directory_config = match config.file_system.read_to_string(&config_file_path) {
+                Ok(text) => Some(parse(&text).map_err(|e| format!("could not parse configuration file '{}': {}", config_file_path.display(), e))?),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(format!("could not read configuration file '{}': {}", config_file_path.display(), e)),
+            };
+
+            self.configs_by_directory.insert(directory.to_owned(), directory_config);
+        }
+
+        Ok(self.configs_by_directory[directory].as_ref())
+    }
+}
+
+/// Parses the contents of a configuration file.
+pub fn parse(text: &str) -> Result<DirectoryConfig, String> {
+    let mut directory_config = DirectoryConfig::default();
+    let mut section = None;
+
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let error = |message: String| format!("line {}: {}", line_idx + 1, message);
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(section_name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            match section_name.trim() {
+                "constants" => section = Some("constants"),
+                name => return Err(error(format!("unknown section '{}'", name))),
+            }
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => return Err(error(format!("expected 'key = value' but got '{}'", line))),
+        };
+
+        match (section, key) {
+            (Some("constants"), name) => {
+                directory_config.constants.insert(name.to_owned(), string(value).map_err(error)?);
+            },
+            (None, "features") => directory_config.features = array_of_strings(value).map_err(error)?,
+            (None, "comment_prefix") => directory_config.comment_prefix = Some(string(value).map_err(error)?),
+            (None, "parallel") => directory_config.parallel = Some(boolean(value).map_err(error)?),
+            (None, key) => return Err(error(format!("unknown key '{}'", key))),
+            (Some(..), _) => unreachable!(),
+        }
+    }
+
+    Ok(directory_config)
+}
+
+/// Parses a double-quoted string, along with the escapes `\"`, `\\`, `\n` and `\t`.
+fn string(value: &str) -> Result<String, String> {
+    let inner = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) if value.len() >= 2 => inner,
+        _ => return Err(format!("expected a double-quoted string but got '{}'", value)),
+    };
+
+    let mut unescaped = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('"') => unescaped.push('"'),
+                Some('\\') => unescaped.push('\\'),
+                Some('n') => unescaped.push('\n'),
+                Some('t') => unescaped.push('\t'),
+                _ => return Err(format!("invalid escape sequence in '{}'", value)),
+            },
+            '"' => return Err(format!("unescaped quote in '{}'", value)),
+            c => unescaped.push(c),
+        }
+    }
+
+    Ok(unescaped)
+}
+
+/// Parses `true` or `false`.
+fn boolean(value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("expected 'true' or 'false' but got '{}'", value)),
+    }
+}
+
+/// Parses an array of double-quoted strings, like `["a", "b"]`.
+fn array_of_strings(value: &str) -> Result<Vec<String>, String> {
+    let inner = match value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+        Some(inner) => inner.trim(),
+        None => return Err(format!("expected an array of strings but got '{}'", value)),
+    };
+
+    if inner.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Strings cannot contain commas, which keeps the parsing simple.
+    inner.trim_end_matches(',').split(',').map(|element| self::string(element.trim())).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::InMemoryFileSystem;
+    use std::sync::Arc;
+
+    #[test]
+    fn parses_constants_features_and_comment_prefix() {
+        let directory_config = parse("# tool settings\n\nfeatures = [\"asserts\", \"x86\"]\ncomment_prefix = \";\"\n\n[constants]\ntool = \"/opt/tool\"\nflags = \"-a \\\"b\\\"\"\n").unwrap();
+
+        assert_eq!(directory_config.features, vec!["asserts".to_owned(), "x86".to_owned()]);
+        assert_eq!(directory_config.comment_prefix, Some(";".to_owned()));
+        assert_eq!(directory_config.constants["tool"], "/opt/tool");
+        assert_eq!(directory_config.constants["flags"], "-a \"b\"");
+    }
+
+    #[test]
+    fn invalid_lines_are_errors() {
+        assert_eq!(parse("features = [\"a\"]\nverbose = true\n"), Err("line 2: unknown key 'verbose'".to_owned()));
+        assert!(parse("[substitutions]").is_err());
+        assert!(parse("comment_prefix = ;").is_err());
+        assert!(parse("features = \"a\"").is_err());
+        assert!(parse("parallel = no").is_err());
+    }
+
+    fn config_with_files(files: &[(&str, &str)]) -> Config {
+        let mut file_system = InMemoryFileSystem::new();
+        for &(path, contents) in files {
+            file_system.add_file(path, contents);
+        }
+
+        Config {
+            directory_config_file_name: Some("lit.toml".to_owned()),
+            file_system: Arc::new(file_system),
+            ..Config::default()
+        }
+    }
+
+    /// The path of a test that was found in `/tests`.
+    fn test_path(relative: &str) -> TestFilePath {
+        TestFilePath { absolute: Path::new("/tests").join(relative), relative: PathBuf::from(relative) }
+    }
+
+    #[test]
+    fn nearer_configuration_files_override_farther_ones() {
+        let config = config_with_files(&[
+            ("/tests/lit.toml", "features = [\"outer\"]\n[constants]\ntool = \"outer\"\nflags = \"-x\"\n"),
+            ("/tests/nested/lit.toml", "features = [\"inner\"]\n[constants]\ntool = \"inner\"\n"),
+        ]);
+        let mut loader = Loader::default();
+
+        let nested_config = loader.for_test(&test_path("nested/test.txt"), &config).unwrap();
+        assert_eq!(nested_config.constants["tool"], "inner");
+        assert_eq!(nested_config.constants["flags"], "-x");
+        assert!(nested_config.is_feature_available("outer") && nested_config.is_feature_available("inner"));
+
+        let outer_config = loader.for_test(&test_path("test.txt"), &config).unwrap();
+        assert_eq!(outer_config.constants["tool"], "outer");
+        assert!(!outer_config.is_feature_available("inner"));
+    }
+
+    #[test]
+    fn configuration_files_outside_the_test_root_are_ignored() {
+        let config = config_with_files(&[("/lit.toml", "features = [\"outside\"]\n"), ("/tests/lit.toml", "features = [\"inside\"]\n")]);
+        let test_config = Loader::default().for_test(&test_path("test.txt"), &config).unwrap();
+
+        assert!(test_config.is_feature_available("inside"));
+        assert!(!test_config.is_feature_available("outside"));
+    }
+
+    #[test]
+    fn tests_in_non_parallel_directories_share_a_serialization_key() {
+        let config = config_with_files(&[
+            ("/tests/serial/lit.toml", "parallel = false\n"),
+            ("/tests/serial/parallel/lit.toml", "parallel = true\n"),
+        ]);
+        let serial_dir = Path::new("/tests/serial");
+        let mut loader = Loader::default();
+
+        assert_eq!(loader.for_test(&test_path("serial/a.txt"), &config).unwrap().serialization_key, Some(serial_dir.to_owned()));
+        assert_eq!(loader.for_test(&test_path("serial/b.txt"), &config).unwrap().serialization_key, Some(serial_dir.to_owned()));
+        assert_eq!(loader.for_test(&test_path("serial/parallel/c.txt"), &config).unwrap().serialization_key, None);
+        assert_eq!(loader.for_test(&test_path("d.txt"), &config).unwrap().serialization_key, None);
+    }
+}
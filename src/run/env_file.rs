@@ -0,0 +1,91 @@
+//! Logic for loading per-directory environment files.
+
+use crate::{Config, model::TestFile};
+use std::{collections::HashMap, io};
+
+/// Loads the environment file that applies to a test, if there is one.
+pub fn for_test(test_file: &TestFile, config: &Config) -> Result<HashMap<String, String>, String> {
+    let env_file_name = match config.env_file_name {
+        Some(ref name) => name,
+        None => return Ok(HashMap::new()),
+    };
+
+    let env_file_path = match test_file.path.absolute.parent() {
+        Some(test_dir) => test_dir.join(env_file_name),
+        None => return Ok(HashMap::new()),
+    };
+
+    match config.file_system.read_to_string(&env_file_path) {
+        Ok(text) => parse(&text).map_err(|e| format!("could not parse environment file '{}': {}", env_file_path.display(), e)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(format!("could not read environment file '{}': {}", env_file_path.display(), e)),
+    }
+}
+
+/// Parses the contents of an environment file.
+pub fn parse(text: &str) -> Result<HashMap<String, String>, String> {
+    let mut variables = HashMap::new();
+
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+
+        let (name, value) = match line.find('=') {
+            Some(equals_idx) => (line[..equals_idx].trim(), line[equals_idx + 1..].trim()),
+            None => return Err(format!("line {}: expected 'NAME=VALUE' but got '{}'", line_idx + 1, line)),
+        };
+
+        if name.is_empty() || name.chars().any(char::is_whitespace) {
+            return Err(format!("line {}: invalid variable name '{}'", line_idx + 1, name));
+        }
+
+        variables.insert(name.to_owned(), unquote(value).to_owned());
+    }
+
+    Ok(variables)
+}
+
+/// Removes a matching pair of quotes surrounding a value.
+fn unquote(value: &str) -> &str {
+    for quote in &['"', '\''] {
+        if value.len() >= 2 && value.starts_with(*quote) && value.ends_with(*quote) {
+            return &value[1..value.len() - 1];
+        }
+    }
+
+    value
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_variables_ignoring_comments_and_blank_lines() {
+        let variables = parse("# licensing\n\nLICENSE_PATH=/opt/license\nexport CONFIG_DIR = \"/etc/tool\"\n").unwrap();
+
+        assert_eq!(variables.len(), 2);
+        assert_eq!(variables["LICENSE_PATH"], "/opt/license");
+        assert_eq!(variables["CONFIG_DIR"], "/etc/tool");
+    }
+
+    #[test]
+    fn values_may_contain_equals_signs() {
+        assert_eq!(parse("FLAGS=-a=b").unwrap()["FLAGS"], "-a=b");
+    }
+
+    #[test]
+    fn line_without_equals_is_an_error() {
+        assert_eq!(parse("A=1\nNOT A VARIABLE"), Err("line 2: expected 'NAME=VALUE' but got 'NOT A VARIABLE'".to_owned()));
+    }
+
+    #[test]
+    fn empty_name_is_an_error() {
+        assert!(parse("=value").is_err());
+    }
+}
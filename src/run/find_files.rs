@@ -1,10 +1,9 @@
-//! Functions for retrieving lists of files from disk.
+//! Functions for retrieving lists of files from the file system of the config.
 
 use crate::{Config, model::TestFilePath};
 
 use std;
 use std::path::Path;
-use walkdir::WalkDir;
 
 /// Recursively finds tests for the given paths.
 pub fn with_config(config: &Config) -> Result<Vec<TestFilePath>, String> {
@@ -18,7 +17,7 @@ pub fn with_config(config: &Config) -> Result<Vec<TestFilePath>, String> {
     }
 
     let test_paths = absolute_paths.into_iter().map(|absolute_path| {
-        let absolute_path = std::fs::canonicalize(absolute_path).unwrap();
+        let absolute_path = config.file_system.canonicalize(&absolute_path).unwrap();
         let relative_path =  relative_path::compute(&absolute_path, config).expect("could not compute relative path");
 
         TestFilePath { absolute: absolute_path, relative: relative_path }
@@ -30,20 +29,20 @@ pub fn with_config(config: &Config) -> Result<Vec<TestFilePath>, String> {
 pub fn in_path(path: &str,
                config: &Config)
     -> Result<Vec<String>,String> {
-    let metadata = match std::fs::metadata(path) {
-        Ok(meta) => meta,
+    let is_dir = match config.file_system.is_dir(Path::new(path)) {
+        Ok(is_dir) => is_dir,
         Err(e) => return Err(format!("failed to open '{}': {}",
                                      path, e)),
     };
 
-    if metadata.is_dir() {
+    if is_dir {
         tests_in_dir(path, config)
     } else {
         Ok(vec![path.to_owned()])
     }
 }
 
-mod relative_path {
+pub(crate) mod relative_path {
     use crate::Config;
     use std::path::{Path, PathBuf};
 
@@ -81,11 +80,7 @@ mod relative_path {
 
         let least_specific_matching_test_search_directory = matching_parent_test_search_directories.min_by_key(|p| p.components().count());
 
-        if let Some(least_specific_matching_test_search_directory) = least_specific_matching_test_search_directory {
-            Some(least_specific_matching_test_search_directory.to_owned())
-        } else {
-            None
-        }
+        least_specific_matching_test_search_directory.map(|p| p.to_owned())
     }
 
     /// Otherwise, find the most common path from all the test file paths.
@@ -143,7 +138,7 @@ mod relative_path {
             };
 
             assert_eq!(super::compute(
-                    &Path::new("/home/foo/projects/cool-project/tests/run-pass/test1.txt"), &config),
+                    Path::new("/home/foo/projects/cool-project/tests/run-pass/test1.txt"), &config),
                 Some(Path::new("run-pass/test1.txt").to_owned()));
         }
 
@@ -159,7 +154,7 @@ mod relative_path {
             };
 
             assert_eq!(super::least_specific_parent_test_search_directory_path(
-                    &Path::new("/home/foo/projects/cool-project/tests/run-pass/test1.txt"), &config),
+                    Path::new("/home/foo/projects/cool-project/tests/run-pass/test1.txt"), &config),
                 Some(Path::new("/home/foo/projects/cool-project/tests/").to_owned()));
         }
 
@@ -173,7 +168,7 @@ mod relative_path {
             };
 
             assert_eq!(super::least_specific_parent_test_search_directory_path(
-                    &Path::new("/home/foo/projects/cool-project/tests/run-pass/test1.txt"), &config),
+                    Path::new("/home/foo/projects/cool-project/tests/run-pass/test1.txt"), &config),
                 Some(Path::new("/home/foo/projects/cool-project/tests/").to_owned()));
         }
 
@@ -189,7 +184,7 @@ mod relative_path {
             };
 
             assert_eq!(super::most_common_test_path_ancestor(
-                    &Path::new("/home/foo/projects/cool-project/tests/run-pass/test1.txt"), &config),
+                    Path::new("/home/foo/projects/cool-project/tests/run-pass/test1.txt"), &config),
                 Some(Path::new("/home/foo/projects/cool-project/tests").to_owned()));
         }
 
@@ -207,7 +202,7 @@ mod relative_path {
             };
 
             assert_eq!(super::most_common_test_path_ancestor(
-                    &Path::new("C:/tests/run-pass/test2.txt"), &config),
+                    Path::new("C:/tests/run-pass/test2.txt"), &config),
                 None);
         }
     }
@@ -215,7 +210,7 @@ mod relative_path {
 
 fn tests_in_dir(path: &str,
                 config: &Config) -> Result<Vec<String>,String> {
-    let tests = files_in_dir(path)?.into_iter()
+    let tests = files_in_dir(path, config)?.into_iter()
                      .filter(|f| {
                          let path = std::path::Path::new(f);
                          path.extension().map(|ext| config.is_extension_supported(ext.to_str().unwrap())).unwrap_or(false)
@@ -224,22 +219,32 @@ fn tests_in_dir(path: &str,
     Ok(tests)
 }
 
-fn files_in_dir(path: &str) -> Result<Vec<String>,String> {
-    let mut dir_tests = Vec::new();
-
-    for entry in WalkDir::new(path) {
-        let entry = entry.unwrap();
+fn files_in_dir(path: &str, config: &Config) -> Result<Vec<String>,String> {
+    let files = config.file_system.files_in_dir(Path::new(path))
+        .map_err(|e| format!("failed to list the files in '{}': {}", path, e))?;
 
-        // don't go into an infinite loop
-        if entry.path().to_str().unwrap() == path {
-            continue;
-        }
+    Ok(files.into_iter().map(|file| file.to_str().unwrap().to_owned()).collect())
+}
 
-        if entry.metadata().unwrap().is_file() {
-            dir_tests.push(entry.path().to_str().unwrap().to_owned());
-        }
+#[cfg(test)]
+mod test {
+    use crate::{Config, InMemoryFileSystem};
+    use std::{path::PathBuf, sync::Arc};
+
+    #[test]
+    fn tests_are_found_in_the_file_system_of_the_config() {
+        let mut file_system = InMemoryFileSystem::new();
+        file_system.add_file("/tests/b.txt", "").add_file("/tests/a/test.sh", "").add_file("/tests/a/input.dat", "");
+
+        let config = Config {
+            test_paths: vec![PathBuf::from("/tests")],
+            supported_file_extensions: vec!["txt".to_owned(), "sh".to_owned()],
+            file_system: Arc::new(file_system),
+            ..Config::default()
+        };
+
+        let relative_paths: Vec<_> = super::with_config(&config).unwrap().into_iter().map(|p| p.relative).collect();
+        assert_eq!(relative_paths, vec![PathBuf::from("a/test.sh"), PathBuf::from("b.txt")]);
     }
-
-    Ok(dir_tests)
 }
 
@@ -1,12 +1,24 @@
 //! Routines for running tests.
 
+mod debug;
+mod directory_config;
+mod env_file;
 pub(crate) mod find_files;
+mod parse_cache;
+mod plan;
 mod test_evaluator;
 
+pub use self::debug::{test as debug_test, DebugReport};
+pub use self::plan::export as export_plan;
 pub use self::test_evaluator::CommandLine;
+#[allow(deprecated)]
+pub use self::test_evaluator::TestEvaluator;
+pub(crate) use self::test_evaluator::run_against;
 
-use crate::{Config, event_handler::{EventHandler, TestSuiteDetails}};
+use crate::{Config, event_handler::{EventHandler, Progress, TestSuiteDetails}};
 use crate::model::*;
+use std::sync::{atomic::{AtomicUsize, Ordering}, mpsc};
+use std::{collections::HashMap, path::PathBuf, thread, time::{Duration, Instant}};
 
 /// Runs all tests according to a given config.
 ///
@@ -17,11 +29,79 @@ use crate::model::*;
 /// * `config_fn` is a function which sets up the test config.
 /// * `event_handler` is an object which presents the user interface to the user.
 ///
+#[allow(clippy::result_unit_err)]
 pub fn tests<F>(
-    mut event_handler: impl EventHandler,
+    event_handler: impl EventHandler,
     config_fn: F,
     ) -> Result<(), ()>
     where F: Fn(&mut Config) {
+    let (results, config) = self::run_test_suite(event_handler, config_fn);
+
+    if results.exit_code(&config) == 0 { Ok(()) } else { Err(()) }
+}
+
+/// Runs all tests according to a given config, returning the results of every test.
+///
+/// The results are always stored in the order that the tests were discovered in,
+/// regardless of the order that they finished executing in.
+///
+/// # Parameters
+///
+/// * `config_fn` is a function which sets up the test config.
+/// * `event_handler` is an object which presents the user interface to the user.
+///
+pub fn tests_with_results<F>(
+    event_handler: impl EventHandler,
+    config_fn: F,
+    ) -> Results
+    where F: Fn(&mut Config) {
+    self::run_test_suite(event_handler, config_fn).0
+}
+
+/// Parses every test that would be run according to a given config, without running any of them.
+///
+/// Returns the path and error of every test file that could not be parsed.
+pub fn validate(config: &Config) -> Vec<(PathBuf, crate::Error)> {
+    let test_paths = match find_files::with_config(config) {
+        Ok(paths) => paths,
+        Err(e) => return config.test_paths.iter().map(|path| (path.clone(), crate::ErrorKind::Msg(e.clone()).into())).collect(),
+    };
+
+    self::parse_tests(test_paths, config).into_iter().filter_map(Result::err).collect()
+}
+
+/// Lists every test that would be run according to a given config, without running any of them.
+pub fn list(config: &Config) -> Result<Vec<TestInfo>, crate::Error> {
+    let test_paths = find_files::with_config(config).map_err(crate::ErrorKind::Msg)?;
+
+    self::parse_tests(test_paths, config).into_iter().filter_map(|parsed| match parsed {
+        Ok((test_file, _)) => if config.is_selected(&test_file) { Some(Ok(test_file.info())) } else { None },
+        Err((_, e)) => Some(Err(e)),
+    }).collect()
+}
+
+/// Parses tests, each with its own config which includes its directory configuration files.
+fn parse_tests(test_paths: Vec<TestFilePath>, config: &Config) -> Vec<Result<(TestFile, Config), (PathBuf, crate::Error)>> {
+    let mut directory_configs = directory_config::Loader::default();
+
+    test_paths.into_iter().map(|test_file_path| {
+        let absolute_path = test_file_path.absolute.clone();
+        let test_config = directory_configs.for_test(&test_file_path, config)
+            .map_err(|e| (absolute_path.clone(), crate::ErrorKind::Msg(e).into()))?;
+
+        match util::parse_test(test_file_path, &test_config) {
+            Ok(test_file) => Ok((test_file, test_config)),
+            Err(e) => Err((absolute_path, e)),
+        }
+    }).collect()
+}
+
+/// Runs all tests, returning the results along with the config they were run with.
+fn run_test_suite<F>(
+    mut event_handler: impl EventHandler,
+    config_fn: F,
+    ) -> (Results, Config)
+    where F: Fn(&mut Config) {
     let mut config = Config::default();
     config_fn(&mut config);
 
@@ -38,86 +118,230 @@ pub fn tests<F>(
         Ok(paths) => paths,
         Err(e) => util::abort(format!("could not find test files: {}", e)),
     };
+    let number_of_found_test_files = test_paths.len();
+
+    let test_paths: Vec<_> = match config.shard {
+        Some((shard_index, shard_count)) if shard_index < shard_count => {
+            test_paths.into_iter().enumerate().filter(|(i, _)| i % shard_count == shard_index).map(|(_, p)| p).collect()
+        },
+        Some((shard_index, shard_count)) => util::abort(format!("shard index {} is out of range for {} shards", shard_index, shard_count)),
+        None => test_paths,
+    };
 
     if test_paths.is_empty() {
         event_handler.note_warning("could not find any tests");
-        return Err(());
+        return (Results { test_results: Vec::new(), cancelled: false, total_duration: Duration::ZERO }, config);
+    }
+
+    let started_at = Instant::now();
+    let parsed_tests = self::parse_tests(test_paths, &config);
+
+    if config.validate_first {
+        let errors: Vec<_> = parsed_tests.iter().filter_map(|parsed| parsed.as_ref().err()).map(|(_, e)| format!("  {}", e)).collect();
+
+        if !errors.is_empty() {
+            util::abort(format!("{} test files are invalid, so no tests were run:\n{}", errors.len(), errors.join("\n")));
+        }
     }
 
+    let test_files: Vec<_> = parsed_tests.into_iter().map(|parsed| match parsed {
+        Ok(parsed) => parsed,
+        Err((_, e)) => util::abort(e.to_string()),
+    }).filter(|(test_file, _)| config.is_selected(test_file)).collect();
+
     let test_suite_details = TestSuiteDetails {
-        number_of_test_files: test_paths.len(),
+        number_of_test_files: test_files.len(),
+        number_of_filtered_test_files: number_of_found_test_files - test_files.len(),
     };
 
     event_handler.on_test_suite_started(&test_suite_details, &config);
 
-    let mut has_failure = false;
-    for test_file_path in test_paths {
-        let test_file = util::parse_test(test_file_path).unwrap();
-        let is_successful = self::single_file(&test_file, &mut event_handler, &config, &artifact_config);
-
-        if !is_successful { has_failure = true; }
+    // Tests may finish in any order, so each result is stored at the index of its test.
+    let mut results_by_test_index: Vec<Option<TestResult>> = test_files.iter().map(|_| None).collect();
+    let mut batches = self::serialized_batches(&test_files);
+    if let Some(seed) = config.shuffle_seed {
+        crate::util::shuffle(&mut batches, seed);
     }
-    let is_successful = !has_failure;
+    let next_batch_index = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        let (event_sender, event_receiver) = mpsc::channel();
+
+        for _ in 0..config.jobs.max(1) {
+            let event_sender = event_sender.clone();
+            let (test_files, batches, next_batch_index, config) = (&test_files, &batches, &next_batch_index, &config);
+
+            scope.spawn(move || 'batches: while let Some(batch) = batches.get(next_batch_index.fetch_add(1, Ordering::SeqCst)) {
+                for &test_index in batch {
+                    if config.is_cancelled() { break 'batches }
+
+                    let (test_file, test_config) = &test_files[test_index];
+                    if event_sender.send(TestEvent::Started(test_index)).is_err() { break 'batches }
+                    let result = self::single_file(test_file, test_config);
+                    if event_sender.send(TestEvent::Finished(test_index, Box::new(result))).is_err() { break 'batches }
+                }
+            });
+        }
+        drop(event_sender);
+
+        let (mut completed, mut failed) = (0, 0);
+        for event in event_receiver {
+            let (test_index, result) = match event {
+                TestEvent::Started(test_index) => {
+                    event_handler.on_test_started(&test_files[test_index].0, &config);
+                    continue;
+                },
+                TestEvent::Finished(test_index, result) => (test_index, *result),
+            };
+
+            completed += 1;
+            if result.overall_result.is_erroneous_with_config(&config) { failed += 1; }
+
+            save_artifacts::run_results(&result, &test_files[test_index].0, &artifact_config);
+            event_handler.on_test_finished(result.clone(), &config);
+            event_handler.on_progress(&Progress { completed, failed, total: test_files.len(), elapsed: started_at.elapsed() }, &config);
+
+            results_by_test_index[test_index] = Some(result);
+        }
+    });
+
+    // Tests which did not start before the run was cancelled are skipped.
+    let test_results = results_by_test_index.into_iter().zip(&test_files).map(|(result, (test_file, _))| result.unwrap_or_else(|| TestResult {
+        path: test_file.path.clone(),
+        overall_result: TestResultKind::Skip { reason: SkipReason::Cancelled },
+        individual_run_results: Vec::new(),
+        metadata: test_file.metadata(),
+        duration: Duration::ZERO,
+    })).collect();
+
+    let results = Results {
+        test_results,
+        cancelled: config.is_cancelled(),
+        total_duration: started_at.elapsed(),
+    };
+    let is_successful = results.is_successful_with_config(&config) && !results.cancelled;
 
     event_handler.on_test_suite_finished(is_successful, &config);
     save_artifacts::suite_status(is_successful, &artifact_config);
 
-    if !has_failure { Ok(()) } else { Err(()) }
+    (results, config)
 }
 
-/// Executes a single, parsed test file.
+/// A notification from a thread executing tests, to the thread which drives the event handler.
+enum TestEvent {
+    /// The test with the index has started executing.
+    Started(usize),
+    /// The test with the index has finished executing.
+    Finished(usize, Box<TestResult>),
+}
+
+/// Groups the indices of tests into batches that can execute at the same time as each other.
 ///
-/// Returns `true` if all the tests in the file succeeded.
+/// The tests within a batch execute one after the other. Tests that share a serialization
+/// key are put in the same batch, and every other test is a batch of its own.
+fn serialized_batches(test_files: &[(TestFile, Config)]) -> Vec<Vec<usize>> {
+    let mut batches: Vec<Vec<usize>> = Vec::new();
+    let mut batch_indices_by_key = HashMap::new();
+
+    for (test_index, (_, test_config)) in test_files.iter().enumerate() {
+        match test_config.serialization_key {
+            Some(ref key) => {
+                let batch_index = *batch_indices_by_key.entry(key).or_insert_with(|| {
+                    batches.push(Vec::new());
+                    batches.len() - 1
+                });
+                batches[batch_index].push(test_index);
+            },
+            None => batches.push(vec![test_index]),
+        }
+    }
+
+    batches
+}
+
+/// Executes a single, parsed test file.
 fn single_file(
     test_file: &TestFile,
-    event_handler: &mut dyn EventHandler,
     config: &Config,
-    artifact_config: &save_artifacts::Config,
-    ) -> bool {
-    let test_results = test_evaluator::execute_tests(test_file, config);
-
-    // The overall result is failure if there are any failures, otherwise it is a pass.
-    let overall_result = test_results.iter().map(|(r, _, _, _)| r).filter(|r| match *r {
-        TestResultKind::Pass { .. } => false,
-        _ => true,
-    }).next().cloned().unwrap_or(TestResultKind::Pass);
+    ) -> TestResult {
+    let started_at = Instant::now();
+
+    if let Some(reason) = test_file.skip_reason(config) {
+        return TestResult {
+            path: test_file.path.clone(),
+            overall_result: TestResultKind::Skip { reason },
+            individual_run_results: Vec::new(),
+            metadata: test_file.metadata(),
+            duration: started_at.elapsed(),
+        };
+    }
 
-    let result = TestResult {
-        path: test_file.path.clone(),
-        overall_result,
-        individual_run_results: test_results.into_iter().map(|(a, b, c, d)| (a, b.clone(), c, d)).collect(),
-    };
+    let individual_run_results = test_evaluator::execute_tests(test_file, config);
 
-    save_artifacts::run_results(&result, test_file, artifact_config);
+    // The test does not have the RUN directive that was selected.
+    if let (true, Some(run_line)) = (individual_run_results.is_empty(), config.run_line) {
+        return TestResult {
+            path: test_file.path.clone(),
+            overall_result: TestResultKind::Skip { reason: SkipReason::RunLineNotFound(run_line) },
+            individual_run_results,
+            metadata: test_file.metadata(),
+            duration: started_at.elapsed(),
+        };
+    }
 
-    let is_erroneous = result.overall_result.is_erroneous();
+    // The overall result is failure if there are any failures, otherwise it is a pass.
+    let overall_result = individual_run_results.iter().map(|r| &r.kind).find(|r| !matches!(*r, TestResultKind::Pass)).cloned().unwrap_or(TestResultKind::Pass);
 
-    event_handler.on_test_finished(result, config);
+    let overall_result = if config.allow_failures.contains(&test_file.path.absolute) || config.allow_failures.contains(&test_file.path.relative) {
+        match overall_result {
+            TestResultKind::Fail { .. } | TestResultKind::Error { .. } | TestResultKind::SpawnError { .. } => TestResultKind::KnownFailure { actual_result: Box::new(overall_result) },
+            TestResultKind::Pass => TestResultKind::UnexpectedPass,
+            r => r,
+        }
+    } else {
+        overall_result
+    };
 
-    !is_erroneous
+    TestResult {
+        path: test_file.path.clone(),
+        overall_result,
+        individual_run_results,
+        metadata: test_file.metadata(),
+        duration: started_at.elapsed(),
+    }
 }
 
 mod util
 {
     use crate::model::*;
-    use crate::parse;
+    use crate::{parse, Config};
 
-    use std::{io::Read, path::Path};
     use std;
 
-    pub fn parse_test(path: TestFilePath) -> Result<TestFile, String> {
-        let mut text = String::new();
-        open_file(&path.absolute).read_to_string(&mut text).unwrap();
-        parse::test_file(path, text.chars())
-    }
+    pub fn parse_test(path: TestFilePath, config: &Config) -> Result<TestFile, crate::Error> {
+        let mut text = match config.file_system.read_to_string(&path.absolute) {
+            Ok(text) => text,
+            Err(e) => return Err(crate::ErrorKind::Msg(format!("could not open {}: {}", path.absolute.display(), e)).into()),
+        };
+
+        if config.header_only_directives {
+            let header_length = parse::directive_header(&text).len();
+            text.truncate(header_length);
+        }
 
-    fn open_file(path: &Path) -> std::fs::File {
-        match std::fs::File::open(path) {
-            Ok(f) => f,
-            Err(e) => abort(format!("could not open {}: {}",
-                                    path.display(), e.to_string())),
+        if let Some(ref comment_prefix) = config.comment_prefix {
+            text = parse::lines_with_comment_prefix(&text, comment_prefix);
         }
+
+        let absolute_path = path.absolute.clone();
+        let test_file = match config.parse_cache_directory {
+            Some(ref cache_dir) => super::parse_cache::test_file(path, &text, cache_dir, config),
+            None => parse::test_file(path, text.chars()),
+        };
+
+        Ok(test_file.map_err(|message| crate::ErrorKind::ParseError(absolute_path, message))?)
     }
+
     pub fn abort<S>(msg: S) -> !
         where S: Into<String> {
         eprintln!("error: {}", msg.into());
@@ -127,12 +351,11 @@ mod util
 }
 
 mod save_artifacts {
-    use super::CommandLine;
     use crate::model::*;
     use std::path::{Path, PathBuf};
     use std::fs;
 
-    const SUITE_STATUS_PATH: &'static str = "suite-status.txt";
+    const SUITE_STATUS_PATH: &str = "suite-status.txt";
 
     #[derive(Clone, Debug)]
     pub struct Config {
@@ -140,7 +363,7 @@ mod save_artifacts {
     }
 
     pub fn suite_status(is_successful: bool, config: &Config) {
-        save(&Path::new(SUITE_STATUS_PATH), config, || {
+        save(Path::new(SUITE_STATUS_PATH), config, || {
             if is_successful {
                 "successful\n"
             } else {
@@ -152,13 +375,15 @@ mod save_artifacts {
     pub fn run_results(test_result: &TestResult, test_file: &TestFile, artifact_config: &Config) {
         let only_one_run_command = test_result.individual_run_results.len() == 1;
 
-        for (i, (result_kind, _, command_line, output)) in test_result.individual_run_results.iter().enumerate() {
+        for (i, run_result) in test_result.individual_run_results.iter().enumerate() {
             let run_number = if only_one_run_command { None } else { Some(i + 1) };
-            self::individual_run_result(run_number, result_kind, command_line, output, test_file, artifact_config);
+            self::individual_run_result(run_number, run_result, test_file, artifact_config);
         }
     }
 
-    pub fn individual_run_result(run_number: Option<usize>, result_kind: &TestResultKind, command_line: &CommandLine, output: &ProgramOutput, test_file: &TestFile, config: &Config) {
+    pub fn individual_run_result(run_number: Option<usize>, run_result: &IndividualRunResult, test_file: &TestFile, config: &Config) {
+        let IndividualRunResult { kind: result_kind, command_line, output, .. } = run_result;
+
         let test_file_extension = test_file.path.absolute.extension().and_then(|s| s.to_str()).unwrap_or("txt");
 
         let dir_run_result = match run_number {
@@ -174,9 +399,9 @@ mod save_artifacts {
         save(&dir_run_result.join("stderr.txt"), config, || &output.stderr[..]);
         save(&dir_run_result.join("command-line.txt"), config, || format!("{}\n", command_line.0));
 
-        save(&dir_run_result.join(&format!("copy-of-test-case.{}", test_file_extension)), config, || std::fs::read(&test_file.path.absolute).unwrap());
+        save(&dir_run_result.join(format!("copy-of-test-case.{}", test_file_extension)), config, || std::fs::read(&test_file.path.absolute).unwrap());
 
-        create_symlink(&test_file.path.absolute, &dir_run_result.join(&format!("symlink-to-test-case.{}", test_file_extension)), config)
+        create_symlink(&test_file.path.absolute, &dir_run_result.join(format!("symlink-to-test-case.{}", test_file_extension)), config)
     }
 
     fn save<C>(relative_path: &Path, config: &Config, render: impl FnOnce() -> C )
@@ -210,3 +435,161 @@ mod save_artifacts {
 
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{Config, InMemoryFileSystem, event_handler::Progress, model::{SkipReason, TestFile, TestFilePath, TestResult, TestResultKind}};
+    use std::{path::{Path, PathBuf}, sync::Arc};
+
+    #[test]
+    fn validation_reports_every_invalid_test() {
+        let mut file_system = InMemoryFileSystem::new();
+        file_system.add_file("/tests/a.txt", "; CHEKC: typo\n")
+            .add_file("/tests/b.txt", "; RUN: true\n; CHECK: ok\n")
+            .add_file("/tests/c.txt", "; TIMEOUT: soon\n");
+
+        let config = Config {
+            test_paths: vec![PathBuf::from("/tests")],
+            supported_file_extensions: vec!["txt".to_owned()],
+            file_system: Arc::new(file_system),
+            ..Config::default()
+        };
+
+        let invalid_paths: Vec<_> = super::validate(&config).into_iter().map(|(path, _)| path).collect();
+        assert_eq!(invalid_paths, vec![PathBuf::from("/tests/a.txt"), PathBuf::from("/tests/c.txt")]);
+        assert!(super::list(&config).is_err());
+    }
+
+    #[test]
+    fn tests_are_listed_with_a_summary_of_their_directives() {
+        let mut file_system = InMemoryFileSystem::new();
+        file_system.add_file("/tests/a.txt", "; RUN: true\n; RUN: false\n; META: owner=me\n; DEPENDS: inputs/a.c\n; CHECK{x86}: a\n; CHECK-NEXT: b\n")
+            .add_file("/tests/b.txt", "; XFAIL:\n");
+
+        let config = Config {
+            test_paths: vec![PathBuf::from("/tests")],
+            supported_file_extensions: vec!["txt".to_owned()],
+            file_system: Arc::new(file_system),
+            ..Config::default()
+        };
+
+        let tests = super::list(&config).unwrap();
+        assert_eq!(tests.iter().map(|t| (t.path.relative.clone(), t.run_commands, t.checks)).collect::<Vec<_>>(),
+                   vec![(PathBuf::from("a.txt"), 2, 2), (PathBuf::from("b.txt"), 0, 0)]);
+        assert_eq!(tests[0].metadata["owner"], "me");
+        assert_eq!(tests[0].features, vec!["x86".to_owned()]);
+        assert_eq!(tests[0].dependencies, vec![PathBuf::from("/tests/a.txt"), PathBuf::from("/tests/inputs/a.c")]);
+        assert!(tests[0].depends_on(Path::new("/tests/inputs/a.c")) && !tests[1].depends_on(Path::new("/tests/inputs/a.c")));
+    }
+
+    #[test]
+    fn tests_with_unavailable_required_features_are_skipped() {
+        let path = TestFilePath { absolute: PathBuf::from("/tests/a.txt"), relative: PathBuf::from("a.txt") };
+        let test_file = crate::parse::test_file(path, "; REQUIRES: x86, asserts\n; RUN: true\n".chars()).unwrap();
+
+        let mut config = Config::default();
+        config.add_feature("x86");
+        assert_eq!(super::single_file(&test_file, &config).overall_result,
+                   TestResultKind::Skip { reason: SkipReason::MissingFeature("asserts".to_owned()) });
+
+        config.add_feature("asserts");
+        assert_eq!(super::single_file(&test_file, &config).overall_result, TestResultKind::Pass);
+        assert_eq!(test_file.info().features, vec!["asserts".to_owned(), "x86".to_owned()]);
+    }
+
+    #[test]
+    fn tests_with_available_unsupported_features_are_skipped() {
+        let path = TestFilePath { absolute: PathBuf::from("/tests/a.txt"), relative: PathBuf::from("a.txt") };
+        let test_file = crate::parse::test_file(path, "; UNSUPPORTED: windows, os=macos\n; RUN: true\n".chars()).unwrap();
+
+        let mut config = Config::default();
+        assert_eq!(super::single_file(&test_file, &config).overall_result, TestResultKind::Pass);
+
+        config.params.insert("os".to_owned(), "macos".to_owned());
+        assert_eq!(super::single_file(&test_file, &config).overall_result,
+                   TestResultKind::Skip { reason: SkipReason::UnsupportedFeature("os=macos".to_owned()) });
+    }
+
+    #[test]
+    fn event_handlers_are_notified_when_each_test_starts_and_finishes() {
+        #[derive(Default)]
+        struct Recorder { events: Vec<String> }
+
+        impl crate::event_handler::EventHandler for Recorder {
+            fn on_test_suite_started(&mut self, _: &crate::event_handler::TestSuiteDetails, _: &Config) {}
+            fn on_test_suite_finished(&mut self, passed: bool, _: &Config) { self.events.push(format!("suite finished, passed: {}", passed)) }
+            fn on_test_started(&mut self, test_file: &TestFile, _: &Config) { self.events.push(format!("started {}", test_file.path.relative.display())) }
+            fn on_test_finished(&mut self, result: TestResult, _: &Config) { self.events.push(format!("finished {}", result.path.relative.display())) }
+            fn on_progress(&mut self, progress: &Progress, _: &Config) { self.events.push(format!("{}/{}, {} failed", progress.completed, progress.total, progress.failed)) }
+            fn note_warning(&mut self, _: &str) {}
+        }
+
+        let mut file_system = InMemoryFileSystem::new();
+        file_system.add_file("/tests/a.txt", "; RUN: true\n").add_file("/tests/b.txt", "; RUN: false\n");
+        let file_system = Arc::new(file_system);
+
+        let mut recorder = Recorder::default();
+        super::tests_with_results(&mut recorder, |config| {
+            config.test_paths = vec![PathBuf::from("/tests")];
+            config.supported_file_extensions = vec!["txt".to_owned()];
+            config.file_system = file_system.clone();
+            config.jobs = 1;
+        });
+
+        assert_eq!(recorder.events, vec![
+            "started a.txt", "finished a.txt", "1/2, 0 failed",
+            "started b.txt", "finished b.txt", "2/2, 1 failed",
+            "suite finished, passed: false",
+        ]);
+    }
+
+    #[test]
+    fn tests_which_did_not_start_before_cancellation_are_skipped() {
+        struct Silent;
+
+        impl crate::event_handler::EventHandler for Silent {
+            fn on_test_suite_started(&mut self, _: &crate::event_handler::TestSuiteDetails, _: &Config) {}
+            fn on_test_suite_finished(&mut self, _: bool, _: &Config) {}
+            fn on_test_finished(&mut self, _: TestResult, _: &Config) {}
+            fn note_warning(&mut self, _: &str) {}
+        }
+
+        let mut file_system = InMemoryFileSystem::new();
+        file_system.add_file("/tests/a.txt", "; RUN: true\n").add_file("/tests/b.txt", "; RUN: true\n; META: owner=me\n");
+        let file_system = Arc::new(file_system);
+        let cancellation_token = crate::CancellationToken::new();
+        cancellation_token.cancel();
+
+        let results = super::tests_with_results(Silent, |config| {
+            config.test_paths = vec![PathBuf::from("/tests")];
+            config.supported_file_extensions = vec!["txt".to_owned()];
+            config.file_system = file_system.clone();
+            config.cancellation_token = Some(cancellation_token.clone());
+        });
+
+        assert!(results.cancelled);
+        assert_eq!(results.test_results.iter().map(|r| (r.path.relative.clone(), r.overall_result.clone())).collect::<Vec<_>>(), vec![
+            (PathBuf::from("a.txt"), TestResultKind::Skip { reason: SkipReason::Cancelled }),
+            (PathBuf::from("b.txt"), TestResultKind::Skip { reason: SkipReason::Cancelled }),
+        ]);
+        assert_eq!(results.test_results[1].metadata["owner"], "me");
+    }
+
+    #[test]
+    fn tests_which_are_not_selected_are_not_listed() {
+        let mut file_system = InMemoryFileSystem::new();
+        file_system.add_file("/tests/a.txt", "; RUN: true\n; META: owner=me\n")
+            .add_file("/tests/b.txt", "; RUN: true\n; META: owner=you\n");
+
+        let config = Config {
+            test_paths: vec![PathBuf::from("/tests")],
+            supported_file_extensions: vec!["txt".to_owned()],
+            selector: Some(crate::TestSelector::new(|test_file| test_file.metadata().get("owner").is_some_and(|owner| owner == "you"))),
+            file_system: Arc::new(file_system),
+            ..Config::default()
+        };
+
+        let tests = super::list(&config).unwrap();
+        assert_eq!(tests.iter().map(|t| t.path.relative.clone()).collect::<Vec<_>>(), vec![PathBuf::from("b.txt")]);
+    }
+}
@@ -0,0 +1,148 @@
+//! A cache of parsed test files, keyed by the path and contents of each test.
+//!
+//! The cache stores the directive lines of each test so that unchanged tests
+//! do not need to be scanned again. Any change to the contents of a test,
+//! to the config options that affect parsing, or to the version of lit,
+//! invalidates its cache entry.
+
+use crate::{model::*, parse, Config};
+use std::{fs, path::Path};
+
+const CACHE_FORMAT_NAME: &str = "lit-parse-cache";
+
+/// Parses a test file, reusing a cached parse if the file has not changed.
+pub fn test_file(path: TestFilePath, text: &str, cache_dir: &Path, config: &Config) -> Result<TestFile, String> {
+    let cache_file_path = cache_dir.join(format!("{:016x}.txt", hash(path.absolute.to_string_lossy().as_bytes())));
+    let parse_options = format!("{} {:?}", config.header_only_directives, config.comment_prefix);
+    let header = format!("{} {} {:016x} {:016x}", CACHE_FORMAT_NAME, env!("CARGO_PKG_VERSION"), hash(text.as_bytes()), hash(parse_options.as_bytes()));
+
+    if let Some(commands) = load(&cache_file_path, &header) {
+        return Ok(TestFile { path, commands });
+    }
+
+    let test_file = parse::test_file(path, text.chars())?;
+
+    // Failing to write the cache is not fatal, the test will simply be parsed again next time.
+    if let Err(e) = store(&cache_file_path, &header, text) {
+        debug!("could not write parse cache file '{}': {}", cache_file_path.display(), e);
+    }
+
+    Ok(test_file)
+}
+
+/// Loads the commands from a cache file, if it exists and is up to date.
+fn load(cache_file_path: &Path, header: &str) -> Option<Vec<Command>> {
+    let cached = fs::read_to_string(cache_file_path).ok()?;
+    let mut lines = cached.lines();
+
+    if lines.next()? != header {
+        return None;
+    }
+
+    let directives = lines.map(|line| {
+        let (line_number, directive) = line.split_once('\t')?;
+        Some((line_number.parse().ok()?, directive))
+    }).collect::<Option<Vec<_>>>()?;
+
+    // Every cached line held a directive, so a line which no longer is one means the cache is stale.
+    if directives.iter().any(|&(line_number, directive)| parse::possible_command(directive, line_number).is_none()) {
+        return None;
+    }
+
+    parse::commands(directives.into_iter()).ok()
+}
+
+/// Stores every directive line of a test.
+///
+/// A single command can be declared by several lines, like a block of `CHECK-REST`
+/// directives, so storing one line per command would lose the rest of the block.
+fn store(cache_file_path: &Path, header: &str, text: &str) -> std::io::Result<()> {
+    let mut cached = format!("{}\n", header);
+    for (line_number, line) in (1..).zip(text.lines()) {
+        if parse::possible_command(line, line_number).is_some() {
+            cached += &format!("{}\t{}\n", line_number, line);
+        }
+    }
+
+    if let Some(parent) = cache_file_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(cache_file_path, cached)
+}
+
+/// Hashes bytes with 64-bit FNV-1a.
+///
+/// Unlike the hashers of the standard library, its results never change between Rust
+/// releases, so cache files stay valid when lit is rebuilt.
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn test_file_path() -> TestFilePath {
+        TestFilePath { absolute: PathBuf::from("/tests/foo.txt"), relative: PathBuf::from("foo.txt") }
+    }
+
+    #[test]
+    fn cached_parse_matches_fresh_parse() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let text = "; RUN: cat @file\n\nfoo\n; CHECK: foo\n; CHECK-NEXT: [[\\d+]]\n";
+
+        let fresh = test_file(test_file_path(), text, cache_dir.path(), &Config::default()).unwrap();
+        assert_eq!(fs::read_dir(cache_dir.path()).unwrap().count(), 1, "cache file should be written");
+
+        let cached = test_file(test_file_path(), text, cache_dir.path(), &Config::default()).unwrap();
+        assert_eq!(fresh, cached);
+        assert_eq!(cached.commands[1].line_number, 4);
+    }
+
+    #[test]
+    fn cached_parse_keeps_the_match_mode() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let text = "; MATCH-MODE: regex\n; RUN: cat @file\n; CHECK: fo+\n";
+
+        let fresh = test_file(test_file_path(), text, cache_dir.path(), &Config::default()).unwrap();
+        let cached = test_file(test_file_path(), text, cache_dir.path(), &Config::default()).unwrap();
+        assert_eq!(fresh, cached);
+        assert_eq!(cached.commands[2].kind, CommandKind::Check(TextPattern { components: vec![PatternComponent::Regex("fo+".to_owned())] }));
+    }
+
+    #[test]
+    fn cached_parse_keeps_every_line_of_a_block() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let text = "; RUN: cat\n; CHECK-REST: a\n; CHECK-REST:   b  \n; CHECK-REST:\n";
+
+        let fresh = test_file(test_file_path(), text, cache_dir.path(), &Config::default()).unwrap();
+        let cached = test_file(test_file_path(), text, cache_dir.path(), &Config::default()).unwrap();
+        assert_eq!(fresh, cached);
+        assert_eq!(cached.commands[1].kind, CommandKind::CheckRest(vec!["a".to_owned(), "  b  ".to_owned(), String::new()]));
+    }
+
+    #[test]
+    fn changed_contents_invalidate_the_cache() {
+        let cache_dir = tempfile::tempdir().unwrap();
+
+        test_file(test_file_path(), "; RUN: cat @file\n; CHECK: foo\n", cache_dir.path(), &Config::default()).unwrap();
+        let changed = test_file(test_file_path(), "; RUN: cat @file\n; CHECK: bar\n", cache_dir.path(), &Config::default()).unwrap();
+
+        assert_eq!(changed.commands[1].kind, CommandKind::Check(parse::text_pattern("bar")));
+    }
+
+    #[test]
+    fn changed_parse_options_invalidate_the_cache() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let text = "; RUN: cat @file\n; CHECK: foo\n";
+        let config = Config { comment_prefix: Some(";".to_owned()), ..Config::default() };
+
+        test_file(test_file_path(), text, cache_dir.path(), &Config::default()).unwrap();
+        test_file(test_file_path(), text, cache_dir.path(), &config).unwrap();
+        let header = fs::read_to_string(fs::read_dir(cache_dir.path()).unwrap().next().unwrap().unwrap().path()).unwrap();
+
+        assert!(header.ends_with(&format!("{:016x}\n1\t; RUN: cat @file\n2\t; CHECK: foo\n", hash(b"false Some(\";\")"))), "{}", header);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
@@ -0,0 +1,96 @@
+//! Exporting what a test run would execute, without executing anything.
+
+use crate::{Config, json::Value, model::*, vars};
+use super::test_evaluator;
+use std::collections::BTreeMap;
+
+/// Exports the plan of a test run as a JSON manifest, without running any of the tests.
+///
+/// The manifest has the features and parameters of the config, and for every test, its
+/// variables, the features it depends on, and the command line of each `RUN` directive.
+/// Command lines are resolved like they are when the tests run, including directory
+/// configuration files, except that tempfile variables are left as `@<name>`, so that
+/// no files are created and the plans of two runs can be compared.
+pub fn export(config: &Config) -> Result<String, crate::Error> {
+    let test_paths = super::find_files::with_config(config).map_err(crate::ErrorKind::Msg)?;
+
+    let tests = super::parse_tests(test_paths, config).into_iter().filter_map(|parsed| match parsed {
+        Ok((test_file, test_config)) => if config.is_selected(&test_file) { Some(Ok(self::test(&test_file, &test_config))) } else { None },
+        Err((_, e)) => Some(Err(e)),
+    }).collect::<Result<Vec<_>, crate::Error>>()?;
+
+    let mut available_features: Vec<_> = config.available_features.iter().cloned().map(Value::String).collect();
+    available_features.sort_by_key(|feature| feature.to_string());
+
+    let plan = self::object(vec![
+        ("available_features", Value::Array(available_features)),
+        ("params", self::string_map(config.params.iter())),
+        ("tests", Value::Array(tests)),
+    ]);
+
+    Ok(format!("{:#}", plan))
+}
+
+fn test(test_file: &TestFile, config: &Config) -> Value {
+    let info = test_file.info();
+    let variables = test_evaluator::initial_variables(test_file, config);
+
+    let features = info.features.iter().map(|feature| (feature.clone(), Value::Bool(config.is_feature_available(feature)))).collect();
+    let run_commands = test_file.run_command_invocations().map(|(line_number, invocation)| {
+        let mut variables = variables.clone();
+        for name in vars::resolve::variable_names(&invocation.original_command) {
+            if name.contains("tempfile") {
+                variables.entry(name.clone()).or_insert_with(|| format!("@{}", name));
+            }
+        }
+
+        let command_line = match vars::resolve::invocation(invocation, config, &mut variables) {
+            Ok(command_line) => ("command_line", Value::String(command_line)),
+            Err(e) => ("error", Value::String(e)),
+        };
+
+        self::object(vec![("line", Value::Number(line_number as f64)), command_line])
+    }).collect();
+
+    self::object(vec![
+        ("path", Value::String(info.path.relative.display().to_string())),
+        ("expected_failure", Value::Bool(test_file.is_expected_failure_with_config(config))),
+        ("features", Value::Object(features)),
+        ("variables", self::string_map(variables.iter())),
+        ("run", Value::Array(run_commands)),
+    ])
+}
+
+fn object(members: Vec<(&str, Value)>) -> Value {
+    Value::Object(members.into_iter().map(|(key, value)| (key.to_owned(), value)).collect())
+}
+
+fn string_map<'a>(entries: impl Iterator<Item=(&'a String, &'a String)>) -> Value {
+    Value::Object(entries.map(|(key, value)| (key.clone(), Value::String(value.clone()))).collect::<BTreeMap<_, _>>())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Config, InMemoryFileSystem, json};
+    use std::{path::PathBuf, sync::Arc};
+
+    #[test]
+    fn plans_have_the_resolved_command_lines() {
+        let mut file_system = InMemoryFileSystem::new();
+        file_system.add_file("/tests/a.txt", "; RUN: tool --target=%{param:triple} @file > @tempfile\n; RUN: %if asserts %{ tool -verify %} %else %{ tool %}\n; CHECK{asserts}: ok\n");
+
+        let mut config = Config {
+            test_paths: vec![PathBuf::from("/tests")],
+            supported_file_extensions: vec!["txt".to_owned()],
+            file_system: Arc::new(file_system),
+            ..Config::default()
+        };
+        config.params.insert("triple".to_owned(), "x86_64-linux".to_owned());
+
+        let plan = json::parse(&super::export(&config).unwrap()).unwrap();
+        assert_eq!(plan.to_string(), format!("{{\"available_features\":[],\"params\":{{\"triple\":\"x86_64-linux\"}},\"tests\":[{{\
+            \"expected_failure\":false,\"features\":{{\"asserts\":false}},\"path\":\"a.txt\",\"run\":[\
+            {{\"command_line\":\"tool --target=x86_64-linux /tests/a.txt > @tempfile\",\"line\":1}},{{\"command_line\":\"tool\",\"line\":2}}],\
+            \"variables\":{{\"file\":\"/tests/a.txt\",\"os\":\"{}\"}}}}]}}", std::env::consts::OS));
+    }
+}
@@ -1,46 +1,522 @@
 use crate::{
-    model::{CommandKind, Invocation, TestFile, TestResultKind, TestFailReason, ProgramOutput},
+    model::{CheckCoverage, CheckStatus, Command, CommandKind, ExpectedExitCode, IndividualRunResult, Invocation, OutputStream, PatternComponent, SkipReason, TestFile, TestResultKind, TestFailReason, ProgramOutput},
     Config,
+    json,
     vars,
+    Variables,
     VariablesExt,
 };
 use self::state::TestRunState;
-use std::{collections::HashMap, env, fs, process};
+use std::{collections::{HashMap, HashSet}, env, fs, io, path::{Path, PathBuf}, process, thread};
+use std::time::{Duration, Instant};
 
+/// How often the output of a streaming `RUN` command is checked for new lines.
+const STREAMING_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+mod hex_dump;
+mod child_process;
+mod glob;
 mod state;
 #[cfg(test)] mod state_tests;
 
 /// Responsible for evaluating specific tests and collecting
 /// the results.
+#[deprecated(note = "tests are evaluated by `lit::run::tests`, this type is no longer used")]
 #[derive(Clone)]
 pub struct TestEvaluator
 {
     pub invocation: Invocation,
 }
 
-pub fn execute_tests<'test>(test_file: &'test TestFile, config: &Config) -> Vec<(TestResultKind, &'test Invocation, CommandLine, ProgramOutput)> {
-    test_file.run_command_invocations().map(|invocation| {
-        let initial_variables = {
-            let mut vars = HashMap::new();
-            vars.extend(config.constants.clone());
-            vars.extend(test_file.variables());
-            vars
+pub fn execute_tests(test_file: &TestFile, config: &Config) -> Vec<IndividualRunResult> {
+    if config.kill_children_on_termination {
+        child_process::kill_children_on_termination();
+    }
+    let selected_run_index = config.run_line.map(|run_line| run_line.saturating_sub(1));
+
+    let mut run_results: Vec<_> = test_file.run_command_invocations().enumerate().filter(|&(i, _)| selected_run_index.is_none_or(|selected| i == selected)).map(|(_, (line_number, invocation))| {
+        // The variables are shared by the command and the checks, so that both refer to the same tempfiles.
+        let mut variables = self::initial_variables(test_file, config);
+        let command = self::build_command(invocation, test_file, &mut variables, config);
+        let mut test_run_state = TestRunState::new(variables);
+        test_run_state.set_masks(test_file.masks());
+        self::record_files_before_run(&mut test_run_state, test_file, config);
+
+        let stdin = self::stdin(test_file, &mut test_run_state, config);
+
+        let ((command, command_line), stdin) = match (command, stdin) {
+            (Ok(c), Ok(stdin)) => (c, stdin),
+            (Err(message), _) | (_, Err(message)) => return IndividualRunResult {
+                kind: TestResultKind::Error { message },
+                invocation: invocation.clone(),
+                line_number,
+                command_line: CommandLine(invocation.original_command.clone()),
+                output: ProgramOutput::empty(),
+                checks: self::unevaluated_checks(test_file),
+                kept_tempfiles: Vec::new(),
+            },
+        };
+
+        let (program_output, scrubbed_stderr, mut kind, checks) = match test_file.streaming_check_timeout() {
+            Some(check_timeout) => self::execute_streaming(command, stdin.as_deref(), &command_line, check_timeout, test_file.timeout().or(config.timeout), &mut test_run_state, test_file, config),
+            None => self::execute_buffered(command, stdin.as_deref(), &command_line, &mut test_run_state, test_file, config),
+        };
+
+        if kind == TestResultKind::Pass && config.fail_on_unexpected_stderr && !config.combine_stderr_into_stdout &&
+            !scrubbed_stderr.is_empty() && !test_file.checks_stderr() {
+            kind = TestResultKind::Fail {
+                reason: TestFailReason::UnexpectedStderr {
+                    program_command_line: command_line.0.clone(),
+                    stderr: program_output.stderr.clone(),
+                },
+                hint: None,
+            };
+        }
+
+        let kept_tempfiles = self::remove_temporary_files(&test_run_state, &kind, config);
+
+        IndividualRunResult {
+            kind,
+            invocation: invocation.clone(),
+            line_number,
+            command_line,
+            output: program_output,
+            checks,
+            kept_tempfiles,
+        }
+    }).collect();
+
+    // Outputs can only be compared if every RUN command was executed.
+    if selected_run_index.is_none() {
+        self::compare_run_outputs(test_file, &mut run_results);
+    }
+
+    run_results
+}
+
+/// Evaluates the `CHECK-RUNS-EQUAL` directives of a test, against the results of all of its `RUN` commands.
+///
+/// A difference fails the result of the second `RUN` command, unless it has already failed.
+fn compare_run_outputs(test_file: &TestFile, run_results: &mut [IndividualRunResult]) {
+    for command in test_file.commands.iter() {
+        let (first_run_index, second_run_index) = match command.kind {
+            CommandKind::CheckRunsEqual(first, second) => (first, second),
+            _ => continue,
+        };
+
+        let result_index = match run_results.len() {
+            0 => return,
+            run_count if first_run_index >= run_count || second_run_index >= run_count => {
+                let result = run_results.last_mut().unwrap();
+                if !result.kind.is_erroneous() {
+                    result.kind = TestResultKind::Error {
+                        message: format!("CHECK-RUNS-EQUAL on line {} refers to RUN {}, but the test only has {} RUN commands",
+                                         command.line_number, first_run_index.max(second_run_index), run_count),
+                    };
+                }
+                continue;
+            },
+            _ => second_run_index,
         };
 
-        let mut test_run_state = TestRunState::new(initial_variables);
-        let (command, command_line) = self::build_command(invocation, test_file, config);
+        let (first_stdout, second_stdout) = (&run_results[first_run_index].output.stdout, &run_results[second_run_index].output.stdout);
+        if first_stdout != second_stdout && !run_results[result_index].kind.is_erroneous() {
+            let diff = crate::util::line_diff(first_stdout, second_stdout);
+
+            run_results[result_index].kind = TestResultKind::Fail {
+                reason: TestFailReason::RunOutputsDiffer { first_run_index, second_run_index, diff },
+                hint: None,
+            };
+        }
+    }
+}
+
+/// Runs a command to completion, and then evaluates the checks of the test against its output.
+///
+/// Returns the output, the scrubbed standard error, the result and the coverage of the checks.
+fn execute_buffered(
+    command: process::Command,
+    stdin: Option<&[u8]>,
+    command_line: &CommandLine,
+    test_run_state: &mut TestRunState,
+    test_file: &TestFile,
+    config: &Config,
+) -> (ProgramOutput, String, TestResultKind, Vec<CheckCoverage>) {
+    let started_at = Instant::now();
+    let (program_output, interleaved_output, execution_result) = self::collect_output(command, stdin, command_line.clone(), test_file.timeout().or(config.timeout), test_file.expected_exit_code(), test_run_state, config);
+    test_run_state.set_run_duration(started_at.elapsed());
+
+    // Checks run against the scrubbed output, but the original output is reported.
+    let (scrubbed_stdout, scrubbed_stderr) = (self::scrub(&program_output.stdout, config), self::scrub(&program_output.stderr, config));
+
+    test_run_state.append_program_output(&scrubbed_stdout);
+    if config.combine_stderr_into_stdout {
+        test_run_state.append_program_output(&scrubbed_stderr);
+    }
+    test_run_state.append_program_stderr(&scrubbed_stderr);
+    test_run_state.append_interleaved_output(&self::scrub(&interleaved_output, config));
+
+    let (kind, checks) = match execution_result {
+        // A program that fails as expected must still emit the output that the test checks for.
+        TestResultKind::Fail { reason: actual_reason, .. } if test_file.is_expected_failure_with_config(config) => {
+            match run_test_checks(test_run_state, test_file, config) {
+                (TestResultKind::Fail { reason, .. }, checks) => (TestResultKind::Fail {
+                    reason,
+                    hint: Some("the program failed, as expected by the XFAIL directive, but its output did not match the checks".to_owned()),
+                }, checks),
+                (_, checks) => (TestResultKind::ExpectedFailure { actual_reason }, checks),
+            }
+        },
+        TestResultKind::Skip { reason } => (TestResultKind::Skip { reason }, self::unevaluated_checks(test_file)),
+        // Tests which declare their exit code still evaluate their other criteria if it is wrong.
+        execution_result if execution_result.is_erroneous() &&
+            !(test_file.expected_exit_code().is_some() && matches!(execution_result, TestResultKind::Fail { .. })) => {
+            (execution_result, self::unevaluated_checks(test_file))
+        },
+        execution_result => {
+            let (check_result, checks) = run_test_checks(test_run_state, test_file, config);
+            let result = self::success_criteria_result(execution_result, check_result, &scrubbed_stdout, &scrubbed_stderr, command_line, test_file, config);
+
+            (self::expected_failure_result(result, test_file, config), checks)
+        },
+    };
+
+    (program_output, scrubbed_stderr, kind, checks)
+}
+
+/// Runs a command whilst evaluating the checks of the test against its output, as it is printed.
+///
+/// Each check must pass within the check timeout, otherwise the test fails. The command is killed
+/// once every check has passed or one has failed, so it does not need to exit by itself, or once
+/// it has run for longer than the timeout of the whole command, if it has one.
+#[allow(clippy::too_many_arguments)]
+fn execute_streaming(
+    mut command: process::Command,
+    stdin: Option<&[u8]>,
+    command_line: &CommandLine,
+    check_timeout: Duration,
+    timeout: Option<Duration>,
+    test_run_state: &mut TestRunState,
+    test_file: &TestFile,
+    config: &Config,
+) -> (ProgramOutput, String, TestResultKind, Vec<CheckCoverage>) {
+    let started_at = Instant::now();
+    let mut child = match child_process::StreamingChild::spawn(&mut command, stdin, config.spawn_retries) {
+        Ok(child) => child,
+        Err(e) => {
+            let kind = TestResultKind::SpawnError { program_command_line: command_line.0.clone(), message: self::spawn_error_message(&e, config) };
+            return (ProgramOutput::empty(), String::new(), kind, self::unevaluated_checks(test_file));
+        },
+    };
+
+    let mut stream_positions = StreamPositions::default();
+    let mut is_timed_out = false;
+    let (check_result, checks) = self::run_test_checks_with(test_run_state, test_file, config, |test_run_state, command| {
+        let deadline = Instant::now() + check_timeout;
+
+        loop {
+            // Whether the child had finished is checked before its output is read, so that no output is missed.
+            let is_finished = child.is_finished();
+            self::append_streamed_output(test_run_state, &child, &mut stream_positions, is_finished, config);
+            test_run_state.set_run_duration(started_at.elapsed());
+
+            let result = self::evaluate_command(test_run_state, command, config);
+            is_timed_out = is_timed_out || timeout.is_some_and(|timeout| started_at.elapsed() >= timeout);
+            if !result.is_erroneous() || is_finished || is_timed_out || config.is_cancelled() {
+                return result;
+            }
+
+            if Instant::now() >= deadline {
+                return match result {
+                    TestResultKind::Fail { reason, hint: None } => TestResultKind::Fail {
+                        reason,
+                        hint: Some(format!("the check was not satisfied within the streaming timeout of {:?}", check_timeout)),
+                    },
+                    result => result,
+                };
+            }
+
+            thread::sleep(STREAMING_POLL_INTERVAL);
+        }
+    });
+
+    let output = match child.stop(if is_timed_out { child_process::Status::TimedOut } else { child_process::Status::Stopped }) {
+        Ok(output) => output,
+        Err(e) => {
+            let kind = TestResultKind::Error { message: e.to_string() };
+            return (ProgramOutput::empty(), String::new(), kind, checks);
+        },
+    };
+
+    let program_output = ProgramOutput {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    };
+    let (scrubbed_stdout, scrubbed_stderr) = (self::scrub(&program_output.stdout, config), self::scrub(&program_output.stderr, config));
+
+    let execution_result = match output.status {
+        _ if config.is_cancelled() => TestResultKind::Skip { reason: SkipReason::Cancelled },
+        status => self::execution_result(status, command_line, timeout, test_file.expected_exit_code()),
+    };
+    let kind = match execution_result {
+        TestResultKind::Skip { reason } => TestResultKind::Skip { reason },
+        TestResultKind::Timeout { duration } => TestResultKind::Timeout { duration },
+        execution_result => {
+            let result = self::success_criteria_result(execution_result, check_result, &scrubbed_stdout, &scrubbed_stderr, command_line, test_file, config);
+            self::expected_failure_result(result, test_file, config)
+        },
+    };
+
+    (program_output, scrubbed_stderr, kind, checks)
+}
+
+/// How much of the output of a streaming child has been appended to the state of a test.
+#[derive(Default)]
+struct StreamPositions {
+    stdout: usize,
+    stderr: usize,
+    interleaved: usize,
+    /// Whether standard error has been appended to standard output, like `Config::combine_stderr_into_stdout` asks for.
+    is_stderr_combined: bool,
+}
+
+/// Appends the complete lines that a streaming child has printed since the last call.
+///
+/// Scrubbers are applied to each new piece of output separately. Once the child has
+/// finished, any incomplete last line is appended too, and standard error is appended
+/// after standard output if the config combines them.
+fn append_streamed_output(
+    test_run_state: &mut TestRunState,
+    child: &child_process::StreamingChild,
+    stream_positions: &mut StreamPositions,
+    is_finished: bool,
+    config: &Config,
+) {
+    let (stdout, stderr, interleaved) = child.output_so_far();
+
+    fn new_bytes<'a>(output: &'a [u8], position: &mut usize, is_finished: bool) -> &'a [u8] {
+        let unread = &output[*position..];
+        let length = if is_finished { unread.len() } else { unread.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1) };
+        *position += length;
+
+        &unread[..length]
+    }
+
+    let (new_stdout, new_stderr) = (new_bytes(&stdout, &mut stream_positions.stdout, is_finished), new_bytes(&stderr, &mut stream_positions.stderr, is_finished));
+    let new_interleaved = new_bytes(&interleaved, &mut stream_positions.interleaved, is_finished);
+    test_run_state.append_raw_output(OutputStream::Stdout, new_stdout);
+    test_run_state.append_raw_output(OutputStream::Stderr, new_stderr);
+    test_run_state.append_raw_output(OutputStream::Interleaved, new_interleaved);
+
+    test_run_state.append_program_output(&self::scrub(&String::from_utf8_lossy(new_stdout), config));
+    test_run_state.append_program_stderr(&self::scrub(&String::from_utf8_lossy(new_stderr), config));
+    test_run_state.append_interleaved_output(&self::scrub(&String::from_utf8_lossy(new_interleaved), config));
+
+    // Like for buffered commands, standard error is only combined once all of standard output is known.
+    if is_finished && config.combine_stderr_into_stdout && !stream_positions.is_stderr_combined {
+        test_run_state.append_program_output(&self::scrub(&String::from_utf8_lossy(&stderr), config));
+        stream_positions.is_stderr_combined = true;
+    }
+}
+
+/// Evaluates the checks of a test against the given output, instead of the output of its `RUN` commands.
+///
+/// No processes are spawned. The output is treated as the standard output of a
+/// successful program with an empty standard error stream.
+pub fn run_against(test_file: &TestFile, output: &str, config: &Config) -> TestResultKind {
+    if let Err(message) = self::check_pattern_params(test_file, config).and_then(|()| self::check_pattern_variables(test_file, config)) {
+        return TestResultKind::Error { message };
+    }
+
+    let mut test_run_state = TestRunState::new(self::initial_variables(test_file, config));
+    test_run_state.set_masks(test_file.masks());
+    test_run_state.append_raw_output(OutputStream::Stdout, output.as_bytes());
+    test_run_state.append_program_output(&self::scrub(output, config));
+
+    let (check_result, _) = run_test_checks(&mut test_run_state, test_file, config);
+    let command_line = CommandLine("<in-memory output>".to_owned());
+    let result = self::success_criteria_result(TestResultKind::Pass, check_result, &self::scrub(output, config), "", &command_line, test_file, config);
+    let result = self::expected_failure_result(result, test_file, config);
+
+    self::remove_temporary_files(&test_run_state, &result, config);
+    result
+}
+
+/// Makes sure that every parameter referred to by a check pattern is defined.
+fn check_pattern_params(test_file: &TestFile, config: &Config) -> Result<(), String> {
+    for command in test_file.commands.iter() {
+        for component in command.text_patterns().iter().flat_map(|pattern| pattern.components.iter()) {
+            if let PatternComponent::Text(ref text) = *component {
+                vars::resolve::params(text, config).map_err(|e| format!("line {}: {}", command.line_number, e))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes sure that every variable used by a check pattern is defined, and that no check
+/// pattern uses a variable before the check which captures it.
+///
+/// Variables which are captured later in the test, and which are not constants, would
+/// otherwise have no value when the earlier check is evaluated. The variables of numeric
+/// expressions must have been captured as numbers, or be constants with numeric values.
+fn check_pattern_variables(test_file: &TestFile, config: &Config) -> Result<(), String> {
+    fn components(command: &Command) -> impl Iterator<Item=&PatternComponent> {
+        command.text_patterns().iter().flat_map(|pattern| pattern.components.iter())
+    }
+    fn captured_name(component: &PatternComponent) -> Option<&str> {
+        match *component {
+            PatternComponent::NamedRegex { ref name, .. } | PatternComponent::NumericCapture(ref name) => Some(name),
+            _ => None,
+        }
+    }
+
+    let initial_variables = self::initial_variables(test_file, config);
+    let captured_names: HashSet<_> = test_file.commands.iter().flat_map(components).filter_map(captured_name).collect();
+
+    let (mut defined_names, mut numeric_names) = (HashSet::new(), HashSet::new());
+    for command in test_file.commands.iter() {
+        for component in components(command) {
+            let (used_names, is_numeric_use): (Vec<&str>, _) = match *component {
+                PatternComponent::Variable(ref name) => (vec![name], false),
+                PatternComponent::NumericExpression(ref expression) => (expression.variable_names().collect(), true),
+                _ => continue,
+            };
+
+            for name in used_names {
+                let is_defined = captured_names.contains(name) || config.has_variable(name, &initial_variables);
+                if !is_numeric_use && !is_defined {
+                    return Err(format!("line {}: variable '{}' is not defined", command.line_number, name));
+                }
 
-        let (program_output, execution_result) = self::collect_output(command, command_line.clone(), config);
+                if captured_names.contains(name) && !defined_names.contains(name) && !initial_variables.contains_key(name) {
+                    return Err(format!("line {}: variable '{}' is used before the check that captures it", command.line_number, name));
+                }
+
+                let is_numeric_constant = !defined_names.contains(name) && initial_variables.get(name).is_some_and(|value| value.trim().parse::<i64>().is_ok());
+                if is_numeric_use && !numeric_names.contains(name) && !is_numeric_constant {
+                    return Err(format!("line {}: '{}' is not a numeric variable, numbers can be captured with '[[#{}:]]'", command.line_number, name, name));
+                }
+            }
+        }
+
+        for component in components(command) {
+            if let Some(name) = captured_name(component) {
+                defined_names.insert(name);
+
+                match *component {
+                    PatternComponent::NumericCapture(..) => numeric_names.insert(name),
+                    _ => numeric_names.remove(name),
+                };
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the tempfiles used by a `RUN` command and its checks, if the config allows it.
+///
+/// Returns the tempfiles that were kept.
+fn remove_temporary_files(test_run_state: &TestRunState, result: &TestResultKind, config: &Config) -> Vec<PathBuf> {
+    let tempfiles = test_run_state.variables().tempfile_paths();
+
+    #[allow(deprecated)]
+    let should_keep = !config.cleanup_temporary_files || config.keep_tempfiles.should_keep(result);
+
+    if should_keep {
+        // Only the tempfiles that were actually created are worth inspecting.
+        return tempfiles.into_iter().filter(|tempfile| tempfile.exists()).collect();
+    }
+
+    for tempfile in tempfiles {
+        // Ignore errors, these are tempfiles, they go away anyway.
+        fs::remove_file(tempfile).ok();
+    }
+    Vec::new()
+}
 
-        test_run_state.append_program_output(&program_output.stdout);
-        test_run_state.append_program_stderr(&program_output.stderr);
+/// Gets the variables that are available to a test before any of its commands run.
+pub(super) fn initial_variables(test_file: &TestFile, config: &Config) -> HashMap<String, String> {
+    let mut vars = HashMap::new();
+    vars.insert("os".to_owned(), env::consts::OS.to_owned());
+    vars.extend(config.constants.clone());
+    vars.extend(test_file.variables());
+    vars
+}
 
-        if execution_result.is_erroneous() {
-            return (execution_result, invocation, command_line, program_output);
+/// Gets the input declared by the `STDIN` or `STDIN-FILE` directive of a test, if it has one.
+fn stdin(test_file: &TestFile, test_run_state: &mut TestRunState, config: &Config) -> Result<Option<Vec<u8>>, String> {
+    for command in test_file.commands.iter() {
+        match command.kind {
+            CommandKind::Stdin(ref lines) => return Ok(Some(lines.iter().flat_map(|line| [line.as_bytes(), b"\n"]).flatten().copied().collect())),
+            CommandKind::StdinFile(ref path) => {
+                let (_, contents) = self::read_test_relative_file(path, "stdin file", test_run_state, config)?;
+                return Ok(Some(contents));
+            },
+            _ => (),
         }
+    }
+
+    Ok(None)
+}
+
+/// Reads the file of a `CHECK-FILE` or `CHECK-SCHEMA` directive as text, like `read_test_relative_file`.
+fn read_expected_file(path: &str, description: &str, test_run_state: &mut TestRunState, config: &Config) -> Result<(String, String), String> {
+    let (resolved_path, contents) = self::read_test_relative_file(path, description, test_run_state, config)?;
+
+    match String::from_utf8(contents) {
+        Ok(contents) => Ok((resolved_path, contents)),
+        Err(e) => Err(format!("could not read {} '{}': {}", description, resolved_path, e)),
+    }
+}
+
+/// Reads the file of a `CHECK-FILE`, `CHECK-SCHEMA` or `STDIN-FILE` directive, relative to the directory of the test.
+///
+/// If the file does not exist and the config has a fallback for `@os`, the fallback file is read instead.
+fn read_test_relative_file(path: &str, description: &str, test_run_state: &mut TestRunState, config: &Config) -> Result<(String, Vec<u8>), String> {
+    let test_directory = test_run_state.variables().get("file").and_then(|file| Path::new(file).parent().map(Path::to_owned));
+    let read = |variables: &mut Variables| {
+        let resolved_path = vars::resolve::string(path, config, variables);
+        let full_path = test_directory.as_ref().map_or_else(|| PathBuf::from(&resolved_path), |dir| dir.join(&resolved_path));
+
+        (resolved_path, config.file_system.read(&full_path))
+    };
+
+    let (resolved_path, contents) = match read(test_run_state.variables_mut()) {
+        (_, Err(ref e)) if e.kind() == io::ErrorKind::NotFound && path.contains("@os") && config.check_file_fallback_os.is_some() => {
+            let mut fallback_variables = test_run_state.variables().clone();
+            fallback_variables.insert("os".to_owned(), config.check_file_fallback_os.clone().unwrap());
+
+            read(&mut fallback_variables)
+        },
+        read_result => read_result,
+    };
+
+    match contents {
+        Ok(contents) => Ok((resolved_path, contents)),
+        Err(e) => Err(format!("could not read {} '{}': {}", description, resolved_path, e)),
+    }
+}
+
+/// Applies the output scrubbers of the config to a program output, before it is checked.
+fn scrub(output: &str, config: &Config) -> String {
+    let scrubbed = config.output_scrubbers.iter().fold(output.to_owned(), |output, s| s.scrub(&output));
+    if config.ignore_trailing_whitespace { self::trim_trailing_whitespace(&scrubbed) } else { scrubbed }
+}
+
+/// Removes the trailing whitespace from every line of a program output.
+fn trim_trailing_whitespace(output: &str) -> String {
+    output.split('\n').map(str::trim_end).collect::<Vec<_>>().join("\n")
+}
 
-        let overall_test_result_kind = run_test_checks(&mut test_run_state, test_file, config);
-        (overall_test_result_kind, invocation, command_line, program_output)
+/// Gets the coverage of a run in which none of the checks were evaluated.
+fn unevaluated_checks(test_file: &TestFile) -> Vec<CheckCoverage> {
+    test_file.commands.iter().filter(|c| c.is_check()).map(|c| CheckCoverage {
+        line_number: c.line_number,
+        status: CheckStatus::NotReached,
+        checked_up_to_line: None,
     }).collect()
 }
 
@@ -48,28 +524,51 @@ fn run_test_checks(
     test_run_state: &mut TestRunState,
     test_file: &TestFile,
     config: &Config,
-) -> TestResultKind {
+) -> (TestResultKind, Vec<CheckCoverage>) {
+    self::run_test_checks_with(test_run_state, test_file, config, |test_run_state, command| self::evaluate_command(test_run_state, command, config))
+}
+
+/// Evaluates every directive of a test in order, using a function to evaluate each one,
+/// until one of them fails.
+fn run_test_checks_with(
+    test_run_state: &mut TestRunState,
+    test_file: &TestFile,
+    config: &Config,
+    mut evaluate: impl FnMut(&mut TestRunState, &Command) -> TestResultKind,
+) -> (TestResultKind, Vec<CheckCoverage>) {
     let mut check_result = TestResultKind::EmptyTest;
+    let mut checks = self::unevaluated_checks(test_file);
+    let mut checks_evaluated = 0;
 
-    for command in test_file.commands.iter() {
-        let test_result = match command.kind {
-            CommandKind::Run(..) | // RUN commands are already handled above, in the loop.
-                CommandKind::XFail => { // XFAIL commands are handled separately too.
-                    TestResultKind::Pass
-                },
-            CommandKind::Check(ref text_pattern) => test_run_state.check(text_pattern, config),
-            CommandKind::CheckNext(ref text_pattern) => test_run_state.check_next(text_pattern, config),
-        };
+    for (command_index, command) in test_file.commands.iter().enumerate() {
+        // Checks guarded by an unavailable feature are ignored entirely, as if they were not in the test.
+        if !command.is_enabled(config) {
+            checks[checks_evaluated].status = CheckStatus::Disabled;
+            checks_evaluated += 1;
+            continue;
+        }
 
-        if config.cleanup_temporary_files {
-            let tempfile_paths = test_run_state.variables().tempfile_paths();
+        // Any other directive separates consecutive groups of CHECK-DAG directives.
+        if !matches!(command.kind, CommandKind::CheckDag(..) | CommandKind::CheckDagOnce(..)) {
+            test_run_state.end_dag_group();
+        }
 
-            for tempfile in tempfile_paths {
-                // Ignore errors, these are tempfiles, they go away anyway.
-                fs::remove_file(tempfile).ok();
-            }
+        // The region of a label ends where the next label matches.
+        if let CommandKind::CheckLabel(..) = command.kind {
+            let next_label = test_file.commands[command_index + 1..].iter().filter(|c| c.is_enabled(config)).find_map(|c| match c.kind {
+                CommandKind::CheckLabel(ref text_pattern) => Some(text_pattern.clone()),
+                _ => None,
+            });
+            test_run_state.set_next_label(next_label);
         }
 
+        let test_result = evaluate(test_run_state, command);
+
+        if command.is_check() {
+            checks[checks_evaluated].status = if test_result.is_erroneous() { CheckStatus::Failed } else { CheckStatus::Passed };
+            checks[checks_evaluated].checked_up_to_line = Some(test_run_state.checked_line_number());
+            checks_evaluated += 1;
+        }
 
         // Early return for failures.
         if test_result.is_erroneous() {
@@ -80,81 +579,413 @@ fn run_test_checks(
         }
     }
 
+    // CHECK-NOT directives after the last check apply to the rest of the output.
+    if !check_result.is_erroneous() {
+        let not_check_result = test_run_state.end_not_checks(config);
+        if not_check_result.is_erroneous() {
+            check_result = not_check_result;
+        }
+    }
+
+    (check_result, checks)
+}
+
+/// Evaluates a single directive of a test against the output so far.
+fn evaluate_command(
+    test_run_state: &mut TestRunState,
+    command: &Command,
+    config: &Config,
+) -> TestResultKind {
+    match command.kind {
+        CommandKind::Run(..) | // RUN commands are already handled above, in the loop.
+            CommandKind::Timeout(..) | // So are timeouts.
+            CommandKind::Streaming(..) | // And the streaming mode.
+            CommandKind::XFail(..) | // XFAIL commands are handled separately too.
+            CommandKind::Requires(..) | // So are REQUIRES commands, before any command is run.
+            CommandKind::Unsupported(..) | // And UNSUPPORTED commands.
+            CommandKind::Meta(..) | // Metadata is only used for reporting.
+            CommandKind::Depends(..) | // So are dependencies.
+            CommandKind::MatchMode(..) | // Match modes are applied to the patterns when they are parsed.
+            CommandKind::Stdin(..) | CommandKind::StdinFile(..) | // Input is written when the RUN command is started.
+            CommandKind::Exit(..) | // Success criteria are evaluated separately, after the checks.
+            CommandKind::CheckStderrEmpty |
+            CommandKind::CheckNoOutput |
+            CommandKind::Mask(..) | // Masks are applied to the output and patterns beforehand.
+            CommandKind::CheckRunsEqual(..) => { // Outputs are compared after every RUN command has finished.
+                TestResultKind::Pass
+            },
+        CommandKind::CheckFile(ref path) => match self::read_expected_file(path, "expected file", test_run_state, config) {
+            Ok((resolved_path, contents)) => test_run_state.check_file_contents(&resolved_path, &self::scrub(&contents, config)),
+            Err(message) => TestResultKind::Error { message },
+        },
+        CommandKind::CheckSchema(ref path) => match self::read_expected_file(path, "schema", test_run_state, config) {
+            Ok((resolved_path, contents)) => match json::parse(&contents) {
+                Ok(schema) => match json::schema::check_supported(&schema) {
+                    Ok(()) => test_run_state.check_schema(&resolved_path, &schema),
+                    Err(e) => TestResultKind::Error { message: format!("schema '{}' is not supported: {}", resolved_path, e) },
+                },
+                Err(e) => TestResultKind::Error { message: format!("schema '{}' is not valid JSON: {}", resolved_path, e) },
+            },
+            Err(message) => TestResultKind::Error { message },
+        },
+        CommandKind::CheckOutputFile(ref path) => {
+            let path = vars::resolve::string(path, config, test_run_state.variables_mut());
+
+            // Output files are written by the program itself, so `Config::file_system` is not used.
+            match fs::read(&path) {
+                Ok(contents) => {
+                    test_run_state.replace_stdout(&self::scrub(&String::from_utf8_lossy(&contents), config), &contents);
+                    TestResultKind::Pass
+                },
+                Err(e) => TestResultKind::Fail {
+                    reason: TestFailReason::UnreadableOutputFile { path, error: e.to_string() },
+                    hint: None,
+                },
+            }
+        },
+        CommandKind::Check(ref text_pattern) => test_run_state.check(text_pattern, config),
+        CommandKind::CheckNext(ref text_pattern) => test_run_state.check_next(text_pattern, config),
+        CommandKind::CheckWithin(max_line_distance, ref text_pattern) => test_run_state.check_within(max_line_distance, text_pattern, config),
+        CommandKind::CheckDag(ref text_pattern) => test_run_state.check_dag(text_pattern, config),
+        CommandKind::CheckDagOnce(ref text_pattern) => test_run_state.check_dag_once(text_pattern, config),
+        CommandKind::CheckAny(ref text_pattern) => test_run_state.check_any(text_pattern, config),
+        CommandKind::CheckNone(ref text_pattern) => test_run_state.check_none(text_pattern, config),
+        CommandKind::CheckNot(ref text_pattern) => test_run_state.check_not(text_pattern),
+        CommandKind::CheckLabel(ref text_pattern) => test_run_state.check_label(text_pattern, config),
+        CommandKind::CheckSame(ref text_pattern) => test_run_state.check_same(text_pattern, config),
+        CommandKind::CheckEmpty => test_run_state.check_empty(config),
+        CommandKind::CheckCount(count, ref text_pattern) => test_run_state.check_count(count, text_pattern, config),
+        CommandKind::CheckAlt(ref text_patterns) => test_run_state.check_alt(text_patterns, config),
+        CommandKind::CheckSameCount(expected_count, ref text_pattern) => test_run_state.check_same_count(expected_count, text_pattern, config),
+        CommandKind::CheckBytes(ref bytes) => test_run_state.check_bytes(bytes),
+        CommandKind::CheckSize(comparison, expected_size) => test_run_state.check_size(comparison, expected_size),
+        CommandKind::CheckFinalNewline(expected) => test_run_state.check_final_newline(expected),
+        CommandKind::CheckRest(ref expected_lines) => test_run_state.check_rest(expected_lines, config),
+        CommandKind::CheckCreated { ref paths, only } => self::check_created(paths, only, test_run_state, config),
+        CommandKind::CheckTime(comparison, expected_duration) => test_run_state.check_time(comparison, expected_duration, config.time_scale),
+        CommandKind::CheckCustom(ref matcher_name, ref argument) => match config.custom_matchers.get(matcher_name) {
+            Some(&matcher) => {
+                let argument = vars::resolve::string(argument, config, test_run_state.variables_mut());
+                test_run_state.check_custom(matcher_name, matcher, &argument, config)
+            },
+            None => TestResultKind::Error {
+                message: format!("no custom matcher named '{}' exists, it must be registered with Config::add_custom_matcher", matcher_name),
+            },
+        },
+        CommandKind::CheckNum { expected, tolerance } => test_run_state.check_num(expected, tolerance, config),
+        CommandKind::CheckStream(stream) => {
+            test_run_state.select_stream(stream);
+            TestResultKind::Pass
+        },
+    }
+}
+
+/// Records the files in the directories checked by `CHECK-CREATED-ONLY`, so that
+/// files which already existed are not reported as unexpected.
+fn record_files_before_run(test_run_state: &mut TestRunState, test_file: &TestFile, config: &Config) {
+    for command in test_file.commands.iter() {
+        if let CommandKind::CheckCreated { ref paths, only: true } = command.kind {
+            for directory in self::created_file_paths(paths, test_run_state, config).into_iter().map(|(directory, _)| directory) {
+                let files = self::files_in_directory(&directory);
+                test_run_state.set_files_before_run(directory, files);
+            }
+        }
+    }
+}
+
+/// Resolves the paths of a `CHECK-CREATED` directive, along with the directory of each.
+fn created_file_paths(paths: &[String], test_run_state: &mut TestRunState, config: &Config) -> Vec<(PathBuf, PathBuf)> {
+    paths.iter().map(|path| {
+        let path = PathBuf::from(vars::resolve::string(path, config, test_run_state.variables_mut()));
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+            _ => PathBuf::from("."),
+        };
+        let file_name = path.file_name().map(|file_name| directory.join(file_name)).unwrap_or(path);
+
+        (directory, file_name)
+    }).collect()
+}
+
+/// Lists the files directly inside a directory, without descending into subdirectories.
+///
+/// Created files are written by the program itself, so `Config::file_system` is not used.
+fn files_in_directory(directory: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(..) => return Vec::new(),
+    };
+
+    entries.filter_map(Result::ok)
+        .filter(|entry| entry.file_type().map(|file_type| file_type.is_file()).unwrap_or(false))
+        .map(|entry| entry.path())
+        .collect()
+}
+
+/// Verifies that a `RUN` command created files, and with `only`, that no other files appeared in their directories.
+fn check_created(paths: &[String], only: bool, test_run_state: &mut TestRunState, config: &Config) -> TestResultKind {
+    let created_file_paths = self::created_file_paths(paths, test_run_state, config);
+
+    let missing: Vec<_> = created_file_paths.iter().map(|(_, path)| path)
+        .filter(|path| !fs::metadata(path).map(|metadata| metadata.is_file()).unwrap_or(false))
+        .map(|path| path.display().to_string()).collect();
+
+    let mut unexpected = Vec::new();
+    if only {
+        let mut directories: Vec<_> = created_file_paths.iter().map(|(directory, _)| directory).collect();
+        directories.sort();
+        directories.dedup();
+
+        for directory in directories {
+            let files_before_run = test_run_state.files_before_run(directory);
+
+            for file in self::files_in_directory(directory) {
+                if !files_before_run.contains(&file) && !created_file_paths.iter().any(|(_, path)| *path == file) {
+                    unexpected.push(file.display().to_string());
+                }
+            }
+        }
+    }
+
+    if missing.is_empty() && unexpected.is_empty() {
+        TestResultKind::Pass
+    } else {
+        TestResultKind::Fail { reason: TestFailReason::UnexpectedCreatedFiles { missing, unexpected }, hint: None }
+    }
+}
+
+/// Combines the exit code, standard error, and check criteria of a test, all of which must hold.
+///
+/// If more than one criterion does not hold, the result lists every one of them.
+fn success_criteria_result(
+    execution_result: TestResultKind,
+    check_result: TestResultKind,
+    stdout: &str,
+    stderr: &str,
+    command_line: &CommandLine,
+    test_file: &TestFile,
+    config: &Config,
+) -> TestResultKind {
+    let mut failures = Vec::new();
+
+    if let TestResultKind::Fail { reason, hint } = execution_result {
+        failures.push((reason, hint));
+    }
+
+    if test_file.requires_empty_stderr() && !stderr.is_empty() {
+        failures.push((TestFailReason::UnexpectedStderr {
+            program_command_line: command_line.0.clone(),
+            stderr: stderr.to_owned(),
+        }, Some("the test declares CHECK-STDERR-EMPTY".to_owned())));
+    }
+
+    // Whitespace is insignificant at the end of the output when trailing whitespace is ignored.
+    let is_silent = |output: &str| if config.ignore_trailing_whitespace { output.trim_end().is_empty() } else { output.is_empty() };
+    if test_file.requires_no_output() && !(is_silent(stdout) && is_silent(stderr)) {
+        failures.push((TestFailReason::UnexpectedOutput {
+            program_command_line: command_line.0.clone(),
+            stdout: stdout.to_owned(),
+            stderr: stderr.to_owned(),
+        }, None));
+    }
+
+    let check_result = match check_result {
+        TestResultKind::Fail { reason, hint } => {
+            failures.push((reason, hint));
+            TestResultKind::Pass
+        },
+        r => r,
+    };
+
+    match failures.len() {
+        0 => check_result,
+        1 => {
+            let (reason, hint) = failures.remove(0);
+            TestResultKind::Fail { reason, hint }
+        },
+        _ => TestResultKind::Fail {
+            reason: TestFailReason::CriteriaNotMet(failures.into_iter().map(|(reason, _)| reason).collect()),
+            hint: None,
+        },
+    }
+}
+
+/// Adjusts the result of the checks of a program which succeeded when the test is declared with `XFAIL`.
+fn expected_failure_result(check_result: TestResultKind, test_file: &TestFile, config: &Config) -> TestResultKind {
     match check_result {
         TestResultKind::Fail { reason, hint } => {
-            if test_file.is_expected_failure() {
+            if test_file.is_expected_failure_with_config(config) {
                 TestResultKind::ExpectedFailure { actual_reason: reason }
             } else {
                 TestResultKind::Fail { reason, hint}
             }
         },
+        TestResultKind::Pass if test_file.is_expected_failure_with_config(config) => TestResultKind::UnexpectedPass,
         r => r,
     }
 }
 
 fn collect_output(
     mut command: process::Command,
+    stdin: Option<&[u8]>,
     command_line: CommandLine,
+    timeout: Option<Duration>,
+    expected_exit_code: Option<ExpectedExitCode>,
+    test_run_state: &mut TestRunState,
     config: &Config,
-) -> (ProgramOutput, TestResultKind) {
-    let mut test_result_kind = TestResultKind::Pass;
-
-    let output = match command.output() {
+) -> (ProgramOutput, String, TestResultKind) {
+    let output = match self::child_process::output(&mut command, stdin, timeout, config.cancellation_token.as_ref(), config.spawn_retries) {
         Ok(o) => o,
         Err(e) => {
-            let error_message = match e.kind() {
-                std::io::ErrorKind::NotFound => format!("shell '{}' does not exist", &config.shell).into(),
-                _ => e.to_string(),
-            };
-
-            return (ProgramOutput::empty(), TestResultKind::Error { message: error_message });
+            let kind = TestResultKind::SpawnError { program_command_line: command_line.0, message: self::spawn_error_message(&e, config) };
+            return (ProgramOutput::empty(), String::new(), kind);
         },
     };
 
+    test_run_state.append_raw_output(OutputStream::Stdout, &output.stdout);
+    test_run_state.append_raw_output(OutputStream::Stderr, &output.stderr);
+    test_run_state.append_raw_output(OutputStream::Interleaved, &output.interleaved);
+
     let program_output = ProgramOutput {
         stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
         stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
     };
 
-    if !output.status.success() {
-        test_result_kind = TestResultKind::Fail {
-            reason: TestFailReason::UnsuccessfulExecution {
-                exit_status: output.status.code().unwrap_or_else(|| if output.status.success() { 0 } else { 1 }),
-                program_command_line: command_line.0,
-            },
-            hint: None,
-        };
-    }
+    let interleaved_output = String::from_utf8_lossy(&output.interleaved).into_owned();
 
-    (program_output, test_result_kind)
+    (program_output, interleaved_output, self::execution_result(output.status, &command_line, timeout, expected_exit_code))
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct CommandLine(pub String);
+/// Describes why the process of a `RUN` command could not be started.
+fn spawn_error_message(error: &io::Error, config: &Config) -> String {
+    match error.kind() {
+        io::ErrorKind::NotFound => match config.run_wrapper.as_ref().and_then(|w| w.first()) {
+            Some(wrapper_program) => format!("run wrapper '{}' does not exist", wrapper_program),
+            None => format!("shell '{}' does not exist", &config.shell),
+        },
+        _ => error.to_string(),
+    }
+}
+
+/// Gets the signal that terminated a `RUN` command, if it was terminated by one.
+///
+/// Shells do not die with a pipeline stage that was killed by a signal, they exit with
+/// 128 plus the signal number instead, so those exit codes of pipelines are signals too.
+fn termination_signal(status: &process::ExitStatus, command_line: &CommandLine) -> Option<i32> {
+    const SHELL_SIGNAL_EXIT_CODE_BASE: i32 = 128;
+    const MAX_SIGNAL: i32 = 64;
+
+    child_process::termination_signal(status).or_else(|| match status.code() {
+        Some(code) if cfg!(unix) && code > SHELL_SIGNAL_EXIT_CODE_BASE && code <= SHELL_SIGNAL_EXIT_CODE_BASE + MAX_SIGNAL
+            && self::is_pipeline(&command_line.0) => Some(code - SHELL_SIGNAL_EXIT_CODE_BASE),
+        _ => None,
+    })
+}
+
+/// Checks whether a shell command line pipes the output of one command into another.
+fn is_pipeline(command_line: &str) -> bool {
+    command_line.split("||").any(|part| part.contains('|'))
+}
+
+/// Gets the result of a `RUN` command from how its process finished.
+fn execution_result(
+    status: child_process::Status,
+    command_line: &CommandLine,
+    timeout: Option<Duration>,
+    expected_exit_code: Option<ExpectedExitCode>,
+) -> TestResultKind {
+    let mut test_result_kind = TestResultKind::Pass;
+
+    // A crash is never a clean exit, whatever exit code the test expects.
+    if let Some(signal) = match status { child_process::Status::Exited(ref status) => self::termination_signal(status, command_line), _ => None } {
+        return TestResultKind::Fail {
+            reason: TestFailReason::TerminatedBySignal { program_command_line: command_line.0.clone(), signal },
+            hint: None,
+        };
+    }
+
+    match status {
+        // Programs only finish without an exit code when killed by a signal, which is handled above.
+        child_process::Status::Exited(status) if status.code().is_none() => {
+            test_result_kind = TestResultKind::Error { message: format!("command '{}' finished without an exit code", command_line.0) };
+        },
+        child_process::Status::Exited(status) if expected_exit_code.is_some() => {
+            let (expected_exit_status, exit_status) = (expected_exit_code.unwrap(), status.code().unwrap());
+
+            if !expected_exit_status.matches(exit_status) {
+                test_result_kind = TestResultKind::Fail {
+                    reason: TestFailReason::UnexpectedExitCode {
+                        program_command_line: command_line.0.clone(),
+                        expected_exit_status,
+                        exit_status,
+                    },
+                    hint: None,
+                };
+            }
+        },
+        child_process::Status::Exited(status) if !status.success() => {
+            test_result_kind = TestResultKind::Fail {
+                reason: TestFailReason::UnsuccessfulExecution {
+                    exit_status: status.code().unwrap(),
+                    program_command_line: command_line.0.clone(),
+                },
+                hint: None,
+            };
+        },
+        child_process::Status::Exited(..) => (),
+        child_process::Status::TimedOut => test_result_kind = TestResultKind::Timeout { duration: timeout.unwrap() },
+        // Tests which were running when the test suite was cancelled are skipped.
+        child_process::Status::Cancelled => test_result_kind = TestResultKind::Skip { reason: SkipReason::Cancelled },
+        // Streaming commands are killed once their checks are done, so their exit code does not matter.
+        child_process::Status::Stopped => (),
+    }
+
+    test_result_kind
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CommandLine(pub String);
 
 /// Builds a command that can be used to execute the process behind a `RUN` directive.
 fn build_command(invocation: &Invocation,
                  test_file: &TestFile,
-                 config: &Config) -> (process::Command, CommandLine) {
-    let mut variables = config.constants.clone();
-    variables.extend(test_file.variables());
+                 variables: &mut Variables,
+                 config: &Config) -> Result<(process::Command, CommandLine), String> {
+    let command_line: String = vars::resolve::invocation(invocation, config, variables)?;
+    self::check_pattern_params(test_file, config)?;
+    self::check_pattern_variables(test_file, config)?;
+
+    let command_line = match test_file.path.absolute.parent() {
+        Some(test_directory) if config.expand_globs => glob::expand(&command_line, test_directory, config.keep_unmatched_globs)?,
+        _ => command_line,
+    };
 
-    let command_line: String = vars::resolve::invocation(invocation, &config, &mut variables);
+    let mut cmd = match config.run_wrapper.as_ref().and_then(|w| w.split_first()) {
+        Some((wrapper_program, wrapper_args)) => {
+            let mut cmd = process::Command::new(wrapper_program);
+            cmd.args(wrapper_args);
+            cmd.arg(&config.shell);
+            cmd
+        },
+        None => process::Command::new(&config.shell),
+    };
+    cmd.args(["-c", &command_line]);
 
-    let mut cmd = process::Command::new(&config.shell);
-    cmd.args(&["-c", &command_line]);
+    // Variables from the environment file override the globally configured ones.
+    cmd.envs(&config.env);
+    cmd.envs(super::env_file::for_test(test_file, config)?);
 
     if !config.extra_executable_search_paths.is_empty() {
         let os_path_separator = if cfg!(windows) { ";" } else { ":" };
 
-        let current_path = env::var("PATH").unwrap_or(String::new());
+        let current_path = env::var("PATH").unwrap_or_default();
         let paths_to_inject = config.extra_executable_search_paths.iter().map(|p| p.display().to_string()).collect::<Vec<_>>();
         let os_path_to_inject = format!("{}{}{}", paths_to_inject.join(os_path_separator), os_path_separator, current_path);
 
         cmd.env("PATH", os_path_to_inject);
     }
 
-    (cmd, CommandLine(command_line))
+    Ok((cmd, CommandLine(command_line)))
 }
 
 impl std::fmt::Display for CommandLine {
@@ -162,3 +993,776 @@ impl std::fmt::Display for CommandLine {
         self.0.fmt(fmt)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{model::*, parse, Config};
+    use std::{path::PathBuf, time::Duration};
+
+    fn execute(text: &str) -> Vec<IndividualRunResult> {
+        execute_with_config(text, &Config::default())
+    }
+
+    fn execute_with_config(text: &str, config: &Config) -> Vec<IndividualRunResult> {
+        let path = TestFilePath { absolute: PathBuf::from("/tests/foo.txt"), relative: PathBuf::from("foo.txt") };
+        let test_file = parse::test_file(path, text.chars()).unwrap();
+
+        super::execute_tests(&test_file, config)
+    }
+
+    /// Creates a config whose file system only has the given files, relative to the directory of the test.
+    fn config_with_files(files: &[(&str, &[u8])]) -> Config {
+        let mut file_system = crate::InMemoryFileSystem::new();
+        for &(path, contents) in files {
+            file_system.add_file(PathBuf::from("/tests").join(path), contents);
+        }
+
+        Config { file_system: std::sync::Arc::new(file_system), ..Config::default() }
+    }
+
+    #[test]
+    fn checks_after_a_failing_check_are_not_reached() {
+        let results = execute("; RUN: echo foo\n; CHECK: foo\n; CHECK: bar\n; CHECK: baz\n");
+        let statuses: Vec<_> = results[0].checks.iter().map(|c| (c.line_number, c.status)).collect();
+
+        assert_eq!(statuses, vec![(2, CheckStatus::Passed), (3, CheckStatus::Failed), (4, CheckStatus::NotReached)]);
+        assert_eq!(results[0].number_of_checks_evaluated(), 2);
+    }
+
+    #[test]
+    fn no_checks_are_evaluated_when_execution_fails() {
+        let results = execute("; RUN: false\n; CHECK: foo\n");
+
+        assert_eq!(results[0].number_of_checks_evaluated(), 0);
+        assert_eq!(results[0].checks.len(), 1);
+    }
+
+    #[test]
+    fn stderr_fails_the_test_when_configured() {
+        let test = "; RUN: echo foo && echo warning >&2\n; CHECK: foo\n";
+        let config = Config { fail_on_unexpected_stderr: true, ..Config::default() };
+
+        assert_eq!(execute(test)[0].kind, TestResultKind::Pass);
+        match execute_with_config(test, &config)[0].kind {
+            TestResultKind::Fail { reason: TestFailReason::UnexpectedStderr { ref stderr, .. }, .. } => assert_eq!(stderr, "warning\n"),
+            ref kind => panic!("expected unexpected stderr failure but got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn check_stream_selects_stderr_for_subsequent_checks() {
+        let test = "; RUN: echo out && echo err >&2\n; CHECK: out\n; CHECK-STREAM: stderr\n; CHECK: err\n";
+        let config = Config { fail_on_unexpected_stderr: true, ..Config::default() };
+
+        assert_eq!(execute_with_config(test, &config)[0].kind, TestResultKind::Pass);
+        assert!(execute("; RUN: echo out && echo err >&2\n; CHECK-STREAM: stderr\n; CHECK: out\n")[0].kind.is_erroneous());
+    }
+
+    #[test]
+    fn interleaved_stream_preserves_the_order_of_lines_across_streams() {
+        let run = "; RUN: echo prompt && sleep 0.1 && echo error >&2 && sleep 0.1 && printf done\n; CHECK-STREAM: interleaved\n";
+
+        assert_eq!(execute(&format!("{}; CHECK: [stdout] prompt\n; CHECK-NEXT: [stderr] error\n; CHECK-NEXT: [stdout] done\n", run))[0].kind, TestResultKind::Pass);
+        assert!(execute(&format!("{}; CHECK: [stderr] error\n; CHECK: [stdout] prompt\n", run))[0].kind.is_erroneous());
+        assert_eq!(execute(&format!("; STREAMING: 5s\n{}; CHECK: [stdout] prompt\n; CHECK-NEXT: [stderr] error\n", run))[0].kind, TestResultKind::Pass);
+    }
+
+    #[test]
+    fn run_commands_exceeding_the_timeout_are_killed() {
+        let results = execute("; TIMEOUT: 100ms\n; RUN: echo started && sleep 10\n; CHECK: started\n");
+
+        assert_eq!(results[0].kind, TestResultKind::Timeout { duration: Duration::from_millis(100) });
+        assert_eq!(results[0].output.stdout, "started\n");
+    }
+
+    #[test]
+    fn run_commands_exceeding_the_config_timeout_are_killed() {
+        let config = Config { timeout: Some(Duration::from_millis(100)), ..Config::default() };
+
+        assert_eq!(execute_with_config("; RUN: sleep 10\n", &config)[0].kind, TestResultKind::Timeout { duration: Duration::from_millis(100) });
+        assert_eq!(execute_with_config("; TIMEOUT: 5s\n; RUN: sleep 0.3\n", &config)[0].kind, TestResultKind::Pass);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn timeouts_kill_every_process_started_by_the_command() {
+        let directory = tempfile::tempdir().unwrap();
+        let pid_path = directory.path().join("pid");
+
+        let results = execute(&format!("; TIMEOUT: 200ms\n; RUN: sleep 30 | cat & echo $! > {}; wait\n", pid_path.display()));
+        assert_eq!(results[0].kind, TestResultKind::Timeout { duration: Duration::from_millis(200) });
+
+        let pid = std::fs::read_to_string(&pid_path).unwrap();
+        assert!(!is_running_after_a_moment(pid.trim()), "the background job of the command should have been killed");
+    }
+
+    /// Checks if a process is still running, giving it a moment to be killed.
+    #[cfg(unix)]
+    fn is_running_after_a_moment(pid: &str) -> bool {
+        let is_running = || std::process::Command::new("kill").args(["-0", pid]).stderr(std::process::Stdio::null()).status().unwrap().success();
+        let started_at = std::time::Instant::now();
+
+        while is_running() && started_at.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        is_running()
+    }
+
+    #[test]
+    fn streaming_commands_exceeding_the_timeout_are_killed() {
+        let config = Config { timeout: Some(Duration::from_millis(200)), ..Config::default() };
+        let started_at = std::time::Instant::now();
+
+        assert_eq!(execute_with_config("; STREAMING: 5s\n; RUN: echo starting && sleep 10\n; CHECK: ready\n", &config)[0].kind,
+                   TestResultKind::Timeout { duration: Duration::from_millis(200) });
+        assert!(started_at.elapsed() < Duration::from_secs(5), "the command should have been killed once it timed out");
+    }
+
+    #[test]
+    fn streaming_commands_can_combine_stderr_into_stdout() {
+        let config = Config { combine_stderr_into_stdout: true, ..Config::default() };
+
+        assert_eq!(execute_with_config("; STREAMING: 5s\n; RUN: echo out && echo err >&2\n; CHECK: out\n; CHECK-NEXT: err\n", &config)[0].kind, TestResultKind::Pass);
+    }
+
+    fn dag_test(output: &str, checks: &str) -> TestResultKind {
+        let test = format!("; RUN: printf '{}'\n{}", output, checks);
+        execute(&test)[0].kind.clone()
+    }
+
+    #[test]
+    fn check_dag_matches_in_any_order() {
+        assert_eq!(dag_test("b\\na\\nsep\\n", "; CHECK-DAG: a\n; CHECK-DAG: b\n; CHECK: sep\n"), TestResultKind::Pass);
+    }
+
+    #[test]
+    fn check_dag_group_after_separator_cannot_match_before_it() {
+        let checks = "; CHECK-DAG: a\n; CHECK: sep\n; CHECK-DAG: b\n";
+
+        assert_eq!(dag_test("a\\nsep\\nb\\n", checks), TestResultKind::Pass);
+        assert!(dag_test("a\\nb\\nsep\\n", checks).is_erroneous());
+    }
+
+    #[test]
+    fn check_dag_group_before_separator_cannot_match_after_it() {
+        assert!(dag_test("a\\nsep\\nc\\n", "; CHECK-DAG: a\n; CHECK-DAG: c\n; CHECK: sep\n").is_erroneous());
+    }
+
+    #[test]
+    fn check_dag_matches_cannot_overlap() {
+        let checks = "; CHECK-DAG: foo\n; CHECK-DAG: foo\n";
+
+        assert_eq!(dag_test("foo\\nfoo\\n", checks), TestResultKind::Pass);
+        match dag_test("foo\\n", checks) {
+            TestResultKind::Fail { hint, .. } => assert!(hint.unwrap().contains("overlaps")),
+            kind => panic!("expected overlapping CHECK-DAG to fail but got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn check_not_before_a_dag_group_applies_until_the_next_ordered_check() {
+        let checks = "; CHECK: begin\n; CHECK-NOT: error\n; CHECK-DAG: b\n; CHECK-DAG: a\n; CHECK: end\n";
+
+        assert_eq!(dag_test("begin\\na\\nb\\nend\\nerror\\n", checks), TestResultKind::Pass);
+        assert!(dag_test("begin\\na\\nerror\\nb\\nend\\n", checks).is_erroneous());
+    }
+
+    #[test]
+    fn run_wrapper_executes_the_shell() {
+        let config = Config { run_wrapper: Some(vec!["env".to_owned(), "WRAPPED=yes".to_owned()]), ..Config::default() };
+        let results = execute_with_config("; RUN: echo \"wrapped=$WRAPPED file=@file\"\n; CHECK: wrapped=yes file=/tests/foo.txt\n", &config);
+
+        assert_eq!(results[0].kind, TestResultKind::Pass);
+    }
+
+    #[test]
+    fn expected_failures_that_pass_are_unexpected_passes() {
+        assert_eq!(execute("; XFAIL:\n; RUN: echo foo\n; CHECK: foo\n")[0].kind, TestResultKind::UnexpectedPass);
+    }
+
+    #[test]
+    fn conditional_expected_failures_only_apply_with_matching_features() {
+        let test = "; XFAIL: windows || (linux && !asserts)\n; RUN: false\n";
+        let with_features = |features: &[&str]| {
+            let mut config = Config::default();
+            features.iter().for_each(|feature| config.add_feature(feature));
+            execute_with_config(test, &config)[0].kind.clone()
+        };
+
+        assert!(matches!(with_features(&["windows"]), TestResultKind::ExpectedFailure { .. }));
+        assert!(matches!(with_features(&["linux"]), TestResultKind::ExpectedFailure { .. }));
+        assert!(matches!(with_features(&["linux", "asserts"]), TestResultKind::Fail { .. }));
+        assert!(matches!(with_features(&[]), TestResultKind::Fail { .. }));
+
+        let path = TestFilePath { absolute: PathBuf::from("/tests/foo.txt"), relative: PathBuf::from("foo.txt") };
+        let test_file = parse::test_file(path, test.chars()).unwrap();
+        assert!(test_file.is_expected_failure(), "a test with any XFAIL directive is expected to fail somewhere");
+        assert!(!test_file.is_expected_failure_with_config(&Config::default()));
+    }
+
+    #[test]
+    fn checks_match_scrubbed_output() {
+        let config = Config { output_scrubbers: vec![crate::config::OutputScrubber::timestamps()], ..Config::default() };
+        let results = execute_with_config("; RUN: echo 'at 2020-01-31 12:30:59'\n; CHECK: at <TIMESTAMP>\n", &config);
+
+        assert_eq!(results[0].kind, TestResultKind::Pass);
+        assert_eq!(results[0].output.stdout, "at 2020-01-31 12:30:59\n");
+    }
+
+    #[test]
+    fn expected_failures_must_still_match_their_checks() {
+        let test = |check: &str| format!("; XFAIL:\n; RUN: echo 'error: bad input' && false\n; CHECK: {}\n", check);
+
+        match execute(&test("error: bad input"))[0].kind {
+            TestResultKind::ExpectedFailure { actual_reason: TestFailReason::UnsuccessfulExecution { .. } } => (),
+            ref kind => panic!("expected an expected failure but got {:?}", kind),
+        }
+        match execute(&test("error: bad output"))[0].kind {
+            TestResultKind::Fail { reason: TestFailReason::CheckFailed(..), .. } => (),
+            ref kind => panic!("expected a check failure but got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn trailing_whitespace_can_be_ignored() {
+        let test = "; RUN: printf 'foo  \\nbar \\t\\n'\n; CHECK: foo[[\\n]]bar[[\\n]]\n";
+        let config = Config { ignore_trailing_whitespace: true, ..Config::default() };
+
+        assert!(execute(test)[0].kind.is_erroneous());
+        assert_eq!(execute_with_config(test, &config)[0].kind, TestResultKind::Pass);
+        assert_eq!(super::trim_trailing_whitespace("a \n  \r\nb\t"), "a\n\nb");
+    }
+
+    #[test]
+    fn declared_exit_code_is_required() {
+        assert_eq!(execute("; RUN: echo foo && exit 3\n; EXIT: 3\n; CHECK: foo\n")[0].kind, TestResultKind::Pass);
+
+        match execute("; RUN: echo foo\n; EXIT: 3\n; CHECK: foo\n")[0].kind {
+            TestResultKind::Fail { reason: TestFailReason::UnexpectedExitCode { expected_exit_status: ExpectedExitCode::Code(3), exit_status: 0, .. }, .. } => (),
+            ref kind => panic!("expected an unexpected exit code, but got {:?}", kind),
+        }
+
+        assert_eq!(execute("; RUN: echo foo && exit 7\n; EXIT: nonzero\n; CHECK: foo\n")[0].kind, TestResultKind::Pass);
+        assert!(execute("; RUN: echo foo\n; EXIT: nonzero\n; CHECK: foo\n")[0].kind.is_erroneous());
+    }
+
+    #[test]
+    fn every_failing_success_criterion_is_reported() {
+        let results = execute("; RUN: echo foo && echo oops >&2 && exit 1\n; EXIT: 0\n; CHECK-STDERR-EMPTY:\n; CHECK: bar\n");
+
+        match results[0].kind {
+            TestResultKind::Fail { reason: TestFailReason::CriteriaNotMet(ref reasons), .. } => {
+                assert!(matches!(reasons[..], [
+                    TestFailReason::UnexpectedExitCode { .. },
+                    TestFailReason::UnexpectedStderr { .. },
+                    TestFailReason::CheckFailed(..),
+                ]), "unexpected reasons: {:?}", reasons);
+            },
+            ref kind => panic!("expected several unmet criteria, but got {:?}", kind),
+        }
+
+        assert_eq!(execute("; RUN: echo foo\n; CHECK-STDERR-EMPTY:\n; CHECK: foo\n")[0].kind, TestResultKind::Pass);
+    }
+
+    #[test]
+    fn checks_can_run_against_in_memory_output() {
+        let path = TestFilePath { absolute: PathBuf::from("/tests/foo.txt"), relative: PathBuf::from("foo.txt") };
+        let test_file = TestFile::parse(path, "; RUN: false\n; CHECK: foo\n; CHECK-NEXT: bar\n").unwrap();
+        let config = Config::default();
+
+        for (output, expected_to_pass) in [("foo\nbar\n", true), ("foo\nbaz\nbar\n", false), ("", false)] {
+            assert_eq!(test_file.run_against(output, &config).is_erroneous(), !expected_to_pass, "output: {:?}", output);
+        }
+    }
+
+    #[test]
+    fn only_the_selected_run_line_is_executed() {
+        let test = "; RUN: echo foo\n; RUN: echo bar\n; CHECK: bar\n";
+        let results = execute_with_config(test, &Config { run_line: Some(2), ..Config::default() });
+
+        assert_eq!(results.len(), 1);
+        assert_eq!((results[0].line_number, &results[0].kind), (2, &TestResultKind::Pass));
+        assert!(execute_with_config(test, &Config { run_line: Some(3), ..Config::default() }).is_empty());
+    }
+
+    #[test]
+    fn whole_line_checks_only_match_complete_lines() {
+        assert_eq!(execute("; MATCH-MODE: whole-line\n; RUN: printf 'value: 10\\nvalue: 1\\nvalue: 2\\n'\n; CHECK: value: 1\n; CHECK-NEXT[literal]: value\n")[0].kind, TestResultKind::Pass);
+        assert!(execute("; MATCH-MODE: whole-line\n; RUN: echo 'value: 10'\n; CHECK: value: 1\n")[0].kind.is_erroneous());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn crashes_are_reported_with_their_signal() {
+        for text in ["; RUN: kill -SEGV $$\n", "; RUN: kill -SEGV $$\n; EXIT: nonzero\n", "; RUN: kill -SEGV $$\n; EXIT: 1\n"] {
+            match execute(text)[0].kind {
+                TestResultKind::Fail { reason: ref reason @ TestFailReason::TerminatedBySignal { signal: 11, .. }, .. } => {
+                    assert_eq!(reason.human_detail_message(&Config::default()), "command 'kill -SEGV $$' was terminated by signal 11 (SIGSEGV)");
+                },
+                ref kind => panic!("expected the crash to be reported, but got {:?}", kind),
+            }
+        }
+
+        match execute("; RUN: true | sh -c 'kill -SEGV $$'\n")[0].kind {
+            TestResultKind::Fail { reason: TestFailReason::TerminatedBySignal { signal: 11, .. }, .. } => (),
+            ref kind => panic!("expected the crash of a pipeline stage to be reported, but got {:?}", kind),
+        }
+        match execute("; RUN: false || exit 139\n; EXIT: 139\n")[0].kind {
+            TestResultKind::Pass => (),
+            ref kind => panic!("expected exit codes of commands which are not pipelines to be kept, but got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn check_not_fails_the_check_after_a_forbidden_match() {
+        assert_eq!(execute("; RUN: printf 'a\\nb\\nc\\n'\n; CHECK: a\n; CHECK-NOT: c\n; CHECK: b\n")[0].kind, TestResultKind::Pass);
+
+        let results = execute("; RUN: printf 'a\\nerror\\nb\\n'\n; CHECK: a\n; CHECK-NOT: error\n; CHECK: b\n");
+        match results[0].kind {
+            TestResultKind::Fail { reason: TestFailReason::ForbiddenMatch { line_number: 2, anywhere: false, .. }, .. } => (),
+            ref kind => panic!("expected the forbidden match to fail, but got {:?}", kind),
+        }
+        assert_eq!(results[0].checks.iter().map(|c| c.status).collect::<Vec<_>>(), vec![CheckStatus::Passed, CheckStatus::Passed, CheckStatus::Failed]);
+    }
+
+    #[test]
+    fn checks_after_a_label_only_match_up_to_the_next_label() {
+        let output = "define foo\\nret 1\\ndefine bar\\nret 2\\n";
+        let test = |checks: &str| execute(&format!("; RUN: printf '{}'\n{}", output, checks))[0].kind.clone();
+
+        assert_eq!(test("; CHECK-LABEL: define foo\n; CHECK: ret 1\n; CHECK-LABEL: define bar\n; CHECK-NEXT: ret 2\n"), TestResultKind::Pass);
+        assert_eq!(test("; CHECK-LABEL: define foo\n; CHECK-DAG: ret\n; CHECK-LABEL: define bar\n; CHECK-REST: ret 2\n"), TestResultKind::Pass);
+
+        match test("; CHECK-LABEL: define foo\n; CHECK: ret 2\n; CHECK-LABEL: define bar\n") {
+            TestResultKind::Fail { reason: TestFailReason::CheckFailed(..), hint: Some(hint) } => assert!(hint.contains("CHECK-LABEL"), "{}", hint),
+            kind => panic!("expected the check to fail within the region of the label, but got {:?}", kind),
+        }
+        assert_eq!(test("; CHECK-LABEL: define foo\n; CHECK: ret 2\n"), TestResultKind::Pass);
+    }
+
+    #[test]
+    fn check_rest_requires_the_remaining_output_to_be_identical() {
+        assert_eq!(execute("; RUN: printf 'log\\nok\\ndone\\n'\n; CHECK: log\n; CHECK-REST: ok\n; CHECK-REST: done\n")[0].kind, TestResultKind::Pass);
+
+        match execute("; RUN: printf 'ok\\ndone\\nextra\\n'\n; CHECK-REST: ok\n; CHECK-REST: done\n")[0].kind {
+            TestResultKind::Fail { reason: TestFailReason::RestMismatch { ref diff }, .. } => assert!(diff.ends_with("+ extra\n"), "{}", diff),
+            ref kind => panic!("expected the extra line to fail, but got {:?}", kind),
+        }
+
+        assert_eq!(execute("; RUN: printf 'ok\\040\\040\\n'\n; CHECK-REST: ok  \n")[0].kind, TestResultKind::Pass, "trailing spaces should be compared");
+    }
+
+    #[test]
+    fn sizes_are_checked_against_the_raw_output() {
+        assert_eq!(execute("; RUN: printf '\\377\\376'\n; CHECK-SIZE: == 2\n")[0].kind, TestResultKind::Pass);
+        assert_eq!(execute("; RUN: printf '\\377\\376' > @out_tempfile\n; CHECK-OUTPUT-FILE: @out_tempfile\n; CHECK-SIZE: == 2\n")[0].kind, TestResultKind::Pass);
+        assert_eq!(execute("; STREAMING: 5s\n; RUN: printf 'ab\\377'\n; CHECK: ab\n; CHECK-SIZE: == 3\n")[0].kind, TestResultKind::Pass);
+    }
+
+    #[test]
+    fn final_newlines_are_checked_against_the_raw_output() {
+        assert_eq!(execute("; RUN: printf 'a\\nb\\n'\n; CHECK: b\n; CHECK-FINAL-NEWLINE:\n")[0].kind, TestResultKind::Pass);
+        assert_eq!(execute("; RUN: printf 'a\\nb'\n; CHECK-NO-FINAL-NEWLINE:\n")[0].kind, TestResultKind::Pass);
+
+        match execute("; RUN: printf 'a'\n; CHECK-FINAL-NEWLINE:\n")[0].kind {
+            TestResultKind::Fail { reason: TestFailReason::UnexpectedFinalNewline { expected: true }, .. } => (),
+            ref kind => panic!("expected the missing newline to fail, but got {:?}", kind),
+        }
+
+        let config = Config { combine_stderr_into_stdout: true, ..Config::default() };
+        let results = execute_with_config("; RUN: printf 'a\\n' && printf 'b' >&2\n; CHECK: a\n; CHECK-FINAL-NEWLINE:\n", &config);
+        assert_eq!(results[0].kind, TestResultKind::Pass, "standard error should not count as the end of standard output");
+    }
+
+    #[test]
+    fn checks_can_match_an_output_file() {
+        let results = execute("; RUN: echo foo && echo bar > @out_tempfile\n; CHECK: foo\n; CHECK-OUTPUT-FILE: @out_tempfile\n; CHECK: bar\n");
+        assert_eq!(results[0].kind, TestResultKind::Pass);
+
+        match execute("; RUN: true\n; CHECK-OUTPUT-FILE: /nonexistent/output.txt\n; CHECK: bar\n")[0].kind {
+            TestResultKind::Fail { reason: TestFailReason::UnreadableOutputFile { ref path, .. }, .. } => assert_eq!(path, "/nonexistent/output.txt"),
+            ref kind => panic!("expected a missing output file to fail, but got {:?}", kind),
+        }
+
+        let config = self::config_with_files(&[]);
+        let results = execute_with_config("; RUN: echo bar > @out_tempfile\n; CHECK-OUTPUT-FILE: @out_tempfile\n; CHECK: bar\n", &config);
+        assert_eq!(results[0].kind, TestResultKind::Pass, "output files should be read from the real file system");
+    }
+
+    #[test]
+    fn checks_that_files_were_created() {
+        let results = execute("; RUN: touch @o_tempfile @d_tempfile\n; CHECK-CREATED: @o_tempfile @d_tempfile\n");
+        assert_eq!(results[0].kind, TestResultKind::Pass);
+
+        match execute("; RUN: true\n; CHECK-CREATED: /nonexistent/output.o\n")[0].kind {
+            TestResultKind::Fail { reason: TestFailReason::UnexpectedCreatedFiles { ref missing, .. }, .. } => assert_eq!(missing, &["/nonexistent/output.o"]),
+            ref kind => panic!("expected a missing file to fail, but got {:?}", kind),
+        }
+
+        match execute("; RUN: mkdir @tempfile\n; CHECK-CREATED: @tempfile\n")[0].kind {
+            TestResultKind::Fail { reason: TestFailReason::UnexpectedCreatedFiles { ref missing, .. }, .. } => assert_eq!(missing.len(), 1),
+            ref kind => panic!("expected a directory not to count as a created file, but got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn only_the_declared_files_may_be_created() {
+        let output_directory = tempfile::tempdir().unwrap();
+        let directory = output_directory.path().display();
+        std::fs::write(output_directory.path().join("existing.txt"), "").unwrap();
+
+        let results = execute(&format!("; RUN: touch {0}/a.o\n; CHECK-CREATED-ONLY: {0}/a.o\n", directory));
+        assert_eq!(results[0].kind, TestResultKind::Pass);
+
+        match execute(&format!("; RUN: touch {0}/b.o {0}/b.d\n; CHECK-CREATED-ONLY: {0}/b.o\n", directory))[0].kind {
+            TestResultKind::Fail { reason: TestFailReason::UnexpectedCreatedFiles { ref missing, ref unexpected }, .. } => {
+                assert!(missing.is_empty());
+                assert_eq!(unexpected, &[format!("{}/b.d", directory)]);
+            },
+            ref kind => panic!("expected an unexpected file to fail, but got {:?}", kind),
+        }
+
+        let results = execute(&format!("; RUN: mkdir {0}/sub && touch {0}/c.o {0}/sub/c.d\n; CHECK-CREATED-ONLY: {0}/c.o\n", directory));
+        assert_eq!(results[0].kind, TestResultKind::Pass, "files in subdirectories should not be unexpected");
+    }
+
+    #[test]
+    fn tempfiles_are_shared_by_commands_and_checks() {
+        let results = execute("; RUN: echo @tempfile && touch @tempfile\n; CHECK: $$tempfile\n");
+        assert_eq!(results[0].kind, TestResultKind::Pass);
+
+        let tempfile_path = results[0].output.stdout.trim();
+        assert!(!std::path::Path::new(tempfile_path).exists(), "tempfile '{}' was not cleaned up", tempfile_path);
+    }
+
+    #[test]
+    fn captured_variables_must_match_the_same_text_later() {
+        let test = |returned: &str| execute(&format!("; RUN: echo '%7 = add' && echo 'ret {}'\n; CHECK: %[[REG_1:[0-9]+]] = add\n; CHECK: ret %[[REG_1]]\n", returned))[0].kind.clone();
+
+        assert_eq!(test("%7"), TestResultKind::Pass);
+        assert!(test("%8").is_erroneous());
+
+        match execute("; RUN: echo a\n; CHECK: [[NAME]]\n; CHECK: [[NAME:a]]\n")[0].kind {
+            TestResultKind::Error { ref message } => assert!(message.contains("variable 'NAME' is used before the check that captures it"), "{}", message),
+            ref kind => panic!("expected a variable ordering error, but got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn captured_variables_match_their_text_literally() {
+        let test = |output: &str| execute(&format!("; RUN: printf '{}'\n; CHECK: [[NAME:[^ ]+]] =\n; CHECK: is [[NAME]]\n", output))[0].kind.clone();
+
+        assert_eq!(test("a.c =\\nis a.c"), TestResultKind::Pass);
+        assert!(test("a.c =\\nis abc").is_erroneous());
+        assert_eq!(test("f( =\\nis f("), TestResultKind::Pass);
+    }
+
+    #[test]
+    fn bracketed_names_which_are_never_captured_are_regexes() {
+        assert_eq!(execute("; RUN: echo hello NOPE\n; CHECK: [[hello]] [[NOPE]]\n")[0].kind, TestResultKind::Pass);
+
+        match execute("; RUN: echo a\n; CHECK: $$NOPE\n")[0].kind {
+            TestResultKind::Error { ref message } => assert!(message.contains("variable 'NOPE' is not defined"), "{}", message),
+            ref kind => panic!("expected an undefined variable error, but got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn numeric_expressions_are_computed_from_captured_numbers() {
+        let test = "; RUN: echo 'base 16 size 4' && echo 'end 20' && echo 'next 15'\n; CHECK: base [[#BASE:]] size [[#SIZE:]]\n; CHECK: end [[#BASE+SIZE]]\n; CHECK: next [[#BASE - 1]]\n";
+        assert_eq!(execute(test)[0].kind, TestResultKind::Pass);
+        assert!(execute(&test.replace("end 20", "end 21"))[0].kind.is_erroneous());
+
+        let error_message = |test: &str| match execute(test)[0].kind {
+            TestResultKind::Error { ref message } => message.clone(),
+            ref kind => panic!("expected an error, but got {:?}", kind),
+        };
+        assert!(error_message("; RUN: echo 1\n; CHECK: [[NAME:.]]\n; CHECK: [[#NAME+1]]\n").contains("'NAME' is not a numeric variable"));
+        assert!(error_message("; RUN: echo 1\n; CHECK: [[#NAME]]\n; CHECK: [[#NAME:]]\n").contains("variable 'NAME' is used before the check that captures it"));
+    }
+
+    #[test]
+    fn numeric_expressions_which_cannot_be_evaluated_are_errors() {
+        let error_message = |test: &str| match execute(test)[0].kind {
+            TestResultKind::Error { ref message } => message.clone(),
+            ref kind => panic!("expected an error, but got {:?}", kind),
+        };
+
+        assert!(error_message("; RUN: echo n=18446744073709551615 && echo 18446744073709551615\n; CHECK: n=[[#N:]]\n; CHECK: [[#N]]\n").contains("variable 'N' is not a number"));
+        assert!(error_message("; RUN: echo n=9223372036854775807 && echo 0\n; CHECK: n=[[#N:]]\n; CHECK: [[#N+1]]\n").contains("numeric expression 'N+1' overflows"));
+    }
+
+    #[test]
+    fn percent_substitutions_are_expanded_in_run_commands() {
+        let config = Config { percent_substitutions: true, ..Config::default() };
+        let results = execute_with_config("; RUN: echo %s %S 100%% && touch %t && test -d %T && echo %t\n; CHECK: /tests/foo.txt /tests 100%\n; CHECK-NEXT: $$tempfile\n", &config);
+        assert_eq!(results[0].kind, TestResultKind::Pass);
+
+        let tempfile_path = results[0].output.stdout.lines().nth(1).unwrap();
+        assert!(!std::path::Path::new(tempfile_path).exists(), "tempfile '{}' was not cleaned up", tempfile_path);
+    }
+
+    #[test]
+    fn stderr_can_be_combined_deterministically_after_stdout() {
+        let test = "; RUN: echo first-err >&2 && echo out && echo second-err >&2\n; CHECK: out\n; CHECK-NEXT: first-err\n; CHECK-NEXT: second-err\n";
+        let config = Config { combine_stderr_into_stdout: true, ..Config::default() };
+
+        assert!(execute(test)[0].kind.is_erroneous());
+        assert_eq!(execute_with_config(test, &config)[0].kind, TestResultKind::Pass);
+    }
+
+    #[test]
+    fn cancelled_commands_are_killed() {
+        let cancellation_token = crate::config::CancellationToken::new();
+        let config = Config { cancellation_token: Some(cancellation_token.clone()), ..Config::default() };
+
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            cancellation_token.cancel();
+        });
+
+        let started_at = std::time::Instant::now();
+        let results = execute_with_config("; RUN: sleep 10\n; CHECK: foo\n", &config);
+        canceller.join().unwrap();
+
+        assert_eq!(results[0].kind, TestResultKind::Skip { reason: SkipReason::Cancelled });
+        assert!(started_at.elapsed() < Duration::from_secs(5), "the command should have been killed");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cancellation_kills_every_process_started_by_the_command() {
+        let directory = tempfile::tempdir().unwrap();
+        let pid_path = directory.path().join("pid");
+        let cancellation_token = crate::config::CancellationToken::new();
+        let config = Config { cancellation_token: Some(cancellation_token.clone()), ..Config::default() };
+
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            cancellation_token.cancel();
+        });
+        let results = execute_with_config(&format!("; RUN: sleep 30 & echo $! > {}; wait\n", pid_path.display()), &config);
+        canceller.join().unwrap();
+        assert_eq!(results[0].kind, TestResultKind::Skip { reason: SkipReason::Cancelled });
+
+        let pid = std::fs::read_to_string(&pid_path).unwrap();
+        assert!(!is_running_after_a_moment(pid.trim()), "the background job of the command should have been killed");
+    }
+
+    #[test]
+    fn custom_matchers_are_looked_up_by_name() {
+        let mut config = Config::default();
+        config.add_custom_matcher("floateq", crate::config::matchers::float_eq);
+
+        assert_eq!(execute_with_config("; RUN: echo 3.1416\n; CHECK-CUSTOM[floateq]: 3.14 +- 0.01\n", &config)[0].kind, TestResultKind::Pass);
+        assert!(execute_with_config("; RUN: echo 3.1416\n; CHECK-CUSTOM[floateq]: 2.71\n", &config)[0].kind.is_erroneous());
+
+        match execute("; RUN: echo 3.1416\n; CHECK-CUSTOM[floateq]: 3.14\n")[0].kind {
+            TestResultKind::Error { ref message } => assert!(message.contains("floateq")),
+            ref kind => panic!("expected an unregistered matcher to be an error, but got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn masked_spans_are_equal_in_the_output_and_the_checks() {
+        let test = "; RUN: echo built on 2023-05-06 at 10:42\n; MASK: \\d{4}-\\d{2}-\\d{2}\n; MASK: \\d{2}:\\d{2}\n; CHECK: built on 2000-01-01 at 00:00\n";
+        assert_eq!(execute(test)[0].kind, TestResultKind::Pass);
+
+        assert_eq!(execute("; RUN: echo built on 2023-05-06\n; MASK: \\d{4}-\\d{2}-\\d{2}\n; CHECK: built on <masked>\n")[0].kind, TestResultKind::Pass);
+        assert!(execute("; RUN: echo built on 2023-05-06\n; CHECK: built on 2000-01-01\n")[0].kind.is_erroneous());
+    }
+
+    #[test]
+    fn outputs_of_run_commands_can_be_compared() {
+        assert!(execute("; RUN: echo 42\n; RUN: echo 42\n; CHECK-RUNS-EQUAL: 0 1\n").iter().all(|r| r.kind == TestResultKind::Pass));
+
+        let results = execute("; RUN: printf 'a\\nb\\n'\n; RUN: printf 'a\\nc\\n'\n; CHECK-RUNS-EQUAL: 0 1\n");
+        assert_eq!(results[0].kind, TestResultKind::Pass);
+        match results[1].kind {
+            TestResultKind::Fail { reason: TestFailReason::RunOutputsDiffer { first_run_index: 0, second_run_index: 1, ref diff }, .. } => {
+                assert_eq!(diff, "  a\n- b\n+ c\n");
+            },
+            ref kind => panic!("expected the outputs to differ, but got {:?}", kind),
+        }
+
+        assert!(matches!(execute("; RUN: echo 42\n; CHECK-RUNS-EQUAL: 0 1\n")[0].kind, TestResultKind::Error { .. }));
+    }
+
+    #[test]
+    fn tempfiles_of_failing_runs_are_kept() {
+        let test = "; RUN: echo @tempfile && touch @tempfile\n; CHECK: missing\n";
+
+        let results = execute(test);
+        assert!(results[0].kind.is_erroneous());
+        assert_eq!(results[0].kept_tempfiles.len(), 1);
+        assert!(results[0].kept_tempfiles[0].exists());
+        std::fs::remove_file(&results[0].kept_tempfiles[0]).unwrap();
+
+        let results = execute_with_config(test, &Config { keep_tempfiles: crate::config::KeepTempfiles::Never, ..Config::default() });
+        assert!(results[0].kept_tempfiles.is_empty());
+        assert!(!std::path::Path::new(results[0].output.stdout.trim()).exists());
+
+        #[allow(deprecated)]
+        let config = Config { keep_tempfiles: crate::config::KeepTempfiles::Never, cleanup_temporary_files: false, ..Config::default() };
+        let results = execute_with_config("; RUN: echo @tempfile && touch @tempfile\n", &config);
+        assert_eq!(results[0].kept_tempfiles.len(), 1, "tempfiles should be kept when the deprecated setting disables the cleanup");
+        std::fs::remove_file(&results[0].kept_tempfiles[0]).unwrap();
+    }
+
+    #[test]
+    fn streaming_checks_are_evaluated_whilst_the_command_runs() {
+        let started_at = std::time::Instant::now();
+        let results = execute("; STREAMING: 5s\n; RUN: echo starting && echo ready && sleep 10\n; CHECK: starting\n; CHECK-NEXT: ready\n");
+        assert_eq!(results[0].kind, TestResultKind::Pass);
+        assert!(started_at.elapsed() < Duration::from_secs(5), "the command should have been killed once the checks passed");
+        assert_eq!(results[0].output.stdout, "starting\nready\n");
+
+        match execute("; STREAMING: 100ms\n; RUN: echo starting && sleep 10\n; CHECK: ready\n")[0].kind {
+            TestResultKind::Fail { ref hint, .. } => assert!(hint.as_ref().unwrap().contains("streaming timeout")),
+            ref kind => panic!("expected the check to time out, but got {:?}", kind),
+        }
+        assert!(started_at.elapsed() < Duration::from_secs(5), "the command should have been killed once a check failed");
+
+        assert!(execute("; STREAMING: 5s\n; RUN: echo starting\n; CHECK: ready\n")[0].kind.is_erroneous(),
+                "checks that are not satisfied before the command exits should fail");
+    }
+
+    #[test]
+    fn stdin_is_written_to_every_run_command() {
+        let config = self::config_with_files(&[("input.txt", b"from a file\n"), ("binary.bin", &[0xff, 0xfe])]);
+
+        let results = execute("; RUN: tr a-z A-Z\n; RUN: wc -l\n; STDIN: first\n; STDIN:   second\n; CHECK: [[[A-Z0-9 ]+]]\n");
+        assert_eq!(results[0].output.stdout, "FIRST\n  SECOND\n");
+        assert_eq!(results[1].output.stdout.trim(), "2");
+
+        let results = execute_with_config("; RUN: cat\n; STDIN-FILE: input.txt\n; CHECK: from a file\n", &config);
+        assert_eq!(results[0].kind, TestResultKind::Pass);
+        let results = execute_with_config("; RUN: wc -c\n; STDIN-FILE: binary.bin\n; CHECK: 2\n", &config);
+        assert_eq!(results[0].kind, TestResultKind::Pass, "input files do not have to be text");
+
+        let results = execute("; RUN: cat\n; STDIN: trailing  \n; CHECK-REST: trailing  \n");
+        assert_eq!(results[0].kind, TestResultKind::Pass, "trailing spaces should be written to standard input");
+        assert!(execute_with_config("; RUN: cat\n; STDIN-FILE: missing.txt\n", &config)[0].kind.is_erroneous());
+    }
+
+    #[test]
+    fn output_can_be_compared_with_expected_files() {
+        let platform_file_name = format!("expected.{}.txt", std::env::consts::OS);
+        let config = Config {
+            check_file_fallback_os: Some("default".to_owned()),
+            ..self::config_with_files(&[("expected.txt", b"a\nb\n"), (&platform_file_name, b"platform\n"), ("only.default.txt", b"default\n")])
+        };
+
+        assert_eq!(execute_with_config("; RUN: printf 'a\\nb\\n'\n; CHECK-FILE: expected.txt\n", &config)[0].kind, TestResultKind::Pass);
+        assert_eq!(execute_with_config("; RUN: echo platform\n; CHECK-FILE: expected.@os.txt\n", &config)[0].kind, TestResultKind::Pass);
+        assert_eq!(execute_with_config("; RUN: echo default\n; CHECK-FILE: only.@os.txt\n", &config)[0].kind, TestResultKind::Pass);
+
+        match execute_with_config("; RUN: printf 'a\\nc\\n'\n; CHECK-FILE: expected.txt\n", &config)[0].kind {
+            TestResultKind::Fail { reason: TestFailReason::ExpectedFileMismatch { ref diff, .. }, .. } => assert_eq!(diff, "  a\n- b\n+ c\n"),
+            ref kind => panic!("expected the output to differ from the file, but got {:?}", kind),
+        }
+        assert!(matches!(execute_with_config("; RUN: true\n; CHECK-FILE: missing.txt\n", &config)[0].kind, TestResultKind::Error { .. }));
+    }
+
+    #[test]
+    fn output_can_be_validated_against_schemas() {
+        let config = self::config_with_files(&[
+            ("schema.json", br#"{ "type": "object", "required": ["status"], "properties": { "status": { "enum": ["ok"] } } }"#),
+            ("invalid.json", b"{"),
+            ("reference.json", br#"{ "$ref": "schema.json" }"#),
+        ]);
+
+        assert_eq!(execute_with_config("; RUN: echo '{\"status\": \"ok\"}'\n; CHECK-SCHEMA: schema.json\n", &config)[0].kind, TestResultKind::Pass);
+
+        match execute_with_config("; RUN: echo '{\"status\": \"failed\"}'\n; CHECK-SCHEMA: schema.json\n", &config)[0].kind {
+            TestResultKind::Fail { reason: TestFailReason::SchemaViolation { ref errors, .. }, .. } => {
+                assert_eq!(errors, &["/status: \"failed\" is not one of the allowed values".to_owned()]);
+            },
+            ref kind => panic!("expected the output to violate the schema, but got {:?}", kind),
+        }
+        assert!(matches!(execute_with_config("; RUN: echo 'status: ok'\n; CHECK-SCHEMA: schema.json\n", &config)[0].kind,
+                         TestResultKind::Fail { reason: TestFailReason::SchemaViolation { .. }, .. }));
+        assert!(matches!(execute_with_config("; RUN: true\n; CHECK-SCHEMA: invalid.json\n", &config)[0].kind, TestResultKind::Error { .. }));
+        assert!(matches!(execute_with_config("; RUN: echo '{}'\n; CHECK-SCHEMA: reference.json\n", &config)[0].kind, TestResultKind::Error { .. }));
+    }
+
+    #[test]
+    fn checks_guarded_by_unavailable_features_are_ignored() {
+        let mut config = Config::default();
+        config.add_feature("linux");
+
+        let results = execute_with_config("; RUN: printf 'common\\nlinux\\n'\n; CHECK: common\n; CHECK-NEXT{windows}: windows\n; CHECK-NEXT{linux}: linux\n", &config);
+        assert_eq!(results[0].kind, TestResultKind::Pass);
+        assert_eq!(results[0].checks.iter().map(|c| c.status).collect::<Vec<_>>(),
+                   vec![CheckStatus::Passed, CheckStatus::Disabled, CheckStatus::Passed]);
+
+        assert!(execute("; RUN: echo common\n; CHECK{linux}: missing\n; CHECK: common\n")[0].kind == TestResultKind::Pass);
+    }
+
+    #[test]
+    fn params_are_substituted_into_commands_and_checks() {
+        let mut config = Config::default();
+        config.params.insert("triple".to_owned(), "x86_64-linux".to_owned());
+
+        assert_eq!(execute_with_config("; RUN: echo target x86_64-linux\n; CHECK: target %{param:triple}\n", &config)[0].kind, TestResultKind::Pass);
+        assert_eq!(execute_with_config("; RUN: echo target %{param:triple}\n; CHECK: target x86_64-linux\n", &config)[0].kind, TestResultKind::Pass);
+
+        match execute_with_config("; RUN: echo target\n; CHECK: target %{param:cpu}\n", &config)[0].kind {
+            TestResultKind::Error { ref message } => assert!(message.contains("parameter 'cpu' is not defined"), "{}", message),
+            ref kind => panic!("expected an undefined parameter error, but got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn check_same_count_counts_matches_on_the_matched_line() {
+        assert_eq!(execute("; RUN: echo 'error: a error b'\n; CHECK: error:\n; CHECK-SAME-COUNT-1: error\n")[0].kind, TestResultKind::Pass);
+        assert!(execute("; RUN: echo 'error: a error b'\n; CHECK: a\n; CHECK-SAME-COUNT-2: error\n")[0].kind.is_erroneous());
+    }
+
+    #[test]
+    fn check_same_matches_the_rest_of_the_matched_line() {
+        assert_eq!(execute("; RUN: echo 'warning: unused x in main'\n; CHECK: warning:\n; CHECK-SAME: x\n; CHECK-SAME: main\n")[0].kind, TestResultKind::Pass);
+        assert!(execute("; RUN: echo 'warning: unused x in main'\n; CHECK: main\n; CHECK-SAME: x\n")[0].kind.is_erroneous());
+    }
+
+    #[test]
+    fn check_count_matches_a_pattern_several_times_in_a_row() {
+        assert_eq!(execute("; RUN: printf 'row\\nrow\\nrow\\nend\\n'\n; CHECK-COUNT-3: row\n; CHECK-NEXT: end\n")[0].kind, TestResultKind::Pass);
+
+        match execute("; RUN: printf 'row\\nrow\\nend\\n'\n; CHECK-COUNT-3: row\n")[0].kind {
+            TestResultKind::Fail { hint: Some(ref hint), .. } => assert!(hint.starts_with("found 2 consecutive matches"), "{}", hint),
+            ref kind => panic!("expected the missing match to fail, but got {:?}", kind),
+        }
+
+        match execute("; RUN: printf 'row\\nother\\nrow\\nrow\\n'\n; CHECK-COUNT-3: row\n")[0].kind {
+            TestResultKind::Fail { hint: Some(ref hint), .. } => assert!(hint.starts_with("found 1 consecutive matches"), "{}", hint),
+            ref kind => panic!("expected matches which are not consecutive to fail, but got {:?}", kind),
+        }
+        match execute("; RUN: printf 'row\\nrow\\nrow\\nrow\\n'\n; CHECK-COUNT-3: row\n")[0].kind {
+            TestResultKind::Fail { hint: Some(ref hint), .. } => assert!(hint.starts_with("found more than 3"), "{}", hint),
+            ref kind => panic!("expected an extra match to fail, but got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn check_no_output_requires_both_streams_to_be_empty() {
+        assert_eq!(execute("; RUN: true\n; CHECK-NO-OUTPUT:\n")[0].kind, TestResultKind::Pass);
+        assert!(matches!(execute("; RUN: echo done\n; CHECK-NO-OUTPUT:\n")[0].kind,
+                         TestResultKind::Fail { reason: TestFailReason::UnexpectedOutput { .. }, .. }));
+        assert!(matches!(execute("; RUN: echo warning >&2\n; CHECK-NO-OUTPUT:\n")[0].kind,
+                         TestResultKind::Fail { reason: TestFailReason::UnexpectedOutput { .. }, .. }));
+
+        let config = Config { ignore_trailing_whitespace: true, ..Config::default() };
+        assert_eq!(execute_with_config("; RUN: echo\n; CHECK-NO-OUTPUT:\n", &config)[0].kind, TestResultKind::Pass);
+        assert!(execute("; RUN: echo\n; CHECK-NO-OUTPUT:\n")[0].kind.is_erroneous());
+    }
+
+    #[test]
+    fn only_temporary_spawn_errors_are_retried() {
+        // Retrying this many times would take far longer than the test.
+        let config = Config { shell: "/nonexistent/shell".to_owned(), spawn_retries: 30, ..Config::default() };
+
+        assert_eq!(execute_with_config("; RUN: true\n", &config)[0].kind,
+                   TestResultKind::SpawnError { program_command_line: "true".to_owned(), message: "shell '/nonexistent/shell' does not exist".to_owned() });
+    }
+}
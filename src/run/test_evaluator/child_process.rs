@@ -0,0 +1,403 @@
+//! Execution of the child processes behind `RUN` directives.
+
+use crate::config::CancellationToken;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::process::{self, Stdio};
+use std::sync::{Arc, Mutex, Once};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a child process with a timeout or cancellation token is polled for completion.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long to wait before the first retry of starting a child process, which doubles on each retry.
+const INITIAL_SPAWN_RETRY_DELAY: Duration = Duration::from_millis(50);
+/// The longest delay between retries of starting a child process, however many retries there are.
+const MAX_SPAWN_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How long to wait for the pipes of a killed child to be closed before giving up on the rest of its output.
+///
+/// Processes which left the process group of the child, like daemons, can keep the pipes open forever.
+const KILLED_PIPE_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The maximum number of process groups which are killed when lit is interrupted.
+#[cfg(unix)]
+const MAX_TRACKED_PROCESS_GROUPS: usize = 1024;
+
+/// The process groups of the running children, or zero for unused slots.
+///
+/// Atomics are used rather than a lock because the groups are killed from a signal handler.
+#[cfg(unix)]
+static RUNNING_PROCESS_GROUPS: [AtomicI32; MAX_TRACKED_PROCESS_GROUPS] = [const { AtomicI32::new(0) }; MAX_TRACKED_PROCESS_GROUPS];
+
+/// The prefixes of the lines of each stream in the interleaved output.
+const STDOUT_LINE_PREFIX: &str = "[stdout] ";
+const STDERR_LINE_PREFIX: &str = "[stderr] ";
+
+/// The output of a finished child process.
+pub struct Output {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// The lines of both streams, in the order that they were read, each prefixed by its stream.
+    pub interleaved: Vec<u8>,
+    pub status: Status,
+}
+
+/// How a child process finished.
+pub enum Status {
+    Exited(process::ExitStatus),
+    /// The process was killed for exceeding its timeout.
+    TimedOut,
+    /// The process was killed because the test suite run was cancelled.
+    Cancelled,
+    /// The process was killed because its output was no longer needed.
+    Stopped,
+}
+
+/// A child process whose output is read whilst it is running.
+pub struct StreamingChild {
+    child: RunningChild,
+    stdout: PipeReader,
+    stderr: PipeReader,
+    interleaved: Arc<Mutex<Interleaved>>,
+}
+
+/// The lines of both output streams of a child, in the order that they were read.
+#[derive(Default)]
+struct Interleaved {
+    text: Vec<u8>,
+    /// The incomplete last line of each stream, by the prefix of the stream.
+    partial_lines: Vec<(&'static str, Vec<u8>)>,
+}
+
+/// Runs a command to completion, killing it if it runs for longer than the timeout
+/// or if the cancellation token is cancelled.
+///
+/// The input is written to the standard input of the command, which is empty without one.
+pub fn output(command: &mut process::Command, stdin: Option<&[u8]>, timeout: Option<Duration>,
+              cancellation_token: Option<&CancellationToken>, spawn_retries: u32) -> io::Result<Output> {
+    let mut child = self::spawn(command, stdin, spawn_retries)?;
+    let interleaved = Arc::new(Mutex::new(Interleaved::default()));
+
+    // Both pipes are read on their own threads so that neither can fill up and block the child.
+    let stdout = PipeReader::spawn(child.child.stdout.take().unwrap(), STDOUT_LINE_PREFIX, interleaved.clone());
+    let stderr = PipeReader::spawn(child.child.stderr.take().unwrap(), STDERR_LINE_PREFIX, interleaved.clone());
+
+    let status = match (timeout, cancellation_token) {
+        (None, None) => Status::Exited(child.child.wait()?),
+        (timeout, cancellation_token) => self::wait_polling(&mut child, timeout, cancellation_token)?,
+    };
+
+    let (stdout, stderr) = match status {
+        Status::Exited(..) => (stdout.finish(), stderr.finish()),
+        Status::TimedOut | Status::Cancelled | Status::Stopped => (stdout.finish_within(KILLED_PIPE_CLOSE_TIMEOUT), stderr.finish_within(KILLED_PIPE_CLOSE_TIMEOUT)),
+    };
+    let interleaved = interleaved.lock().unwrap().text.clone();
+
+    Ok(Output { stdout, stderr, interleaved, status })
+}
+
+impl StreamingChild {
+    /// Starts a command, writing its input and reading its output in the background.
+    pub fn spawn(command: &mut process::Command, stdin: Option<&[u8]>, spawn_retries: u32) -> io::Result<Self> {
+        let mut child = self::spawn(command, stdin, spawn_retries)?;
+        let interleaved = Arc::new(Mutex::new(Interleaved::default()));
+
+        let stdout = PipeReader::spawn(child.child.stdout.take().unwrap(), STDOUT_LINE_PREFIX, interleaved.clone());
+        let stderr = PipeReader::spawn(child.child.stderr.take().unwrap(), STDERR_LINE_PREFIX, interleaved.clone());
+
+        Ok(StreamingChild { child, stdout, stderr, interleaved })
+    }
+
+    /// Gets everything that the child has printed on stdout and stderr so far, and the interleaved lines of both.
+    pub fn output_so_far(&self) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        (self.stdout.contents_so_far(), self.stderr.contents_so_far(), self.interleaved.lock().unwrap().text.clone())
+    }
+
+    /// Checks if the child has exited and all of its output has been read.
+    pub fn is_finished(&mut self) -> bool {
+        // An error means that the status can never be known, so nothing more will be printed.
+        let has_exited = self.child.child.try_wait().map(|status| status.is_some()).unwrap_or(true);
+
+        has_exited && self.stdout.is_closed() && self.stderr.is_closed()
+    }
+
+    /// Kills the child and the processes it started if it is still running, and gets its output.
+    ///
+    /// The status of a killed child is the given status, like `Status::Stopped`.
+    pub fn stop(self, status_if_killed: Status) -> io::Result<Output> {
+        let StreamingChild { mut child, stdout, stderr, interleaved } = self;
+
+        let (stdout, stderr, status) = match child.child.try_wait()? {
+            Some(status) => (stdout.finish(), stderr.finish(), Status::Exited(status)),
+            None => {
+                child.kill()?;
+                (stdout.finish_within(KILLED_PIPE_CLOSE_TIMEOUT), stderr.finish_within(KILLED_PIPE_CLOSE_TIMEOUT), status_if_killed)
+            },
+        };
+        let interleaved = interleaved.lock().unwrap().text.clone();
+
+        Ok(Output { stdout, stderr, interleaved, status })
+    }
+}
+
+/// Gets the signal that terminated a process, if it was terminated by one.
+///
+/// Only Unix has signals, so on other platforms there never is one.
+pub fn termination_signal(status: &process::ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    fn termination_signal_impl(status: &process::ExitStatus) -> Option<i32> { std::os::unix::process::ExitStatusExt::signal(status) }
+    #[cfg(not(unix))]
+    fn termination_signal_impl(_: &process::ExitStatus) -> Option<i32> { None }
+
+    termination_signal_impl(status)
+}
+
+/// Kills the process group of every running child, when lit is terminated by `SIGINT`,
+/// `SIGTERM` or `SIGHUP`.
+///
+/// Children run in their own process groups, so they would otherwise keep running after lit
+/// has exited. Signals which already have a handler are left alone. After the children are
+/// killed, the signal terminates lit as usual.
+pub fn kill_children_on_termination() {
+    static INSTALL_HANDLERS: Once = Once::new();
+
+    #[cfg(unix)]
+    extern "C" fn handle_termination(signal: libc::c_int) {
+        self::kill_running_process_groups();
+
+        // SAFETY: `signal` and `raise` are async-signal-safe.
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+
+    INSTALL_HANDLERS.call_once(|| {
+        #[cfg(unix)]
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            let handler = handle_termination as extern "C" fn(libc::c_int) as libc::sighandler_t;
+
+            // SAFETY: the handler only calls async-signal-safe functions.
+            unsafe {
+                let previous_handler = libc::signal(signal, handler);
+                if previous_handler != libc::SIG_DFL {
+                    libc::signal(signal, previous_handler);
+                }
+            }
+        }
+    });
+}
+
+/// Kills the process groups of every running child.
+///
+/// This only uses atomics and `kill`, so that it can be called from a signal handler.
+#[cfg(unix)]
+fn kill_running_process_groups() {
+    for slot in RUNNING_PROCESS_GROUPS.iter() {
+        let process_group = slot.swap(0, Ordering::SeqCst);
+
+        if process_group > 0 {
+            // SAFETY: `kill` has no memory safety requirements.
+            unsafe { libc::kill(-process_group, libc::SIGKILL); }
+        }
+    }
+}
+
+/// A child process which leads its own process group on Unix, so that the processes it starts,
+/// like the stages of a pipeline or background jobs, can be killed along with it.
+struct RunningChild {
+    child: process::Child,
+}
+
+impl RunningChild {
+    fn new(child: process::Child) -> Self {
+        // Children beyond the maximum are simply not killed when lit is interrupted.
+        #[cfg(unix)]
+        for slot in RUNNING_PROCESS_GROUPS.iter() {
+            if slot.compare_exchange(0, child.id() as i32, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                break;
+            }
+        }
+
+        RunningChild { child }
+    }
+
+    /// Kills the child and its process group, and waits for the child to exit.
+    fn kill(&mut self) -> io::Result<()> {
+        #[cfg(unix)]
+        // SAFETY: `kill` has no memory safety requirements. The group is led by the child,
+        // which has not been waited for yet, so its id cannot have been reused.
+        unsafe { libc::kill(-(self.child.id() as i32), libc::SIGKILL); }
+
+        // The child may exit by itself just before it is killed.
+        self.child.kill().ok();
+        self.child.wait().map(|_| ())
+    }
+}
+
+impl Drop for RunningChild {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        for slot in RUNNING_PROCESS_GROUPS.iter() {
+            if slot.compare_exchange(self.child.id() as i32, 0, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                break;
+            }
+        }
+    }
+}
+
+/// Starts a command with piped output, retrying with a growing delay whilst the operating
+/// system is temporarily unable to start it.
+fn spawn(command: &mut process::Command, stdin: Option<&[u8]>, retries: u32) -> io::Result<RunningChild> {
+    let stdin_pipe = if stdin.is_some() { Stdio::piped() } else { Stdio::null() };
+    command.stdin(stdin_pipe).stdout(Stdio::piped()).stderr(Stdio::piped());
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(command, 0);
+
+    let mut delay = INITIAL_SPAWN_RETRY_DELAY;
+    let mut result = command.spawn();
+    for _ in 0..retries {
+        match result {
+            // Like `EAGAIN` when the process limit has been reached.
+            Err(ref e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted) => {
+                thread::sleep(delay);
+                delay = delay.checked_mul(2).map_or(MAX_SPAWN_RETRY_DELAY, |doubled| doubled.min(MAX_SPAWN_RETRY_DELAY));
+                result = command.spawn();
+            },
+            _ => break,
+        }
+    }
+
+    let mut child = RunningChild::new(result?);
+    if let (Some(input), Some(mut pipe)) = (stdin, child.child.stdin.take()) {
+        let input = input.to_owned();
+
+        // The input is written on its own thread so that a child which prints before it has read
+        // all of its input cannot block, and the pipe is closed afterwards so the child sees its end.
+        // A child may exit without reading all of its input, which is not an error.
+        thread::spawn(move || { pipe.write_all(&input).ok(); });
+    }
+
+    Ok(child)
+}
+
+fn wait_polling(child: &mut RunningChild, timeout: Option<Duration>,
+                cancellation_token: Option<&CancellationToken>) -> io::Result<Status> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    loop {
+        if let Some(status) = child.child.try_wait()? {
+            return Ok(Status::Exited(status));
+        }
+
+        let status = if cancellation_token.is_some_and(CancellationToken::is_cancelled) {
+            Some(Status::Cancelled)
+        } else if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            Some(Status::TimedOut)
+        } else {
+            None
+        };
+
+        if let Some(status) = status {
+            child.kill()?;
+            return Ok(status);
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Reads everything from a pipe on a background thread.
+struct PipeReader {
+    contents: Arc<Mutex<Vec<u8>>>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl PipeReader {
+    /// Starts reading a pipe, also appending its lines to the interleaved output with a prefix.
+    fn spawn(mut pipe: impl Read + Send + 'static, line_prefix: &'static str, interleaved: Arc<Mutex<Interleaved>>) -> Self {
+        let contents = Arc::new(Mutex::new(Vec::new()));
+
+        let thread = {
+            let contents = contents.clone();
+
+            thread::spawn(move || {
+                let mut buffer = [0; 4096];
+
+                while let Ok(bytes_read) = pipe.read(&mut buffer) {
+                    if bytes_read == 0 { break }
+                    contents.lock().unwrap().extend_from_slice(&buffer[..bytes_read]);
+                    interleaved.lock().unwrap().append(line_prefix, &buffer[..bytes_read]);
+                }
+
+                interleaved.lock().unwrap().close(line_prefix);
+            })
+        };
+
+        PipeReader { contents, thread }
+    }
+
+    /// Waits for the pipe to be closed, returning everything that was read from it.
+    fn finish(self) -> Vec<u8> {
+        let PipeReader { contents, thread } = self;
+        thread.join().ok();
+
+        let contents = contents.lock().unwrap();
+        contents.clone()
+    }
+
+    /// Waits a limited time for the pipe to be closed, returning everything that was read from it.
+    fn finish_within(self, timeout: Duration) -> Vec<u8> {
+        let deadline = Instant::now() + timeout;
+        while !self.is_closed() && Instant::now() < deadline {
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        if self.is_closed() { self.finish() } else { self.contents_so_far() }
+    }
+
+    /// Checks if the pipe has been closed and everything has been read from it.
+    fn is_closed(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Gets everything that has been read from the pipe so far.
+    fn contents_so_far(&self) -> Vec<u8> {
+        self.contents.lock().unwrap().clone()
+    }
+}
+
+impl Interleaved {
+    /// Appends output read from a stream, where only complete lines are interleaved.
+    fn append(&mut self, line_prefix: &'static str, bytes: &[u8]) {
+        let partial_line_index = match self.partial_lines.iter().position(|&(prefix, _)| prefix == line_prefix) {
+            Some(i) => i,
+            None => {
+                self.partial_lines.push((line_prefix, Vec::new()));
+                self.partial_lines.len() - 1
+            },
+        };
+        let partial_line = &mut self.partial_lines[partial_line_index].1;
+        partial_line.extend_from_slice(bytes);
+
+        while let Some(new_line_index) = partial_line.iter().position(|&b| b == b'\n') {
+            self.text.extend_from_slice(line_prefix.as_bytes());
+            self.text.extend(partial_line.drain(..=new_line_index));
+        }
+    }
+
+    /// Appends the incomplete last line of a stream that has closed, terminated by a new line.
+    fn close(&mut self, line_prefix: &'static str) {
+        if let Some(&mut (_, ref mut partial_line)) = self.partial_lines.iter_mut().find(|&&mut (prefix, _)| prefix == line_prefix) {
+            if !partial_line.is_empty() {
+                let line = mem::take(partial_line);
+
+                self.text.extend_from_slice(line_prefix.as_bytes());
+                self.text.extend(line);
+                self.text.push(b'\n');
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Expansion of glob patterns in `RUN` commands, relative to the directory of the test.
+
+use crate::parse;
+use regex::Regex;
+use std::{fs, path::{Path, PathBuf}};
+
+/// Expands the unquoted words of a command line which contain glob patterns, like `*.c`.
+///
+/// Relative patterns are matched against the files in the directory of the test, and are
+/// replaced by the quoted absolute paths of the matching files, in sorted order. Commands
+/// which use more shell syntax than pipes and redirects are left unchanged.
+pub fn expand(command_line: &str, directory: &Path, keep_unmatched: bool) -> Result<String, String> {
+    let mut pipeline = match parse::pipeline(command_line) {
+        Some(pipeline) => pipeline,
+        None => return Ok(command_line.to_owned()),
+    };
+    let mut is_expanded = false;
+
+    for stage in pipeline.stages.iter_mut() {
+        let mut words = Vec::new();
+
+        for word in stage.words.drain(..) {
+            if !self::is_glob(&word) {
+                words.push(word);
+                continue;
+            }
+
+            let matches = self::matching_paths(&word, directory)?;
+            match matches.is_empty() {
+                true if keep_unmatched => words.push(word),
+                true => return Err(format!("the glob '{}' does not match any files in '{}'", word, directory.display())),
+                false => {
+                    words.extend(matches.iter().map(|path| self::quote(&path.display().to_string())));
+                    is_expanded = true;
+                },
+            }
+        }
+
+        stage.words = words;
+    }
+
+    Ok(if is_expanded { pipeline.to_string() } else { command_line.to_owned() })
+}
+
+/// Checks if a word is an unquoted glob pattern.
+///
+/// Words with quotes, escapes or shell expansions are never globs, and neither is a `[`
+/// without a closing `]`, like the `[` command.
+fn is_glob(word: &str) -> bool {
+    if word.contains(['\'', '"', '\\', '$', '`']) {
+        return false;
+    }
+
+    let chars: Vec<char> = word.chars().collect();
+    word.contains(['*', '?']) || (0..chars.len()).any(|i| self::bracket_expression(&chars[i..]).is_some())
+}
+
+/// A bracket expression of a glob pattern, like `[a-z]` or `[!.]`.
+struct BracketExpression {
+    is_negated: bool,
+    /// The inclusive ranges of the characters that the expression matches.
+    ranges: Vec<(char, char)>,
+    /// The number of characters of the pattern that the expression spans, brackets included.
+    length: usize,
+}
+
+/// Parses the bracket expression at the start of a glob pattern, if it is a complete one.
+///
+/// Like in shells, a `]` right after the opening `[` or `[!` is a member of the expression,
+/// and a `[` without a closing `]` is an ordinary character.
+fn bracket_expression(pattern: &[char]) -> Option<BracketExpression> {
+    if pattern.first() != Some(&'[') {
+        return None;
+    }
+
+    let is_negated = matches!(pattern.get(1), Some('!') | Some('^'));
+    let first_member = if is_negated { 2 } else { 1 };
+    let mut ranges = Vec::new();
+    let mut i = first_member;
+
+    loop {
+        let c = *pattern.get(i)?;
+
+        if c == ']' && i > first_member {
+            return Some(BracketExpression { is_negated, ranges, length: i + 1 });
+        }
+
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some('-'), Some(&last)) if last != ']' => {
+                ranges.push((c, last));
+                i += 3;
+            },
+            _ => {
+                ranges.push((c, c));
+                i += 1;
+            },
+        }
+    }
+}
+
+/// Finds the paths matching a glob pattern, one path component at a time.
+fn matching_paths(pattern: &str, directory: &Path) -> Result<Vec<PathBuf>, String> {
+    let (mut paths, components) = match pattern.strip_prefix('/') {
+        Some(absolute_pattern) => (vec![PathBuf::from("/")], absolute_pattern),
+        None => (vec![directory.to_owned()], pattern),
+    };
+
+    for component in components.split('/').filter(|c| !c.is_empty()) {
+        if !self::is_glob(component) {
+            paths = paths.into_iter().map(|path| path.join(component)).filter(|path| path.exists()).collect();
+            continue;
+        }
+
+        let regex = self::component_regex(component).map_err(|e| format!("invalid glob '{}': {}", pattern, e))?;
+        let mut matches = Vec::new();
+
+        for path in paths {
+            // Directories which cannot be read simply have no matches.
+            for entry in fs::read_dir(&path).into_iter().flatten().flatten() {
+                let file_name = entry.file_name().to_string_lossy().into_owned();
+
+                // Like in shells, hidden files are only matched by patterns that start with a dot.
+                if regex.is_match(&file_name) && (!file_name.starts_with('.') || component.starts_with('.')) {
+                    matches.push(entry.path());
+                }
+            }
+        }
+
+        matches.sort();
+        paths = matches;
+    }
+
+    Ok(paths)
+}
+
+/// Builds a regex matching the file names that a component of a glob pattern matches.
+fn component_regex(component: &str) -> Result<Regex, regex::Error> {
+    let escape = |c: char| regex::escape(&c.to_string());
+    let chars: Vec<char> = component.chars().collect();
+    let mut regex = String::from("^");
+    let mut i = 0;
+
+    while i < chars.len() {
+        if let Some(expression) = self::bracket_expression(&chars[i..]) {
+            regex.push_str(if expression.is_negated { "[^" } else { "[" });
+            for (first, last) in expression.ranges {
+                match first == last {
+                    true => regex.push_str(&escape(first)),
+                    false => regex.push_str(&format!("{}-{}", escape(first), escape(last))),
+                }
+            }
+            regex.push(']');
+            i += expression.length;
+            continue;
+        }
+
+        match chars[i] {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&escape(c)),
+        }
+        i += 1;
+    }
+
+    regex.push('$');
+    Regex::new(&regex)
+}
+
+/// Quotes a path so that the shell does not interpret it again.
+fn quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unquoted_globs_are_expanded_relative_to_the_directory() {
+        let directory = tempfile::tempdir().unwrap();
+        for file_name in ["b.c", "a.c", "a.h", ".hidden.c"] {
+            fs::write(directory.path().join(file_name), "").unwrap();
+        }
+        let path = |file_name: &str| quote(&directory.path().join(file_name).display().to_string());
+
+        assert_eq!(expand("cc *.c | wc -l", directory.path(), false).unwrap(), format!("cc {} {} | wc -l", path("a.c"), path("b.c")));
+        assert_eq!(expand("cat a.[ch]", directory.path(), false).unwrap(), format!("cat {} {}", path("a.c"), path("a.h")));
+        assert_eq!(expand("cat '*.c' [", directory.path(), false).unwrap(), "cat '*.c' [");
+        assert_eq!(expand("cat *.c && true", directory.path(), false).unwrap(), "cat *.c && true");
+        assert_eq!(expand("cat \"*.c\"", directory.path(), false).unwrap(), "cat \"*.c\"");
+
+        assert!(expand("cat *.rs", directory.path(), false).is_err());
+        assert_eq!(expand("cat *.rs", directory.path(), true).unwrap(), "cat *.rs");
+    }
+
+    #[test]
+    fn character_classes_are_translated_like_in_shells() {
+        let directory = tempfile::tempdir().unwrap();
+        for file_name in ["a.c", "b.c", "].c", "x[.c", "-.c"] {
+            fs::write(directory.path().join(file_name), "").unwrap();
+        }
+        let path = |file_name: &str| quote(&directory.path().join(file_name).display().to_string());
+
+        assert_eq!(expand("cat [!a].c", directory.path(), false).unwrap(), format!("cat {} {} {}", path("-.c"), path("].c"), path("b.c")));
+        assert_eq!(expand("cat [^a-].c", directory.path(), false).unwrap(), format!("cat {} {}", path("].c"), path("b.c")));
+        assert_eq!(expand("cat []a].c", directory.path(), false).unwrap(), format!("cat {} {}", path("].c"), path("a.c")));
+        assert_eq!(expand("cat [!]].c", directory.path(), false).unwrap(), format!("cat {} {} {}", path("-.c"), path("a.c"), path("b.c")));
+        assert_eq!(expand("cat *[.c", directory.path(), false).unwrap(), format!("cat {}", path("x[.c")));
+        assert_eq!(expand("cat x[.c ]", directory.path(), false).unwrap(), "cat x[.c ]");
+    }
+}
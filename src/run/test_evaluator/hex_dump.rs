@@ -0,0 +1,85 @@
+//! Decoding of textual hex dumps, such as those printed by `xxd` or `hexdump -C`.
+
+use crate::util::decode_hex;
+
+/// Decodes the bytes shown on a single line of a hex dump.
+///
+/// A line is only part of a dump if it starts with an offset column, or if it consists
+/// solely of space separated byte pairs like `de ad be ef`. Otherwise words of ordinary
+/// text, like `face`, would be read as bytes. Leading offsets and trailing ASCII gutters
+/// are ignored.
+pub fn decode_line(line: &str) -> Vec<u8> {
+    // Strip the `|....|` ASCII gutter printed by `hexdump -C`.
+    let line = match line.find('|') {
+        Some(gutter_idx) => &line[..gutter_idx],
+        None => line,
+    };
+
+    let mut tokens: Vec<&str> = line.split_whitespace().collect();
+
+    // Skip the offset, which is either terminated by a colon, or longer than the byte groups after it.
+    if let Some(first_token) = tokens.first().cloned() {
+        let is_offset = first_token.ends_with(':') ||
+            (tokens.len() > 1 && first_token.len() >= 6 && tokens[1..].iter().all(|t| t.len() < first_token.len()));
+
+        if is_offset && first_token.trim_end_matches(':').chars().all(|c| c.is_ascii_hexdigit()) {
+            tokens.remove(0);
+        } else if !tokens.iter().all(|token| token.len() == 2) {
+            return Vec::new();
+        }
+    }
+
+    let mut bytes = Vec::new();
+    for token in tokens {
+        match decode_hex(token) {
+            Some(token_bytes) => bytes.extend(token_bytes),
+            // The remainder of the line is an ASCII gutter.
+            None => break,
+        }
+    }
+
+    bytes
+}
+
+/// Finds the first index of a byte subsequence.
+pub fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() { return Some(0) }
+
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decodes_hexdump_canonical_line() {
+        assert_eq!(decode_line("00000000  de ad be ef 0a                                    |.....|"),
+                   vec![0xde, 0xad, 0xbe, 0xef, 0x0a]);
+    }
+
+    #[test]
+    fn decodes_xxd_line() {
+        assert_eq!(decode_line("00000010: dead beef 0a                             ....."),
+                   vec![0xde, 0xad, 0xbe, 0xef, 0x0a]);
+    }
+
+    #[test]
+    fn decodes_plain_byte_pairs() {
+        assert_eq!(decode_line("de ad be ef\n"), vec![0xde, 0xad, 0xbe, 0xef]);
+    }
+
+    #[test]
+    fn non_hex_lines_decode_to_nothing() {
+        assert_eq!(decode_line("hello world"), Vec::<u8>::new());
+        assert_eq!(decode_line("deadbeef"), Vec::<u8>::new());
+        assert_eq!(decode_line("face dead cafe"), Vec::<u8>::new());
+        assert_eq!(decode_line("not a dump: face"), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn finds_subsequences() {
+        assert_eq!(find_subsequence(&[1, 2, 3, 4], &[3, 4]), Some(2));
+        assert_eq!(find_subsequence(&[1, 2, 3, 4], &[4, 3]), None);
+    }
+}
@@ -3,14 +3,21 @@
 
 use crate::{
     Config, Variables,
-    model::{self, TestResultKind, TestFailReason, TextPattern},
+    json,
+    config::{matchers, CustomMatcher},
+    model::{self, Comparison, OutputStream, TestResultKind, TestFailReason, TextPattern},
     vars,
 };
-use std::collections::HashMap;
+use super::hex_dump;
+use itertools::Itertools;
+use std::{collections::{BTreeMap, HashMap}, mem, path::{Path, PathBuf}, time::Duration};
 use regex::Regex;
 
+/// The text that spans matching a `MASK` regex are replaced with.
+pub const MASK_PLACEHOLDER: &str = "<masked>";
+
 /// Byte-index relative to entire stream.
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 struct AbsoluteByteIndex(pub usize);
 
 /// Byte-index relative to start of unprocessed stream.
@@ -27,15 +34,63 @@ struct MatchedRange {
 /// Responsible for storing the state of execution for a single `RUN` execution.
 #[derive(Debug)]
 pub struct TestRunState {
-    /// All output bytes emitted by the program.
+    /// All output bytes emitted by the program on the stream being checked.
     complete_output_stream: String,
     /// The current position in the stream at which all prior output has been
     /// successfully checked by the test script.
     current_stream_byte_position: AbsoluteByteIndex,
-    /// The stderr portion of the command output. This does not get used by `CHECK`s.
-    complete_stderr: String,
+    /// The stream that `CHECK`s are currently matched against.
+    current_stream: OutputStream,
+    /// All output bytes emitted by the program on the streams not being checked,
+    /// along with the checked position in each stream.
+    other_output_streams: BTreeMap<OutputStream, (String, AbsoluteByteIndex)>,
+    /// What the program wrote to each stream, before it was decoded, scrubbed and masked.
+    raw_streams: BTreeMap<OutputStream, RawStream>,
     /// A list of available variables to the test script.
     variables: HashMap<String, String>,
+    /// The group of consecutive `CHECK-DAG` directives currently being checked.
+    dag_group: Option<DagGroup>,
+    /// The regexes whose matches are masked in both the output and the checked patterns.
+    masks: Vec<Regex>,
+    /// How long the program has run for.
+    run_duration: Duration,
+    /// The rest of the line after the most recent match, which checks of the same line are matched against.
+    same_line: Option<(AbsoluteByteIndex, AbsoluteByteIndex)>,
+    /// The files in each directory checked by `CHECK-CREATED-ONLY`, before the program ran.
+    files_before_run: HashMap<PathBuf, Vec<PathBuf>>,
+    /// The `CHECK-NOT` patterns which may not appear before the match of the next check.
+    not_checks: Vec<NotCheck>,
+    /// The end of the region of the stream after the most recent `CHECK-LABEL`, which checks
+    /// cannot match beyond. This is where the next `CHECK-LABEL` matches.
+    label_region_end: Option<AbsoluteByteIndex>,
+    /// The pattern of the next `CHECK-LABEL` of the test, which ends the region of the current one.
+    next_label: Option<TextPattern>,
+}
+
+/// A `CHECK-NOT` pattern, and where the output it may not appear in starts.
+#[derive(Debug)]
+struct NotCheck {
+    pattern: TextPattern,
+    stream: OutputStream,
+    start: AbsoluteByteIndex,
+}
+
+/// The facts about the bytes a program wrote to a stream which its checks need.
+#[derive(Clone, Copy, Debug, Default)]
+struct RawStream {
+    /// The number of bytes written.
+    size: usize,
+    /// Whether the last byte written is a newline.
+    ends_with_newline: bool,
+}
+
+/// The state of a group of consecutive `CHECK-DAG` directives.
+#[derive(Debug)]
+struct DagGroup {
+    /// The position that every check in the group searches from.
+    start: AbsoluteByteIndex,
+    /// The byte ranges matched by the checks in the group so far, which later checks may not overlap.
+    matched_ranges: Vec<(AbsoluteByteIndex, AbsoluteByteIndex)>,
 }
 
 impl TestRunState {
@@ -43,19 +98,103 @@ impl TestRunState {
         TestRunState {
             complete_output_stream: String::new(),
             current_stream_byte_position: AbsoluteByteIndex(0),
-            complete_stderr: String::new(),
+            current_stream: OutputStream::Stdout,
+            other_output_streams: BTreeMap::new(),
+            raw_streams: BTreeMap::new(),
             variables: initial_variables,
+            dag_group: None,
+            masks: Vec::new(),
+            run_duration: Duration::default(),
+            same_line: None,
+            files_before_run: HashMap::new(),
+            not_checks: Vec::new(),
+            label_region_end: None,
+            next_label: None,
         }
     }
 
+    /// Sets the regexes whose matches are replaced by a placeholder before matching.
+    ///
+    /// This must be called before any output is appended.
+    pub fn set_masks(&mut self, masks: Vec<Regex>) {
+        self.masks = masks;
+    }
+
     /// Appends output from the inner program.
     pub fn append_program_output(&mut self, output: &str) {
-        self.complete_output_stream.extend(output.chars())
+        let output = self.mask(output);
+        self.output_stream_mut(OutputStream::Stdout).push_str(&output)
     }
 
     /// Appends stderr output.
     pub fn append_program_stderr(&mut self, stderr: &str) {
-        self.complete_stderr.extend(stderr.chars())
+        let stderr = self.mask(stderr);
+        self.output_stream_mut(OutputStream::Stderr).push_str(&stderr)
+    }
+
+    /// Appends lines of both streams, each prefixed by its stream, in the order that they were printed.
+    pub fn append_interleaved_output(&mut self, output: &str) {
+        let output = self.mask(output);
+        self.output_stream_mut(OutputStream::Interleaved).push_str(&output)
+    }
+
+    /// Records the bytes that the program wrote to a stream, before they were decoded and scrubbed
+    /// into the text that is appended to the stream.
+    pub fn append_raw_output(&mut self, stream: OutputStream, bytes: &[u8]) {
+        let raw_stream = self.raw_streams.entry(stream).or_default();
+        raw_stream.size += bytes.len();
+        if let Some(&last_byte) = bytes.last() {
+            raw_stream.ends_with_newline = last_byte == b'\n';
+        }
+    }
+
+    /// Selects the stream that subsequent checks are matched against.
+    ///
+    /// Each stream remembers how far it has been checked.
+    pub fn select_stream(&mut self, stream: OutputStream) {
+        if stream != self.current_stream {
+            let (output, position) = self.other_output_streams.remove(&stream).unwrap_or_default();
+            let previous_output = mem::replace(&mut self.complete_output_stream, output);
+            let previous_position = mem::replace(&mut self.current_stream_byte_position, position);
+
+            self.other_output_streams.insert(self.current_stream, (previous_output, previous_position));
+            self.current_stream = stream;
+            self.same_line = None;
+            self.label_region_end = None;
+        }
+    }
+
+    /// Replaces the standard output of the program with other text, like the contents of an
+    /// output file, and selects it so that subsequent checks are matched against it from the start.
+    ///
+    /// The text is the scrubbed version of the raw contents.
+    pub fn replace_stdout(&mut self, text: &str, raw_contents: &[u8]) {
+        self.select_stream(OutputStream::Stdout);
+        self.raw_streams.remove(&OutputStream::Stdout);
+        self.append_raw_output(OutputStream::Stdout, raw_contents);
+        self.complete_output_stream = self.mask(text);
+        self.current_stream_byte_position = AbsoluteByteIndex(0);
+        self.same_line = None;
+        self.label_region_end = None;
+    }
+
+    /// Replaces the spans of a text matching any mask with the placeholder.
+    fn mask(&self, text: &str) -> String {
+        self.masks.iter().fold(text.to_owned(), |text, mask| mask.replace_all(&text, MASK_PLACEHOLDER).into_owned())
+    }
+
+    /// Masks the literal text of a pattern, so that it matches masked output.
+    fn masked_pattern(&self, text_pattern: &TextPattern) -> TextPattern {
+        TextPattern {
+            components: text_pattern.components.iter().map(|component| match *component {
+                model::PatternComponent::Text(ref text) => model::PatternComponent::Text(self.mask(text)),
+                ref component => component.clone(),
+            }).collect(),
+        }
+    }
+
+    fn output_stream_mut(&mut self, stream: OutputStream) -> &mut String {
+        if stream == self.current_stream { &mut self.complete_output_stream } else { &mut self.other_output_streams.entry(stream).or_default().0 }
     }
 
     /// Verifies that a text pattern appears subsequently in the stream.
@@ -63,7 +202,7 @@ impl TestRunState {
         &mut self,
         text_pattern: &TextPattern,
         config: &Config) -> TestResultKind {
-        self.check_extended(text_pattern, false, config)
+        self.check_extended(text_pattern, None, config)
     }
 
     /// Verifies that the very-next non-whitespace line matches a text pattern.
@@ -71,41 +210,65 @@ impl TestRunState {
         &mut self,
         text_pattern: &TextPattern,
         config: &Config) -> TestResultKind {
-        self.check_extended(text_pattern, true, config)
+        self.check_extended(text_pattern, Some(1), config)
+    }
+
+    /// Verifies that a text pattern matches within the next few non-whitespace lines.
+    pub fn check_within(
+        &mut self,
+        max_line_distance: usize,
+        text_pattern: &TextPattern,
+        config: &Config) -> TestResultKind {
+        self.check_extended(text_pattern, Some(max_line_distance), config)
     }
 
     fn check_extended(
         &mut self,
         text_pattern: &TextPattern,
-        require_on_next_line: bool,
+        max_line_distance: Option<usize>,
         config: &Config) -> TestResultKind {
 
         self.eat_whitespace();
 
-        let next_relative_matched_range = self.next_unprocessed_byte_index_of(text_pattern, config);
+        let next_relative_matched_range = match self.next_unprocessed_byte_index_of(text_pattern, config) {
+            Ok(matched_range) => matched_range,
+            Err(result) => return result,
+        };
 
         match next_relative_matched_range {
             Some(matched_range) => {
-                // Logic for the CHECK-NEXT directive.
-                if require_on_next_line {
-                    match self.unprocessed_output_stream().find("\n") {
-                        Some(index_of_first_new_line_byte) => {
-                            if matched_range.start.0 >= index_of_first_new_line_byte {
-                                return TestResultKind::Fail {
-                                    reason: TestFailReason::CheckFailed(model::CheckFailureInfo {
-                                        complete_output_text: self.complete_output_stream.clone(),
-                                        successfully_checked_until_byte_index: self.current_stream_byte_position.0,
-                                        expected_pattern: text_pattern.clone(),
-                                    }),
-                                    hint: Some(format!("found a match for '{}', but it does not appear on the next line, as required by the CHECK-NEXT directive", text_pattern)),
-                                };
-                            }
-                        },
-                        None => (), // we are on the last line, no need to verify that explicitly.
+                // Logic for the CHECK-NEXT and CHECK-WITHIN directives.
+                if let Some(max_line_distance) = max_line_distance {
+                    // If the match is within the last few lines, there is no need to verify that explicitly.
+                    let index_of_last_allowed_new_line_byte = self.unprocessed_output_stream().match_indices('\n')
+                        .nth(max_line_distance.saturating_sub(1)).map(|(i, _)| i);
+
+                    if let Some(index_of_last_allowed_new_line_byte) = index_of_last_allowed_new_line_byte {
+                        if matched_range.start.0 >= index_of_last_allowed_new_line_byte {
+                            let hint = match max_line_distance {
+                                1 => format!("found a match for '{}', but it does not appear on the next line, as required by the CHECK-NEXT directive", text_pattern),
+                                n => format!("found a match for '{}', but it does not appear within the next {} lines, as required by the CHECK-WITHIN-{} directive", text_pattern, n, n),
+                            };
+
+                            return TestResultKind::Fail {
+                                reason: TestFailReason::CheckFailed(model::CheckFailureInfo {
+                                    complete_output_text: self.complete_output_stream.clone(),
+                                    successfully_checked_until_byte_index: self.current_stream_byte_position.0,
+                                    expected_pattern: text_pattern.clone(),
+                                }),
+                                hint: Some(hint),
+                            };
+                        }
                     }
                 }
 
+                let not_check_result = self.verify_not_checks(AbsoluteByteIndex(self.current_stream_byte_position.0 + matched_range.start.0), config);
+                if not_check_result.is_erroneous() {
+                    return not_check_result;
+                }
+
                 self.current_stream_byte_position += matched_range.end;
+                self.set_same_line(self.current_stream_byte_position);
 
                 // No other checks should run against the partial line.
                 self.eat_until_end_of_line();
@@ -119,14 +282,658 @@ impl TestRunState {
                         successfully_checked_until_byte_index: self.current_stream_byte_position.0,
                         expected_pattern: text_pattern.clone(),
                     }),
-                    hint: None,
+                    hint: self.label_region_end.map(|_| "the text was only searched for up to the match of the next CHECK-LABEL directive".to_owned()),
+                }
+            },
+        }
+    }
+
+    /// Sets the pattern of the `CHECK-LABEL` after the one that is about to be checked, if there is one.
+    pub fn set_next_label(&mut self, next_label: Option<TextPattern>) {
+        self.next_label = next_label;
+    }
+
+    /// Verifies that a label appears subsequently in the stream, and restricts the
+    /// subsequent checks to the region between its line and the match of the next label.
+    pub fn check_label(
+        &mut self,
+        text_pattern: &TextPattern,
+        config: &Config) -> TestResultKind {
+        // A label always searches the rest of the stream, beyond the region of the previous label.
+        self.label_region_end = None;
+
+        let result = self.check(text_pattern, config);
+        if result.is_erroneous() {
+            return result;
+        }
+
+        if let Some(next_label) = self.next_label.take() {
+            // Variables captured by the next label are only defined once it is checked itself.
+            let regex = match self::pattern_regex(&self.masked_pattern(&next_label), config, &mut self.variables.clone()) {
+                Ok(regex) => regex,
+                Err(result) => return result,
+            };
+            self.label_region_end = regex.find(self.unprocessed_output_stream())
+                .map(|regex_match| AbsoluteByteIndex(self.current_stream_byte_position.0 + regex_match.start()));
+        }
+
+        TestResultKind::Pass
+    }
+
+    /// Verifies that a text pattern matches exactly a number of consecutive lines, like a `CHECK`
+    /// followed by `CHECK-NEXT` directives with the same pattern.
+    ///
+    /// The line after the last match may not match the pattern too.
+    pub fn check_count(
+        &mut self,
+        count: usize,
+        text_pattern: &TextPattern,
+        config: &Config) -> TestResultKind {
+        for matches in 0..count {
+            let result = match matches {
+                0 => self.check(text_pattern, config),
+                _ => self.check_next(text_pattern, config),
+            };
+
+            match result {
+                TestResultKind::Fail { reason: reason @ TestFailReason::CheckFailed(..), .. } => return TestResultKind::Fail {
+                    reason,
+                    hint: Some(format!("found {} consecutive matches for '{}', but the CHECK-COUNT-{} directive requires {}", matches, text_pattern, count, count)),
+                },
+                result if result.is_erroneous() => return result,
+                _ => (),
+            }
+        }
+
+        // Variables captured by the pattern are only defined by the matches that were counted.
+        let regex = match self::pattern_regex(&self.masked_pattern(text_pattern), config, &mut self.variables.clone()) {
+            Ok(regex) => regex,
+            Err(result) => return result,
+        };
+        let next_line = self.unprocessed_output_stream().trim_start().split('\n').next().unwrap_or("");
+
+        if !next_line.is_empty() && regex.is_match(next_line) {
+            return TestResultKind::Fail {
+                reason: TestFailReason::CheckFailed(model::CheckFailureInfo {
+                    complete_output_text: self.complete_output_stream.clone(),
+                    successfully_checked_until_byte_index: self.current_stream_byte_position.0,
+                    expected_pattern: text_pattern.clone(),
+                }),
+                hint: Some(format!("found more than {} consecutive matches for '{}', but the CHECK-COUNT-{} directive requires exactly {}", count, text_pattern, count, count)),
+            };
+        }
+
+        TestResultKind::Pass
+    }
+
+    /// Verifies that one of several text patterns appears subsequently in the stream.
+    ///
+    /// The alternative whose match starts earliest is used, or the first declared one if several
+    /// start at the same position. Only the variables captured by that alternative are kept.
+    pub fn check_alt(
+        &mut self,
+        text_patterns: &[TextPattern],
+        config: &Config) -> TestResultKind {
+        self.eat_whitespace();
+
+        let mut earliest_match: Option<(usize, &TextPattern)> = None;
+        for text_pattern in text_patterns {
+            let regex = match self::pattern_regex(&self.masked_pattern(text_pattern), config, &mut self.variables) {
+                Ok(regex) => regex,
+                Err(result) => return result,
+            };
+
+            if let Some(regex_match) = regex.find(self.unprocessed_output_stream()) {
+                if earliest_match.is_none_or(|(start, _)| regex_match.start() < start) {
+                    earliest_match = Some((regex_match.start(), text_pattern));
+                }
+            }
+        }
+
+        if let Some((_, text_pattern)) = earliest_match {
+            return self.check(text_pattern, config);
+        }
+
+        let alternatives = text_patterns.iter().map(ToString::to_string).join(" | ");
+        TestResultKind::Fail {
+            reason: TestFailReason::CheckFailed(model::CheckFailureInfo {
+                complete_output_text: self.complete_output_stream.clone(),
+                successfully_checked_until_byte_index: self.current_stream_byte_position.0,
+                expected_pattern: model::PatternComponent::Text(alternatives).into(),
+            }),
+            hint: Some(format!("none of the {} alternatives of the CHECK-ALT directive matched", text_patterns.len())),
+        }
+    }
+
+    /// Verifies that a text pattern appears anywhere in the remaining stream.
+    ///
+    /// Unlike the other checks, this does not advance the stream, so a subsequent
+    /// check may match text that appears before this match.
+    pub fn check_any(
+        &mut self,
+        text_pattern: &TextPattern,
+        config: &Config) -> TestResultKind {
+        match self.next_unprocessed_byte_index_of(text_pattern, config) {
+            Ok(Some(..)) => TestResultKind::Pass,
+            Err(result) => result,
+            Ok(None) => TestResultKind::Fail {
+                reason: TestFailReason::CheckFailed(model::CheckFailureInfo {
+                    complete_output_text: self.complete_output_stream.clone(),
+                    successfully_checked_until_byte_index: self.current_stream_byte_position.0,
+                    expected_pattern: text_pattern.clone(),
+                }),
+                hint: None,
+            },
+        }
+    }
+
+    /// Verifies that a text pattern appears nowhere in the complete stream, regardless of
+    /// how far it has been checked.
+    ///
+    /// The stream is not advanced.
+    pub fn check_none(
+        &mut self,
+        text_pattern: &TextPattern,
+        config: &Config) -> TestResultKind {
+        let regex = match self::pattern_regex(&self.masked_pattern(text_pattern), config, &mut self.variables) {
+            Ok(regex) => regex,
+            Err(result) => return result,
+        };
+
+        match regex.find(&self.complete_output_stream) {
+            Some(regex_match) => self::forbidden_match(&self.complete_output_stream, regex_match.start(), text_pattern, true),
+            None => TestResultKind::Pass,
+        }
+    }
+
+    /// Remembers a text pattern which may not appear between the previous check and the
+    /// match of the next check, or the end of the stream if there is no next check.
+    pub fn check_not(&mut self, text_pattern: &TextPattern) -> TestResultKind {
+        self.not_checks.push(NotCheck { pattern: text_pattern.clone(), stream: self.current_stream, start: self.current_stream_byte_position });
+        TestResultKind::Pass
+    }
+
+    /// Verifies that none of the `CHECK-NOT` patterns of the current stream appear before a position.
+    fn verify_not_checks(&mut self, end: AbsoluteByteIndex, config: &Config) -> TestResultKind {
+        let (not_checks, other_not_checks) = mem::take(&mut self.not_checks).into_iter().partition::<Vec<_>, _>(|c| c.stream == self.current_stream);
+        self.not_checks = other_not_checks;
+
+        for not_check in not_checks {
+            let regex = match self::pattern_regex(&self.masked_pattern(&not_check.pattern), config, &mut self.variables) {
+                Ok(regex) => regex,
+                Err(result) => return result,
+            };
+
+            // The stream may have been replaced since, like by `CHECK-OUTPUT-FILE`.
+            let region = self.complete_output_stream.get(not_check.start.0..end.0).unwrap_or("");
+
+            if let Some(regex_match) = regex.find(region) {
+                return self::forbidden_match(&self.complete_output_stream, not_check.start.0 + regex_match.start(), &not_check.pattern, false);
+            }
+        }
+
+        TestResultKind::Pass
+    }
+
+    /// Verifies the `CHECK-NOT` patterns which no check followed, against the rest of their streams.
+    pub fn end_not_checks(&mut self, config: &Config) -> TestResultKind {
+        let current_stream = self.current_stream;
+        let streams: Vec<_> = self.not_checks.iter().map(|c| c.stream).unique().collect();
+
+        for stream in streams {
+            self.select_stream(stream);
+            let result = self.verify_not_checks(AbsoluteByteIndex(self.complete_output_stream.len()), config);
+
+            if result.is_erroneous() {
+                self.select_stream(current_stream);
+                return result;
+            }
+        }
+
+        self.select_stream(current_stream);
+        TestResultKind::Pass
+    }
+
+    /// Verifies that a text pattern appears anywhere after the checks that preceded the
+    /// current group of `CHECK-DAG` directives, without overlapping other matches in the group.
+    ///
+    /// Afterwards, the stream is positioned after the line of the last match in the group.
+    pub fn check_dag(
+        &mut self,
+        text_pattern: &TextPattern,
+        config: &Config) -> TestResultKind {
+        self.check_dag_extended(text_pattern, false, config)
+    }
+
+    /// Verifies that a text pattern appears exactly once after the checks that preceded the
+    /// current group of `CHECK-DAG` directives, and otherwise behaves like `check_dag`.
+    ///
+    /// Counting stops at the second match, so this costs at most one more search of the output
+    /// after the start of the group than `check_dag`, however often the pattern appears.
+    pub fn check_dag_once(
+        &mut self,
+        text_pattern: &TextPattern,
+        config: &Config) -> TestResultKind {
+        self.check_dag_extended(text_pattern, true, config)
+    }
+
+    fn check_dag_extended(
+        &mut self,
+        text_pattern: &TextPattern,
+        exactly_once: bool,
+        config: &Config) -> TestResultKind {
+        let current_position = self.current_stream_byte_position;
+        let group = self.dag_group.get_or_insert_with(|| DagGroup { start: current_position, matched_ranges: Vec::new() });
+        let group_start = group.start;
+
+        let regex = match self::pattern_regex(&self.masked_pattern(text_pattern), config, &mut self.variables) {
+            Ok(regex) => regex,
+            Err(result) => return result,
+        };
+        let overlaps_group_match = |start: usize, end: usize, group: &DagGroup| {
+            group.matched_ranges.iter().find(|(s, e)| start < e.0 && s.0 < end.max(start + 1)).map(|(_, e)| e.0)
+        };
+
+        let mut search_from = group_start.0;
+        let mut found_overlapping_match = false;
+        let matched = loop {
+            let regex_match = match regex.find_at(&self.complete_output_stream[..self.searched_end()], search_from) {
+                Some(m) => m,
+                None => break None,
+            };
+
+            match overlaps_group_match(regex_match.start(), regex_match.end(), self.dag_group.as_ref().unwrap()) {
+                Some(overlapping_end) => {
+                    found_overlapping_match = true;
+                    search_from = overlapping_end.max(regex_match.start() + 1);
+                    if search_from > self.searched_end() { break None; }
+                },
+                None => break Some((regex_match.start(), regex_match.end())),
+            }
+        };
+
+        match matched {
+            Some(..) if exactly_once && regex.find_iter(&self.complete_output_stream[group_start.0..self.searched_end()]).take(2).count() > 1 => {
+                TestResultKind::Fail {
+                    reason: TestFailReason::CheckFailed(model::CheckFailureInfo {
+                        complete_output_text: self.complete_output_stream.clone(),
+                        successfully_checked_until_byte_index: group_start.0,
+                        expected_pattern: text_pattern.clone(),
+                    }),
+                    hint: Some(format!("found more than one match for '{}', but the CHECK-DAG-ONCE directive requires exactly one", text_pattern)),
+                }
+            },
+            Some((start, end)) => {
+                // The `CHECK-NOT`s before a group may not appear before the first match of the group.
+                let not_check_result = self.verify_not_checks(AbsoluteByteIndex(start), config);
+                if not_check_result.is_erroneous() {
+                    return not_check_result;
+                }
+
+                let new_variables = process_captures(&regex, &self.complete_output_stream[start..end]);
+                self.variables.extend(new_variables);
+
+                let group = self.dag_group.as_mut().unwrap();
+                group.matched_ranges.push((AbsoluteByteIndex(start), AbsoluteByteIndex(end)));
+                self.set_same_line(AbsoluteByteIndex(end));
+
+                // Subsequent checks must match after every match in the group.
+                if end > self.current_stream_byte_position.0 {
+                    self.current_stream_byte_position = AbsoluteByteIndex(end);
+                    self.eat_until_end_of_line();
+                }
+
+                TestResultKind::Pass
+            },
+            None => TestResultKind::Fail {
+                reason: TestFailReason::CheckFailed(model::CheckFailureInfo {
+                    complete_output_text: self.complete_output_stream.clone(),
+                    successfully_checked_until_byte_index: group_start.0,
+                    expected_pattern: text_pattern.clone(),
+                }),
+                hint: if found_overlapping_match {
+                    Some(format!("found a match for '{}', but it overlaps the match of another CHECK-DAG directive in the same group", text_pattern))
+                } else {
+                    None
+                },
+            },
+        }
+    }
+
+    /// Verifies that a text pattern matches exactly a number of times, without overlapping, in the
+    /// rest of the line after the match of the previous check.
+    pub fn check_same_count(
+        &mut self,
+        expected_count: usize,
+        text_pattern: &TextPattern,
+        config: &Config) -> TestResultKind {
+        let (start, end) = match self.same_line {
+            Some(range) => range,
+            None => return TestResultKind::Error {
+                message: format!("CHECK-SAME-COUNT-{}: {} must follow a check that matched a line", expected_count, text_pattern),
+            },
+        };
+
+        let regex = match self::pattern_regex(&self.masked_pattern(text_pattern), config, &mut self.variables) {
+            Ok(regex) => regex,
+            Err(result) => return result,
+        };
+        let actual_count = regex.find_iter(&self.complete_output_stream[start.0..end.0]).count();
+
+        if actual_count == expected_count {
+            TestResultKind::Pass
+        } else {
+            TestResultKind::Fail {
+                reason: TestFailReason::CheckFailed(model::CheckFailureInfo {
+                    complete_output_text: self.complete_output_stream.clone(),
+                    successfully_checked_until_byte_index: start.0,
+                    expected_pattern: text_pattern.clone(),
+                }),
+                hint: Some(format!("found {} matches for '{}' on the line, but the CHECK-SAME-COUNT-{} directive requires exactly {}",
+                                   actual_count, text_pattern, expected_count, expected_count)),
+            }
+        }
+    }
+
+    /// Verifies that a text pattern appears in the rest of the line after the match of the previous check.
+    ///
+    /// Afterwards, subsequent checks of the same line continue after this match.
+    pub fn check_same(
+        &mut self,
+        text_pattern: &TextPattern,
+        config: &Config) -> TestResultKind {
+        let (start, end) = match self.same_line {
+            Some(range) => range,
+            None => return TestResultKind::Error {
+                message: format!("CHECK-SAME: {} must follow a check that matched a line", text_pattern),
+            },
+        };
+
+        let regex = match self::pattern_regex(&self.masked_pattern(text_pattern), config, &mut self.variables) {
+            Ok(regex) => regex,
+            Err(result) => return result,
+        };
+
+        match regex.find(&self.complete_output_stream[start.0..end.0]).map(|regex_match| (start.0 + regex_match.start(), start.0 + regex_match.end())) {
+            Some((match_start, match_end)) => {
+                let not_check_result = self.verify_not_checks(AbsoluteByteIndex(match_start), config);
+                if not_check_result.is_erroneous() {
+                    return not_check_result;
+                }
+
+                let new_variables = process_captures(&regex, &self.complete_output_stream[match_start..match_end]);
+                self.variables.extend(new_variables);
+                self.same_line = Some((AbsoluteByteIndex(match_end), end));
+
+                TestResultKind::Pass
+            },
+            None => TestResultKind::Fail {
+                reason: TestFailReason::CheckFailed(model::CheckFailureInfo {
+                    complete_output_text: self.complete_output_stream.clone(),
+                    successfully_checked_until_byte_index: start.0,
+                    expected_pattern: text_pattern.clone(),
+                }),
+                hint: Some(format!("'{}' was not found in the rest of the line, as required by the CHECK-SAME directive", text_pattern)),
+            },
+        }
+    }
+
+    /// Verifies that the very next line is empty, without skipping any whitespace first.
+    pub fn check_empty(&mut self, config: &Config) -> TestResultKind {
+        let unprocessed = self.unprocessed_output_stream();
+
+        let found = match unprocessed.find('\n') {
+            Some(end) if unprocessed[..end].trim_end_matches('\r').is_empty() => {
+                let not_check_result = self.verify_not_checks(self.current_stream_byte_position, config);
+                if not_check_result.is_erroneous() {
+                    return not_check_result;
+                }
+
+                self.current_stream_byte_position += RelativeByteIndex(end + 1);
+                self.same_line = None;
+                return TestResultKind::Pass;
+            },
+            Some(end) => Some(unprocessed[..end].to_owned()),
+            None if unprocessed.is_empty() => None,
+            None => Some(unprocessed.to_owned()),
+        };
+
+        TestResultKind::Fail { reason: TestFailReason::NonEmptyLine { found }, hint: None }
+    }
+
+    /// Verifies that a subsequent line matches an argument according to a custom matcher.
+    pub fn check_custom(&mut self, matcher_name: &str, matcher: CustomMatcher, argument: &str, config: &Config) -> TestResultKind {
+        // Custom matchers only say whether a line matches, so they match the whole line.
+        self.check_line(argument, |line| if matcher(argument, line) { Some(line.len()) } else { None }, config,
+                        || format!("no line matched '{}' using the custom matcher '{}'", argument, matcher_name))
+    }
+
+    /// Verifies that the first number on a subsequent line is within a tolerance of an expected number.
+    pub fn check_num(&mut self, expected: f64, tolerance: f64, config: &Config) -> TestResultKind {
+        let description = format!("{} +/- {}", expected, tolerance);
+
+        self.check_line(&description, |line| matchers::first_number(line).filter(|&(number, _)| (number - expected).abs() <= tolerance).map(|(_, end)| end), config,
+                        || format!("no line started with a number within {} of {}", tolerance, expected))
+    }
+
+    /// Verifies that a subsequent line satisfies a predicate, consuming the output up to the end of the line.
+    ///
+    /// The predicate gives the end of the match within the line, after which checks of the same line continue.
+    fn check_line(&mut self, description: &str, matches: impl Fn(&str) -> Option<usize>, config: &Config, hint: impl FnOnce() -> String) -> TestResultKind {
+        self.eat_whitespace();
+
+        let mut line_start_index = 0;
+        let mut matched_line = None;
+        for line in self.unprocessed_output_stream().split_inclusive('\n') {
+            if let Some(match_end) = matches(line.trim_end_matches(['\n', '\r'])) {
+                matched_line = Some((line_start_index, line.len(), match_end));
+                break;
+            }
+
+            line_start_index += line.len();
+        }
+
+        if let Some((line_start_index, line_length, match_end)) = matched_line {
+            let not_check_result = self.verify_not_checks(AbsoluteByteIndex(self.current_stream_byte_position.0 + line_start_index), config);
+            if not_check_result.is_erroneous() {
+                return not_check_result;
+            }
+
+            let match_end = AbsoluteByteIndex(self.current_stream_byte_position.0 + line_start_index + match_end);
+            self.current_stream_byte_position += RelativeByteIndex(line_start_index + line_length);
+            self.set_same_line(match_end);
+            return TestResultKind::Pass;
+        }
+
+        TestResultKind::Fail {
+            reason: TestFailReason::CheckFailed(model::CheckFailureInfo {
+                complete_output_text: self.complete_output_stream.clone(),
+                successfully_checked_until_byte_index: self.current_stream_byte_position.0,
+                expected_pattern: model::PatternComponent::Text(description.to_owned()).into(),
+            }),
+            hint: Some(hint()),
+        }
+    }
+
+    /// Verifies that the number of bytes the program wrote to the stream being checked satisfies a comparison.
+    pub fn check_size(&self, comparison: Comparison, expected_size: usize) -> TestResultKind {
+        let actual_size = self.raw_streams.get(&self.current_stream).map_or(0, |raw_stream| raw_stream.size);
+
+        if comparison.holds(actual_size, expected_size) {
+            TestResultKind::Pass
+        } else {
+            TestResultKind::Fail {
+                reason: TestFailReason::UnexpectedOutputSize { comparison, expected_size, actual_size },
+                hint: None,
+            }
+        }
+    }
+
+    /// Verifies whether the bytes the program wrote to the stream being checked end with a newline.
+    ///
+    /// This is independent of how much of the stream has been checked, and of scrubbers and masks.
+    pub fn check_final_newline(&self, expected: bool) -> TestResultKind {
+        let ends_with_newline = self.raw_streams.get(&self.current_stream).is_some_and(|raw_stream| raw_stream.ends_with_newline);
+
+        if ends_with_newline == expected {
+            TestResultKind::Pass
+        } else {
+            TestResultKind::Fail { reason: TestFailReason::UnexpectedFinalNewline { expected }, hint: None }
+        }
+    }
+
+    /// Verifies that all of the stream which has not been checked yet consists of exactly the
+    /// expected lines, and consumes it.
+    ///
+    /// Whether the stream ends with a newline is not compared.
+    pub fn check_rest(&mut self, expected_lines: &[String], config: &Config) -> TestResultKind {
+        // The rest of the stream is compared exactly, so it ends the region of the `CHECK-NOT`s before it.
+        let not_check_result = self.verify_not_checks(AbsoluteByteIndex(self.complete_output_stream.len()), config);
+        if not_check_result.is_erroneous() {
+            return not_check_result;
+        }
+
+        let expected: String = expected_lines.iter().map(|line| format!("{}\n", self.mask(line))).collect();
+        let actual: String = self.unprocessed_output_stream().lines().map(|line| format!("{}\n", line)).collect();
+        self.set_position_eof();
+        self.same_line = None;
+
+        if actual == expected {
+            TestResultKind::Pass
+        } else {
+            TestResultKind::Fail {
+                reason: TestFailReason::RestMismatch { diff: crate::util::line_diff(&expected, &actual) },
+                hint: None,
+            }
+        }
+    }
+
+    /// Records the files that were in a directory before the program ran.
+    pub fn set_files_before_run(&mut self, directory: PathBuf, files: Vec<PathBuf>) {
+        self.files_before_run.insert(directory, files);
+    }
+
+    /// Gets the files that were in a directory before the program ran, if they were recorded.
+    pub fn files_before_run(&self, directory: &Path) -> &[PathBuf] {
+        self.files_before_run.get(directory).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Sets how long the program has run for.
+    pub fn set_run_duration(&mut self, duration: Duration) {
+        self.run_duration = duration;
+    }
+
+    /// Verifies that the duration of the program satisfies a comparison.
+    ///
+    /// The expected duration is multiplied by a scale, to allow for slower environments.
+    pub fn check_time(&self, comparison: Comparison, expected_duration: Duration, scale: f64) -> TestResultKind {
+        // The scale comes from the user, so it may be negative, not a number, or too large.
+        let expected_duration = match Duration::try_from_secs_f64(expected_duration.as_secs_f64() * scale) {
+            Ok(expected_duration) if scale > 0.0 => expected_duration,
+            _ => return TestResultKind::Error { message: format!("cannot scale the duration {:?} by the time scale {}", expected_duration, scale) },
+        };
+
+        if comparison.holds(self.run_duration, expected_duration) {
+            TestResultKind::Pass
+        } else {
+            TestResultKind::Fail {
+                reason: TestFailReason::UnexpectedDuration { comparison, expected_duration, actual_duration: self.run_duration },
+                hint: None,
+            }
+        }
+    }
+
+    /// Verifies that the complete stream being checked is identical to the contents of an expected file.
+    pub fn check_file_contents(&self, path: &str, expected_contents: &str) -> TestResultKind {
+        let expected_contents = self.mask(expected_contents);
+
+        if self.complete_output_stream == expected_contents {
+            TestResultKind::Pass
+        } else {
+            TestResultKind::Fail {
+                reason: TestFailReason::ExpectedFileMismatch {
+                    path: path.to_owned(),
+                    diff: crate::util::line_diff(&expected_contents, &self.complete_output_stream),
+                },
+                hint: None,
+            }
+        }
+    }
+
+    /// Verifies that the complete stream being checked is JSON which is valid according to a schema.
+    pub fn check_schema(&self, schema_path: &str, schema: &json::Value) -> TestResultKind {
+        let errors = match json::parse(&self.complete_output_stream) {
+            Ok(value) => json::schema::validate(schema, &value),
+            Err(e) => vec![format!("the output is not valid JSON: {}", e)],
+        };
+
+        if errors.is_empty() {
+            TestResultKind::Pass
+        } else {
+            TestResultKind::Fail {
+                reason: TestFailReason::SchemaViolation { schema_path: schema_path.to_owned(), errors },
+                hint: None,
+            }
+        }
+    }
+
+    /// Ends the current group of `CHECK-DAG` directives, if any.
+    pub fn end_dag_group(&mut self) {
+        self.dag_group = None;
+    }
+
+    /// Verifies that a sequence of bytes appears subsequently in a hex dump printed by the program.
+    ///
+    /// Offsets and ASCII gutters in the dump are ignored, and the sequence may span several lines.
+    pub fn check_bytes(&mut self, expected_bytes: &[u8]) -> TestResultKind {
+        let mut dumped_bytes = Vec::new();
+        // The index of the end of the line that each dumped byte was printed on.
+        let mut line_end_indices = Vec::new();
+
+        let mut line_start_index = 0;
+        for line in self.unprocessed_output_stream().split_inclusive('\n') {
+            let line_end_index = line_start_index + line.len();
+
+            for byte in hex_dump::decode_line(line) {
+                dumped_bytes.push(byte);
+                line_end_indices.push(RelativeByteIndex(line_end_index));
+            }
+
+            line_start_index = line_end_index;
+        }
+
+        let last_matched_byte_index = hex_dump::find_subsequence(&dumped_bytes, expected_bytes)
+            .and_then(|first_idx| (first_idx + expected_bytes.len()).checked_sub(1));
+
+        match last_matched_byte_index {
+            Some(last_idx) => {
+                self.current_stream_byte_position += line_end_indices[last_idx];
+                TestResultKind::Pass
+            },
+            None if expected_bytes.is_empty() => TestResultKind::Pass,
+            None => {
+                let expected_text = expected_bytes.iter().map(|b| format!("{:02X}", b)).join(" ");
+
+                TestResultKind::Fail {
+                    reason: TestFailReason::CheckFailed(model::CheckFailureInfo {
+                        complete_output_text: self.complete_output_stream.clone(),
+                        successfully_checked_until_byte_index: self.current_stream_byte_position.0,
+                        expected_pattern: model::PatternComponent::Text(expected_text).into(),
+                    }),
+                    hint: Some("the byte sequence was not found in the hex dump, as required by the CHECK-BYTES directive".to_owned()),
                 }
             },
         }
     }
 
     pub fn unprocessed_output_bytes(&self) -> &[u8] {
-        &self.complete_output_stream.as_bytes()[self.current_stream_byte_position.0..]
+        &self.complete_output_stream.as_bytes()[self.current_stream_byte_position.0..self.searched_end()]
+    }
+
+    /// Gets the end of the part of the stream that checks may match, which is the end of the
+    /// region of the current `CHECK-LABEL` if there is one.
+    fn searched_end(&self) -> usize {
+        self.label_region_end.map_or(self.complete_output_stream.len(), |end| end.0)
     }
 
     /// Gets all of the non-consumed inner program bytes.
@@ -134,9 +941,17 @@ impl TestRunState {
         convert_bytes_to_str(self.unprocessed_output_bytes())
     }
 
+    /// Gets the line number of the stream being checked that all prior output has been checked up to.
+    pub fn checked_line_number(&self) -> usize {
+        self.complete_output_stream.as_bytes()[..self.current_stream_byte_position.0].iter().filter(|&&b| b == b'\n').count() + 1
+    }
+
     /// Gets all variables in scope.
     pub fn variables(&self) -> &Variables { &self.variables }
 
+    /// Gets all variables in scope, so that new variables like tempfiles can be defined.
+    pub fn variables_mut(&mut self) -> &mut Variables { &mut self.variables }
+
     fn eat_whitespace(&mut self) {
         if self.unprocessed_output_stream().chars().next().map(char::is_whitespace).unwrap_or(false) {
             let first_nonwhitespace_offset = self.unprocessed_output_stream().chars().take_while(|c| c.is_whitespace()).map(char::len_utf8).sum();
@@ -150,6 +965,12 @@ impl TestRunState {
         }
     }
 
+    /// Remembers the rest of the line from a position, for checks of the same line.
+    fn set_same_line(&mut self, start: AbsoluteByteIndex) {
+        let end = self.complete_output_stream[start.0..].find('\n').map_or(self.complete_output_stream.len(), |i| start.0 + i);
+        self.same_line = Some((start, AbsoluteByteIndex(end)));
+    }
+
     /// Eats all characters until the end of the current line.
     fn eat_until_end_of_line(&mut self) {
         let unprocessed = self.unprocessed_output_stream();
@@ -167,8 +988,8 @@ impl TestRunState {
     /// N.B. Does not advance the unprocessed stream pointer. This only takes a mutable
     /// reference because of the need to resolve the internal test variable list.
     fn next_unprocessed_byte_index_of(&mut self, text_pattern: &TextPattern, config: &Config)
-        -> Option<MatchedRange> {
-        let regex = vars::resolve::text_pattern(text_pattern, config, &mut self.variables);
+        -> Result<Option<MatchedRange>, TestResultKind> {
+        let regex = self::pattern_regex(&self.masked_pattern(text_pattern), config, &mut self.variables)?;
         let output_str = self.unprocessed_output_stream();
 
         debug!("converting expected text pattern to regex: {:?}", regex);
@@ -183,15 +1004,14 @@ impl TestRunState {
                 let new_variables = process_captures(&regex, regex_match.as_str());
                 self.variables.extend(new_variables);
 
-                Some(matched_range)
+                Ok(Some(matched_range))
             },
-            None => None,
+            None => Ok(None),
         }
     }
 
     fn set_position_eof(&mut self) {
-        let output_bytes = self.complete_output_stream.as_bytes();
-        self.current_stream_byte_position = AbsoluteByteIndex(output_bytes.len());
+        self.current_stream_byte_position = AbsoluteByteIndex(self.searched_end());
     }
 }
 
@@ -205,13 +1025,20 @@ fn convert_bytes_to_str(bytes: &[u8]) -> &str {
     std::str::from_utf8(bytes).expect("invalid UTF-8 in output stream")
 }
 
+/// Builds the regex of a text pattern, or the error of a numeric expression in it which cannot be evaluated.
+fn pattern_regex(text_pattern: &TextPattern, config: &Config, variables: &mut Variables) -> Result<Regex, TestResultKind> {
+    vars::resolve::text_pattern(text_pattern, config, variables).map_err(|message| TestResultKind::Error {
+        message: format!("cannot match '{}': {}", text_pattern, message),
+    })
+}
+
 /// Returns all named capture groups from regexes as variables.
 fn process_captures(
     regex: &Regex,
     matched_text: &str)
     -> HashMap<String, String> {
     // We shouldn't be calling this function if it didn't match.
-    debug_assert_eq!(regex.is_match(matched_text), true);
+    debug_assert!(regex.is_match(matched_text));
 
     let captures = if let Some(captures) = regex.captures(matched_text) {
         captures
@@ -221,14 +1048,27 @@ fn process_captures(
 
     let mut variables = HashMap::new();
 
-    for capture_name in regex.capture_names() {
-        // we only care about named captures.
-        if let Some(name) = capture_name {
-            let captured_value = captures.name(name).unwrap();
+    // we only care about named captures.
+    for name in regex.capture_names().flatten() {
+        let captured_value = captures.name(name).unwrap();
 
-            variables.insert(name.to_owned(), captured_value.as_str().to_owned());
-        }
+        variables.insert(name.to_owned(), captured_value.as_str().to_owned());
     }
 
     variables
 }
+
+/// Builds the failure of a pattern which was forbidden from appearing, but which matched at a byte of the output.
+fn forbidden_match(output: &str, match_start: usize, text_pattern: &TextPattern, anywhere: bool) -> TestResultKind {
+    let line_start = output[..match_start].rfind('\n').map_or(0, |i| i + 1);
+
+    TestResultKind::Fail {
+        reason: TestFailReason::ForbiddenMatch {
+            pattern: text_pattern.clone(),
+            line_number: output[..line_start].matches('\n').count() + 1,
+            line: output[line_start..].lines().next().unwrap_or("").to_owned(),
+            anywhere,
+        },
+        hint: None,
+    }
+}
@@ -24,7 +24,7 @@ fn fixture_program_prints_unicode_emoji() -> TestRunState {
 
 // Prints the periodic table in order, useful of testing line constraints.
 fn fixture_program_prints_periodic_table_in_order() -> TestRunState {
-    const ELEMENTS: &'static [&'static str] = &[
+    const ELEMENTS: &[&str] = &[
         "Hydrogen", "Helium", "Lithium", "Beryllium", "Boron", "Carbon",
         "Nitrogen", "Oxygen", "Fluorine", "Neon", "Sodium", "Magnesium",
     ];
@@ -41,10 +41,10 @@ fn check_next_works_standalone_in_very_basic_scenario() {
 
     assert!(test_state.unprocessed_output_stream().starts_with("  "));
 
-    test_state.check_next(&model::PatternComponent::Text(EMOJI_SMILEY.to_string()).into(), &config).unwrap();
+    test_state.check_next(&text(&EMOJI_SMILEY.to_string()), &config).unwrap();
     assert_eq!(test_state.unprocessed_output_stream(), "hello \nworld");
 
-    let res = test_state.check_next(&model::PatternComponent::Text("world".to_owned()).into(), &config);
+    let res = test_state.check_next(&text("world"), &config);
     match res {
         TestResultKind::Fail { reason, hint } => {
             match reason {
@@ -59,7 +59,7 @@ fn check_next_works_standalone_in_very_basic_scenario() {
         _ => panic!("unexpected failure reason: {:?}", res),
     }
 
-    test_state.check_next(&model::PatternComponent::Text("hello".to_owned()).into(), &config).unwrap();
+    test_state.check_next(&text("hello"), &config).unwrap();
     assert_eq!(test_state.unprocessed_output_stream(), "world");
 }
 
@@ -71,19 +71,19 @@ fn check_next_can_handle_multibyte_unicode_chars() {
     assert!(test_state.unprocessed_output_stream().starts_with("  "));
 
     // Consume first smiley emoji
-    test_state.check_next(&model::PatternComponent::Text(EMOJI_SMILEY.to_string()).into(), &config).unwrap();
+    test_state.check_next(&text(&EMOJI_SMILEY.to_string()), &config).unwrap();
     assert!(test_state.unprocessed_output_stream().starts_with(&format!("  {} smiles.\n", EMOJI_SMILEY)));
 
     // Consume next identical smiley.
-    test_state.check_next(&model::PatternComponent::Text(EMOJI_SMILEY.to_string()).into(), &config).unwrap();
+    test_state.check_next(&text(&EMOJI_SMILEY.to_string()), &config).unwrap();
     assert!(test_state.unprocessed_output_stream().starts_with("\t"));
 
     // Consume the joy emoji.
-    test_state.check_next(&model::PatternComponent::Text(EMOJI_JOY.to_string()).into(), &config).unwrap();
+    test_state.check_next(&text(&EMOJI_JOY.to_string()), &config).unwrap();
     assert_eq!(test_state.unprocessed_output_stream(), format!("joy{}.", EMOJI_SMILEY));
 
     // Consume the last smiley and terminating full stop.
-    test_state.check_next(&model::PatternComponent::Text(format!("{}.", EMOJI_SMILEY)).into(), &config).unwrap();
+    test_state.check_next(&text(&format!("{}.", EMOJI_SMILEY)), &config).unwrap();
     assert_eq!(test_state.unprocessed_output_stream(), "");
 }
 
@@ -94,11 +94,11 @@ fn check_next_rejects_matches_not_on_next_line() {
 
     assert!(test_state.unprocessed_output_stream().starts_with("Hydrogen, is an element.\nHelium, is an element.\n"));
 
-    test_state.check_next(&model::PatternComponent::Text("Hydrogen".to_owned()).into(), &config).unwrap();
+    test_state.check_next(&text("Hydrogen"), &config).unwrap();
     assert!(test_state.unprocessed_output_stream().starts_with("Helium"));
 
     // Attempt to read ahead of next line, expect failure.
-    let res = test_state.check_next(&model::PatternComponent::Text("Lithium".to_owned()).into(), &config);
+    let res = test_state.check_next(&text("Lithium"), &config);
     match res {
         TestResultKind::Fail { reason, hint } => {
             match reason {
@@ -121,9 +121,9 @@ fn check_with_nonexistent_regex_produces_failure() {
 
     assert!(test_state.unprocessed_output_stream().starts_with("Hydrogen, is an element.\nHelium, is an element.\n"));
 
-    test_state.check(&model::PatternComponent::Text("Helium".to_owned()).into(), &config).unwrap();
+    test_state.check(&text("Helium"), &config).unwrap();
 
-    let res = test_state.check(&model::PatternComponent::Text("nonexistent".to_owned()).into(), &config);
+    let res = test_state.check(&text("nonexistent"), &config);
 
     // Validate that a nonexistent regex triggers a failure.
     if let TestResultKind::Fail { reason, hint } = res {
@@ -139,3 +139,308 @@ fn check_with_nonexistent_regex_produces_failure() {
         panic!("expected the pattern to fail: {:?}", res);
     }
 }
+
+fn text(s: &str) -> model::TextPattern {
+    model::PatternComponent::Text(s.to_owned()).into()
+}
+
+#[test]
+fn selected_streams_are_checked_independently() {
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.append_program_output("out 1\nout 2\n");
+    test_state.append_program_stderr("err 1\nerr 2\n");
+    let config = Config::default();
+
+    test_state.check(&text("out 1"), &config).unwrap();
+    test_state.select_stream(model::OutputStream::Stderr);
+    test_state.check(&text("err 2"), &config).unwrap();
+    assert!(test_state.check(&text("out 2"), &config).is_erroneous());
+
+    test_state.select_stream(model::OutputStream::Stdout);
+    test_state.check_next(&text("out 2"), &config).unwrap();
+
+    test_state.append_interleaved_output("[stdout] out 1\n[stderr] err 1\n");
+    test_state.select_stream(model::OutputStream::Interleaved);
+    test_state.check(&text("[stderr] err 1"), &config).unwrap();
+    test_state.select_stream(model::OutputStream::Stderr);
+    assert!(test_state.check(&text("err"), &config).is_erroneous(), "stderr should remain checked up to its end");
+}
+
+#[test]
+fn check_any_does_not_advance_the_stream() {
+    let mut test_state = fixture_program_prints_periodic_table_in_order();
+    let config = Config::default();
+
+    test_state.check(&text("Helium"), &config).unwrap();
+    test_state.check_any(&text("Neon"), &config).unwrap();
+    test_state.check_any(&text("Boron"), &config).unwrap();
+    test_state.check_next(&text("Lithium"), &config).unwrap();
+
+    assert!(test_state.check_any(&text("Hydrogen"), &config).is_erroneous(),
+            "text before the stream position should not match");
+}
+
+#[test]
+fn check_within_requires_a_match_in_the_next_few_lines() {
+    let mut test_state = fixture_program_prints_periodic_table_in_order();
+    let config = Config::default();
+
+    test_state.check(&text("Helium"), &config).unwrap();
+    test_state.check_within(3, &text("Boron"), &config).unwrap();
+
+    match test_state.check_within(2, &text("Oxygen"), &config) {
+        TestResultKind::Fail { hint, .. } => assert!(hint.unwrap().contains("CHECK-WITHIN-2")),
+        res => panic!("expected a match too far away to fail, but got {:?}", res),
+    }
+    test_state.check_within(3, &text("Oxygen"), &config).unwrap();
+}
+
+#[test]
+fn check_dag_once_rejects_repeated_records() {
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.append_program_output("record b\nrecord a\nrecord c\nrecord a\n");
+    let config = Config::default();
+
+    test_state.check_dag_once(&text("record b"), &config).unwrap();
+    test_state.check_dag_once(&text("record c"), &config).unwrap();
+
+    match test_state.check_dag_once(&text("record a"), &config) {
+        TestResultKind::Fail { hint, .. } => assert!(hint.unwrap().contains("CHECK-DAG-ONCE")),
+        res => panic!("expected a repeated record to fail, but got {:?}", res),
+    }
+    test_state.check_dag(&text("record a"), &config).unwrap();
+}
+
+#[test]
+fn check_size_compares_the_raw_length_of_the_stream() {
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.append_program_output("12\u{FFFD}");
+    test_state.append_raw_output(model::OutputStream::Stdout, b"12\xff\xfe\xfd");
+    test_state.append_raw_output(model::OutputStream::Stderr, b"1234567890");
+
+    test_state.check_size(model::Comparison::Equal, 5).unwrap();
+    test_state.check_size(model::Comparison::Less, 6).unwrap();
+    assert!(test_state.check_size(model::Comparison::Greater, 5).is_erroneous());
+
+    test_state.select_stream(model::OutputStream::Stderr);
+    test_state.check_size(model::Comparison::GreaterOrEqual, 10).unwrap();
+}
+
+#[test]
+fn check_final_newline_looks_at_the_end_of_the_stream() {
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.append_raw_output(model::OutputStream::Stdout, b"done\n");
+    test_state.append_raw_output(model::OutputStream::Stderr, b"warning");
+    test_state.append_raw_output(model::OutputStream::Stderr, b"");
+
+    test_state.check_final_newline(true).unwrap();
+    assert!(test_state.check_final_newline(false).is_erroneous());
+
+    test_state.select_stream(model::OutputStream::Stderr);
+    test_state.check_final_newline(false).unwrap();
+    assert!(test_state.check_final_newline(true).is_erroneous());
+}
+
+#[test]
+fn check_rest_compares_everything_after_the_last_match() {
+    let config = Config::default();
+    let lines = |lines: &[&str]| lines.iter().map(|line| line.to_string()).collect::<Vec<_>>();
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.append_program_output("header\n  one\ntwo\n");
+
+    test_state.check(&text("header"), &Config::default()).unwrap();
+    test_state.check_rest(&lines(&["  one", "two"]), &config).unwrap();
+    assert!(test_state.unprocessed_output_stream().is_empty());
+
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.append_program_output("one\ntwo\nstray\n");
+
+    match test_state.check_rest(&lines(&["one", "two"]), &config) {
+        TestResultKind::Fail { reason: TestFailReason::RestMismatch { ref diff }, .. } => assert_eq!(diff, "  one\n  two\n+ stray\n"),
+        kind => panic!("expected the stray line to fail, but got {:?}", kind),
+    }
+}
+
+#[test]
+fn check_not_looks_between_the_surrounding_checks() {
+    let config = Config::default();
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.append_program_output("begin\nwarning: a\nend\nwarning: b\n");
+
+    test_state.check_not(&text("error")).unwrap();
+    test_state.check(&text("begin"), &config).unwrap();
+    test_state.check_not(&text("warning: b")).unwrap();
+    test_state.check(&text("end"), &config).unwrap();
+    test_state.check_not(&text("begin")).unwrap();
+    test_state.end_not_checks(&config).unwrap();
+
+    test_state.check_not(&text("warning")).unwrap();
+    match test_state.end_not_checks(&config) {
+        TestResultKind::Fail { reason: TestFailReason::ForbiddenMatch { line_number, ref line, anywhere: false, .. }, .. } => {
+            assert_eq!((line_number, line.as_str()), (4, "warning: b"));
+        },
+        kind => panic!("expected the trailing line to fail, but got {:?}", kind),
+    }
+}
+
+#[test]
+fn check_not_regions_end_at_every_positional_check() {
+    let config = Config::default();
+    let is_forbidden_match = |kind: TestResultKind| matches!(kind, TestResultKind::Fail { reason: TestFailReason::ForbiddenMatch { .. }, .. });
+    let state = |output: &str| {
+        let mut test_state = TestRunState::new(HashMap::new());
+        test_state.append_program_output(output);
+        test_state.check_not(&text("error")).unwrap();
+        test_state
+    };
+
+    assert!(is_forbidden_match(state("error\nfoo\n").check_dag(&text("foo"), &config)));
+    assert!(is_forbidden_match(state("error\n1.5\n").check_num(1.5, 0.1, &config)));
+    assert!(is_forbidden_match(state("ok\nerror\n").check_rest(&["ok".to_owned(), "error".to_owned()], &config)));
+    assert!(is_forbidden_match(state("error\nfoo\nbar\n").check_alt(&[text("foo"), text("bar")], &config)));
+
+    let mut test_state = state("foo error bar\n");
+    test_state.check(&text("foo"), &config).unwrap();
+    test_state.check_not(&text("error")).unwrap();
+    assert!(!is_forbidden_match(test_state.check_same(&text("bar"), &config)), "CHECK-NOTs only look at the lines after a check");
+
+    let mut test_state = state("\nerror\n");
+    test_state.check_empty(&config).unwrap();
+    test_state.end_not_checks(&config).unwrap();
+}
+
+#[test]
+fn check_none_looks_at_the_whole_stream() {
+    let config = Config::default();
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.append_program_output("compiling\nwarning: unused\ndone\n");
+
+    test_state.check(&text("done"), &config).unwrap();
+    test_state.check_none(&text("error"), &config).unwrap();
+
+    match test_state.check_none(&text("unused"), &config) {
+        TestResultKind::Fail { reason: TestFailReason::ForbiddenMatch { line_number, ref line, .. }, .. } => {
+            assert_eq!((line_number, line.as_str()), (2, "warning: unused"));
+        },
+        kind => panic!("expected the earlier line to fail, but got {:?}", kind),
+    }
+}
+
+#[test]
+fn check_custom_matches_lines_using_the_matcher() {
+    let config = Config::default();
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.append_program_output("pi: 3.1416\ne: 2.7183\n");
+
+    test_state.check_custom("floateq", crate::config::matchers::float_eq, "2.718 +- 0.001", &config).unwrap();
+    assert!(test_state.check_custom("floateq", crate::config::matchers::float_eq, "3.14 +- 0.01", &config).is_erroneous(),
+            "lines before the previous match should not match");
+}
+
+#[test]
+fn check_num_compares_the_first_number_on_a_line() {
+    let config = Config::default();
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.append_program_output("step 1: 0.5\nresult 2.7100001 (2 iterations)\n");
+
+    assert!(test_state.check_num(0.5, 0.01, &config).is_erroneous(), "only the first number on each line is compared");
+    test_state.check_num(2.71, 0.001, &config).unwrap();
+    assert!(test_state.check_num(2.0, 0.1, &config).is_erroneous());
+}
+
+#[test]
+fn check_same_continues_after_the_number_of_check_num() {
+    let config = Config::default();
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.append_program_output("label 2 done\n");
+
+    test_state.check_num(2.0, 0.1, &config).unwrap();
+    assert!(test_state.check_same(&text("label"), &config).is_erroneous(), "text before the number has already been checked");
+    test_state.check_same(&text("done"), &config).unwrap();
+}
+
+#[test]
+fn check_time_compares_the_scaled_duration() {
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.set_run_duration(Duration::from_millis(300));
+
+    test_state.check_time(model::Comparison::Less, Duration::from_millis(500), 1.0).unwrap();
+    test_state.check_time(model::Comparison::Less, Duration::from_millis(200), 2.0).unwrap();
+
+    match test_state.check_time(model::Comparison::Less, Duration::from_millis(200), 1.0) {
+        TestResultKind::Fail { reason: TestFailReason::UnexpectedDuration { actual_duration, .. }, .. } => {
+            assert_eq!(actual_duration, Duration::from_millis(300));
+        },
+        res => panic!("expected a slow program to fail, but got {:?}", res),
+    }
+
+    for invalid_scale in [-1.0, f64::NAN, f64::INFINITY, 1e300] {
+        assert!(matches!(test_state.check_time(model::Comparison::Less, Duration::from_millis(200), invalid_scale), TestResultKind::Error { .. }));
+    }
+}
+
+#[test]
+fn check_same_count_counts_matches_after_the_previous_match() {
+    let config = Config::default();
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.append_program_output("header a,b,c
+row 1,2
+");
+
+    assert!(matches!(test_state.check_same_count(2, &text(","), &config), TestResultKind::Error { .. }),
+            "there is no line to count in before the first check");
+
+    test_state.check(&text("header"), &config).unwrap();
+    test_state.check_same_count(2, &text(","), &config).unwrap();
+    assert!(test_state.check_same_count(3, &text(","), &config).is_erroneous());
+
+    test_state.check(&text("row 1"), &config).unwrap();
+    test_state.check_same_count(1, &text(","), &config).unwrap();
+    test_state.check_same_count(0, &text("1"), &config).unwrap();
+}
+
+#[test]
+fn check_same_continues_after_the_previous_match_on_the_line() {
+    let config = Config::default();
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.append_program_output("error: a.c:3: missing ';'\nnote: b.c\n");
+
+    assert!(matches!(test_state.check_same(&text("a.c"), &config), TestResultKind::Error { .. }));
+
+    test_state.check(&text("error:"), &config).unwrap();
+    test_state.check_same(&text("a.c"), &config).unwrap();
+    test_state.check_same(&text("missing"), &config).unwrap();
+    assert!(test_state.check_same(&text("a.c"), &config).is_erroneous(), "matches cannot go backwards");
+    assert!(test_state.check_same(&text("b.c"), &config).is_erroneous(), "matches cannot go past the line");
+
+    test_state.check_next(&text("note:"), &config).unwrap();
+}
+
+#[test]
+fn check_empty_requires_the_next_line_to_be_empty() {
+    let config = Config::default();
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.append_program_output("header\n\nbody\n");
+
+    test_state.check(&text("header"), &config).unwrap();
+    test_state.check_empty(&config).unwrap();
+    match test_state.check_empty(&config) {
+        TestResultKind::Fail { reason: TestFailReason::NonEmptyLine { found: Some(ref line) }, .. } => assert_eq!(line, "body"),
+        kind => panic!("expected the non-empty line to fail, but got {:?}", kind),
+    }
+
+    test_state.check_next(&text("body"), &config).unwrap();
+    assert_eq!(test_state.check_empty(&config), TestResultKind::Fail { reason: TestFailReason::NonEmptyLine { found: None }, hint: None });
+}
+
+#[test]
+fn check_alt_uses_the_alternative_that_matches_earliest() {
+    let config = Config::default();
+    let mut test_state = TestRunState::new(HashMap::new());
+    test_state.append_program_output("path: C:\\temp\nsize: 10\nsize: 20\n");
+
+    test_state.check_alt(&[text("path: /tmp"), text("path: C:\\temp")], &config).unwrap();
+    test_state.check_alt(&[text("size: 20"), text("size: 10")], &config).unwrap();
+    test_state.check(&text("size: 20"), &config).unwrap();
+    assert!(test_state.check_alt(&[text("size: 10"), text("path")], &config).is_erroneous());
+}
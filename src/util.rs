@@ -1,7 +1,7 @@
 //! Utility functions for internal use.
 
-const DEFAULT_INDENT_ATOM: &'static str = "  ";
-const TRUNCATED_TEXT_MARKER: &'static str = "... (truncated)";
+const DEFAULT_INDENT_ATOM: &str = "  ";
+const TRUNCATED_TEXT_MARKER: &str = "... (truncated)";
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum TruncateDirection { Top, Bottom }
@@ -12,7 +12,7 @@ pub fn indent(text: &str, level: usize) -> String {
 }
 
 pub fn indent_ext(text: &str, level: usize, indentation_atom: &str) -> String {
-    let indent = (0..level).into_iter().map(|_| indentation_atom).collect::<Vec<_>>().join("");
+    let indent = (0..level).map(|_| indentation_atom).collect::<Vec<_>>().join("");
     text.lines().map(|l| format!("{}{}", indent, l.trim())).collect::<Vec<_>>().join("\n") + "\n"
 }
 
@@ -22,7 +22,7 @@ pub fn decorate_with_line_numbers(text: &str, starts_from_line_number: usize) ->
     text.lines().enumerate().map(|(relative_lineno, line)| {
         let line_number_str = (starts_from_line_number + relative_lineno).to_string();
         let number_of_pad_chars = max_line_num_digits - line_number_str.len();
-        let horizontal_padding_str = (0..number_of_pad_chars).into_iter().map(|_| " ").collect::<String>();
+        let horizontal_padding_str = (0..number_of_pad_chars).map(|_| " ").collect::<String>();
 
         format!("{}{}|      {}", line_number_str, horizontal_padding_str, line)
     }).collect::<Vec<_>>().join("\n")
@@ -52,3 +52,159 @@ pub fn truncate_to_max_lines(
         truncated_text // the text was not actually truncated
     }
 }
+
+/// Decodes a non-empty string of hex digits, two digits per byte.
+pub fn decode_hex(digits: &str) -> Option<Vec<u8>> {
+    if digits.is_empty() || !digits.len().is_multiple_of(2) || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0..digits.len()).step_by(2).map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok()).collect()
+}
+
+/// Builds a line-by-line diff between two texts.
+///
+/// Every line is prefixed with `-` if it is only in the old text, `+` if it is only
+/// in the new text, and a space if it is in both.
+pub fn line_diff(old: &str, new: &str) -> String {
+    let (old_lines, new_lines): (Vec<_>, Vec<_>) = (old.lines().collect(), new.lines().collect());
+
+    // The lengths of the longest common subsequences of the remaining lines of both texts.
+    let mut common_lengths = vec![vec![0usize; new_lines.len() + 1]; old_lines.len() + 1];
+    for i in (0..old_lines.len()).rev() {
+        for j in (0..new_lines.len()).rev() {
+            common_lengths[i][j] = if old_lines[i] == new_lines[j] {
+                common_lengths[i + 1][j + 1] + 1
+            } else {
+                common_lengths[i + 1][j].max(common_lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old_lines.len() || j < new_lines.len() {
+        if i < old_lines.len() && j < new_lines.len() && old_lines[i] == new_lines[j] {
+            diff += &format!("  {}\n", old_lines[i]);
+            i += 1;
+            j += 1;
+        } else if j == new_lines.len() || (i < old_lines.len() && common_lengths[i + 1][j] >= common_lengths[i][j + 1]) {
+            diff += &format!("- {}\n", old_lines[i]);
+            i += 1;
+        } else {
+            diff += &format!("+ {}\n", new_lines[j]);
+            j += 1;
+        }
+    }
+
+    diff
+}
+
+/// Finds the substring of a text which needs the fewest single character insertions, deletions
+/// and substitutions to become the pattern.
+///
+/// Returns the number of edits and the byte range of the substring, which is the earliest one
+/// if several are equally close.
+pub fn closest_substring(pattern: &str, text: &str) -> (usize, std::ops::Range<usize>) {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text_chars: Vec<(usize, char)> = text.char_indices().collect();
+    let byte_index = |char_index: usize| text_chars.get(char_index).map_or(text.len(), |&(i, _)| i);
+
+    // The fewest edits, and the character index that the substring starts at, for aligning
+    // each prefix of the pattern with a substring that ends at the current character.
+    let mut previous: Vec<(usize, usize)> = (0..=pattern.len()).map(|i| (i, 0)).collect();
+    let mut current = previous.clone();
+    let mut closest = (pattern.len(), 0, 0);
+
+    for (j, &(_, c)) in text_chars.iter().enumerate() {
+        current[0] = (0, j + 1);
+        for i in 1..=pattern.len() {
+            let substitution = (previous[i - 1].0 + usize::from(pattern[i - 1] != c), previous[i - 1].1);
+            let deletion = (current[i - 1].0 + 1, current[i - 1].1);
+            let insertion = (previous[i].0 + 1, previous[i].1);
+            current[i] = substitution.min(deletion).min(insertion);
+        }
+
+        let (edits, start) = current[pattern.len()];
+        if edits < closest.0 {
+            closest = (edits, start, j + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    (closest.0, byte_index(closest.1)..byte_index(closest.2))
+}
+
+/// Escapes text for use in XML attributes and elements.
+///
+/// Control characters other than tabs and line breaks cannot appear in XML at all, so they are dropped.
+pub fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => (),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Shuffles items into an order which only depends on the seed and the number of items.
+pub fn shuffle<T>(items: &mut [T], seed: u64) {
+    // A splitmix64 generator, which is good enough for ordering and works with any seed.
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let z = (state ^ (state >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        let z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    };
+
+    for i in (1..items.len()).rev() {
+        items.swap(i, (next() % (i as u64 + 1)) as usize);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn line_diff_marks_removed_and_added_lines() {
+        assert_eq!(super::line_diff("a\nb\nc\n", "a\nc\nd\n"), "  a\n- b\n  c\n+ d\n");
+        assert_eq!(super::line_diff("same\n", "same\n"), "  same\n");
+    }
+
+    #[test]
+    fn closest_substrings_need_the_fewest_edits() {
+        let closest = |pattern, text: &'static str| { let (edits, range) = super::closest_substring(pattern, text); (edits, &text[range]) };
+
+        assert_eq!(closest("hello world", "say hello wrold, please"), (2, "hello wrold"));
+        assert_eq!(closest("abc", "xxabcxx abd"), (0, "abc"));
+        assert_eq!(closest("abc", "zzz"), (3, ""));
+        assert_eq!(closest("héllo", "a hallo"), (1, "hallo"));
+    }
+
+    #[test]
+    fn xml_is_escaped() {
+        assert_eq!(super::xml_escape("a < b && c > \"d\" 'e'\u{1b}[0m\n"), "a &lt; b &amp;&amp; c &gt; &quot;d&quot; &apos;e&apos;[0m\n");
+    }
+
+    #[test]
+    fn shuffles_are_reproducible_from_the_seed() {
+        let shuffled = |seed| { let mut items: Vec<_> = (0..20).collect(); super::shuffle(&mut items, seed); items };
+
+        assert_eq!(shuffled(42), shuffled(42));
+        assert_ne!(shuffled(42), shuffled(43));
+        assert_ne!(shuffled(42), (0..20).collect::<Vec<_>>());
+        let mut sorted = shuffled(42);
+        sorted.sort();
+        assert_eq!(sorted, (0..20).collect::<Vec<_>>());
+    }
+}
@@ -5,9 +5,14 @@ use crate::vars::Variables;
 use crate::Config;
 
 use regex::Regex;
+use std::path::Path;
 
 lazy_static! {
     static ref CONSTANT_REGEX: Regex = Regex::new("@([_a-zA-Z]+)").unwrap();
+    static ref CONDITIONAL_REGEX: Regex = Regex::new(r"%if\s+([^\s%]+)\s*%\{").unwrap();
+    static ref ELSE_REGEX: Regex = Regex::new(r"^\s*%else\s*%\{").unwrap();
+    static ref PARAM_REGEX: Regex = Regex::new(r"%\{param:([^}]*)\}").unwrap();
+    static ref PERCENT_REGEX: Regex = Regex::new("%([%sSptT])").unwrap();
 }
 
 /// A span representing where a constant name resides in a string.
@@ -17,16 +22,23 @@ struct ConstantSpan {
     name: String,
     /// The index of the first character.
     start: usize,
-    /// The index of the last character.
-    end: usize,
 }
 
+/// Builds the regex of a text pattern, with the current values of its variables.
+///
+/// Fails if a numeric expression cannot be evaluated, like when it overflows.
 pub fn text_pattern(pattern: &TextPattern, config: &Config,
-                    variables: &mut Variables) -> Regex {
-    let regex_parts: Vec<_> = pattern.components.iter().map(|comp| match *comp {
-        PatternComponent::Text(ref text) => regex::escape(text),
+                    variables: &mut Variables) -> Result<Regex, String> {
+    let last_component_index = pattern.components.len().saturating_sub(1);
+
+    let regex_parts: Vec<_> = pattern.components.iter().enumerate().map(|(i, comp)| Ok(match *comp {
+        // Undefined parameters are reported before any checks are evaluated, so they can be ignored here.
+        PatternComponent::Text(ref text) if config.ignore_trailing_whitespace && i == last_component_index => {
+            regex::escape(self::params(text, config).as_deref().unwrap_or(text).trim_end())
+        },
+        PatternComponent::Text(ref text) => regex::escape(self::params(text, config).as_deref().unwrap_or(text)),
+        // Undefined variables are reported before any checks are evaluated.
         PatternComponent::Variable(ref name) => {
-            // FIXME: proper error handling.
             let value = config.lookup_variable(name, variables);
 
             let var_resolution_log = format!("resolving '@{}' to '{}' in {:?}", name, value, pattern);
@@ -36,27 +48,62 @@ pub fn text_pattern(pattern: &TextPattern, config: &Config,
                 eprintln!("[info] {}", var_resolution_log);
             }
 
-            value.to_owned()
+            regex::escape(value)
         },
         PatternComponent::Regex(ref regex) => regex.clone(),
         PatternComponent::NamedRegex { ref name, ref regex } => format!("(?P<{}>{})", name, regex),
-    }).collect();
-    Regex::new(&regex_parts.join("")).expect("generated invalid line match regex")
+        PatternComponent::NumericCapture(ref name) => format!("(?P<{}>[0-9]+)", name),
+        PatternComponent::NumericExpression(ref expression) => {
+            // Undefined variables are reported before any checks are evaluated, but captured
+            // numbers can be too large, and the expression can overflow.
+            let value = expression.evaluate(variables)?;
+
+            let var_resolution_log = format!("resolving '[[#{}]]' to '{}' in {:?}", expression, value, pattern);
+            debug!("{}", var_resolution_log);
+
+            if config.dump_variable_resolution {
+                eprintln!("[info] {}", var_resolution_log);
+            }
+
+            regex::escape(&value.to_string())
+        },
+    })).collect::<Result<_, String>>()?;
+    Ok(Regex::new(&regex_parts.join("")).expect("generated invalid line match regex"))
 }
 
+/// Resolves the command line of an invocation.
+///
+/// This expands any `%if` conditionals, then percent substitutions like `%s` if the config
+/// enables them, and substitutes parameters and then variables in the rest of the command.
 pub fn invocation(invocation: &Invocation,
                   config: &Config,
-                  constants: &mut Variables) -> String {
-    let mut command_line = String::new();
+                  constants: &mut Variables) -> Result<String, String> {
+    // Conditionals can leave the whitespace around their branches at the ends of the command.
+    let command = self::conditionals(&invocation.original_command, config)?.trim().to_owned();
+    let resolve_text = |text: &str, constants: &mut Variables| -> Result<String, String> {
+        Ok(self::string(&self::params(text, config)?, config, constants))
+    };
+
+    // Percent substitutions are only expanded in the text of the command, not in the values of
+    // its parameters and variables, and substituted paths are not resolved again.
+    match config.percent_substitutions {
+        true => self::percent_substitutions(&command, config, constants, resolve_text),
+        false => resolve_text(&command, constants),
+    }
+}
+
+/// Substitutes all `@<name>` variables inside a string.
+pub fn string(text: &str,
+          config: &Config,
+          constants: &mut Variables) -> String {
+    let mut resolved = String::new();
 
-    let _cmd: String = invocation.original_command.clone();
-    let mut constant_spans = CONSTANT_REGEX.find_iter(&_cmd).map(|mat| {
+    let mut constant_spans = CONSTANT_REGEX.find_iter(text).map(|mat| {
         let name = mat.as_str()[1..].to_owned(); // Skip the '@' character.
 
         ConstantSpan {
-            name: name,
+            name,
             start: mat.start(),
-            end: mat.end(),
         }
     });
 
@@ -67,7 +114,7 @@ pub fn invocation(invocation: &Invocation,
 
             let value = config.lookup_variable(&next_span.name, constants);
 
-            let var_resolution_log = format!("resolving '@{}' to '{}' in {:?}", next_span.name, value, _cmd);
+            let var_resolution_log = format!("resolving '@{}' to '{}' in {:?}", next_span.name, value, text);
             debug!("{}", var_resolution_log);
 
             if config.dump_variable_resolution {
@@ -76,23 +123,155 @@ pub fn invocation(invocation: &Invocation,
 
             // Check if there is some text between us and the regex.
             if next_span.start != index {
-                let part = &invocation.original_command[index..next_span.start];
+                let part = &text[index..next_span.start];
 
-                command_line += part;
+                resolved += part;
                 index += part.len();
             }
 
             assert_eq!(index, next_span.start, "we should be up to the regex");
-            command_line += &value;
+            resolved += value;
             index += next_span.name.len() + 1; // Skip the `@` and the name.
         } else {
             // Almost finished, just copy over the rest of the text.
-            command_line += &invocation.original_command[index..];
+            resolved += &text[index..];
             break;
         }
     }
 
-    command_line
+    resolved
+}
+
+/// Gets the names of all `@<name>` variables inside a string, in order of appearance.
+///
+/// The `%t` and `%T` substitutions count as uses of the `tempfile` variable.
+pub fn variable_names(text: &str) -> Vec<String> {
+    let constants = CONSTANT_REGEX.captures_iter(text).map(|captures| (captures.get(0).unwrap().start(), captures[1].to_owned()));
+    let tempfiles = PERCENT_REGEX.captures_iter(text)
+        .filter(|captures| matches!(&captures[1], "t" | "T"))
+        .map(|captures| (captures.get(0).unwrap().start(), "tempfile".to_owned()));
+
+    let mut names: Vec<_> = constants.chain(tempfiles).collect();
+    names.sort_by_key(|&(start, _)| start);
+    names.into_iter().map(|(_, name)| name).collect()
+}
+
+/// Expands the percent substitutions of LLVM's lit inside a command.
+///
+/// `%s` is the path of the test file and `%S` and `%p` are its directory. `%t` is a
+/// temporary file path that is unique to the test, the same one as `@tempfile`, and
+/// `%T` is the directory containing it. `%%` is a literal `%`.
+///
+/// The text between the substitutions is resolved with `resolve_text`.
+pub fn percent_substitutions<F>(text: &str,
+                                config: &Config,
+                                constants: &mut Variables,
+                                mut resolve_text: F) -> Result<String, String>
+    where F: FnMut(&str, &mut Variables) -> Result<String, String> {
+    let parent = |path: &str| Path::new(path).parent().map(|parent| parent.display().to_string()).unwrap_or_default();
+    let mut resolved = String::new();
+    let mut index = 0;
+
+    for captures in PERCENT_REGEX.captures_iter(text) {
+        let substitution = captures.get(0).unwrap();
+        resolved += &resolve_text(&text[index..substitution.start()], constants)?;
+        index = substitution.end();
+
+        let value = match &captures[1] {
+            "s" => constants.get("file").cloned().unwrap_or_default(),
+            "S" | "p" => parent(constants.get("file").map(String::as_str).unwrap_or_default()),
+            "t" => config.lookup_variable("tempfile", constants).to_owned(),
+            "T" => parent(config.lookup_variable("tempfile", constants)),
+            _ => {
+                resolved.push('%');
+                continue;
+            },
+        };
+
+        let var_resolution_log = format!("resolving '{}' to '{}' in {:?}", substitution.as_str(), value, text);
+        debug!("{}", var_resolution_log);
+
+        if config.dump_variable_resolution {
+            eprintln!("[info] {}", var_resolution_log);
+        }
+
+        resolved += &value;
+    }
+
+    resolved += &resolve_text(&text[index..], constants)?;
+    Ok(resolved)
+}
+
+/// Substitutes all `%{param:<name>}` parameters inside a string.
+///
+/// Referring to a parameter that the config does not define is an error.
+pub fn params(text: &str, config: &Config) -> Result<String, String> {
+    let mut undefined_param = None;
+
+    let substituted = PARAM_REGEX.replace_all(text, |captures: &regex::Captures| {
+        let name = captures[1].trim();
+
+        match config.params.get(name) {
+            Some(value) => value.clone(),
+            None => {
+                undefined_param.get_or_insert_with(|| name.to_owned());
+                String::new()
+            },
+        }
+    });
+
+    match undefined_param {
+        Some(name) => Err(format!("parameter '{}' is not defined, it can be set with '--param {}=<VALUE>'", name, name)),
+        None => Ok(substituted.into_owned()),
+    }
+}
+
+/// Expands `%if <feature> %{ <text> %} %else %{ <text> %}` conditionals.
+///
+/// The `%else` branch is optional. Features that the config does not know
+/// about are treated as unavailable. Nested conditionals are not supported.
+pub fn conditionals(command: &str, config: &Config) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = command;
+
+    while let Some(captures) = CONDITIONAL_REGEX.captures(rest) {
+        let conditional_start = captures.get(0).unwrap();
+        let feature = captures.get(1).unwrap().as_str();
+
+        expanded += &rest[..conditional_start.start()];
+
+        let (then_text, after_then) = conditional_branch(&rest[conditional_start.end()..])?;
+        let (else_text, after_else) = match ELSE_REGEX.find(after_then) {
+            Some(else_start) => conditional_branch(&after_then[else_start.end()..])?,
+            None => ("", after_then),
+        };
+
+        expanded += if config.is_feature_available(feature) { then_text } else { else_text };
+        rest = after_else;
+    }
+
+    if rest.contains("%if") {
+        return Err(format!("malformed '%if' conditional, expected '%if <feature> %{{ <text> %}}' in '{}'", command));
+    }
+
+    expanded += rest;
+    Ok(expanded)
+}
+
+/// Splits the text of a conditional branch from the text that follows its terminating `%}`.
+fn conditional_branch(text: &str) -> Result<(&str, &str), String> {
+    match text.find("%}") {
+        Some(end_index) => {
+            let branch_text = &text[..end_index];
+
+            if branch_text.contains("%if") {
+                return Err("nested '%if' conditionals are not supported".to_owned());
+            }
+
+            Ok((branch_text, &text[end_index + 2..]))
+        },
+        None => Err("'%{' in '%if' conditional is never closed with '%}'".to_owned()),
+    }
 }
 
 #[cfg(test)]
@@ -115,7 +294,7 @@ mod test {
 
         fn resolve(s: &str) -> String {
             let text_pattern = parse::text_pattern(s);
-            vars::resolve::text_pattern(&text_pattern, &Config::default(), &mut VARIABLES.clone()).as_str().to_owned()
+            vars::resolve::text_pattern(&text_pattern, &Config::default(), &mut VARIABLES.clone()).unwrap().as_str().to_owned()
         }
 
         #[test]
@@ -150,8 +329,12 @@ mod test {
         }
 
         fn resolve(s: &str, consts: &mut HashMap<String, String>) -> String {
+            resolve_with_config(s, consts, &Config::default())
+        }
+
+        fn resolve_with_config(s: &str, consts: &mut HashMap<String, String>, config: &Config) -> String {
             let invocation = parse::invocation(s.split_whitespace()).unwrap();
-            vars::resolve::invocation(&invocation, &Config::default(), consts)
+            vars::resolve::invocation(&invocation, config, consts).unwrap()
         }
 
         #[test]
@@ -173,6 +356,96 @@ mod test {
         fn junk_then_const_then_junk() {
             assert_eq!(resolve("hello @cc world", &mut BASIC_CONSTANTS.clone()), "hello clang++ world");
         }
+
+        #[test]
+        fn percent_substitutions() {
+            let mut constants = BASIC_CONSTANTS.clone();
+            constants.insert("file".to_owned(), "/tests/dir/foo.c".to_owned());
+            constants.insert("tempfile".to_owned(), "/tmp/lit/foo.tmp".to_owned());
+            let config = Config { percent_substitutions: true, ..Config::default() };
+
+            assert_eq!(resolve_with_config("@cc %s -I %S -I %p -o %t -L %T", &mut constants, &config),
+                       "clang++ /tests/dir/foo.c -I /tests/dir -I /tests/dir -o /tmp/lit/foo.tmp -L /tmp/lit");
+            assert_eq!(resolve_with_config("printf 100%% %%s %{ %q", &mut constants, &config), "printf 100% %s %{ %q");
+            assert_eq!(resolve("printf %s %t", &mut constants), "printf %s %t", "percent substitutions are off by default");
+
+            constants.insert("file".to_owned(), "/tests/@cc/foo.c".to_owned());
+            assert_eq!(resolve_with_config("cat %s", &mut constants, &config), "cat /tests/@cc/foo.c", "substituted paths are not resolved again");
+            assert_eq!(vars::resolve::variable_names("@cc %s %t @out_tempfile %%t"), vec!["cc", "tempfile", "out_tempfile"]);
+        }
+
+        #[test]
+        fn percent_substitutions_are_not_expanded_in_the_values_of_variables() {
+            let mut constants = BASIC_CONSTANTS.clone();
+            constants.insert("file".to_owned(), "/tests/foo.c".to_owned());
+            constants.insert("format".to_owned(), "%s\\n".to_owned());
+            let mut config = Config { percent_substitutions: true, ..Config::default() };
+            config.params.insert("format".to_owned(), "%t\\n".to_owned());
+
+            assert_eq!(resolve_with_config("printf @format %{param:format} %s", &mut constants, &config), "printf %s\\n %t\\n /tests/foo.c");
+        }
     }
-}
 
+    mod params {
+        use crate::{vars, Config};
+
+        #[test]
+        fn params_are_substituted() {
+            let mut config = Config::default();
+            config.params.insert("triple".to_owned(), "x86_64-linux".to_owned());
+
+            assert_eq!(vars::resolve::params("tool --target=%{param:triple} @file", &config), Ok("tool --target=x86_64-linux @file".to_owned()));
+            assert!(vars::resolve::params("tool %{param:cpu}", &config).unwrap_err().contains("'cpu' is not defined"));
+            assert!(config.is_feature_available("triple=x86_64-linux"));
+            assert!(!config.is_feature_available("triple=arm"));
+        }
+    }
+
+    mod conditionals {
+        use crate::{vars, Config};
+
+        fn expand(s: &str, features: &[&str]) -> Result<String, String> {
+            let mut config = Config::default();
+            for feature in features {
+                config.add_feature(feature);
+            }
+
+            vars::resolve::conditionals(s, &config)
+        }
+
+        #[test]
+        fn no_conditionals_is_nop() {
+            assert_eq!(expand("tool -v @file", &[]), Ok("tool -v @file".to_owned()));
+        }
+
+        #[test]
+        fn available_feature_takes_then_branch() {
+            assert_eq!(expand("%if asserts %{tool -verify%} %else %{tool%}", &["asserts"]),
+                       Ok("tool -verify".to_owned()));
+        }
+
+        #[test]
+        fn unknown_feature_takes_else_branch() {
+            assert_eq!(expand("%if asserts %{tool -verify%} %else %{tool%}", &[]),
+                       Ok("tool".to_owned()));
+        }
+
+        #[test]
+        fn else_branch_is_optional() {
+            assert_eq!(expand("tool%if asserts %{ -verify%} @file", &[]),
+                       Ok("tool @file".to_owned()));
+            assert_eq!(expand("tool%if asserts %{ -verify%} @file", &["asserts"]),
+                       Ok("tool -verify @file".to_owned()));
+        }
+
+        #[test]
+        fn unterminated_branch_is_an_error() {
+            assert!(expand("%if asserts %{tool -verify", &[]).is_err());
+        }
+
+        #[test]
+        fn nested_conditionals_are_an_error() {
+            assert!(expand("%if a %{ %if b %{ x %} %}", &["a", "b"]).is_err());
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use lit::run;
 
-const CRATE_PATH: &'static str = env!("CARGO_MANIFEST_DIR");
+const CRATE_PATH: &str = env!("CARGO_MANIFEST_DIR");
 
 /// Runs all of the integration tests in the top-level directory
 /// of the repository.
@@ -25,3 +25,80 @@ fn integration_tests() {
         config.shell = "sh".to_string();
     }).expect("unit test(s) failed");
 }
+
+/// Runs the integration tests in parallel twice, verifying that the results
+/// are always reported in the order the tests were discovered in.
+#[test]
+fn parallel_results_are_in_discovery_order() {
+    let run_in_parallel = || {
+        let results = run::tests_with_results(lit::event_handler::Default::default(), |config| {
+            config.add_search_path(format!("{}/integration-tests", CRATE_PATH));
+            for ext in lit::INTEGRATION_TEST_FILE_EXTENSIONS {
+                config.add_extension(ext);
+            }
+
+            config.jobs = 4;
+        });
+        assert!(results.is_successful(), "unit test(s) failed");
+
+        results.test_results.into_iter().map(|r| r.path.relative).collect::<Vec<_>>()
+    };
+
+    let first_run_order = run_in_parallel();
+    let second_run_order = run_in_parallel();
+
+    let mut discovery_order = first_run_order.clone();
+    discovery_order.sort();
+
+    assert_eq!(first_run_order, second_run_order);
+    assert_eq!(first_run_order, discovery_order);
+}
+
+/// Runs every shard of the integration tests, verifying that together
+/// they run each test exactly once.
+#[test]
+fn shards_partition_the_test_suite() {
+    const SHARD_COUNT: usize = 3;
+
+    let run_shard = |shard: Option<(usize, usize)>| {
+        let results = run::tests_with_results(lit::event_handler::Default::default(), |config| {
+            config.add_search_path(format!("{}/integration-tests", CRATE_PATH));
+            for ext in lit::INTEGRATION_TEST_FILE_EXTENSIONS {
+                config.add_extension(ext);
+            }
+
+            config.shard = shard;
+        });
+        assert!(results.is_successful(), "unit test(s) failed");
+
+        results.test_results.into_iter().map(|r| r.path.relative).collect::<Vec<_>>()
+    };
+
+    let all_tests = run_shard(None);
+    let mut sharded_tests: Vec<_> = (0..SHARD_COUNT).flat_map(|i| run_shard(Some((i, SHARD_COUNT)))).collect();
+    sharded_tests.sort();
+
+    assert_eq!(sharded_tests, all_tests);
+}
+
+/// Runs the integration tests with one of them marked as known to fail, verifying
+/// that it is reported as an unexpected pass because it actually passes.
+#[test]
+fn passing_tests_that_are_allowed_to_fail_are_unexpected_passes() {
+    let run_with_allowed_failures = |allow_failures: &[&str]| {
+        run::tests_with_results(lit::event_handler::Default::default(), |config| {
+            config.add_search_path(format!("{}/integration-tests", CRATE_PATH));
+            for ext in lit::INTEGRATION_TEST_FILE_EXTENSIONS {
+                config.add_extension(ext);
+            }
+
+            config.allow_failures.extend(allow_failures.iter().map(Into::into));
+        }).summary()
+    };
+
+    let summary = run_with_allowed_failures(&[]);
+    let summary_with_allowed_failures = run_with_allowed_failures(&["check-dag.sh"]);
+
+    assert_eq!(summary_with_allowed_failures.unexpected_passes, summary.unexpected_passes + 1);
+    assert_eq!(summary_with_allowed_failures.passes, summary.passes - 1);
+}